@@ -7,14 +7,32 @@
 // except according to those terms.
 
 use debugger::parser;
+use debugger::symbols::Symbols;
+use debugger::viewer::{Viewer, ViewerKind};
 use getopts::Options;
-use nes::nes::NES;
-use std::io::{self, stderr, stdout, Write};
+use io::log::{self, LogLevel};
+use nes::apu::Channel;
+use nes::cpu::{
+    CPU, BREAK_COMMAND, CARRY_FLAG, DECIMAL_MODE, INTERRUPT_DISABLE, NEGATIVE_FLAG, OVERFLOW_FLAG,
+    ZERO_FLAG,
+};
+use nes::instruction::Instruction;
+use nes::mapper::Mapper;
+use nes::memory::*;
+use nes::nes::{NESRuntimeOptions, NES};
+use nes::palette;
+use nes::ppu::{DOTS_PER_SCANLINE, PPU, SCANLINES_PER_FRAME};
+use std::fs::File;
+use std::io::{self, stdout, BufWriter, Write};
 use std::sync::mpsc::{Receiver, SyncSender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use utils::arithmetic;
 
+// How many instructions "next" runs while waiting for a subroutine to return
+// before giving up, in case the subroutine never returns.
+const DEFAULT_NEXT_LIMIT: u64 = 1000000;
+
 #[derive(Debug)]
 enum Command {
     Help,
@@ -23,6 +41,35 @@ enum Command {
     Continue,
     Dump,
     ObjDump,
+    Goto,
+    View,
+    BreakLine,
+    Apu,
+    Until,
+    Info,
+    Palettes,
+    Bindings,
+    Trace,
+    Frame,
+    Current,
+    Break,
+    Delete,
+    Breakpoints,
+    Watch,
+    Step,
+    Next,
+    Registers,
+    Set,
+    Poke,
+    BreakOp,
+}
+
+/// Which accesses to an address stop execution at a watchpoint.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
 }
 
 struct CommandWithArguments {
@@ -35,15 +82,72 @@ pub struct Debugger {
     receiver: Receiver<String>,
     stepping: bool,
     shutdown: bool,
+    viewers: Vec<Viewer>,
+    last_view_update: Instant,
+
+    // Halts execution once the PPU reaches this scanline, and optionally a
+    // specific dot on it.
+    scanline_breakpoint: Option<(u16, Option<u16>)>,
+
+    // Halts execution the first time the program counter reaches this address
+    // and is then removed.
+    temporary_breakpoint: Option<u16>,
+
+    // Instructions left to run before giving up on the temporary breakpoint
+    // set by "next".
+    next_limit: Option<u64>,
+
+    // Halts execution whenever the program counter reaches one of these
+    // addresses, along with the number each was given when it was set.
+    breakpoints: Vec<(u32, u16)>,
+
+    // Where the program counter was while execution was stopped. Breakpoints
+    // there don't stop the first instruction after resuming, otherwise
+    // continuing from a breakpoint would stop on it again straight away.
+    stopped_at: Option<u16>,
+
+    // Halts execution after an instruction reads or writes one of these
+    // addresses. Watchpoints are numbered alongside breakpoints.
+    watchpoints: Vec<(u32, u16, WatchKind)>,
+    next_breakpoint: u32,
+
+    // Halts execution before any instruction with one of these opcodes runs.
+    opcode_breakpoints: Vec<u8>,
+
+    // The last step command entered, which is run again when an empty line
+    // is entered while execution is stopped.
+    last_step: Option<String>,
+
+    runtime_options: NESRuntimeOptions,
 }
 
 impl Debugger {
-    pub fn new(sender: SyncSender<u8>, receiver: Receiver<String>) -> Self {
+    /// Creates a debugger that talks to the readline thread over the given
+    /// channels. Execution starts out stopped unless stepping is set. Output
+    /// goes through the log, so the runtime options decide what's shown.
+    pub fn new(
+        sender: SyncSender<u8>,
+        receiver: Receiver<String>,
+        stepping: bool,
+        runtime_options: NESRuntimeOptions,
+    ) -> Self {
         Self {
             sender: sender,
             receiver: receiver,
-            stepping: true,
+            stepping: stepping,
             shutdown: false,
+            viewers: Vec::new(),
+            last_view_update: Instant::now(),
+            scanline_breakpoint: None,
+            temporary_breakpoint: None,
+            next_limit: None,
+            stopped_at: None,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_breakpoint: 1,
+            opcode_breakpoints: Vec::new(),
+            last_step: None,
+            runtime_options: runtime_options,
         }
     }
 
@@ -57,11 +161,13 @@ impl Debugger {
     pub fn step(&mut self, nes: &mut NES) -> bool {
         match self.receiver.try_recv() {
             Ok(input) => {
+                let input = self.repeated_input(input);
                 if let Some(command) = self.interpret(input.clone()) {
+                    self.remember_step(&input, &command.command);
                     self.execute_command(command, nes);
                 } else {
                     if input.len() > 0 {
-                        writeln!(stderr(), "nes-rs: unknown command specified").unwrap();
+                        log::error("unknown command specified", &self.runtime_options);
                     }
                 }
 
@@ -77,22 +183,150 @@ impl Debugger {
         // If the debugger is in stepping mode, continue execution like normal,
         // otherwise the CPU and other peripherals should not update. In the
         // meantime, sleep the host CPU while we wait for input.
-        if self.stepping {
-            nes.step();
+        if self.running(nes.paused) {
+            if self.run_instruction(nes, false) {
+                self.halt();
+            }
         } else {
+            self.stopped_at = Some(nes.cpu.pc);
             thread::sleep(Duration::from_millis(16));
         }
 
+        // Audio shouldn't keep playing while execution is stopped or paused,
+        // and is muted while fast-forwarding.
+        if let Some(ref mut audio) = nes.audio {
+            audio.set_paused(!self.running(nes.paused) || nes.fast_forward);
+        }
+
+        // Refresh any open views at roughly 60Hz so they update live without
+        // slowing emulation down too much.
+        if !self.viewers.is_empty() && self.last_view_update.elapsed() >= Duration::from_millis(16)
+        {
+            for viewer in self.viewers.iter_mut() {
+                viewer.render(nes);
+            }
+            self.last_view_update = Instant::now();
+        }
+
         return self.shutdown;
     }
 
+    /// Pressing enter while stopped repeats the last step so code can be
+    /// walked through without retyping the command. Returns the input that
+    /// should be run in place of what was typed.
+    fn repeated_input(&self, input: String) -> String {
+        match self.last_step {
+            Some(ref last_step) if !self.stepping && input.trim().is_empty() => last_step.clone(),
+            _ => input,
+        }
+    }
+
+    /// Remembers the input of a step or next command so an empty line can
+    /// repeat it. Any other command forgets it.
+    fn remember_step(&mut self, input: &str, command: &Command) {
+        self.last_step = match *command {
+            Command::Step | Command::Next => Some(input.to_string()),
+            _ => None,
+        };
+    }
+
+    /// Returns true if the NES should be stepped, which is while execution
+    /// hasn't been stopped from the debugger or paused from the keyboard.
+    /// Execution starts out stopped with --debug-break-on-start, so nothing
+    /// runs until "continue" or a step command.
+    fn running(&self, paused: bool) -> bool {
+        self.stepping && !paused
+    }
+
+    /// Runs the next instruction the same way whether execution continues or
+    /// is stepped through by hand, so breakpoints, watchpoints and the rest
+    /// stop execution either way. The instruction is logged before it runs if
+    /// asked to. Returns true if execution should stop.
+    ///
+    /// Breakpoints at the address execution resumed from are skipped, since
+    /// execution would otherwise stop on them again straight away.
+    fn run_instruction(&mut self, nes: &mut NES, log_instruction: bool) -> bool {
+        let before = (nes.ppu.scanline(), nes.ppu.dot());
+        let pc = nes.cpu.pc;
+        let resumed_here = self.stopped_at.take() == Some(pc);
+        self.next_limit = self.next_limit.map(|limit| limit.saturating_sub(1));
+
+        // Memory only records accesses while there are watchpoints to
+        // check them against.
+        nes.memory.accesses = if self.watchpoints.is_empty() {
+            None
+        } else {
+            Some(Vec::new())
+        };
+
+        // Breakpoints stop execution before the instruction at them runs,
+        // while everything else is checked once the instruction is done.
+        // Halt and drop back to the prompt if the CPU can't continue so the
+        // state can be inspected.
+        let breakpoint = if resumed_here { None } else { self.hit_breakpoint(pc) };
+        if let Some(number) = breakpoint {
+            let message =
+                format!("Hit breakpoint {} at ${:04X}, stopping execution...", number, pc);
+            log::info(message, &self.runtime_options);
+            print_current_instruction(nes);
+            log::info(nes.cpu.to_string(), &self.runtime_options);
+            true
+        } else if !resumed_here && self.hit_opcode_breakpoint(nes) {
+            let message = "Reached a breakpoint opcode, stopping execution...";
+            log::info(message, &self.runtime_options);
+            print_current_instruction(nes);
+            log::info(nes.cpu.to_string(), &self.runtime_options);
+            true
+        } else if let Err(e) = self.step_instruction(nes, log_instruction) {
+            log::error(e, &self.runtime_options);
+            log::info(nes.cpu.to_string(), &self.runtime_options);
+            true
+        } else if self.hit_watchpoint(pc, nes) {
+            log::info(nes.cpu.to_string(), &self.runtime_options);
+            true
+        } else if self.hit_temporary_breakpoint(nes.cpu.pc) {
+            if self.next_limit.is_some() {
+                print_registers(nes);
+            } else {
+                let message = format!("Reached ${:04X}, stopping execution...", nes.cpu.pc);
+                log::info(message, &self.runtime_options);
+                log::info(nes.cpu.to_string(), &self.runtime_options);
+            }
+            true
+        } else if self.hit_scanline_breakpoint(before, &nes.ppu) {
+            let message = "Reached scanline breakpoint, stopping execution...";
+            log::info(message, &self.runtime_options);
+            log::info(nes.ppu.to_string(), &self.runtime_options);
+            log::info(nes.cpu.to_string(), &self.runtime_options);
+            true
+        } else if self.next_limit == Some(0) {
+            log::info("Subroutine didn't return, stopping execution...", &self.runtime_options);
+            print_current_instruction(nes);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Steps the NES, first printing the instruction about to run like a line
+    /// of a Nintendulator log if asked to.
+    fn step_instruction(&self, nes: &mut NES, log_instruction: bool) -> Result<(), String> {
+        if log_instruction {
+            let pc = nes.cpu.pc as usize;
+            if let Ok(instr) = Instruction::parse_unrestricted(pc, &mut nes.memory) {
+                let message = instr.log(&nes.cpu, &mut nes.memory);
+                log::info(message, &self.runtime_options);
+            }
+        }
+        nes.step()
+    }
+
     /// Parse a raw input string into a list of arguments and a command.
     fn interpret(&self, input: String) -> Option<CommandWithArguments> {
-        let mut stderr = io::stderr();
         let args = match parser::input_to_arguments(input) {
             Ok(args) => args,
             Err(e) => {
-                writeln!(stderr, "nes-rs: {}", e).unwrap();
+                log::error(e, &self.runtime_options);
                 return None;
             }
         };
@@ -113,11 +347,40 @@ impl Debugger {
                 "continue" => Command::Continue,
                 "dump" => Command::Dump,
                 "objdump" => Command::ObjDump,
+                "goto" => Command::Goto,
+                "view" => Command::View,
+                "palettes" => Command::Palettes,
+                "bindings" => Command::Bindings,
+                "trace" => Command::Trace,
+                "frame" => Command::Frame,
+                "cur" => Command::Current,
+                "break" => Command::Break,
+                "delete" => Command::Delete,
+                "breakpoints" => Command::Breakpoints,
+                "watch" => Command::Watch,
+                "step" => Command::Step,
+                "next" => Command::Next,
+                "registers" => Command::Registers,
+                "set" => Command::Set,
+                "poke" => Command::Poke,
+                "breakop" => Command::BreakOp,
+                "breakline" => Command::BreakLine,
+                "apu" => Command::Apu,
+                "until" => Command::Until,
+                "info" => Command::Info,
                 // Aliases.
                 "s" => Command::Stop,
                 "c" => Command::Continue,
                 "d" => Command::Dump,
                 "od" => Command::ObjDump,
+                "jump" => Command::Goto,
+                "bl" => Command::BreakLine,
+                "tbreak" => Command::Until,
+                "x/i" => Command::Current,
+                "b" => Command::Break,
+                "si" => Command::Step,
+                "n" => Command::Next,
+                "r" => Command::Registers,
                 // Unknown command.
                 _ => {
                     return None;
@@ -142,13 +405,33 @@ impl Debugger {
             Command::Continue => self.execute_continue(),
             Command::Dump => self.execute_dump(nes, &command.args),
             Command::ObjDump => self.execute_objdump(nes, &command.args),
+            Command::Goto => self.execute_goto(nes, &command.args),
+            Command::View => self.execute_view(nes, &command.args),
+            Command::BreakLine => self.execute_breakline(&command.args),
+            Command::Apu => self.execute_apu(nes, &command.args),
+            Command::Until => self.execute_until(&nes.cpu, &command.args),
+            Command::Info => self.execute_info(nes, &command.args),
+            Command::Palettes => self.execute_palettes(nes),
+            Command::Bindings => self.execute_bindings(nes),
+            Command::Trace => self.execute_trace(&mut nes.cpu, &command.args),
+            Command::Frame => self.execute_frame(nes, &command.args),
+            Command::Current => self.execute_current(nes),
+            Command::Break => self.execute_break(&nes.cpu, &command.args),
+            Command::Delete => self.execute_delete(&command.args),
+            Command::Breakpoints => self.execute_breakpoints(nes),
+            Command::Watch => self.execute_watch(nes, &command.args),
+            Command::Step => self.execute_step(nes, &command.args),
+            Command::Next => self.execute_next(nes, &command.args),
+            Command::Registers => self.execute_registers(nes),
+            Command::Set => self.execute_set(nes, &command.args),
+            Command::Poke => self.execute_poke(nes, &command.args),
+            Command::BreakOp => self.execute_breakop(&command.args),
         };
     }
 
     /// Shows friendly help text for information about using the debugger.
     fn execute_help(&self) {
-        writeln!(
-            stderr(),
+        log::info(
             "
 Welcome to the nes-rs debugger!
 
@@ -156,10 +439,17 @@ This subshell provides access to a few different commands that allow you to
 modify and observe the state of the virtual machine. At the moment there is a
 very limited set of commands and more may be added in the future.
 
-Supported commands: help | exit | stop | continue | dump | objdump
-"
-        )
-        .unwrap();
+Supported commands: help | exit | stop | continue | dump | objdump | goto |
+                    view | breakline | apu | until | info | palettes |
+                    bindings | trace | frame | cur | break | delete |
+                    breakpoints | watch | step | next | registers |
+                    set | poke | breakop
+
+Addresses can be written as expressions like pc+10 or sp-2. Numbers are hex
+unless prefixed with #, and registers pc, sp, a, x, y and p can be used.
+",
+            &self.runtime_options,
+        );
     }
 
     /// Stops the virtual machine by setting the shutdown flag.
@@ -175,19 +465,19 @@ Supported commands: help | exit | stop | continue | dump | objdump
     /// a problem or stare at hex codes all day to look like a l33t haxor.
     fn execute_stop(&mut self) {
         if self.stepping {
-            println!("Stopping execution now...");
-            self.stepping = false;
+            log::info("Stopping execution now...", &self.runtime_options);
+            self.halt();
         } else {
-            println!("Execution is already stopped.");
+            log::info("Execution is already stopped.", &self.runtime_options);
         }
     }
 
     /// Starts execution if it's stopped.
     fn execute_continue(&mut self) {
         if self.stepping {
-            println!("Execution is already happening.");
+            log::info("Execution is already happening.", &self.runtime_options);
         } else {
-            println!("Starting execution now...");
+            log::info("Starting execution now...", &self.runtime_options);
             self.stepping = true;
         }
     }
@@ -195,6 +485,9 @@ Supported commands: help | exit | stop | continue | dump | objdump
     /// Allows dumping memory or program code at a specified memory address. A
     /// custom peek value can be specified which is the number of 16-byte
     /// segments to seek forward with during the dump.
+    ///
+    /// Memory can also be exported as CSV or JSON for other programs to read,
+    /// either to stdout or to a file.
     fn execute_dump(&mut self, nes: &mut NES, args: &Vec<String>) {
         const USAGE: &'static str = "Usage: dump [OPTION]... [ADDRESS]";
 
@@ -205,12 +498,15 @@ Supported commands: help | exit | stop | continue | dump | objdump
             "how far forward should memory be dumped",
             "NUMBER",
         );
+        opts.optopt("l", "len", "number of bytes to dump, up to 65536", "NUMBER");
+        opts.optopt("f", "format", "hex, csv or json (default hex)", "FORMAT");
+        opts.optopt("o", "output", "write a csv or json dump to a file", "FILE");
 
         let matches = match opts.parse(&args[1..]) {
             Ok(m) => m,
             Err(f) => {
-                writeln!(stderr(), "dump: {}", f).unwrap();
-                writeln!(stderr(), "{}", opts.usage(USAGE)).unwrap();
+                log::error(format!("dump: {}", f), &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
                 return;
             }
         };
@@ -218,29 +514,81 @@ Supported commands: help | exit | stop | continue | dump | objdump
             Some(arg) => match arg.parse::<u16>() {
                 Ok(p) => p,
                 Err(e) => {
-                    writeln!(stderr(), "dump: {}", e).unwrap();
-                    writeln!(stderr(), "{}", opts.usage(USAGE)).unwrap();
+                    log::error(format!("dump: {}", e), &self.runtime_options);
+                    log::error(opts.usage(USAGE), &self.runtime_options);
                     return;
                 }
             },
             None => 10,
         };
+        let len = match matches.opt_str("len").map(|arg| arg.parse::<u32>()) {
+            None => peek as u32 * 16,
+            Some(Ok(len)) if len > 0 && len <= 0x10000 => len,
+            Some(_) => {
+                log::error("dump: invalid length", &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
+            }
+        };
+        let format = matches.opt_str("format").map(|arg| arg.to_lowercase());
+        let format = match format.as_ref().map(|format| format.as_str()) {
+            None | Some("hex") => "hex",
+            Some("csv") => "csv",
+            Some("json") => "json",
+            Some(format) => {
+                log::error(format!("dump: unknown format: {}", format), &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
+            }
+        };
+        let output = matches.opt_str("output");
+        if format == "hex" && output.is_some() {
+            let message = "dump: only csv and json dumps can be written to a file";
+            log::error(message, &self.runtime_options);
+            return;
+        }
 
-        // Parse hex representation of a memory address at free argument if
-        // available, otherwise the address will be the program counter.
+        // Evaluate the address expression at free argument if available,
+        // otherwise the address will be the program counter.
         let addr = if !matches.free.is_empty() {
-            let arg = matches.free[0].clone();
-            if let Some(hex) = arithmetic::hex_to_u16(&arg) {
-                hex
-            } else {
-                writeln!(stderr(), "dump: cannot parse address: {}", arg).unwrap();
-                return;
+            match parser::evaluate_address(&matches.free[0], &nes.cpu) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::error(format!("dump: {}", e), &self.runtime_options);
+                    return;
+                }
             }
         } else {
             nes.cpu.pc // Default address if unspecified.
         };
 
-        for idx in 0..peek {
+        if format != "hex" {
+            let memory = &mut nes.memory;
+            let result = match output {
+                Some(ref filename) => File::create(filename).and_then(|file| {
+                    let mut out = BufWriter::new(file);
+                    export_memory(&mut out, memory, addr, len, format).and_then(|_| out.flush())
+                }),
+                None if log::enabled(LogLevel::Info, &self.runtime_options) => {
+                    let stdout = stdout();
+                    let mut out = stdout.lock();
+                    export_memory(&mut out, memory, addr, len, format)
+                }
+                None => Ok(()),
+            };
+            match (result, output) {
+                (Err(e), _) => log::error(format!("dump: {}", e), &self.runtime_options),
+                (Ok(_), Some(filename)) => {
+                    let message = format!("Wrote {} bytes to {}.", len, filename);
+                    log::info(message, &self.runtime_options);
+                }
+                (Ok(_), None) => {}
+            }
+            return;
+        }
+
+        let peek = (len + 15) / 16;
+        for idx in 0..peek as u16 {
             let peek_amount = idx.wrapping_mul(16);
             let peek_offset = addr.wrapping_add(peek_amount);
             let mut bytes: [u8; 16] = [0; 16];
@@ -255,30 +603,811 @@ Supported commands: help | exit | stop | continue | dump | objdump
 
             // Print the memory address for for the first byte in the line and 2
             // 8-bit bytes.
-            print!("{:04x}  ", peek_offset);
+            let mut line = format!("{:04x}  ", peek_offset);
             for offset in 0..8 {
-                print!("{:02x} ", bytes[offset]);
+                line.push_str(&format!("{:02x} ", bytes[offset]));
             }
-            print!(" ");
+            line.push(' ');
             for offset in 0..8 {
-                print!("{:02x} ", bytes[offset + 8]);
+                line.push_str(&format!("{:02x} ", bytes[offset + 8]));
             }
 
             // Print out an ASCII representation of the bytes. If the byte is
             // not safe to print in a terminal just display a dot.
-            print!(" ");
+            line.push(' ');
             for offset in 0..16 {
-                let value = bytes[offset];
-                let human_char = if value >= 0x20 && value <= 0x7E {
-                    value as char
-                } else {
-                    '.'
+                line.push(printable(bytes[offset]));
+            }
+
+            // Name any labeled bytes in the line along with their offset.
+            let mut separator = "  ;";
+            for offset in 0..16 {
+                if let Some(label) = nes.symbols.label(peek_offset.wrapping_add(offset)) {
+                    line.push_str(&format!("{} {}+{:x}", separator, label, offset));
+                    separator = ",";
+                }
+            }
+
+            log::info(line, &self.runtime_options);
+        }
+    }
+
+    /// Writes bytes to consecutive addresses starting at the given one and
+    /// shows what they replaced. Writes go straight to memory by default, so
+    /// registers aren't affected and PRG-ROM can't be changed. With --bus
+    /// they're made like the CPU makes them, side effects and all.
+    fn execute_poke(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: poke [OPTION]... [ADDRESS] [BYTE]...";
+
+        let mut opts = Options::new();
+        opts.optflag("b", "bus", "write through the CPU bus so registers see the writes");
+
+        let matches = match opts.parse(&args[1..]) {
+            Ok(m) => m,
+            Err(f) => {
+                log::error(format!("poke: {}", f), &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
+            }
+        };
+        if matches.free.len() < 2 {
+            log::error(opts.usage(USAGE), &self.runtime_options);
+            return;
+        }
+
+        let addr = match parser::evaluate_address(&matches.free[0], &nes.cpu) {
+            Ok(addr) => addr,
+            Err(e) => {
+                log::error(format!("poke: {}", e), &self.runtime_options);
+                return;
+            }
+        };
+        let mut bytes = Vec::with_capacity(matches.free.len() - 1);
+        for arg in matches.free[1..].iter() {
+            match parser::evaluate_address(arg, &nes.cpu) {
+                Ok(value) if value <= 0xFF => bytes.push(value as u8),
+                _ => {
+                    log::error(format!("poke: invalid byte: {}", arg), &self.runtime_options);
+                    log::error(opts.usage(USAGE), &self.runtime_options);
+                    return;
+                }
+            }
+        }
+        if addr as usize + bytes.len() > 0x10000 {
+            log::error("poke: writing past $FFFF", &self.runtime_options);
+            log::error(opts.usage(USAGE), &self.runtime_options);
+            return;
+        }
+
+        let bus = matches.opt_present("bus");
+        for (offset, &value) in bytes.iter().enumerate() {
+            let target = addr as usize + offset;
+            let old = nes.memory.read_u8_unrestricted(target);
+            if bus {
+                nes.memory.write_u8(target, value);
+            } else {
+                nes.memory.write_u8_unrestricted(target, value);
+            }
+            let message = format!("${:04X}: ${:02X} -> ${:02X}", target, old, value);
+            log::info(message, &self.runtime_options);
+        }
+    }
+
+    /// Moves the program counter to the specified address and shows the
+    /// instruction that will execute next. Nothing is executed and the stack is
+    /// left alone, so this is a plain jump rather than a subroutine call.
+    fn execute_goto(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: goto [ADDRESS]";
+
+        let addr = if args.len() > 1 {
+            match parser::evaluate_address(&args[1], &nes.cpu) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::error(format!("goto: {}", e), &self.runtime_options);
+                    return;
+                }
+            }
+        } else {
+            log::error(USAGE, &self.runtime_options);
+            return;
+        };
+
+        match goto(&mut nes.cpu, &mut nes.memory, &nes.symbols, addr) {
+            Ok(message) => log::info(message, &self.runtime_options),
+            Err(e) => log::error(format!("goto: {}", e), &self.runtime_options),
+        }
+    }
+
+    /// Shows the instruction at the program counter along with its raw bytes
+    /// without executing it, which is handy for checking where execution is
+    /// after changing registers.
+    fn execute_current(&mut self, nes: &mut NES) {
+        print_current_instruction(nes);
+    }
+
+    /// Shows the registers along with each of the status flags.
+    fn execute_registers(&self, nes: &NES) {
+        log::info(nes.cpu.to_string(), &self.runtime_options);
+    }
+
+    /// Changes a register or a single status flag, e.g. "set a 3f" or "set c
+    /// 1". Values are address expressions, so they're hex unless prefixed
+    /// with #. A new program counter takes effect on the next step.
+    fn execute_set(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: set [REGISTER | FLAG] [VALUE]\n\
+                                     Registers: pc | sp | a | x | y | p\n\
+                                     Flags: c | z | i | d | b | v | n";
+
+        if args.len() != 3 {
+            log::error(USAGE, &self.runtime_options);
+            return;
+        }
+        let value = match parser::evaluate_address(&args[2], &nes.cpu) {
+            Ok(value) => value,
+            Err(e) => {
+                log::error(format!("set: {}", e), &self.runtime_options);
+                return;
+            }
+        };
+
+        let name = args[1].to_lowercase();
+        let flag = match name.as_str() {
+            "c" => Some(CARRY_FLAG),
+            "z" => Some(ZERO_FLAG),
+            "i" => Some(INTERRUPT_DISABLE),
+            "d" => Some(DECIMAL_MODE),
+            "b" => Some(BREAK_COMMAND),
+            "v" => Some(OVERFLOW_FLAG),
+            "n" => Some(NEGATIVE_FLAG),
+            _ => None,
+        };
+        if let Some(flag) = flag {
+            match value {
+                0 => nes.cpu.p &= !flag,
+                1 => nes.cpu.p |= flag,
+                _ => {
+                    log::error("set: flags can only be set to 0 or 1", &self.runtime_options);
+                    return;
+                }
+            }
+            log::info(format!("P = ${:02X}", nes.cpu.p), &self.runtime_options);
+            return;
+        }
+
+        if name == "pc" {
+            nes.cpu.pc = value;
+            log::info(format!("PC = ${:04X}", value), &self.runtime_options);
+            return;
+        }
+        if value > 0xFF {
+            log::error(format!("set: ${:X} doesn't fit in {}", value, name), &self.runtime_options);
+            return;
+        }
+        let value = value as u8;
+        match name.as_str() {
+            "sp" => nes.cpu.sp = value,
+            "a" => nes.cpu.a = value,
+            "x" => nes.cpu.x = value,
+            "y" => nes.cpu.y = value,
+            "p" => nes.cpu.p = value,
+            _ => {
+                log::error(format!("set: unknown register: {}", args[1]), &self.runtime_options);
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        }
+        log::info(format!("{} = ${:02X}", name.to_uppercase(), value), &self.runtime_options);
+    }
+
+    /// Sets a breakpoint that stops execution every time the program counter
+    /// reaches the given address, before the instruction there executes.
+    fn execute_break(&mut self, cpu: &CPU, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: break [ADDRESS]";
+
+        let addr = match args.get(1) {
+            Some(arg) => match parser::evaluate_address(arg, cpu) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::error(format!("break: {}", e), &self.runtime_options);
+                    return;
+                }
+            },
+            None => {
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        };
+
+        if let Some(&(number, _)) = self.breakpoints.iter().find(|&&(_, a)| a == addr) {
+            let message = format!("Breakpoint {} is already at ${:04X}.", number, addr);
+            log::info(message, &self.runtime_options);
+            return;
+        }
+        let number = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.breakpoints.push((number, addr));
+        log::info(format!("Breakpoint {} at ${:04X}.", number, addr), &self.runtime_options);
+    }
+
+    /// Removes a breakpoint or watchpoint by the number it was given when it
+    /// was set.
+    fn execute_delete(&mut self, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: delete [NUMBER]";
+
+        let number = match args.get(1).map(|arg| arg.parse::<u32>()) {
+            Some(Ok(number)) => number,
+            Some(Err(_)) => {
+                let message = format!("delete: invalid breakpoint number: {}", args[1]);
+                log::error(message, &self.runtime_options);
+                return;
+            }
+            None => {
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        };
+
+        if let Some(index) = self.breakpoints.iter().position(|&(n, _)| n == number) {
+            let (_, addr) = self.breakpoints.remove(index);
+            let message = format!("Deleted breakpoint {} at ${:04X}.", number, addr);
+            log::info(message, &self.runtime_options);
+        } else if let Some(index) = self.watchpoints.iter().position(|&(n, _, _)| n == number) {
+            let (_, addr, _) = self.watchpoints.remove(index);
+            let message = format!("Deleted watchpoint {} on ${:04X}.", number, addr);
+            log::info(message, &self.runtime_options);
+        } else {
+            let message = format!("delete: no breakpoint or watchpoint {}", number);
+            log::error(message, &self.runtime_options);
+        }
+    }
+
+    /// Lists the breakpoints and watchpoints that are set, with labels for
+    /// their addresses when a symbol file was loaded.
+    fn execute_breakpoints(&self, nes: &NES) {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            log::info("No breakpoints or watchpoints.", &self.runtime_options);
+            return;
+        }
+        let breakpoints = self.breakpoints.iter().map(|&(n, addr)| (n, addr, "break"));
+        let watchpoints = self.watchpoints.iter().map(|&(n, addr, kind)| {
+            let kind = match kind {
+                WatchKind::Read => "read",
+                WatchKind::Write => "write",
+                WatchKind::ReadWrite => "rw",
+            };
+            (n, addr, kind)
+        });
+        let mut points: Vec<(u32, u16, &str)> = breakpoints.chain(watchpoints).collect();
+        points.sort_by_key(|&(number, _, _)| number);
+        for (number, addr, kind) in points {
+            let message = match nes.symbols.label(addr) {
+                Some(label) => format!("{:3}  {:5}  ${:04X}  {}", number, kind, addr, label),
+                None => format!("{:3}  {:5}  ${:04X}", number, kind, addr),
+            };
+            log::info(message, &self.runtime_options);
+        }
+    }
+
+    /// Stops execution before any instruction with the given opcode runs, which
+    /// helps find where BRK or unofficial opcodes are executed. "--list" shows
+    /// the opcodes being watched for and "--clear" removes all of them.
+    fn execute_breakop(&mut self, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: breakop [OPTION]... [OPCODE]...";
+
+        let mut opts = Options::new();
+        opts.optflag("l", "list", "list the opcodes execution stops at");
+        opts.optflag("c", "clear", "remove every opcode breakpoint");
+
+        let matches = match opts.parse(&args[1..]) {
+            Ok(m) => m,
+            Err(f) => {
+                log::error(format!("breakop: {}", f), &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
+            }
+        };
+
+        if matches.opt_present("clear") {
+            self.opcode_breakpoints.clear();
+            log::info("Cleared opcode breakpoints.", &self.runtime_options);
+        }
+        for arg in matches.free.iter() {
+            let opcode = match arithmetic::hex_to_u16(arg) {
+                Some(opcode) if opcode <= 0xFF => opcode as u8,
+                _ => {
+                    log::error(format!("breakop: invalid opcode: {}", arg), &self.runtime_options);
+                    return;
+                }
+            };
+            if !self.opcode_breakpoints.contains(&opcode) {
+                self.opcode_breakpoints.push(opcode);
+            }
+            log::info(format!("Breaking on opcode ${:02X}.", opcode), &self.runtime_options);
+        }
+        if matches.opt_present("list") {
+            if self.opcode_breakpoints.is_empty() {
+                log::info("No opcode breakpoints.", &self.runtime_options);
+            }
+            for opcode in self.opcode_breakpoints.iter() {
+                log::info(format!("${:02X}", opcode), &self.runtime_options);
+            }
+        } else if matches.free.is_empty() && !matches.opt_present("clear") {
+            log::error(opts.usage(USAGE), &self.runtime_options);
+        }
+    }
+
+    /// Stops execution. Whatever stopped it, a pending temporary breakpoint
+    /// shouldn't fire the next time execution continues.
+    fn halt(&mut self) {
+        self.stepping = false;
+        self.temporary_breakpoint = None;
+        self.next_limit = None;
+    }
+
+    /// Returns true if the program counter reached the temporary breakpoint
+    /// set by "until" or "next".
+    fn hit_temporary_breakpoint(&self, pc: u16) -> bool {
+        self.temporary_breakpoint == Some(pc)
+    }
+
+    /// Returns the number of the breakpoint at the given address, if there is
+    /// one.
+    fn hit_breakpoint(&self, pc: u16) -> Option<u32> {
+        self.breakpoints
+            .iter()
+            .find(|&&(_, addr)| addr == pc)
+            .map(|&(number, _)| number)
+    }
+
+    /// Returns true if the instruction about to run has one of the opcodes
+    /// execution should stop at. The opcode is peeked so nothing is disturbed.
+    fn hit_opcode_breakpoint(&self, nes: &mut NES) -> bool {
+        if self.opcode_breakpoints.is_empty() {
+            return false;
+        }
+        let opcode = nes.memory.read_u8_unrestricted(nes.cpu.pc as usize);
+        self.opcode_breakpoints.contains(&opcode)
+    }
+
+    /// Sets a watchpoint that stops execution after an instruction accesses
+    /// the given address. Writes are watched unless --read or --rw is given.
+    /// Mirrors of the address are watched as well.
+    fn execute_watch(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: watch [OPTION]... [ADDRESS]";
+
+        let mut opts = Options::new();
+        opts.optflag("r", "read", "stop when the address is read");
+        opts.optflag("w", "write", "stop when the address is written (default)");
+        opts.optflag("", "rw", "stop when the address is read or written");
+
+        let matches = match opts.parse(&args[1..]) {
+            Ok(m) => m,
+            Err(f) => {
+                log::error(format!("watch: {}", f), &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
+            }
+        };
+
+        let addr = match matches.free.first() {
+            Some(arg) => match parser::evaluate_address(arg, &nes.cpu) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::error(format!("watch: {}", e), &self.runtime_options);
+                    return;
+                }
+            },
+            None => {
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
+            }
+        };
+
+        let read = matches.opt_present("read");
+        let write = matches.opt_present("write");
+        let kind = if matches.opt_present("rw") || (read && write) {
+            WatchKind::ReadWrite
+        } else if read {
+            WatchKind::Read
+        } else {
+            WatchKind::Write
+        };
+
+        let number = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.watchpoints.push((number, addr, kind));
+        log::info(format!("Watchpoint {} on ${:04X}.", number, addr), &self.runtime_options);
+    }
+
+    /// Checks the accesses made by the last step against the watchpoints and
+    /// reports the first one that fired along with the instruction that set it
+    /// off. Returns true if execution should stop.
+    fn hit_watchpoint(&self, pc: u16, nes: &mut NES) -> bool {
+        let accesses = match nes.memory.accesses {
+            Some(ref accesses) => accesses,
+            None => return false,
+        };
+        for access in accesses.iter() {
+            let addr = canonical_address(access.addr);
+            let watchpoint = self.watchpoints.iter().find(|&&(_, watched, kind)| {
+                canonical_address(watched as usize) == addr
+                    && match (kind, access.kind) {
+                        (WatchKind::Read, AccessKind::Write) => false,
+                        (WatchKind::Write, AccessKind::Read) => false,
+                        _ => true,
+                    }
+            });
+            if let Some(&(number, watched, _)) = watchpoint {
+                let message = match access.kind {
+                    AccessKind::Read => format!(
+                        "Watchpoint {}: ${:04X} read by ${:04X}, value ${:02X}",
+                        number, watched, pc, access.value
+                    ),
+                    AccessKind::Write => format!(
+                        "Watchpoint {}: ${:04X} written by ${:04X}, ${:02X} -> ${:02X}",
+                        number, watched, pc, access.old, access.value
+                    ),
                 };
-                print!("{}", human_char);
+                log::info(message, &self.runtime_options);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Resumes execution until the program counter reaches the given address,
+    /// then stops. The breakpoint is removed once hit, and if the address is
+    /// never reached execution can be halted with "stop" or Ctrl-C as usual.
+    fn execute_until(&mut self, cpu: &CPU, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: until [ADDRESS]";
+
+        let addr = match args.get(1) {
+            Some(arg) => match parser::evaluate_address(arg, cpu) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::error(format!("until: {}", e), &self.runtime_options);
+                    return;
+                }
+            },
+            None => {
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        };
+
+        if cpu.pc == addr {
+            log::info(format!("Already at ${:04X}.", addr), &self.runtime_options);
+            return;
+        }
+
+        self.temporary_breakpoint = Some(addr);
+        self.stepping = true;
+        log::info(format!("Continuing until ${:04X}...", addr), &self.runtime_options);
+    }
+
+    /// Shows information about the virtual machine. "info map" prints the CPU
+    /// memory map along with the banks the mapper currently has switched in.
+    fn execute_info(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: info [map]";
+
+        match args.get(1).map(|arg| arg.to_lowercase()) {
+            Some(ref arg) if arg == "map" => self.print_memory_map(nes),
+            _ => log::error(USAGE, &self.runtime_options),
+        }
+    }
+
+    /// Prints the layout of CPU memory and the cartridge's bank assignments.
+    fn print_memory_map(&self, nes: &NES) {
+        let mapper = nes.mapper.borrow();
+        log::info(memory_map(&**mapper), &self.runtime_options);
+    }
+
+    /// Prints the active input bindings in the format used by --bindings.
+    fn execute_bindings(&self, nes: &NES) {
+        let bindings = format!("{}", nes.bindings);
+        log::info(bindings.trim_right(), &self.runtime_options);
+    }
+
+    /// Prints the background and sprite palettes in palette RAM. Each entry is
+    /// shown as its color index along with the RGB color it resolves to.
+    fn execute_palettes(&self, nes: &NES) {
+        log::info(format_palettes(nes.ppu.palettes()), &self.runtime_options);
+    }
+
+    /// Sets a breakpoint that stops execution when the PPU reaches a scanline,
+    /// and optionally a dot on that scanline. Scanlines and dots are given in
+    /// decimal. "breakline clear" removes the breakpoint.
+    fn execute_breakline(&mut self, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: breakline [SCANLINE [DOT] | clear]";
+
+        if args.len() < 2 || args.len() > 3 {
+            log::error(USAGE, &self.runtime_options);
+            return;
+        }
+
+        if args[1].to_lowercase() == "clear" {
+            self.scanline_breakpoint = None;
+            log::info("Cleared scanline breakpoint.", &self.runtime_options);
+            return;
+        }
+
+        let scanline = match args[1].parse::<u16>() {
+            Ok(scanline) if scanline < SCANLINES_PER_FRAME => scanline,
+            _ => {
+                let message = format!("breakline: invalid scanline: {}", args[1]);
+                log::error(message, &self.runtime_options);
+                return;
+            }
+        };
+        let dot = match args.get(2).map(|arg| arg.parse::<u16>()) {
+            Some(Ok(dot)) if dot < DOTS_PER_SCANLINE => Some(dot),
+            Some(_) => {
+                log::error(format!("breakline: invalid dot: {}", args[2]), &self.runtime_options);
+                return;
+            }
+            None => None,
+        };
+
+        self.scanline_breakpoint = Some((scanline, dot));
+        let message = match dot {
+            Some(dot) => format!("Breaking at scanline {}, dot {}.", scanline, dot),
+            None => format!("Breaking at scanline {}.", scanline),
+        };
+        log::info(message, &self.runtime_options);
+    }
+
+    /// Turns printing every instruction as it executes on or off, so the trace
+    /// can be limited to an interesting part of the program. Without an
+    /// argument it shows whether tracing is on.
+    fn execute_trace(&mut self, cpu: &mut CPU, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: trace [on | off]";
+
+        match args.get(1).map(|arg| arg.to_lowercase()) {
+            Some(ref arg) if arg == "on" => cpu.trace = true,
+            Some(ref arg) if arg == "off" => cpu.trace = false,
+            None => {}
+            Some(_) => {
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        }
+        let message = format!("Tracing is {}.", if cpu.trace { "on" } else { "off" });
+        log::info(message, &self.runtime_options);
+    }
+
+    /// Advances execution by a number of frames while stopped, one if no count
+    /// is given. This works the same way as frame advance while paused, except
+    /// breakpoints stop it early like they stop "continue".
+    fn execute_frame(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: frame [COUNT]";
+
+        let count = match args.get(1).map(|arg| arg.parse::<u64>()) {
+            None => 1,
+            Some(Ok(count)) if count > 0 => count,
+            Some(_) => {
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        };
+        if self.stepping {
+            log::error("frame: execution must be stopped first", &self.runtime_options);
+            return;
+        }
+
+        // Frames are run an instruction at a time so breakpoints still stop
+        // execution part way through.
+        for _ in 0..count {
+            let frame = nes.frame;
+            while nes.frame == frame {
+                if self.run_instruction(nes, false) {
+                    return;
+                }
+            }
+        }
+        log::info(format!("Stopped at frame {}.", nes.frame), &self.runtime_options);
+        log::info(nes.cpu.to_string(), &self.runtime_options);
+    }
+
+    /// Executes a number of instructions while stopped, one if no count is
+    /// given. Each instruction is printed like a line of a Nintendulator log
+    /// before it runs, followed by the registers it left behind, so the output
+    /// lines up with verbose mode. Breakpoints stop it early like they stop
+    /// "continue".
+    fn execute_step(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: step [COUNT]";
+
+        let count = match args.get(1).map(|arg| arg.parse::<u64>()) {
+            None => 1,
+            Some(Ok(count)) if count > 0 => count,
+            Some(_) => {
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        };
+        if self.stepping {
+            log::error("step: execution must be stopped first", &self.runtime_options);
+            return;
+        }
+
+        for _ in 0..count {
+            if self.run_instruction(nes, true) {
+                return;
+            }
+        }
+        print_registers(nes);
+    }
+
+    /// Executes a single instruction like "step", except subroutine calls are
+    /// stepped over by running until the subroutine returns. Breakpoints in
+    /// the subroutine still stop execution, and so does running more
+    /// instructions than the limit without returning.
+    fn execute_next(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: next [OPTION]...";
+
+        let mut opts = Options::new();
+        opts.optopt(
+            "l",
+            "limit",
+            "how many instructions to run before giving up on a subroutine",
+            "NUMBER",
+        );
+
+        let matches = match opts.parse(&args[1..]) {
+            Ok(m) => m,
+            Err(f) => {
+                log::error(format!("next: {}", f), &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
             }
-            print!("\n");
+        };
+        let limit = match matches.opt_str("limit").map(|arg| arg.parse::<u64>()) {
+            None => DEFAULT_NEXT_LIMIT,
+            Some(Ok(limit)) if limit > 0 => limit,
+            Some(_) => {
+                log::error(opts.usage(USAGE), &self.runtime_options);
+                return;
+            }
+        };
+        if self.stepping {
+            log::error("next: execution must be stopped first", &self.runtime_options);
+            return;
+        }
 
-            stdout().flush().unwrap();
+        match self.step_over(&nes.cpu, &mut nes.memory, limit) {
+            Some(line) => log::info(line, &self.runtime_options),
+            None => self.execute_step(nes, &vec!["step".to_string()]),
+        }
+    }
+
+    /// Resumes execution until the instruction after the subroutine call at
+    /// the program counter is reached, the same way "until" does. Returns the
+    /// logged call, or None if the instruction isn't a subroutine call.
+    fn step_over(&mut self, cpu: &CPU, memory: &mut Memory, limit: u64) -> Option<String> {
+        let pc = cpu.pc;
+        let line = match Instruction::parse_unrestricted(pc as usize, memory) {
+            Ok(ref instr) if instr.is_subroutine_call() => instr.log(cpu, memory),
+            _ => return None,
+        };
+        self.temporary_breakpoint = Some(pc.wrapping_add(3));
+        self.next_limit = Some(limit);
+        self.stepping = true;
+        Some(line)
+    }
+
+    /// Returns true if the PPU passed the scanline breakpoint while stepping
+    /// from the given position. The PPU runs several dots per instruction, so
+    /// the breakpoint is hit if it lies anywhere within the dots just run.
+    fn hit_scanline_breakpoint(&self, before: (u16, u16), ppu: &PPU) -> bool {
+        const DOTS_PER_FRAME: u32 = DOTS_PER_SCANLINE as u32 * SCANLINES_PER_FRAME as u32;
+
+        let (scanline, dot) = match self.scanline_breakpoint {
+            Some((scanline, dot)) => (scanline, dot.unwrap_or(0)),
+            None => return false,
+        };
+
+        let position =
+            |scanline: u16, dot: u16| scanline as u32 * DOTS_PER_SCANLINE as u32 + dot as u32;
+        let start = position(before.0, before.1);
+        let end = position(ppu.scanline(), ppu.dot());
+        let target = position(scanline, dot);
+
+        let elapsed = (end + DOTS_PER_FRAME - start) % DOTS_PER_FRAME;
+        let distance = (target + DOTS_PER_FRAME - start) % DOTS_PER_FRAME;
+        distance > 0 && distance <= elapsed
+    }
+
+    /// Inspects the APU and controls which channels are heard. "mute" toggles
+    /// a single channel, "solo" mutes every other channel, and "status" prints
+    /// the channel state and APU register values.
+    fn execute_apu(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str =
+            "Usage: apu [status | mute CHANNEL | solo CHANNEL]\n\
+             Channels: pulse1 | pulse2 | triangle | noise | dmc";
+
+        let subcommand = args.get(1).map(|arg| arg.to_lowercase());
+        let channel = args.get(2).and_then(|arg| Channel::from_name(&arg.to_lowercase()));
+
+        match (subcommand.as_ref().map(|s| s.as_str()), channel) {
+            (Some("status"), _) => self.print_apu_status(nes),
+            (Some("mute"), Some(channel)) => {
+                if nes.apu.toggle_mute(channel) {
+                    log::info(format!("Muted {}.", channel.name()), &self.runtime_options);
+                } else {
+                    log::info(format!("Unmuted {}.", channel.name()), &self.runtime_options);
+                }
+            }
+            (Some("solo"), Some(channel)) => {
+                if nes.apu.toggle_solo(channel) {
+                    log::info(format!("Soloed {}.", channel.name()), &self.runtime_options);
+                } else {
+                    log::info("Unmuted all channels.", &self.runtime_options);
+                }
+            }
+            _ => log::error(USAGE, &self.runtime_options),
+        }
+    }
+
+    /// Prints whether each channel is muted along with its length counter, and
+    /// the last values written to the APU registers.
+    fn print_apu_status(&self, nes: &NES) {
+        for &channel in Channel::ALL.iter() {
+            let length = match channel {
+                Channel::Pulse1 => Some(nes.apu.pulse_1.length_counter.counter()),
+                Channel::Pulse2 => Some(nes.apu.pulse_2.length_counter.counter()),
+                Channel::Triangle => Some(nes.apu.triangle_length_counter.counter()),
+                Channel::Noise => Some(nes.apu.noise_length_counter.counter()),
+                Channel::Dmc => None,
+            };
+            let state = if nes.apu.is_muted(channel) { "muted" } else { "on" };
+            let message = match length {
+                Some(length) => format!("{:<8}  {:<5}  length: {}", channel.name(), state, length),
+                None => format!("{:<8}  {}", channel.name(), state),
+            };
+            log::info(message, &self.runtime_options);
+        }
+        let message = format!("dmc bytes remaining: {}", nes.apu.dmc.bytes_remaining());
+        log::info(message, &self.runtime_options);
+
+        for row in 0..3 {
+            let mut line = format!("{:04X} ", 0x4000 + row * 8);
+            for offset in 0..8 {
+                let value = nes.memory.misc_ctrl_registers[row * 8 + offset];
+                line.push_str(&format!(" {:02X}", value));
+            }
+            log::info(line, &self.runtime_options);
+        }
+    }
+
+    /// Toggles an auxiliary window that visualizes PPU state. Supported views
+    /// are "oam" for sprite memory and "nametables" for the background.
+    fn execute_view(&mut self, nes: &mut NES, args: &Vec<String>) {
+        const USAGE: &'static str = "Usage: view [oam | nametables]";
+
+        let kind = match args.get(1).map(|arg| arg.to_lowercase()) {
+            Some(ref arg) if arg == "oam" => ViewerKind::Oam,
+            Some(ref arg) if arg == "nametables" => ViewerKind::Nametables,
+            _ => {
+                log::error(USAGE, &self.runtime_options);
+                return;
+            }
+        };
+
+        // Views are toggled, so close the view if it's already open.
+        if let Some(index) = self.viewers.iter().position(|v| v.kind == kind) {
+            self.viewers.remove(index);
+            log::info(format!("Closed {:?} view.", kind), &self.runtime_options);
+            return;
+        }
+
+        match Viewer::new(kind, &nes.video_subsystem) {
+            Ok(mut viewer) => {
+                viewer.render(nes);
+                self.viewers.push(viewer);
+                log::info(format!("Opened {:?} view.", kind), &self.runtime_options);
+            }
+            Err(e) => {
+                log::error(format!("view: cannot open window: {}", e), &self.runtime_options);
+            }
         }
     }
 
@@ -286,6 +1415,9 @@ Supported commands: help | exit | stop | continue | dump | objdump
     /// instructions can be of varying lengths, peek works differently for
     /// objdump than dump since peek will be the number of instructions to search
     /// for rather than the number of 16-bit words.
+    ///
+    /// With --bytes each line also shows the raw bytes of the instruction like
+    /// the CPU log does, and an ASCII rendering of them like dump does.
     fn execute_objdump(&mut self, nes: &mut NES, args: &Vec<String>) {
         const USAGE: &'static str = "Usage: objdump [OPTION]... [ADDRESS]";
 
@@ -296,12 +1428,13 @@ Supported commands: help | exit | stop | continue | dump | objdump
             "how far forward should memory be dumped",
             "NUMBER",
         );
+        opts.optflag("b", "bytes", "show the raw bytes of each instruction");
 
         let matches = match opts.parse(&args[1..]) {
             Ok(m) => m,
             Err(f) => {
-                writeln!(stderr(), "dump: {}", f).unwrap();
-                writeln!(stderr(), "{}", opts.usage(USAGE)).unwrap();
+                log::error(format!("dump: {}", f), &self.runtime_options);
+                log::error(opts.usage(USAGE), &self.runtime_options);
                 return;
             }
         };
@@ -311,28 +1444,556 @@ Supported commands: help | exit | stop | continue | dump | objdump
             Some(arg) => match arg.parse::<u16>() {
                 Ok(p) => p,
                 Err(e) => {
-                    writeln!(stderr(), "dump: {}", e).unwrap();
-                    writeln!(stderr(), "{}", opts.usage(USAGE)).unwrap();
+                    log::error(format!("dump: {}", e), &self.runtime_options);
+                    log::error(opts.usage(USAGE), &self.runtime_options);
                     return;
                 }
             },
             None => 10,
         };
 
-        // Parse hex representation of a memory address at free argument if
-        // available, otherwise the address will be the program counter.
+        // Evaluate the address expression at free argument if available,
+        // otherwise the address will be the program counter.
         let addr = if !matches.free.is_empty() {
-            let arg = matches.free[0].clone();
-            if let Some(hex) = arithmetic::hex_to_u16(&arg) {
-                hex
-            } else {
-                writeln!(stderr(), "dump: cannot parse address: {}", arg).unwrap();
-                return;
+            match parser::evaluate_address(&matches.free[0], &nes.cpu) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::error(format!("objdump: {}", e), &self.runtime_options);
+                    return;
+                }
             }
         } else {
             nes.cpu.pc
         };
 
-        println!("Unimplemented... for now.");
+        // Relative branches are disassembled against the program counter, so
+        // it follows along with each instruction and is restored afterwards.
+        let show_bytes = matches.opt_present("bytes");
+        let pc = nes.cpu.pc;
+        let mut current_addr = addr;
+        for _ in 0..peek {
+            if let Some(label) = nes.symbols.label(current_addr) {
+                log::info(format!("{}:", label), &self.runtime_options);
+            }
+
+            let (line, next_addr) = objdump_line(
+                &mut nes.cpu,
+                &mut nes.memory,
+                &nes.symbols,
+                current_addr,
+                show_bytes,
+            );
+            log::info(line, &self.runtime_options);
+            current_addr = next_addr;
+        }
+        nes.cpu.pc = pc;
+    }
+}
+
+/// Formats the objdump line for the instruction at the given address and
+/// returns it with the address that follows. Anything that isn't a valid
+/// instruction is shown as a single data byte. The program counter is moved to
+/// the address so relative branches disassemble against it.
+fn objdump_line(
+    cpu: &mut CPU,
+    memory: &mut Memory,
+    symbols: &Symbols,
+    addr: u16,
+    show_bytes: bool,
+) -> (String, u16) {
+    match Instruction::parse_unrestricted(addr as usize, memory) {
+        Ok(instr) => {
+            cpu.pc = addr;
+            let disassembled = instr.disassemble(cpu, memory);
+            let comment = label_comment(symbols, &instr, addr);
+            let line = if show_bytes {
+                let ascii: String = instr.bytes().into_iter().map(printable).collect();
+                let hex = instr.hex();
+                format!("{:04X}  {}  {:30}  {:3}{}", addr, hex, disassembled, ascii, comment)
+            } else {
+                format!("{:04X}  {}{}", addr, disassembled, comment)
+            };
+            (line, addr.wrapping_add(instr.size()))
+        }
+        Err(_) => {
+            let value = memory.read_u8_unrestricted(addr as usize);
+            let db = format!(".db ${:02X}", value);
+            let line = if show_bytes {
+                let hex = format!("{:02X}", value);
+                format!("{:04X}  {:8}  {:30}  {}", addr, hex, db, printable(value))
+            } else {
+                format!("{:04X}  {}", addr, db)
+            };
+            (line, addr.wrapping_add(1))
+        }
+    }
+}
+
+/// Moves the program counter to the given address without executing
+/// anything. Returns the disassembly of the instruction found there, or an
+/// error if there isn't a valid one, in which case the program counter is left
+/// alone.
+fn goto(
+    cpu: &mut CPU,
+    memory: &mut Memory,
+    symbols: &Symbols,
+    addr: u16,
+) -> Result<String, String> {
+    let instr = try!(Instruction::parse_unrestricted(addr as usize, memory));
+    cpu.pc = addr;
+    let disassembled = instr.disassemble(cpu, memory);
+    let comment = label_comment(symbols, &instr, addr);
+    Ok(format!("{:04X}  {}{}", addr, disassembled, comment))
+}
+
+/// Prints the instruction at the program counter along with its raw bytes.
+fn print_current_instruction(nes: &mut NES) {
+    let message = current_instruction(&nes.cpu, &mut nes.memory, &nes.symbols);
+    log::info(message, &nes.runtime_options);
+}
+
+/// Returns the instruction at the program counter along with its raw bytes,
+/// preceded by its label on a line of its own if it has one. Bytes that aren't
+/// a valid instruction are shown as data.
+fn current_instruction(cpu: &CPU, memory: &mut Memory, symbols: &Symbols) -> String {
+    let pc = cpu.pc;
+    let label = match symbols.label(pc) {
+        Some(label) => format!("{}:\n", label),
+        None => String::new(),
+    };
+    match Instruction::parse_unrestricted(pc as usize, memory) {
+        Ok(instr) => {
+            let disassembled = instr.disassemble(cpu, memory);
+            let comment = label_comment(symbols, &instr, pc);
+            format!("{}{:04X}  {}  {}{}", label, pc, instr.hex(), disassembled, comment)
+        }
+        Err(_) => {
+            let value = memory.read_u8_unrestricted(pc as usize);
+            format!("{}{:04X}  {:02X}        .db ${:02X}", label, pc, value, value)
+        }
+    }
+}
+
+/// Returns the layout of CPU memory followed by the cartridge's mapper and the
+/// banks it currently has switched in.
+fn memory_map(mapper: &Mapper) -> String {
+    let regions = [
+        (RAM_START_ADDR, RAM_END_ADDR, "Internal RAM"),
+        (RAM_MIRROR_START, RAM_MIRROR_END, "Internal RAM (mirrors)"),
+        (PPU_CTRL_REGISTERS_START, PPU_CTRL_REGISTERS_END, "PPU registers"),
+        (
+            PPU_CTRL_REGISTERS_MIRROR_START,
+            PPU_CTRL_REGISTERS_MIRROR_END,
+            "PPU registers (mirrors)",
+        ),
+        (MISC_CTRL_REGISTERS_START, MISC_CTRL_REGISTERS_END, "APU and I/O registers"),
+        (EXPANSION_ROM_START, EXPANSION_ROM_END, "Expansion ROM"),
+        (SRAM_START, SRAM_END, "PRG-RAM (mapper)"),
+        (PRG_ROM_START, PRG_ROM_END, "PRG-ROM (mapper)"),
+    ];
+    let mut lines: Vec<String> = regions
+        .iter()
+        .map(|&(start, end, name)| format!("${:04X}-${:04X}  {}", start, end, name))
+        .collect();
+
+    lines.push(String::new());
+    lines.push(format!("Mapper: {} ({:?} mirroring)", mapper.name(), mapper.mirroring()));
+    lines.push(match mapper.prg_ram_size() {
+        0 => "No PRG-RAM, $6000-$7FFF is open bus".to_string(),
+        size => format!("{}KB of PRG-RAM", size / 1024),
+    });
+    for bank in mapper.prg_banks() {
+        lines.push(format!("${:04X}-${:04X}  PRG bank {}", bank.start, bank.end, bank.bank));
+    }
+    for bank in mapper.chr_banks() {
+        lines.push(format!("PPU ${:04X}-${:04X}  CHR bank {}", bank.start, bank.end, bank.bank));
+    }
+    lines.join("\n")
+}
+
+/// Formats palette RAM as one line per palette, with each entry's color index
+/// and the RGB color it resolves to.
+fn format_palettes(palettes: &[u8]) -> String {
+    let lines: Vec<String> = palettes
+        .chunks(4)
+        .enumerate()
+        .map(|(index, entries)| {
+            let kind = if index < 4 { "Background" } else { "Sprite    " };
+            let mut line = format!("{} {}  ${:04X}:", kind, index % 4, 0x3F00 + index * 4);
+            for &entry in entries {
+                let (r, g, b) = palette::rgb(entry);
+                line.push_str(&format!("  ${:02X} #{:02X}{:02X}{:02X}", entry, r, g, b));
+            }
+            line
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Prints the registers lined up with the register columns of a line from a
+/// Nintendulator log.
+fn print_registers(nes: &NES) {
+    let cpu = &nes.cpu;
+    let message = format!(
+        "{:48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:3}",
+        "", cpu.a, cpu.x, cpu.y, cpu.p, cpu.sp, cpu.ppu_dots
+    );
+    log::info(message, &nes.runtime_options);
+}
+
+/// Writes memory in a format other programs can read. Bytes are read and
+/// written one at a time so large dumps aren't buffered up first. CSV has an
+/// address and value on each line, while JSON is an object holding the start
+/// address and an array of the bytes. Numbers are written in decimal.
+fn export_memory<W: Write>(
+    out: &mut W,
+    memory: &mut Memory,
+    addr: u16,
+    len: u32,
+    format: &str,
+) -> io::Result<()> {
+    if format == "csv" {
+        try!(writeln!(out, "address,value"));
+    } else {
+        try!(write!(out, "{{\"address\":{},\"bytes\":[", addr));
+    }
+    for offset in 0..len {
+        let current_addr = addr.wrapping_add(offset as u16);
+        let value = memory.read_u8_unrestricted(current_addr as usize);
+        if format == "csv" {
+            try!(writeln!(out, "{},{}", current_addr, value));
+        } else if offset == 0 {
+            try!(write!(out, "{}", value));
+        } else {
+            try!(write!(out, ",{}", value));
+        }
+    }
+    if format != "csv" {
+        try!(writeln!(out, "]}}"));
+    }
+    Ok(())
+}
+
+/// Returns a byte as an ASCII character, or a dot if it's not safe to print in
+/// a terminal.
+fn printable(value: u8) -> char {
+    if value >= 0x20 && value <= 0x7E {
+        value as char
+    } else {
+        '.'
+    }
+}
+
+/// Returns a comment naming the address an instruction refers to if it has a
+/// label, or an empty string otherwise.
+fn label_comment(symbols: &Symbols, instr: &Instruction, addr: u16) -> String {
+    match instr.operand_address(addr).and_then(|target| symbols.label(target)) {
+        Some(label) => format!(" ; {}", label),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+    use std::fs;
+    use std::sync::mpsc;
+
+    #[test]
+    fn exported_json_holds_the_bytes_dumped() {
+        let (_, mut memory) = testing::machine(&[]);
+        for (offset, &value) in [0x00, 0x7F, 0x80, 0xFF].iter().enumerate() {
+            memory.write_u8(0x0200 + offset, value);
+        }
+
+        let mut out = Vec::new();
+        export_memory(&mut out, &mut memory, 0x0200, 4, "json").unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // Pull the numbers back out of {"address":512,"bytes":[...]}.
+        let json = out.trim();
+        assert!(json.starts_with("{\"address\":512,\"bytes\":["), "{}", json);
+        assert!(json.ends_with("]}"), "{}", json);
+        let start = json.find('[').unwrap() + 1;
+        let bytes: Vec<u8> = json[start..json.len() - 2]
+            .split(',')
+            .map(|value| value.parse().unwrap())
+            .collect();
+        assert_eq!(bytes, vec![0x00, 0x7F, 0x80, 0xFF]);
+    }
+
+    #[test]
+    fn current_shows_the_instruction_goto_moved_to() {
+        // LDA #$01; LDX #$02
+        let (mut cpu, mut memory) = testing::machine(&[0xA9, 0x01, 0xA2, 0x02]);
+        let symbols = Symbols::new();
+
+        let message = goto(&mut cpu, &mut memory, &symbols, 0x8002).unwrap();
+        assert!(message.starts_with("8002  LDX #$02"), "{}", message);
+        assert_eq!(cpu.pc, 0x8002);
+
+        let message = current_instruction(&cpu, &mut memory, &symbols);
+        assert!(message.starts_with("8002  A2 02"), "{}", message);
+        assert!(message.contains("LDX #$02"), "{}", message);
+        assert_eq!(cpu.pc, 0x8002);
+    }
+
+    #[test]
+    fn goto_moves_to_the_evaluated_address() {
+        // LDA $1234 at $C123.
+        let mut program = vec![0x02; 0x4126];
+        program[0x4123..].copy_from_slice(&[0xAD, 0x34, 0x12]);
+        let (mut cpu, mut memory) = testing::machine(&program);
+        let symbols = Symbols::new();
+
+        let addr = parser::evaluate_address("$C123", &cpu).unwrap();
+        let message = goto(&mut cpu, &mut memory, &symbols, addr).unwrap();
+        assert!(message.starts_with("C123  LDA $1234"), "{}", message);
+        assert_eq!(cpu.pc, 0xC123);
+
+        // Going to an illegal opcode leaves the program counter where it was.
+        assert!(goto(&mut cpu, &mut memory, &symbols, 0x8000).is_err());
+        assert_eq!(cpu.pc, 0xC123);
+    }
+
+    /// Returns a stopped debugger that isn't connected to a readline thread.
+    fn debugger() -> Debugger {
+        let (sender, _) = mpsc::sync_channel(1);
+        let (_, receiver) = mpsc::channel();
+        Debugger::new(sender, receiver, false, testing::runtime_options())
+    }
+
+    #[test]
+    fn until_halts_at_the_address_and_clears_the_breakpoint() {
+        // JMP $C000, then NOPs.
+        let mut program = vec![0xEA; 0x4010];
+        program[0..3].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+        let (mut cpu, mut memory) = testing::machine(&program);
+        let mut debugger = debugger();
+
+        let args = vec!["until".to_string(), "$C005".to_string()];
+        debugger.execute_until(&cpu, &args);
+        assert!(debugger.stepping);
+        while !debugger.hit_temporary_breakpoint(cpu.pc) {
+            cpu.step(&mut memory).unwrap();
+            assert!(cpu.pc <= 0xC005);
+        }
+        debugger.halt();
+
+        assert_eq!(cpu.pc, 0xC005);
+        assert!(!debugger.stepping);
+        assert_eq!(debugger.temporary_breakpoint, None);
+        assert!(!debugger.hit_temporary_breakpoint(0xC005));
+    }
+
+    #[test]
+    fn disassembled_jsr_shows_the_label_of_its_target() {
+        let path = testing::temp_path("labels.sym");
+        {
+            let mut file = File::create(&path).unwrap();
+            writeln!(file, "$8000 = main").unwrap();
+            writeln!(file, "8010 = update_sprites").unwrap();
+        }
+        let symbols = Symbols::load(path.to_str().unwrap(), &testing::runtime_options());
+        fs::remove_file(&path).unwrap();
+        let symbols = symbols.unwrap();
+
+        // JSR $8010
+        let (cpu, mut memory) = testing::machine(&[0x20, 0x10, 0x80]);
+        let message = current_instruction(&cpu, &mut memory, &symbols);
+        assert!(message.starts_with("main:\n8000  20 10 80"), "{}", message);
+        assert!(message.ends_with("JSR $8010 ; update_sprites"), "{}", message);
+    }
+
+    #[test]
+    fn break_on_start_runs_nothing_until_continue() {
+        // The NES creates its debugger stopped when breaking on start.
+        let mut debugger = debugger();
+        assert!(!debugger.running(false));
+        assert!(!debugger.running(true));
+
+        debugger.execute_continue();
+        assert!(debugger.running(false));
+        assert!(!debugger.running(true));
+
+        // Without breaking on start it runs straight away.
+        let (sender, _) = mpsc::sync_channel(1);
+        let (_, receiver) = mpsc::channel();
+        let debugger = Debugger::new(sender, receiver, true, testing::runtime_options());
+        assert!(debugger.running(false));
+    }
+
+    #[test]
+    fn trace_turns_instruction_logging_on_and_off() {
+        let (mut cpu, _) = testing::machine(&[]);
+        let mut debugger = debugger();
+        let trace = |arg: &str| vec!["trace".to_string(), arg.to_string()];
+        assert!(!cpu.trace);
+
+        debugger.execute_trace(&mut cpu, &trace("on"));
+        assert!(cpu.trace);
+        debugger.execute_trace(&mut cpu, &vec!["trace".to_string()]);
+        assert!(cpu.trace);
+        debugger.execute_trace(&mut cpu, &trace("sideways"));
+        assert!(cpu.trace);
+        debugger.execute_trace(&mut cpu, &trace("OFF"));
+        assert!(!cpu.trace);
+    }
+
+    #[test]
+    fn scanline_breakpoint_stops_on_the_programmed_scanline() {
+        // JMP $8000
+        let mapper = testing::mapper(&testing::nrom(&[0x4C, 0x00, 0x80]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        let mut cpu = CPU::new(testing::runtime_options());
+        cpu.power_on(&mut memory);
+        let mut debugger = debugger();
+        debugger.execute_breakline(&vec!["breakline".to_string(), "100".to_string()]);
+
+        for _ in 0..2 {
+            loop {
+                let before = (ppu.scanline(), ppu.dot());
+                let cycles = cpu.step(&mut memory).unwrap();
+                for _ in 0..cycles * 3 {
+                    ppu.step(&mut memory);
+                }
+                if debugger.hit_scanline_breakpoint(before, &ppu) {
+                    break;
+                }
+            }
+
+            // A JMP runs 9 dots, so it stops within that many of the start.
+            assert_eq!(ppu.scanline(), 100);
+            assert!(ppu.dot() < 9, "{}", ppu.dot());
+        }
+    }
+
+    #[test]
+    fn memory_map_shows_the_nrom_prg_banks() {
+        let mapper = testing::mapper(&testing::nrom(&[]));
+        let map = memory_map(&**mapper.borrow());
+        assert!(map.contains("\n$6000-$7FFF  PRG-RAM (mapper)\n"), "{}", map);
+        assert!(map.contains("\nMapper: NROM (Horizontal mirroring)\n"), "{}", map);
+        assert!(map.contains("\nNo PRG-RAM, $6000-$7FFF is open bus\n"), "{}", map);
+        assert!(map.contains("\n$8000-$BFFF  PRG bank 0\n$C000-$FFFF  PRG bank 1\n"), "{}", map);
+        assert!(map.ends_with("\nPPU $0000-$1FFF  CHR bank 0"), "{}", map);
+
+        // A single 16KB bank is mirrored into both halves.
+        let mut rom = testing::nrom(&[]);
+        rom[4] = 1;
+        rom.truncate(0x10 + 0x4000);
+        let mapper = testing::mapper(&rom);
+        let map = memory_map(&**mapper.borrow());
+        assert!(map.contains("\n$8000-$BFFF  PRG bank 0\n$C000-$FFFF  PRG bank 0\n"), "{}", map);
+    }
+
+    #[test]
+    fn palettes_show_each_entry_and_its_color() {
+        let mut palettes = [0x0F; 32];
+        palettes[0x11..0x14].copy_from_slice(&[0x16, 0x27, 0x30]);
+        let out = format_palettes(&palettes);
+        let lines: Vec<&str> = out.lines().collect();
+
+        let (r, g, b) = palette::rgb(0x0F);
+        let black = format!("  $0F #{:02X}{:02X}{:02X}", r, g, b);
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], format!("Background 0  $3F00:{}{}{}{}", black, black, black, black));
+        assert!(lines[3].starts_with("Background 3  $3F0C:"), "{}", lines[3]);
+        assert!(lines[4].starts_with("Sprite     0  $3F10:"), "{}", lines[4]);
+        let (r, g, b) = palette::rgb(0x30);
+        assert!(lines[4].ends_with(&format!("  $30 #{:02X}{:02X}{:02X}", r, g, b)), "{}", lines[4]);
+        assert!(lines[7].starts_with("Sprite     3  $3F1C:"), "{}", lines[7]);
+    }
+
+    #[test]
+    fn objdump_lines_show_the_bytes_alongside_the_disassembly() {
+        // LDA #$41, then a byte that isn't a valid opcode.
+        let (mut cpu, mut memory) = testing::machine(&[0xA9, 0x41, 0x02]);
+        let symbols = Symbols::new();
+
+        let (line, next) = objdump_line(&mut cpu, &mut memory, &symbols, 0x8000, true);
+        assert_eq!(line, format!("8000  A9 41     {:30}  .A ", "LDA #$41"));
+        assert_eq!(next, 0x8002);
+
+        let (line, next) = objdump_line(&mut cpu, &mut memory, &symbols, 0x8002, true);
+        assert_eq!(line, format!("8002  02        {:30}  .", ".db $02"));
+        assert_eq!(next, 0x8003);
+
+        let (line, _) = objdump_line(&mut cpu, &mut memory, &symbols, 0x8000, false);
+        assert_eq!(line, "8000  LDA #$41");
+    }
+
+    #[test]
+    fn empty_lines_repeat_the_last_step_while_stopped() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.repeated_input(String::new()), "");
+
+        debugger.remember_step("step 3", &Command::Step);
+        assert_eq!(debugger.repeated_input(String::new()), "step 3");
+        assert_eq!(debugger.repeated_input("  ".to_string()), "step 3");
+        assert_eq!(debugger.repeated_input("regs".to_string()), "regs");
+
+        // Nothing is repeated while running, or after another command.
+        debugger.execute_continue();
+        assert_eq!(debugger.repeated_input(String::new()), "");
+        debugger.halt();
+        debugger.remember_step("regs", &Command::Registers);
+        assert_eq!(debugger.repeated_input(String::new()), "");
+    }
+
+    #[test]
+    fn next_runs_to_the_return_and_cleans_up_after_a_breakpoint() {
+        // JSR $8010; LDA #$01, with a NOP subroutine at $8010.
+        let mut program = vec![0xEA; 0x12];
+        program[0..5].copy_from_slice(&[0x20, 0x10, 0x80, 0xA9, 0x01]);
+        program[0x11] = 0x60;
+        let (mut cpu, mut memory) = testing::machine(&program);
+        let mut debugger = debugger();
+
+        let line = debugger.step_over(&cpu, &mut memory, 100).unwrap();
+        assert!(line.starts_with("8000  20 10 80  JSR $8010"), "{}", line);
+        assert!(debugger.stepping);
+        while !debugger.hit_temporary_breakpoint(cpu.pc) {
+            cpu.step(&mut memory).unwrap();
+        }
+        debugger.halt();
+        assert_eq!(cpu.pc, 0x8003);
+
+        // Anything but a call is left to "step".
+        assert_eq!(debugger.step_over(&cpu, &mut memory, 100), None);
+        assert!(!debugger.stepping);
+
+        // A user breakpoint in the subroutine halts before the return, which
+        // mustn't leave the temporary breakpoint behind.
+        cpu.pc = 0x8000;
+        debugger.step_over(&cpu, &mut memory, 100).unwrap();
+        cpu.step(&mut memory).unwrap();
+        debugger.halt();
+        assert_eq!(debugger.temporary_breakpoint, None);
+        assert_eq!(debugger.next_limit, None);
+    }
+
+    #[test]
+    fn breakpoints_are_numbered_and_survive_continue() {
+        let (cpu, _) = testing::machine(&[]);
+        let mut debugger = debugger();
+        let command = |name: &str, arg: &str| vec![name.to_string(), arg.to_string()];
+
+        debugger.execute_break(&cpu, &command("break", "$8003"));
+        debugger.execute_break(&cpu, &command("break", "C000"));
+        debugger.execute_break(&cpu, &command("break", "$8003"));
+        assert_eq!(debugger.breakpoints, vec![(1, 0x8003), (2, 0xC000)]);
+
+        debugger.execute_continue();
+        assert_eq!(debugger.hit_breakpoint(0x8003), Some(1));
+        assert_eq!(debugger.hit_breakpoint(0xC000), Some(2));
+        assert_eq!(debugger.hit_breakpoint(0x8000), None);
+
+        debugger.execute_delete(&command("delete", "1"));
+        debugger.execute_delete(&command("delete", "7"));
+        assert_eq!(debugger.hit_breakpoint(0x8003), None);
+        assert_eq!(debugger.breakpoints, vec![(2, 0xC000)]);
     }
 }
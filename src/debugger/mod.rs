@@ -8,3 +8,5 @@
 
 pub mod parser;
 pub mod debugger;
+pub mod symbols;
+pub mod viewer;
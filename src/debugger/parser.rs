@@ -6,8 +6,15 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use nes::cpu::CPU;
+use utils::arithmetic;
+
+#[allow(dead_code)]
 const UNCLOSING_QUOTE: &'static str = "quoted arg does not close";
 
+// Where the stack lives in memory. SP is an offset into this page.
+const STACK_PAGE: u16 = 0x0100;
+
 /// Converts raw command-line input into a list of separate arguments. The vector
 /// of strings returned can be parsed by a library such as getopts.
 pub fn input_to_arguments(input: String) -> Result<Vec<String>, &'static str> {
@@ -40,8 +47,100 @@ pub fn input_to_arguments(input: String) -> Result<Vec<String>, &'static str> {
     Ok(args)
 }
 
+/// Evaluates an address expression such as "pc+10" or "sp-2" against the
+/// current CPU state. An expression is a list of terms joined by + and -, with
+/// no whitespace in between. Terms can be:
+///
+/// * pc, a, x, y or p, evaluating to the register's value.
+/// * sp, evaluating to the address in the stack page SP points at.
+/// * A hex number with or without a leading 0x or $, like other addresses in
+///   the debugger. Use the prefix for numbers that look like registers.
+/// * A decimal number with a leading #.
+///
+/// Arithmetic wraps around at 16 bits.
+pub fn evaluate_address(expression: &str, cpu: &CPU) -> Result<u16, String> {
+    let mut result: u16 = 0;
+    let mut subtract = false;
+    let mut leading = true;
+    let mut term = String::new();
+
+    // A trailing + is pushed so the last term is handled inside the loop.
+    for c in expression.chars().chain("+".chars()) {
+        if c != '+' && c != '-' {
+            term.push(c);
+            continue;
+        }
+
+        // Allow a leading minus, but not empty terms between operators.
+        if term.is_empty() {
+            if !leading || subtract || c == '+' {
+                return Err(format!("cannot evaluate address: {}", expression));
+            }
+            subtract = true;
+            continue;
+        }
+
+        let value = match evaluate_term(&term, cpu) {
+            Some(value) => value,
+            None => return Err(format!("cannot evaluate address: {}", expression)),
+        };
+        result = if subtract {
+            result.wrapping_sub(value)
+        } else {
+            result.wrapping_add(value)
+        };
+        subtract = c == '-';
+        leading = false;
+        term.clear();
+    }
+
+    Ok(result)
+}
+
+/// Evaluates a single register name or number in an address expression.
+fn evaluate_term(term: &String, cpu: &CPU) -> Option<u16> {
+    match term.to_lowercase().as_str() {
+        "pc" => Some(cpu.pc),
+        "sp" => Some(STACK_PAGE | cpu.sp as u16),
+        "a" => Some(cpu.a as u16),
+        "x" => Some(cpu.x as u16),
+        "y" => Some(cpu.y as u16),
+        "p" => Some(cpu.p as u16),
+        _ if term.starts_with("#") => term[1..].parse::<u16>().ok(),
+        _ => arithmetic::hex_to_u16(term),
+    }
+}
+
 /// Returns true if the character passed is a whitespace character. Both spaces
 /// and tabs are considered whitespace characters.
 fn is_whitespace(c: char) -> bool {
     c == ' ' || c == '\t'
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+
+    #[test]
+    fn address_expressions_use_the_cpu_registers() {
+        let (mut cpu, _) = testing::machine(&[]);
+        cpu.a = 0x05;
+        cpu.sp = 0xFD;
+
+        assert_eq!(evaluate_address("pc+0x10", &cpu), Ok(0x8010));
+        assert_eq!(evaluate_address("a-1", &cpu), Ok(0x0004));
+        assert_eq!(evaluate_address("sp+#2", &cpu), Ok(0x01FF));
+        assert_eq!(evaluate_address("C000", &cpu), Ok(0xC000));
+        assert_eq!(evaluate_address("$C000", &cpu), Ok(0xC000));
+
+        // Arithmetic wraps at 16 bits, and a leading minus is allowed.
+        cpu.a = 0x00;
+        assert_eq!(evaluate_address("a-1", &cpu), Ok(0xFFFF));
+        assert_eq!(evaluate_address("-1", &cpu), Ok(0xFFFF));
+
+        assert!(evaluate_address("pc+", &cpu).is_err());
+        assert!(evaluate_address("pc++1", &cpu).is_err());
+        assert!(evaluate_address("zz", &cpu).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use io::log;
+use nes::nes::NESRuntimeOptions;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use utils::arithmetic;
+
+/// Labels for addresses in the running ROM, used to annotate disassembly in
+/// the debugger.
+pub struct Symbols {
+    labels: HashMap<u16, String>,
+}
+
+impl Symbols {
+    /// Returns a symbol table without any labels.
+    pub fn new() -> Self {
+        Symbols {
+            labels: HashMap::new(),
+        }
+    }
+
+    /// Loads a symbol file where each line is in the form "ADDRESS = NAME".
+    /// Addresses are in hex with an optional leading $ or 0x. Blank lines and
+    /// lines starting with ; or # are ignored. Malformed lines and duplicate
+    /// addresses are skipped with a warning, keeping the first label.
+    pub fn load(filename: &str, runtime_options: &NESRuntimeOptions) -> Result<Self, String> {
+        let mut contents = String::new();
+        let result = File::open(filename).and_then(|mut f| f.read_to_string(&mut contents));
+        if let Err(e) = result {
+            return Err(format!("cannot open {}: {}", filename, e));
+        }
+
+        let mut symbols = Symbols::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(";") || line.starts_with("#") {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=').map(|part| part.trim().to_string());
+            let addr = parts.next().and_then(|addr| arithmetic::hex_to_u16(&addr));
+            let name = match parts.next() {
+                Some(ref name) if name.is_empty() => None,
+                name => name,
+            };
+            let (addr, name) = match (addr, name) {
+                (Some(addr), Some(name)) => (addr, name),
+                _ => {
+                    let message = format!("{}:{}: malformed symbol, skipping", filename, index + 1);
+                    log::warn(message, runtime_options);
+                    continue;
+                }
+            };
+
+            if let Some(existing) = symbols.labels.get(&addr) {
+                let message = format!(
+                    "{}:{}: ${:04X} is already labeled {}, skipping {}",
+                    filename,
+                    index + 1,
+                    addr,
+                    existing,
+                    name
+                );
+                log::warn(message, runtime_options);
+                continue;
+            }
+            symbols.labels.insert(addr, name);
+        }
+
+        log::init(
+            format!("Loaded {} symbols from {}", symbols.labels.len(), filename),
+            runtime_options,
+        );
+        Ok(symbols)
+    }
+
+    /// Returns the label for an address if there is one.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|label| label.as_str())
+    }
+}
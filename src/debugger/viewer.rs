@@ -0,0 +1,176 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use nes::nes::NES;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+
+// Palettes are not applied in the viewers, so 2-bit pattern data is drawn
+// using these grey shades instead.
+const SHADES: [u8; 4] = [0x00, 0x55, 0xAA, 0xFF];
+
+// Sprites are laid out in an 8x8 grid where each cell is tall enough to fit an
+// 8x16 sprite.
+const OAM_VIEW_WIDTH: usize = 8 * 8;
+const OAM_VIEW_HEIGHT: usize = 8 * 16;
+const OAM_VIEW_SCALE: u32 = 4;
+
+// The four nametables are laid out in a 2x2 grid, each being 32x30 tiles.
+const NAMETABLES_VIEW_WIDTH: usize = 2 * 32 * 8;
+const NAMETABLES_VIEW_HEIGHT: usize = 2 * 30 * 8;
+
+// Sprite attribute bits that flip the sprite's tile.
+const SPRITE_FLIP_HORIZONTAL: u8 = 0b01000000;
+const SPRITE_FLIP_VERTICAL: u8 = 0b10000000;
+
+/// The different kinds of auxiliary views that can be opened.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ViewerKind {
+    Oam,
+    Nametables,
+}
+
+/// An extra SDL window that visualizes some of the PPU's internal state. The
+/// contents are redrawn from scratch on every render so they always reflect
+/// the current state of the PPU.
+pub struct Viewer {
+    pub kind: ViewerKind,
+    canvas: Canvas<Window>,
+}
+
+impl Viewer {
+    /// Opens a new window for the given kind of view.
+    pub fn new(kind: ViewerKind, video_subsystem: &VideoSubsystem) -> Result<Self, String> {
+        let (title, width, height) = match kind {
+            ViewerKind::Oam => (
+                "nes-rs: oam",
+                OAM_VIEW_WIDTH as u32 * OAM_VIEW_SCALE,
+                OAM_VIEW_HEIGHT as u32 * OAM_VIEW_SCALE,
+            ),
+            ViewerKind::Nametables => (
+                "nes-rs: nametables",
+                NAMETABLES_VIEW_WIDTH as u32,
+                NAMETABLES_VIEW_HEIGHT as u32,
+            ),
+        };
+
+        let window = match video_subsystem.window(title, width, height).build() {
+            Ok(window) => window,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let canvas = match window.into_canvas().build() {
+            Ok(canvas) => canvas,
+            Err(e) => return Err(format!("{}", e)),
+        };
+
+        Ok(Viewer {
+            kind: kind,
+            canvas: canvas,
+        })
+    }
+
+    /// Redraws the view using the current state of the PPU.
+    pub fn render(&mut self, nes: &mut NES) {
+        let (width, height) = match self.kind {
+            ViewerKind::Oam => (OAM_VIEW_WIDTH, OAM_VIEW_HEIGHT),
+            ViewerKind::Nametables => (NAMETABLES_VIEW_WIDTH, NAMETABLES_VIEW_HEIGHT),
+        };
+
+        let mut pixels = vec![0; width * height * 3];
+        match self.kind {
+            ViewerKind::Oam => Viewer::draw_oam(nes, &mut pixels),
+            ViewerKind::Nametables => Viewer::draw_nametables(nes, &mut pixels),
+        }
+
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+            .unwrap();
+        texture.update(None, &pixels, width * 3).unwrap();
+
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None).unwrap();
+        self.canvas.present();
+    }
+
+    /// Draws every sprite in OAM into its own cell regardless of where it is
+    /// positioned on screen.
+    fn draw_oam(nes: &mut NES, pixels: &mut [u8]) {
+        let tall_sprites = nes.ppu.tall_sprites();
+        let pattern_table = nes.ppu.sprite_pattern_table();
+
+        for (index, sprite) in nes.ppu.sprites().iter().enumerate() {
+            let x = (index % 8) * 8;
+            let y = (index / 8) * 16;
+            let flip_horizontal = sprite.attributes & SPRITE_FLIP_HORIZONTAL > 0;
+            let flip_vertical = sprite.attributes & SPRITE_FLIP_VERTICAL > 0;
+
+            if tall_sprites {
+                // 8x16 sprites select their pattern table with bit 0 of the
+                // tile index and are made of two consecutive tiles.
+                let pattern_table = (sprite.tile as usize & 1) * 0x1000;
+                let top = nes.ppu.decode_tile(pattern_table, sprite.tile & 0xFE);
+                let bottom = nes.ppu.decode_tile(pattern_table, sprite.tile | 1);
+                let (first, second) = if flip_vertical {
+                    (bottom, top)
+                } else {
+                    (top, bottom)
+                };
+                let flip = (flip_horizontal, flip_vertical);
+                Viewer::draw_tile(pixels, OAM_VIEW_WIDTH, x, y, &first, flip);
+                Viewer::draw_tile(pixels, OAM_VIEW_WIDTH, x, y + 8, &second, flip);
+            } else {
+                let tile = nes.ppu.decode_tile(pattern_table, sprite.tile);
+                let flip = (flip_horizontal, flip_vertical);
+                Viewer::draw_tile(pixels, OAM_VIEW_WIDTH, x, y, &tile, flip);
+            }
+        }
+    }
+
+    /// Draws all four nametables using the current background pattern table.
+    fn draw_nametables(nes: &mut NES, pixels: &mut [u8]) {
+        let pattern_table = nes.ppu.background_pattern_table();
+
+        for nametable in 0..4 {
+            let origin_x = (nametable % 2) * 32 * 8;
+            let origin_y = (nametable / 2) * 30 * 8;
+            let tiles = nes.ppu.nametable_tiles(nametable);
+
+            for (index, tile) in tiles.iter().enumerate() {
+                let x = origin_x + (index % 32) * 8;
+                let y = origin_y + (index / 32) * 8;
+                let decoded = nes.ppu.decode_tile(pattern_table, *tile);
+                Viewer::draw_tile(pixels, NAMETABLES_VIEW_WIDTH, x, y, &decoded, (false, false));
+            }
+        }
+    }
+
+    /// Copies a decoded tile into an RGB24 pixel buffer at the given position.
+    fn draw_tile(
+        pixels: &mut [u8],
+        width: usize,
+        x: usize,
+        y: usize,
+        tile: &[u8; 64],
+        flip: (bool, bool),
+    ) {
+        for row in 0..8 {
+            for col in 0..8 {
+                let src_col = if flip.0 { 7 - col } else { col };
+                let src_row = if flip.1 { 7 - row } else { row };
+                let shade = SHADES[tile[src_row * 8 + src_col] as usize];
+                let offset = ((y + row) * width + x + col) * 3;
+                pixels[offset] = shade;
+                pixels[offset + 1] = shade;
+                pixels[offset + 2] = shade;
+            }
+        }
+    }
+}
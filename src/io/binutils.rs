@@ -6,9 +6,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use nes::mapper;
+use std::fmt;
 use std::fs::File;
 use std::io::Error;
 use std::io::Read;
+use std::io::Write;
 use std::path::Path;
 use std::result::Result;
 
@@ -16,22 +19,72 @@ use std::result::Result;
 // be at the start of every rom.
 const INES_IDENTIFIER: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 
+// Sizes of the sections of an iNES rom. PRG-ROM and CHR-ROM sizes are given in
+// the header in units of these bank sizes.
+pub const HEADER_SIZE:       usize = 0x10;
+pub const TRAINER_SIZE:      usize = 0x200;
+pub const PRG_ROM_BANK_SIZE: usize = 0x4000;
+pub const CHR_ROM_BANK_SIZE: usize = 0x2000;
+pub const PRG_RAM_BANK_SIZE: usize = 0x2000;
+
 const MIRROR_TYPE    : u8 = 0x1;
 const PERSISTENT_FLAG: u8 = 0x2;
 const TRAINER_FLAG   : u8 = 0x4;
 const MIRROR_4_SCREEN: u8 = 0x8;
 const MAPPER_NUMBER  : u8 = 0xF0;
+const FORMAT_VERSION : u8 = 0x0C;
+const TV_SYSTEM      : u8 = 0x1;
+const NES2_TIMING    : u8 = 0x3;
 
-#[derive(Debug)]
+// Values of the format version bits in flag 7. Old dumps often have garbage
+// (usually "DiskDude!") in the end of the header, which shows up as archaic.
+const ARCHAIC_FORMAT: u8 = 0x04;
+const NES2_FORMAT   : u8 = 0x08;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MirrorType {
     Horizontal,
     Vertical,
-    Both
+    Both,
+
+    // Single screen modes can only be selected by mappers at runtime.
+    SingleLower,
+    SingleUpper
 }
 
+/// Problems with the contents of a rom found after its header is parsed.
 #[derive(Debug)]
-pub enum Mapper {
-    NROM
+pub enum RomError {
+    // The file is shorter than the sections listed in the header.
+    Truncated { expected: usize, actual: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Truncated { expected, actual } => write!(
+                f,
+                "rom is truncated, header describes {} bytes but the file is {} bytes",
+                expected, actual
+            ),
+        }
+    }
+}
+
+/// Returned when a rom uses a mapper that hasn't been implemented.
+#[derive(Debug)]
+pub struct UnsupportedMapper(pub u8);
+
+impl fmt::Display for UnsupportedMapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mapper {} is not supported yet", self.0)
+    }
+}
+
+/// Returns the name of the board a mapper number is emulated as, or
+/// "unknown" for mappers that aren't implemented.
+pub fn mapper_name(mapper: u8) -> &'static str {
+    mapper::find(mapper).map(|info| info.name).unwrap_or("unknown")
 }
 
 /// Structure that represents the 16 byte header of an iNES rom. Only missing
@@ -39,6 +92,7 @@ pub enum Mapper {
 #[derive(Debug)]
 pub struct INESHeader {
     // File format identifier for the iNES format.
+    #[allow(dead_code)]
     pub identifier: [u8; 4],
 
     // Size of PRG ROM in 16 KB units.
@@ -53,7 +107,9 @@ pub struct INESHeader {
     flags_6: u8,
     flags_7: u8,
     flags_9: u8,
-    flags_10: u8 // Unofficial, unused by most emulators.
+    #[allow(dead_code)]
+    flags_10: u8, // Unofficial, unused by most emulators.
+    flags_12: u8 // CPU/PPU timing in NES 2.0 headers.
 }
 
 impl INESHeader {
@@ -88,7 +144,8 @@ impl INESHeader {
             flags_7: rom[0x7],
             prg_ram_size: rom[0x8],
             flags_9: rom[0x9],
-            flags_10: rom[0xA]
+            flags_10: rom[0xA],
+            flags_12: rom[0xC]
         })
     }
 
@@ -104,6 +161,35 @@ impl INESHeader {
         }
     }
 
+    /// Returns the version of the header format, which decides how some of the
+    /// flags are read.
+    pub fn format(&self) -> &'static str {
+        match self.flags_7 & FORMAT_VERSION {
+            NES2_FORMAT => "NES 2.0",
+            ARCHAIC_FORMAT => "archaic iNES",
+            _ => "iNES",
+        }
+    }
+
+    /// Returns the TV system the rom was made for using the NES 2.0 timing
+    /// values: 0 for NTSC, 1 for PAL, 2 for multi-region and 3 for Dendy. Plain
+    /// iNES headers can only tell NTSC and PAL apart, and few dumps bother to
+    /// set it.
+    pub fn timing(&self) -> u8 {
+        if self.flags_7 & FORMAT_VERSION == NES2_FORMAT {
+            self.flags_12 & NES2_TIMING
+        } else if self.flags_9 & TV_SYSTEM == TV_SYSTEM {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Returns the name of the TV system the rom was made for.
+    pub fn region(&self) -> &'static str {
+        region_name(self.timing())
+    }
+
     /// Returns true if persistent RAM is used by the ROM.
     #[inline(always)]
     pub fn has_persistent_ram(&self) -> bool {
@@ -116,25 +202,90 @@ impl INESHeader {
         self.flags_6 & TRAINER_FLAG == TRAINER_FLAG
     }
 
+    /// Returns the offset of PRG-ROM in the rom, which follows the header and
+    /// the trainer if there is one.
+    pub fn prg_rom_offset(&self) -> usize {
+        if self.has_trainer() {
+            HEADER_SIZE + TRAINER_SIZE
+        } else {
+            HEADER_SIZE
+        }
+    }
+
+    /// Returns the offset of CHR-ROM in the rom, which follows PRG-ROM.
+    pub fn chr_rom_offset(&self) -> usize {
+        self.prg_rom_offset() + self.prg_rom_size as usize * PRG_ROM_BANK_SIZE
+    }
+
+    /// Returns the size of PRG-RAM in bytes. A size of 0 in the header means
+    /// 8KB for compatibility with older dumps.
+    pub fn prg_ram_bytes(&self) -> usize {
+        if self.prg_ram_size == 0 {
+            PRG_RAM_BANK_SIZE
+        } else {
+            self.prg_ram_size as usize * PRG_RAM_BANK_SIZE
+        }
+    }
+
+    /// Returns the size of a rom with the sections the header describes: the
+    /// header, an optional trainer, and the PRG-ROM and CHR-ROM banks.
+    pub fn expected_size(&self) -> usize {
+        self.chr_rom_offset() + self.chr_rom_size as usize * CHR_ROM_BANK_SIZE
+    }
+
+    /// Checks that the rom holds every section the header says it does so the
+    /// sections can be sliced out without going out of bounds. Extra data at
+    /// the end is allowed.
+    pub fn validate(&self, rom: &[u8]) -> Result<(), RomError> {
+        let expected = self.expected_size();
+        if rom.len() < expected {
+            return Err(RomError::Truncated {
+                expected: expected,
+                actual: rom.len(),
+            });
+        }
+        Ok(())
+    }
+
     /// Returns the mapper number that signifies which mapper is in use by the
     /// cartridge. The lower nybble is stored in bits 4-7 in flag 6 while the
     /// upper nybble is stored in bits 4-7 in flag 7 (same bitmask). The results
     /// are then OR'd together to create the final 8-bit number.
+    ///
+    /// Archaic dumps have text where flag 7 should be, so the upper nybble is
+    /// ignored for them.
     #[inline(always)]
-    pub fn mapper(&self) -> Mapper {
+    pub fn mapper_number(&self) -> u8 {
         let lower = (self.flags_6 & MAPPER_NUMBER) >> 4;
-        let upper = self.flags_7 & MAPPER_NUMBER;
-        let mapper = lower | upper;
-
-        match mapper {
-            0 => Mapper::NROM,
-            _ => {
-                panic!("ROM uses unimplemented mapper: {}", mapper);
-            }
+        let upper = if self.flags_7 & FORMAT_VERSION == ARCHAIC_FORMAT {
+            0
+        } else {
+            self.flags_7 & MAPPER_NUMBER
+        };
+        lower | upper
+    }
+
+    /// Returns the mapper number if the emulator supports that mapper.
+    pub fn mapper(&self) -> Result<u8, UnsupportedMapper> {
+        let number = self.mapper_number();
+        if mapper::find(number).is_some() {
+            Ok(number)
+        } else {
+            Err(UnsupportedMapper(number))
         }
     }
 }
 
+/// Returns the name of a TV system from its NES 2.0 timing value.
+pub fn region_name(timing: u8) -> &'static str {
+    match timing {
+        0 => "NTSC",
+        1 => "PAL",
+        2 => "multi-region",
+        _ => "Dendy",
+    }
+}
+
 /// Reads a binary file at a given path and stores it in a vector of bytes.
 pub fn read_bin<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
     let mut buffer: Vec<u8> = Vec::new();
@@ -142,3 +293,45 @@ pub fn read_bin<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
     try!(file.read_to_end(&mut buffer));
     Ok(buffer)
 }
+
+/// Writes a vector of bytes to a binary file at the given path, replacing the
+/// file if it exists.
+pub fn write_bin<P: AsRef<Path>>(path: P, buf: &[u8]) -> Result<(), Error> {
+    let mut file = try!(File::create(path));
+    try!(file.write_all(buf));
+    Ok(())
+}
+
+/// Calculates the CRC32 checksum of a buffer. This is used to identify ROMs
+/// since it's the checksum most ROM databases use.
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for byte in buf {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_roms_are_reported_as_truncated() {
+        // A trainer and 2 PRG-ROM banks, but only one bank's worth of data.
+        let mut rom = vec![0; HEADER_SIZE + TRAINER_SIZE + PRG_ROM_BANK_SIZE];
+        rom[0..7].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A, 2, 0, TRAINER_FLAG]);
+        let header = INESHeader::new(&rom).unwrap();
+        assert_eq!(header.prg_rom_offset(), HEADER_SIZE + TRAINER_SIZE);
+
+        let e = header.validate(&rom).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "rom is truncated, header describes 33296 bytes but the file is 16912 bytes"
+        );
+    }
+}
@@ -13,4 +13,9 @@ pub const EXIT_FAILURE: i32 = 1; // Generic error ¯\_(ツ)_/¯.
 pub const EXIT_INVALID_ROM: i32 = 2; // Invalid rom passed.
 pub const EXIT_CPU_LOG_NOT_FOUND: i32 = 3;
 pub const EXIT_INVALID_PC: i32 = 4;
+pub const EXIT_INVALID_MOVIE: i32 = 5;
+pub const EXIT_CPU_LOG_MISMATCH: i32 = 6;
+pub const EXIT_INVALID_SYMBOLS: i32 = 7;
+pub const EXIT_SDL_INIT: i32 = 8;
+pub const EXIT_INVALID_BINDINGS: i32 = 9;
 pub const EXIT_RUNTIME_FAILURE: i32 = 101;
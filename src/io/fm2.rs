@@ -0,0 +1,226 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use io::movie::{Movie, MOVIE_COMMAND_NONE, MOVIE_COMMAND_POWER, MOVIE_COMMAND_RESET};
+use std::result::Result;
+
+// Every FM2 movie starts with its format version, which is always 3.
+const FM2_IDENTIFIER: &'static str = "version 3";
+
+// Bits of the command field at the start of each input line. Disk system and
+// VS. System commands are ignored.
+const FM2_COMMAND_RESET: u8 = 0x1;
+const FM2_COMMAND_POWER: u8 = 0x2;
+
+// Device types for port0 and port1 in the header.
+const FM2_PORT_NONE:    u8 = 0;
+const FM2_PORT_GAMEPAD: u8 = 1;
+
+// Gamepad buttons are written from right to left, so the first character is
+// the highest bit of the button state.
+const FM2_GAMEPAD_SIZE: usize = 8;
+
+/// Returns true if a movie file is an FCEUX movie rather than an nes-rs one.
+pub fn is_fm2(buf: &[u8]) -> bool {
+    buf.starts_with(FM2_IDENTIFIER.as_bytes())
+}
+
+/// Parses an FCEUX text movie. The header is made of "key value" lines and
+/// every line after it that starts with | is the input for one frame, in the
+/// form "|commands|port0|port1|port2|" where each gamepad is written as
+/// "RLDUTSBA" with any character other than a space or . meaning pressed.
+/// With a Four Score all four gamepads come before port2.
+///
+/// FM2 movies identify the ROM by its MD5 rather than its CRC32, so the
+/// returned movie has no checksum to verify against.
+pub fn parse(buf: &[u8]) -> Result<Movie, String> {
+    let contents = match String::from_utf8(buf.to_vec()) {
+        Ok(contents) => contents,
+        Err(_) => return Err("file is not a text FM2 movie".to_string()),
+    };
+
+    let mut region = 0;
+    let mut four_score = false;
+    let mut ports = [FM2_PORT_GAMEPAD, FM2_PORT_GAMEPAD];
+    let mut frames: Vec<(Vec<u8>, u8)> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim_right();
+        if line.starts_with("|") {
+            let frame = try!(
+                parse_frame(line, four_score, &ports).map_err(|e| format!("{}: {}", index + 1, e))
+            );
+            frames.push(frame);
+            continue;
+        }
+
+        let mut parts = line.splitn(2, ' ');
+        let key = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("").trim();
+        match key {
+            "binary" if value != "0" => {
+                return Err("binary FM2 movies aren't supported".to_string());
+            }
+            "palFlag" => region = if value == "1" { 1 } else { 0 },
+            "fourscore" => four_score = value == "1",
+            "port0" | "port1" => {
+                let port = match value.parse::<u8>() {
+                    Ok(port) if port <= FM2_PORT_GAMEPAD => port,
+                    _ => {
+                        let message = format!("{}: only gamepads are supported", index + 1);
+                        return Err(message);
+                    }
+                };
+                ports[if key == "port0" { 0 } else { 1 }] = port;
+            }
+            _ => {}
+        }
+    }
+
+    let controllers = if four_score { 4 } else { 2 };
+    let mut movie = Movie::new(0, region, controllers);
+    movie.rom_crc = None;
+    for (buttons, command) in frames {
+        movie.push_frame(&buttons, command);
+    }
+    Ok(movie)
+}
+
+/// Parses the commands and gamepad state from one line of input.
+fn parse_frame(line: &str, four_score: bool, ports: &[u8; 2]) -> Result<(Vec<u8>, u8), String> {
+    let mut fields = line[1..].split('|');
+    let commands = match fields.next().map(|field| field.trim().parse::<u8>()) {
+        Some(Ok(commands)) => commands,
+        _ => return Err("malformed commands".to_string()),
+    };
+    let command = if commands & FM2_COMMAND_POWER != 0 {
+        MOVIE_COMMAND_POWER
+    } else if commands & FM2_COMMAND_RESET != 0 {
+        MOVIE_COMMAND_RESET
+    } else {
+        MOVIE_COMMAND_NONE
+    };
+
+    let controllers = if four_score { 4 } else { 2 };
+    let mut buttons = Vec::with_capacity(controllers);
+    for port in 0..controllers {
+        let field = match fields.next() {
+            Some(field) => field,
+            None => return Err("missing controller input".to_string()),
+        };
+        if !four_score && ports[port] == FM2_PORT_NONE {
+            buttons.push(0);
+            continue;
+        }
+        if field.len() != FM2_GAMEPAD_SIZE {
+            return Err(format!("malformed input for controller {}", port + 1));
+        }
+        let state = field
+            .chars()
+            .fold(0u8, |state, c| state << 1 | if c == ' ' || c == '.' { 0 } else { 1 });
+        buttons.push(state);
+    }
+    Ok((buttons, command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::cpu::CPU;
+    use nes::memory::Memory;
+    use nes::ppu::PPU;
+    use nes::testing;
+    use utils::hash;
+
+    // Counts resets in $02, then keeps reading the first controller into $00
+    // and collecting every button that was ever held in $01:
+    //
+    //         INC $02
+    // poll:   LDA #1
+    //         STA $4016
+    //         LDA #0
+    //         STA $4016
+    //         LDX #8
+    // read:   LDA $4016
+    //         LSR A
+    //         ROR $00
+    //         DEX
+    //         BNE read
+    //         LDA $00
+    //         ORA $01
+    //         STA $01
+    //         JMP poll
+    const PROGRAM: [u8; 32] = [
+        0xE6, 0x02, 0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xA2, 0x08, 0xAD,
+        0x16, 0x40, 0x4A, 0x66, 0x00, 0xCA, 0xD0, 0xF7, 0xA5, 0x00, 0x05, 0x01, 0x85, 0x01, 0x4C,
+        0x02, 0x80,
+    ];
+
+    // Holds A, then A and Right, resets, and finishes holding Up.
+    const MOVIE: &'static str = "version 3
+emuVersion 22020
+palFlag 0
+port0 1
+port1 1
+port2 0
+|0|........|........||
+|0|.......A|........||
+|0|R......A|........||
+|1|........|........||
+|0|...U....|........||
+|0|...U....|........||
+";
+
+    // Plays every frame of a movie into the test program and returns the
+    // machine's RAM afterwards along with a hash of the last picture drawn.
+    fn play(movie: &Movie) -> (Vec<u8>, u64) {
+        let mapper = testing::mapper(&testing::nrom(&PROGRAM));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        let mut cpu = CPU::new(testing::runtime_options());
+        cpu.power_on(&mut memory);
+
+        for index in 0..movie.len() {
+            memory.controller_1.buttons = movie.frame(index).unwrap()[0];
+            if movie.command(index) == MOVIE_COMMAND_RESET {
+                cpu.reset(&mut memory);
+            }
+
+            // Run until the PPU finishes the frame.
+            while !ppu.poll_frame() {
+                let cycles = cpu.step(&mut memory).unwrap();
+                for _ in 0..cycles * 3 {
+                    ppu.step(&mut memory);
+                }
+            }
+        }
+
+        let ram = (0..0x800).map(|addr| memory.read_u8(addr)).collect();
+        (ram, hash::fnv1a(ppu.framebuffer()))
+    }
+
+    #[test]
+    fn movie_input_and_resets_reach_the_game() {
+        let movie = parse(MOVIE.as_bytes()).unwrap();
+        let (ram, _) = play(&movie);
+
+        assert_eq!(movie.len(), 6);
+        assert_eq!(ram[0x00], 0x10);
+        assert_eq!(ram[0x01], 0x91);
+        assert_eq!(ram[0x02], 2);
+    }
+
+    #[test]
+    fn replaying_a_movie_reaches_the_same_state() {
+        let movie = parse(MOVIE.as_bytes()).unwrap();
+        let (first_ram, first_hash) = play(&movie);
+        let (second_ram, second_hash) = play(&movie);
+
+        assert_eq!(first_ram, second_ram);
+        assert_eq!(first_hash, second_hash);
+    }
+}
@@ -8,6 +8,77 @@
 
 use chrono::{DateTime, Local};
 use nes::nes::NESRuntimeOptions;
+use std::io::{stderr, stdout, Write};
+
+/// How much the emulator prints. Each level also shows the messages of the
+/// levels before it, so errors are always shown.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// Returns true if messages at the given level should be shown with the log
+/// level set on the command-line.
+pub fn enabled(level: LogLevel, runtime_options: &NESRuntimeOptions) -> bool {
+    level <= runtime_options.log_level
+}
+
+/// Prints an error to stderr.
+pub fn error<T>(text: T, runtime_options: &NESRuntimeOptions)
+where
+    T: Into<String>,
+{
+    if enabled(LogLevel::Error, runtime_options) {
+        writeln!(stderr(), "nes-rs: {}", text.into()).unwrap();
+    }
+}
+
+/// Prints a warning to stderr about something that didn't stop emulation.
+pub fn warn<T>(text: T, runtime_options: &NESRuntimeOptions)
+where
+    T: Into<String>,
+{
+    if enabled(LogLevel::Warn, runtime_options) {
+        writeln!(stderr(), "nes-rs: warning: {}", text.into()).unwrap();
+    }
+}
+
+/// Prints an informational message to stdout, which is hidden in quiet mode.
+pub fn info<T>(text: T, runtime_options: &NESRuntimeOptions)
+where
+    T: Into<String>,
+{
+    if enabled(LogLevel::Info, runtime_options) {
+        println!("{}", text.into());
+    }
+}
+
+/// Prints a message with a given prefix to stderr. This is for diagnostics
+/// that were asked for with their own option, so it's only hidden in quiet
+/// mode.
+pub fn trace<P, T>(prefix: P, text: T, runtime_options: &NESRuntimeOptions)
+where
+    P: Into<String>,
+    T: Into<String>,
+{
+    if enabled(LogLevel::Info, runtime_options) {
+        writeln!(stderr(), "[{}] {}", prefix.into(), text.into()).unwrap();
+    }
+}
+
+/// Logs a message about how the emulator was set up at startup, such as the
+/// cartridge's mapper and mirroring. These are shown in verbose mode, or on
+/// their own with the verbose-init flag set so they aren't buried under the
+/// CPU trace.
+pub fn init<T>(text: T, runtime_options: &NESRuntimeOptions)
+where
+    T: Into<String>,
+{
+    init_to(&mut stdout(), text, runtime_options);
+}
 
 /// Logs a message to stdout with a given prefix if the emulator was started
 /// with the verbose flag set.
@@ -16,8 +87,77 @@ where
     P: Into<String>,
     T: Into<String>,
 {
-    if runtime_options.verbose {
-        let local: DateTime<Local> = Local::now();
-        println!("[{}] -- [{}] {}", local, prefix.into(), text.into());
+    log_to(&mut stdout(), prefix, text, runtime_options);
+}
+
+/// Writes a startup message to the given output, like init does to stdout.
+fn init_to<W, T>(out: &mut W, text: T, runtime_options: &NESRuntimeOptions)
+where
+    W: Write,
+    T: Into<String>,
+{
+    if runtime_options.verbose_init && enabled(LogLevel::Info, runtime_options) {
+        writeln!(out, "{}", timestamped("init".to_string(), text.into())).unwrap();
+    } else {
+        log_to(out, "init", text, runtime_options);
+    }
+}
+
+/// Writes a verbose message to the given output, like log does to stdout.
+fn log_to<W, P, T>(out: &mut W, prefix: P, text: T, runtime_options: &NESRuntimeOptions)
+where
+    W: Write,
+    P: Into<String>,
+    T: Into<String>,
+{
+    if enabled(LogLevel::Debug, runtime_options) {
+        writeln!(out, "{}", timestamped(prefix.into(), text.into())).unwrap();
+    }
+}
+
+/// Formats a message with the local time and the given prefix, which is how
+/// verbose and startup messages are shown.
+fn timestamped(prefix: String, text: String) -> String {
+    let local: DateTime<Local> = Local::now();
+    format!("[{}] -- [{}] {}", local, prefix, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+
+    #[test]
+    fn info_level_filters_debug_messages_but_not_errors() {
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.log_level = LogLevel::Info;
+
+        assert!(!enabled(LogLevel::Debug, &runtime_options));
+        assert!(enabled(LogLevel::Info, &runtime_options));
+        assert!(enabled(LogLevel::Error, &runtime_options));
+    }
+
+    #[test]
+    fn verbose_init_shows_startup_messages_without_the_cpu_trace() {
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.log_level = LogLevel::Info;
+        runtime_options.verbose_init = true;
+
+        let mut out = Vec::new();
+        init_to(&mut out, "Using mapper 0 (NROM)", &runtime_options);
+        log_to(&mut out, "cpu", "C000  4C F5 C5  JMP $C5F5", &runtime_options);
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.ends_with(" -- [init] Using mapper 0 (NROM)\n"));
+    }
+
+    #[test]
+    fn quiet_mode_only_shows_errors() {
+        let runtime_options = testing::runtime_options();
+
+        assert!(!enabled(LogLevel::Info, &runtime_options));
+        assert!(!enabled(LogLevel::Warn, &runtime_options));
+        assert!(enabled(LogLevel::Error, &runtime_options));
     }
 }
@@ -8,4 +8,7 @@
 
 pub mod binutils;
 pub mod errors;
+pub mod fm2;
 pub mod log;
+pub mod movie;
+pub mod nsf;
@@ -0,0 +1,225 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+use std::result::Result;
+
+// Used to identify a file as an nes-rs movie. This byte sequence should be at
+// the start of every movie.
+const MOVIE_IDENTIFIER: [u8; 4] = [0x4E, 0x52, 0x4D, 0x1A];
+const MOVIE_VERSION: u8 = 2;
+
+// The first version only recorded the first controller, with no metadata past
+// the frame count.
+const MOVIE_VERSION_1: u8 = 1;
+const MOVIE_VERSION_1_HEADER_SIZE: usize = 13;
+
+// Identifier, version, ROM checksum, frame count, region, starting state, and
+// number of controllers.
+const MOVIE_HEADER_SIZE: usize = 16;
+
+// States a movie can start recording from. Only power-on is supported, as
+// there are no save states to start from yet.
+pub const MOVIE_START_POWER_ON: u8 = 0;
+
+// Commands recorded alongside the input for a frame, which are run before the
+// frame's input is latched.
+pub const MOVIE_COMMAND_NONE:  u8 = 0;
+pub const MOVIE_COMMAND_RESET: u8 = 1;
+pub const MOVIE_COMMAND_POWER: u8 = 2;
+
+/// A recording of controller input where every frame of emulation has one
+/// byte of button state for each controller, followed by a command byte.
+/// Movies are stored in a simple binary format:
+///
+/// ```text
+/// 0x0  identifier "NRM<0x1A>"
+/// 0x4  format version
+/// 0x5  CRC32 of the ROM the movie was recorded with (little-endian)
+/// 0x9  number of frames (little-endian)
+/// 0xD  region the ROM ran as, using NES 2.0 timing values
+/// 0xE  state recording started from (0 for power-on)
+/// 0xF  number of controllers recorded
+/// 0x10 button state for each controller and a command for each frame
+/// ```
+///
+/// Version 1 movies, which only have the first four fields and the first
+/// controller's buttons, can still be played back.
+pub struct Movie {
+    // Checksum of the ROM the movie was recorded against. Movies played back
+    // against a different ROM would desync immediately. Movies from other
+    // emulators may not have a CRC32 to check.
+    pub rom_crc: Option<u32>,
+
+    // Region the ROM ran as, which changes timing enough to desync.
+    pub region: u8,
+
+    // Number of controllers recorded for every frame.
+    pub controllers: usize,
+
+    frames: Vec<u8>,
+}
+
+impl Movie {
+    /// Creates an empty movie for the ROM with the given checksum, recording
+    /// the given number of controllers from power-on.
+    pub fn new(rom_crc: u32, region: u8, controllers: usize) -> Self {
+        Movie {
+            rom_crc: Some(rom_crc),
+            region: region,
+            controllers: controllers,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Parses a movie from the contents of a movie file.
+    pub fn parse(buf: &[u8]) -> Result<Movie, &'static str> {
+        let invalid_movie = "file is not an nes-rs movie";
+        if buf.len() < MOVIE_VERSION_1_HEADER_SIZE || buf[0x0..0x4] != MOVIE_IDENTIFIER {
+            return Err(invalid_movie);
+        }
+        if buf[0x4] == MOVIE_VERSION_1 {
+            return Movie::parse_version_1(buf);
+        }
+        if buf[0x4] != MOVIE_VERSION {
+            return Err("movie was recorded with an unsupported format version");
+        }
+        if buf.len() < MOVIE_HEADER_SIZE {
+            return Err(invalid_movie);
+        }
+
+        let mut reader = Cursor::new(&buf[0x5..0xD]);
+        let rom_crc = reader.read_u32::<LittleEndian>().unwrap();
+        let frame_count = reader.read_u32::<LittleEndian>().unwrap() as usize;
+        let region = buf[0xD];
+        if buf[0xE] != MOVIE_START_POWER_ON {
+            return Err("movie starts from a state that isn't supported");
+        }
+        let controllers = buf[0xF] as usize;
+
+        let frames = &buf[MOVIE_HEADER_SIZE..];
+        if frames.len() != frame_count * (controllers + 1) {
+            return Err("movie is truncated or has trailing data");
+        }
+        let movie = Movie {
+            rom_crc: Some(rom_crc),
+            region: region,
+            controllers: controllers,
+            frames: frames.to_vec(),
+        };
+        for index in 0..frame_count {
+            if movie.command(index) > MOVIE_COMMAND_POWER {
+                return Err("movie has a command that isn't supported");
+            }
+        }
+        Ok(movie)
+    }
+
+    /// Parses a version 1 movie, which has the first controller's buttons and
+    /// nothing else for every frame. These were always recorded from power-on
+    /// and as NTSC.
+    fn parse_version_1(buf: &[u8]) -> Result<Movie, &'static str> {
+        let mut reader = Cursor::new(&buf[0x5..MOVIE_VERSION_1_HEADER_SIZE]);
+        let rom_crc = reader.read_u32::<LittleEndian>().unwrap();
+        let frame_count = reader.read_u32::<LittleEndian>().unwrap() as usize;
+
+        let buttons = &buf[MOVIE_VERSION_1_HEADER_SIZE..];
+        if buttons.len() != frame_count {
+            return Err("movie is truncated or has trailing data");
+        }
+
+        let mut movie = Movie::new(rom_crc, 0, 1);
+        for &buttons in buttons {
+            movie.push_frame(&[buttons], MOVIE_COMMAND_NONE);
+        }
+        Ok(movie)
+    }
+
+    /// Serializes the movie so it can be written to disk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MOVIE_HEADER_SIZE + self.frames.len());
+        buf.extend_from_slice(&MOVIE_IDENTIFIER);
+        buf.push(MOVIE_VERSION);
+        buf.write_u32::<LittleEndian>(self.rom_crc.unwrap_or(0)).unwrap();
+        buf.write_u32::<LittleEndian>(self.len() as u32).unwrap();
+        buf.push(self.region);
+        buf.push(MOVIE_START_POWER_ON);
+        buf.push(self.controllers as u8);
+        buf.extend_from_slice(&self.frames);
+        buf
+    }
+
+    /// Appends the button state of each controller and a command for the next
+    /// frame.
+    pub fn push_frame(&mut self, buttons: &[u8], command: u8) {
+        self.frames.extend_from_slice(&buttons[..self.controllers]);
+        self.frames.push(command);
+    }
+
+    /// Returns the button state of each controller for the given frame if the
+    /// movie is long enough.
+    pub fn frame(&self, index: usize) -> Option<&[u8]> {
+        let start = index * self.stride();
+        if index < self.len() {
+            Some(&self.frames[start..start + self.controllers])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the command for the given frame, which is MOVIE_COMMAND_NONE
+    /// past the end of the movie.
+    pub fn command(&self, index: usize) -> u8 {
+        if index < self.len() {
+            self.frames[index * self.stride() + self.controllers]
+        } else {
+            MOVIE_COMMAND_NONE
+        }
+    }
+
+    /// Returns the number of frames in the movie.
+    pub fn len(&self) -> usize {
+        self.frames.len() / self.stride()
+    }
+
+    // Number of bytes recorded for every frame.
+    fn stride(&self) -> usize {
+        self.controllers + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_movies_play_back_the_same_input() {
+        let inputs: [([u8; 2], u8); 4] = [
+            ([0x00, 0x00], MOVIE_COMMAND_POWER),
+            ([0x01, 0x80], MOVIE_COMMAND_NONE),
+            ([0x81, 0xFF], MOVIE_COMMAND_RESET),
+            ([0x10, 0x00], MOVIE_COMMAND_NONE),
+        ];
+        let mut recorded = Movie::new(0xDEADBEEF, 1, 2);
+        for &(buttons, command) in inputs.iter() {
+            recorded.push_frame(&buttons, command);
+        }
+
+        let movie = Movie::parse(&recorded.to_bytes()).unwrap();
+        assert_eq!(movie.rom_crc, Some(0xDEADBEEF));
+        assert_eq!(movie.region, 1);
+        assert_eq!(movie.controllers, 2);
+        assert_eq!(movie.len(), inputs.len());
+        for (index, &(buttons, command)) in inputs.iter().enumerate() {
+            assert_eq!(movie.frame(index), Some(&buttons[..]));
+            assert_eq!(movie.command(index), command);
+        }
+        assert_eq!(movie.frame(inputs.len()), None);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use io::binutils::INESHeader;
+use std::io::Cursor;
+use std::result::Result;
+
+// Used to identify a file as an NSF. This byte sequence should be at the start
+// of every NSF.
+const NSF_IDENTIFIER: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A];
+
+// Music data follows the header.
+pub const NSF_HEADER_SIZE: usize = 0x80;
+
+// Bits of the region byte. Tunes made for both regions are played as NTSC.
+const NSF_PAL:  u8 = 0x1;
+const NSF_DUAL: u8 = 0x2;
+
+// Length of a frame in microseconds, which is how often most music is played.
+const NTSC_FRAME_SPEED: u16 = 16639;
+const PAL_FRAME_SPEED:  u16 = 19997;
+
+/// Structure that represents the 128 byte header of an NSF (NES Sound Format)
+/// file, which holds music ripped from a game along with the addresses of the
+/// routines that play it.
+#[derive(Debug)]
+pub struct NsfHeader {
+    // Number of songs in the file and the one to start with, counting from 1.
+    pub songs: u8,
+    pub starting_song: u8,
+
+    // Where the music data is loaded, the routine that sets up a song, and
+    // the routine called every frame to play it.
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+
+    // How often the play routine is called in microseconds.
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+
+    // 4KB banks initially mapped at $8000-$FFFF. The music data isn't bank
+    // switched if these are all 0.
+    pub bankswitch: [u8; 8],
+
+    // Expansion audio chips the music uses, which aren't emulated.
+    pub extra_chips: u8,
+
+    region: u8,
+}
+
+/// Returns true if a file is an NSF rather than an iNES ROM.
+pub fn is_nsf(buf: &[u8]) -> bool {
+    buf.starts_with(&NSF_IDENTIFIER)
+}
+
+impl NsfHeader {
+    /// Parses the header of an NSF file.
+    pub fn new(buf: &[u8]) -> Result<NsfHeader, &'static str> {
+        if buf.len() < NSF_HEADER_SIZE || !is_nsf(buf) {
+            return Err("file does not contain NSF identifier and is invalid");
+        }
+        if buf.len() == NSF_HEADER_SIZE {
+            return Err("NSF has no music data");
+        }
+
+        let mut reader = Cursor::new(&buf[0x08..0x0E]);
+        let load_addr = reader.read_u16::<LittleEndian>().unwrap();
+        let init_addr = reader.read_u16::<LittleEndian>().unwrap();
+        let play_addr = reader.read_u16::<LittleEndian>().unwrap();
+        if load_addr < 0x8000 {
+            return Err("NSF loads below $8000, which isn't supported");
+        }
+
+        let mut bankswitch = [0; 8];
+        bankswitch.copy_from_slice(&buf[0x70..0x78]);
+
+        Ok(NsfHeader {
+            songs: buf[0x06],
+            starting_song: buf[0x07].max(1),
+            load_addr: load_addr,
+            init_addr: init_addr,
+            play_addr: play_addr,
+            name: nsf_string(&buf[0x0E..0x2E]),
+            artist: nsf_string(&buf[0x2E..0x4E]),
+            copyright: nsf_string(&buf[0x4E..0x6E]),
+            ntsc_speed: Cursor::new(&buf[0x6E..0x70]).read_u16::<LittleEndian>().unwrap(),
+            pal_speed: Cursor::new(&buf[0x78..0x7A]).read_u16::<LittleEndian>().unwrap(),
+            bankswitch: bankswitch,
+            extra_chips: buf[0x7B],
+            region: buf[0x7A],
+        })
+    }
+
+    /// Returns true if the music was made for PAL consoles only.
+    pub fn is_pal(&self) -> bool {
+        self.region & (NSF_PAL | NSF_DUAL) == NSF_PAL
+    }
+
+    /// Returns true if the music data is bank switched.
+    pub fn uses_bankswitching(&self) -> bool {
+        self.bankswitch.iter().any(|&bank| bank != 0)
+    }
+
+    /// Returns how often the play routine is called in microseconds. Some
+    /// rips leave this as 0, in which case it's called once a frame.
+    pub fn speed(&self) -> u16 {
+        match (self.is_pal(), self.pal_speed, self.ntsc_speed) {
+            (true, 0, _) => PAL_FRAME_SPEED,
+            (true, speed, _) => speed,
+            (false, _, 0) => NTSC_FRAME_SPEED,
+            (false, _, speed) => speed,
+        }
+    }
+
+    /// Returns an iNES header for a cartridge without CHR-ROM, PRG-RAM or a
+    /// trainer in the NSF's region, so the rest of the emulator can treat the
+    /// NSF like any other cartridge.
+    pub fn ines_header(&self) -> INESHeader {
+        let mut header = [0; 0x10];
+        header[0x0..0x4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        header[0x4] = 2;
+        header[0x9] = if self.is_pal() { 1 } else { 0 };
+        INESHeader::new(&header).unwrap()
+    }
+}
+
+/// Reads a null terminated string from the header, which is padded out to 32
+/// bytes.
+fn nsf_string(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
@@ -6,6 +6,39 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+// The code is written for the 2015 edition and the compilers of its time, so
+// lints suggesting newer syntax or library functions are turned off.
+#![allow(bare_trait_objects, deprecated, ellipsis_inclusive_range_patterns)]
+#![allow(
+    clippy::explicit_write,
+    clippy::legacy_numeric_constants,
+    clippy::manual_div_ceil,
+    clippy::manual_is_multiple_of,
+    clippy::manual_ok_err,
+    clippy::manual_range_contains,
+    clippy::manual_repeat_n,
+    clippy::manual_strip,
+    clippy::manual_unwrap_or_default,
+    clippy::match_like_matches_macro,
+    clippy::option_as_ref_deref,
+    clippy::question_mark,
+    clippy::redundant_field_names,
+    clippy::redundant_static_lifetimes
+)]
+// Matches how the rest of the code is written.
+#![allow(
+    clippy::len_zero,
+    clippy::module_inception,
+    clippy::needless_range_loop,
+    clippy::needless_return,
+    clippy::ptr_arg,
+    clippy::redundant_pattern_matching,
+    clippy::single_match,
+    clippy::upper_case_acronyms,
+    clippy::useless_format,
+    clippy::writeln_empty_string
+)]
+
 #[macro_use]
 extern crate enum_primitive;
 extern crate byteorder;
@@ -21,19 +54,101 @@ mod nes;
 mod utils;
 
 use getopts::Options;
-use io::binutils::INESHeader;
+use io::binutils::{self, INESHeader};
 use io::errors::*;
+use io::log::{self, LogLevel};
+use io::nsf::{NsfHeader, NSF_HEADER_SIZE};
+use nes::mapper;
 use nes::nes::NESRuntimeOptions;
 use nes::nes::NES;
+use nes::picker;
 use std::env;
-use std::io::{stderr, Write};
+use std::fs;
+use std::io::{stderr, stdout, Write};
+use std::path::Path;
 use utils::arithmetic;
 
+// Amount of audio buffered ahead of the device when no latency is given, and
+// the most that can be asked for.
+const DEFAULT_AUDIO_LATENCY: u32 = 50;
+const MAX_AUDIO_LATENCY: u32 = 10000;
+
+// Number of instructions between pretty register panels when no interval is
+// given.
+const DEFAULT_PRETTY_INTERVAL: u64 = 1000;
+
+// Percent of a game controller stick's travel ignored when no dead zone is
+// given.
+const DEFAULT_GAMEPAD_DEAD_ZONE: u8 = 25;
+
+// Frames turbo buttons are held and released for when no rate is given, which
+// works out to 15 presses a second.
+const DEFAULT_TURBO_RATE: (u64, u64) = (2, 2);
+
+// Frames advanced per second while the frame advance key is held when no rate
+// is given.
+const DEFAULT_FRAME_ADVANCE_RATE: u32 = 10;
+
+// Speed multiplier used while the fast-forward key is held when no speed is
+// given. Fast-forwarding is capped so it doesn't run the host flat out.
+const DEFAULT_FF_SPEED: f64 = 8.0;
+
 /// Prints the application name alongside the cargo version.
 fn print_version() {
     println!("nes-rs {}", env!("CARGO_PKG_VERSION"));
 }
 
+/// Prints the details of a rom's header along with a checksum of its PRG-ROM
+/// and CHR-ROM, which is handy for identifying the exact dump being used.
+fn print_rom_info<W: Write>(
+    out: &mut W,
+    rom_file_name: &str,
+    rom: &[u8],
+    header: &INESHeader,
+) -> std::io::Result<()> {
+    let mapper = header.mapper_number();
+    let data_end = header.expected_size().min(rom.len());
+    let data_start = header.prg_rom_offset().min(data_end);
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    try!(writeln!(out, "File:      {}", rom_file_name));
+    try!(writeln!(out, "Format:    {}", header.format()));
+    try!(writeln!(out, "Mapper:    {} ({})", mapper, binutils::mapper_name(mapper)));
+    try!(writeln!(out, "Supported: {}", yes_no(header.mapper().is_ok())));
+    try!(writeln!(out, "PRG-ROM:   {}KB", header.prg_rom_size as usize * 16));
+    try!(writeln!(out, "CHR-ROM:   {}KB", header.chr_rom_size as usize * 8));
+    try!(writeln!(out, "Mirroring: {:?}", header.mirror_type()));
+    try!(writeln!(out, "Battery:   {}", yes_no(header.has_persistent_ram())));
+    try!(writeln!(out, "Trainer:   {}", yes_no(header.has_trainer())));
+    try!(writeln!(out, "Region:    {}", header.region()));
+    writeln!(out, "CRC32:     {:08X}", binutils::crc32(&rom[data_start..data_end]))
+}
+
+/// Prints the details of an NSF's header, which describes the music in it
+/// rather than a cartridge.
+fn print_nsf_info<W: Write>(
+    out: &mut W,
+    nsf_file_name: &str,
+    nsf: &[u8],
+    header: &NsfHeader,
+) -> std::io::Result<()> {
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+
+    try!(writeln!(out, "File:      {}", nsf_file_name));
+    try!(writeln!(out, "Format:    NSF"));
+    try!(writeln!(out, "Name:      {}", header.name));
+    try!(writeln!(out, "Artist:    {}", header.artist));
+    try!(writeln!(out, "Copyright: {}", header.copyright));
+    try!(writeln!(out, "Songs:     {} (starting at {})", header.songs, header.starting_song));
+    try!(writeln!(out, "Load:      ${:04X}", header.load_addr));
+    try!(writeln!(out, "Init:      ${:04X}", header.init_addr));
+    try!(writeln!(out, "Play:      ${:04X}", header.play_addr));
+    try!(writeln!(out, "Banked:    {}", yes_no(header.uses_bankswitching())));
+    try!(writeln!(out, "Expansion: {}", yes_no(header.extra_chips != 0)));
+    try!(writeln!(out, "Region:    {}", if header.is_pal() { "PAL" } else { "NTSC" }));
+    writeln!(out, "CRC32:     {:08X}", binutils::crc32(&nsf[NSF_HEADER_SIZE..]))
+}
+
 /// Prints usage information with an optional reason.
 fn print_usage(opts: Options, reason: Option<&str>) {
     let mut stderr = std::io::stderr();
@@ -54,10 +169,61 @@ fn print_usage(opts: Options, reason: Option<&str>) {
     writeln!(stderr, "<https://github.com/Reshurum/nes-rs>").unwrap();
 }
 
+/// Parses a turbo rate in the form "ON:OFF", where both are a number of
+/// frames and at least one frame is spent held down.
+fn parse_turbo_rate(arg: &str) -> Option<(u64, u64)> {
+    let mut parts = arg.splitn(2, ':').map(|part| part.trim().parse::<u64>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(on)), Some(Ok(off))) if on > 0 => Some((on, off)),
+        _ => None,
+    }
+}
+
+/// Returns where a file written by the emulator should go. Relative paths are
+/// put in the data directory if there is one, otherwise they are left as is.
+fn data_path(data_dir: &Option<String>, filename: &Path) -> String {
+    match *data_dir {
+        Some(ref data_dir) if filename.is_relative() => {
+            Path::new(data_dir).join(filename).to_string_lossy().into_owned()
+        }
+        _ => filename.to_string_lossy().into_owned(),
+    }
+}
+
+/// Returns where the battery save of a rom goes. Saves are kept next to the
+/// rom, or in the data directory if there is one.
+fn save_path(data_dir: &Option<String>, rom_file_name: &str) -> String {
+    let path = Path::new(rom_file_name).with_extension("sav");
+    match *data_dir {
+        Some(_) => data_path(data_dir, Path::new(path.file_name().unwrap())),
+        None => path.to_string_lossy().into_owned(),
+    }
+}
+
 /// Initializes and starts the emulator. Returns an exit code after which the
 /// program unwinds and stops executing. Once the emulator starts executing, the
 /// application should only stop due to user input, or a panic.
-fn init() -> i32 {
+///
+/// A rom dropped onto the window replaces the one given on the command-line.
+/// It's handed back through dropped_rom when emulation stops so the emulator
+/// can be started again with it.
+/// Returns a warning if the rom is larger than its header describes. Some
+/// dumps have junk appended to them which is harmless, but could also mean the
+/// header is wrong.
+fn padding_warning(rom_file_name: &str, rom: &[u8], header: &INESHeader) -> Option<String> {
+    if rom.len() > header.expected_size() {
+        Some(format!(
+            "{} is {} bytes but the header describes {} bytes, ignoring the rest",
+            rom_file_name,
+            rom.len(),
+            header.expected_size()
+        ))
+    } else {
+        None
+    }
+}
+
+fn init(dropped_rom: &mut Option<String>) -> i32 {
     // Collect the argument from the environment (command-line arguments).
     let args: Vec<String> = env::args().collect();
 
@@ -65,21 +231,154 @@ fn init() -> i32 {
     // rules defined against the option object.
     let mut opts = Options::new();
     opts.optopt("t", "test", "test the emulator against a CPU log", "[FILE]");
+    opts.optopt(
+        "",
+        "log-compare-start",
+        "only compare against the CPU log once this address is reached",
+        "[HEX]",
+    );
     opts.optopt(
         "p",
         "program-counter",
         "set the initial program counter to a specified address",
         "[HEX]",
     );
+    opts.optopt("", "nsf", "play the music in an NSF file instead of a rom", "[FILE]");
     opts.optflag("v", "verbose", "display CPU frame information");
+    opts.optflag("", "verbose-init", "display cartridge information at startup");
+    opts.optflag("q", "quiet", "only display errors");
+    opts.optflag("", "guard-stack", "warn when the stack pointer wraps around");
+    opts.optflag("", "warn-rom-writes", "warn about writes to ROM that are dropped");
+    opts.optflag("", "show-fps", "show the frame rate in the window title");
+    opts.optflag("", "input-log", "print controller input whenever it changes");
+    opts.optflag(
+        "",
+        "boot-animation-skip",
+        "run at full speed until the game turns on rendering",
+    );
+    opts.optopt(
+        "",
+        "symbols",
+        "label addresses in the debugger using a symbol file",
+        "[FILE]",
+    );
+    opts.optflag(
+        "",
+        "continue-on-mismatch",
+        "keep testing past CPU log mismatches and summarize them",
+    );
+    opts.optflagopt(
+        "",
+        "pretty",
+        "print a register panel every N instructions (default 1000)",
+        "[N]",
+    );
     opts.optflag("", "version", "print version information");
+    opts.optflag("", "info", "print details from the rom's or NSF's header and exit");
+    opts.optflag("", "list-mappers", "list the mappers this build supports and exit");
     opts.optflag("h", "help", "print this message");
     opts.optflag("d", "debug", "allow use of the CPU debugger");
+    opts.optflag(
+        "",
+        "debug-break-on-start",
+        "start the debugger stopped before the first instruction",
+    );
+    opts.optflag("", "no-audio", "disable audio output");
+    opts.optflag("", "mute", "start with every sound channel muted");
+    opts.optopt("", "volume", "master volume from 0 to 100 (default 100)", "[N]");
+    opts.optflag(
+        "",
+        "headless",
+        "run without a display or audio, as fast as possible",
+    );
+    opts.optopt(
+        "",
+        "gamepad-dead-zone",
+        "percent of a stick's travel to ignore (default 25)",
+        "[N]",
+    );
+    opts.optopt(
+        "",
+        "bindings",
+        "load keyboard and game controller bindings from a file",
+        "[FILE]",
+    );
+    opts.optopt(
+        "",
+        "port2",
+        "device in the second port, controller or zapper (default controller)",
+        "[DEVICE]",
+    );
+    opts.optflag("", "four-score", "plug in a Four Score for players 3 and 4");
+    opts.optopt(
+        "",
+        "turbo-rate",
+        "frames turbo buttons are held and released for (default 2:2)",
+        "[ON:OFF]",
+    );
+    opts.optflag("", "gamepad-swap-ab", "swap the A and B buttons on game controllers");
+    opts.optflag(
+        "",
+        "start-fullscreen-borderless",
+        "start in borderless fullscreen (Alt+Enter toggles it)",
+    );
+    opts.optopt(
+        "",
+        "frame-advance-rate",
+        "frames advanced per second while holding \\ when paused (default 10)",
+        "[N]",
+    );
+    opts.optopt("", "frames", "stop after emulating N frames", "[N]");
+    opts.optflag("", "frame-hash", "print a hash of the last frame on exit");
+    opts.optopt(
+        "",
+        "speed",
+        "emulation speed multiplier (default 1.0)",
+        "[MULT]",
+    );
+    opts.optflag("", "speed-pitch", "let the speed multiplier change audio pitch");
+    opts.optopt(
+        "",
+        "ff-speed",
+        "speed multiplier while holding the fast-forward key (default 8)",
+        "[MULT]",
+    );
+    opts.optopt(
+        "",
+        "audio-latency",
+        "amount of audio to buffer in milliseconds, up to 10000 (default 50)",
+        "[MS]",
+    );
+    opts.optopt(
+        "",
+        "autosave-interval",
+        "also write battery saves every N seconds while running",
+        "[SECONDS]",
+    );
+    opts.optopt(
+        "",
+        "data-dir",
+        "write battery saves and movies to a directory, creating it if missing",
+        "[DIR]",
+    );
+    opts.optopt(
+        "",
+        "record-input",
+        "record controller input to a movie file",
+        "[FILE]",
+    );
+    opts.optopt("", "record-movie", "same as --record-input", "[FILE]");
+    opts.optopt(
+        "",
+        "playback-input",
+        "play back controller input from a movie file or FCEUX FM2 movie",
+        "[FILE]",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
-            println!("{}", f.to_string());
+            println!("{}", f);
             print_usage(opts, None);
             return EXIT_FAILURE;
         }
@@ -94,11 +393,38 @@ fn init() -> i32 {
         print_usage(opts, None);
         return EXIT_SUCCESS;
     }
+    if matches.opt_present("list-mappers") {
+        for info in mapper::MAPPERS.iter() {
+            println!("{:>3}  {}", info.number, info.name);
+        }
+        return EXIT_SUCCESS;
+    }
 
     // Get the ROM filename from the first free argument and read the ROM into
-    // memory (vector of bytes). The ROM is a required argument.
-    let rom_file_name = if !matches.free.is_empty() {
+    // memory (vector of bytes). The ROM is a required argument unless an NSF is
+    // being played instead, or there's a display to drop one onto.
+    let dropped = dropped_rom.take();
+    let mut nsf = if dropped.is_some() {
+        None
+    } else {
+        matches.opt_str("nsf")
+    };
+    let rom_file_name = if let Some(dropped) = dropped.clone() {
+        dropped
+    } else if let Some(ref nsf) = nsf {
+        nsf.clone()
+    } else if !matches.free.is_empty() {
         matches.free[0].clone()
+    } else if !matches.opt_present("headless") && !matches.opt_present("info") {
+        match picker::wait_for_rom() {
+            Ok(Some(filename)) => filename,
+            Ok(None) => return EXIT_SUCCESS,
+            Err(e) => {
+                writeln!(stderr(), "nes-rs: {}", e).unwrap();
+                print_usage(opts, Some("nes-rs: no rom passed, cannot start emulation"));
+                return EXIT_FAILURE;
+            }
+        }
     } else {
         print_usage(opts, Some("nes-rs: no rom passed, cannot start emulation"));
         return EXIT_FAILURE;
@@ -112,15 +438,71 @@ fn init() -> i32 {
         }
     };
 
+    // Dropped files can be music as well as games.
+    if dropped.is_some() && io::nsf::is_nsf(&rom) {
+        nsf = Some(rom_file_name.clone());
+    }
+
     // Parse the rom's header to check if it's a valid iNES ROM and store it in
     // an internal structure. In addition to program code, the iNES file
     // contains useful metadata about the cartrige so we can tweak how the
     // emulator works to cater for that.
-    let header = match INESHeader::new(&rom) {
-        Ok(header) => header,
+    //
+    // NSFs are played from a pretend cartridge, so they get a made up header
+    // and skip the checks below.
+    let header = if nsf.is_some() {
+        match NsfHeader::new(&rom) {
+            Ok(ref nsf_header) if matches.opt_present("info") => {
+                print_nsf_info(&mut stdout(), &rom_file_name, &rom, nsf_header).unwrap();
+                return EXIT_SUCCESS;
+            }
+            Ok(nsf_header) => nsf_header.ines_header(),
+            Err(e) => {
+                writeln!(stderr(), "nes-rs: cannot parse {}: {}", rom_file_name, e).unwrap();
+                return EXIT_INVALID_ROM;
+            }
+        }
+    } else {
+        match INESHeader::new(&rom) {
+            Ok(header) => header,
+            Err(e) => {
+                let mut stderr = std::io::stderr();
+                writeln!(stderr, "nes-rs: cannot parse {}: {}", rom_file_name, e).unwrap();
+                return EXIT_INVALID_ROM;
+            }
+        }
+    };
+
+    // Print what the header says about the rom if that's all that's wanted.
+    // This doesn't depend on the mapper being supported.
+    if matches.opt_present("info") {
+        print_rom_info(&mut stdout(), &rom_file_name, &rom, &header).unwrap();
+        return EXIT_SUCCESS;
+    }
+
+    // Make sure the file actually contains everything the header describes,
+    // as corrupt downloads are often cut short.
+    if let (None, Err(e)) = (nsf.as_ref(), header.validate(&rom)) {
+        writeln!(stderr(), "nes-rs: cannot load {}: {}", rom_file_name, e).unwrap();
+        return EXIT_INVALID_ROM;
+    }
+
+    // Check the mapper is one we can emulate before going any further.
+    if let (None, Err(e)) = (nsf.as_ref(), header.mapper()) {
+        writeln!(stderr(), "nes-rs: {}", e).unwrap();
+        let supported: Vec<String> = mapper::MAPPERS
+            .iter()
+            .map(|info| format!("{} ({})", info.number, info.name))
+            .collect();
+        writeln!(stderr(), "Supported mappers: {}", supported.join(", ")).unwrap();
+        return EXIT_INVALID_ROM;
+    }
+
+    // Load PRG-ROM and CHR-ROM into the mapper used by the cartridge.
+    let mapper = match mapper::load_cartridge(&rom, &header) {
+        Ok(mapper) => mapper,
         Err(e) => {
-            let mut stderr = std::io::stderr();
-            writeln!(stderr, "nes-rs: cannot parse {}: {}", rom_file_name, e).unwrap();
+            writeln!(stderr(), "nes-rs: cannot load {}: {}", rom_file_name, e).unwrap();
             return EXIT_INVALID_ROM;
         }
     };
@@ -138,21 +520,338 @@ fn init() -> i32 {
         None
     };
 
+    // Comparing against a CPU log can start partway through the rom, skipping
+    // over the lines in the log up to that point.
+    let log_compare_start = match matches.opt_str("log-compare-start") {
+        Some(_) if !matches.opt_present("test") => {
+            writeln!(stderr(), "nes-rs: --log-compare-start needs a CPU log to test").unwrap();
+            return EXIT_FAILURE;
+        }
+        Some(arg) => match arithmetic::hex_to_u16(&arg) {
+            Some(addr) => Some(addr),
+            None => {
+                writeln!(stderr(), "nes-rs: cannot parse log comparison start address").unwrap();
+                return EXIT_FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // The pretty panel interval is optional, so only complain if one was given
+    // and it isn't a positive number.
+    let pretty = if matches.opt_present("pretty") {
+        match matches.opt_str("pretty").map(|arg| arg.parse::<u64>()) {
+            None => Some(DEFAULT_PRETTY_INTERVAL),
+            Some(Ok(interval)) if interval > 0 => Some(interval),
+            Some(_) => {
+                writeln!(stderr(), "nes-rs: cannot parse pretty interval").unwrap();
+                return EXIT_FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    let audio_latency = match matches.opt_str("audio-latency").map(|arg| arg.parse::<u32>()) {
+        None => DEFAULT_AUDIO_LATENCY,
+        Some(Ok(latency)) if latency > 0 && latency <= MAX_AUDIO_LATENCY => latency,
+        Some(_) => {
+            writeln!(stderr(), "nes-rs: cannot parse audio latency").unwrap();
+            return EXIT_FAILURE;
+        }
+    };
+
+    let frames = match matches.opt_str("frames").map(|arg| arg.parse::<u64>()) {
+        None => None,
+        Some(Ok(frames)) => Some(frames),
+        Some(Err(_)) => {
+            writeln!(stderr(), "nes-rs: cannot parse frame count").unwrap();
+            return EXIT_FAILURE;
+        }
+    };
+
+    let autosave_interval =
+        match matches.opt_str("autosave-interval").map(|arg| arg.parse::<u64>()) {
+            None => None,
+            Some(Ok(interval)) if interval > 0 => Some(interval),
+            Some(_) => {
+                writeln!(stderr(), "nes-rs: cannot parse autosave interval").unwrap();
+                return EXIT_FAILURE;
+            }
+        };
+
+    // Files the emulator writes go in the data directory when one is given,
+    // which is created up front so a bad path is reported before running.
+    let data_dir = matches.opt_str("data-dir");
+    if let Some(ref data_dir) = data_dir {
+        if let Err(e) = fs::create_dir_all(data_dir) {
+            writeln!(stderr(), "nes-rs: cannot create {}: {}", data_dir, e).unwrap();
+            return EXIT_FAILURE;
+        }
+    }
+
+    let save_file = if header.has_persistent_ram() {
+        Some(save_path(&data_dir, &rom_file_name))
+    } else {
+        None
+    };
+    let recording = matches.opt_present("record-input") || matches.opt_present("record-movie");
+    let record_input = matches
+        .opt_str("record-input")
+        .or_else(|| matches.opt_str("record-movie"))
+        .map(|filename| data_path(&data_dir, Path::new(&filename)));
+
+    // Movies belong to the rom given on the command-line, so they aren't
+    // recorded over or played back on a rom dropped onto the window.
+    let (record_input, playback_input) = if dropped.is_some() {
+        (None, None)
+    } else {
+        (record_input, matches.opt_str("playback-input"))
+    };
+
+    let log_level = match (matches.opt_present("verbose"), matches.opt_present("quiet")) {
+        (true, true) => {
+            writeln!(stderr(), "nes-rs: --verbose and --quiet can't be used together").unwrap();
+            return EXIT_FAILURE;
+        }
+        (true, false) => LogLevel::Debug,
+        (false, true) => LogLevel::Error,
+        (false, false) => LogLevel::Info,
+    };
+
+    let volume = match matches.opt_str("volume").map(|arg| arg.parse::<u8>()) {
+        None => 100,
+        Some(Ok(volume)) if volume <= 100 => volume,
+        Some(_) => {
+            writeln!(stderr(), "nes-rs: volume must be between 0 and 100").unwrap();
+            return EXIT_FAILURE;
+        }
+    };
+
+    let gamepad_dead_zone =
+        match matches.opt_str("gamepad-dead-zone").map(|arg| arg.parse::<u8>()) {
+            None => DEFAULT_GAMEPAD_DEAD_ZONE,
+            Some(Ok(dead_zone)) if dead_zone < 100 => dead_zone,
+            Some(_) => {
+                writeln!(stderr(), "nes-rs: dead zone must be between 0 and 99").unwrap();
+                return EXIT_FAILURE;
+            }
+        };
+
+    let turbo_rate = match matches.opt_str("turbo-rate") {
+        None => DEFAULT_TURBO_RATE,
+        Some(arg) => match parse_turbo_rate(&arg) {
+            Some(rate) => rate,
+            None => {
+                writeln!(stderr(), "nes-rs: turbo rate must be like 2:2").unwrap();
+                return EXIT_FAILURE;
+            }
+        },
+    };
+
+    let frame_advance_rate =
+        match matches.opt_str("frame-advance-rate").map(|arg| arg.parse::<u32>()) {
+            None => DEFAULT_FRAME_ADVANCE_RATE,
+            Some(Ok(rate)) if rate > 0 => rate,
+            Some(_) => {
+                writeln!(stderr(), "nes-rs: cannot parse frame advance rate").unwrap();
+                return EXIT_FAILURE;
+            }
+        };
+
+    let zapper = match matches.opt_str("port2").map(|arg| arg.to_lowercase()) {
+        None => false,
+        Some(ref device) if device == "controller" => false,
+        Some(ref device) if device == "zapper" => true,
+        Some(device) => {
+            writeln!(stderr(), "nes-rs: unknown port 2 device: {}", device).unwrap();
+            return EXIT_FAILURE;
+        }
+    };
+
+    let speed = match matches.opt_str("speed").map(|arg| arg.parse::<f64>()) {
+        None => 1.0,
+        Some(Ok(speed)) if speed > 0.0 => speed,
+        Some(_) => {
+            writeln!(stderr(), "nes-rs: cannot parse speed multiplier").unwrap();
+            return EXIT_FAILURE;
+        }
+    };
+
+    let ff_speed = match matches.opt_str("ff-speed").map(|arg| arg.parse::<f64>()) {
+        None => DEFAULT_FF_SPEED,
+        Some(Ok(speed)) if speed > 0.0 => speed,
+        Some(_) => {
+            writeln!(stderr(), "nes-rs: cannot parse fast-forward speed").unwrap();
+            return EXIT_FAILURE;
+        }
+    };
+
+    // Input can either come from a movie or be recorded to one, but doing
+    // both at once is not supported.
+    if recording && matches.opt_present("playback-input") {
+        print_usage(
+            opts,
+            Some("nes-rs: cannot record and play back input at the same time"),
+        );
+        return EXIT_FAILURE;
+    }
+
     // Initialize the NES with the mapper specified in the INES file and start
     // executing the ROM. The run function will only return when there is a
     // panic in the CPU or other emulated hardware.
     let runtime_options = NESRuntimeOptions {
         program_counter: program_counter,
         cpu_log: matches.opt_str("test"),
-        verbose: matches.opt_present("verbose"),
-        debugging: matches.opt_present("debug"),
+        log_compare_start: log_compare_start,
+        log_level: log_level,
+        verbose_init: matches.opt_present("verbose-init"),
+        debugging: matches.opt_present("debug") || matches.opt_present("debug-break-on-start"),
+        debug_break_on_start: matches.opt_present("debug-break-on-start"),
+        record_input: record_input,
+        playback_input: playback_input,
+        pretty: pretty,
+        continue_on_mismatch: matches.opt_present("continue-on-mismatch"),
+        no_audio: matches.opt_present("no-audio"),
+        audio_latency: audio_latency,
+        headless: matches.opt_present("headless"),
+        frames: frames,
+        frame_hash: matches.opt_present("frame-hash"),
+        speed: speed,
+        speed_pitch: matches.opt_present("speed-pitch"),
+        ff_speed: ff_speed,
+        mute: matches.opt_present("mute"),
+        volume: volume,
+        guard_stack: matches.opt_present("guard-stack"),
+        show_fps: matches.opt_present("show-fps"),
+        boot_animation_skip: matches.opt_present("boot-animation-skip"),
+        input_log: matches.opt_present("input-log"),
+        symbols: matches.opt_str("symbols"),
+        data_dir: data_dir,
+        save_file: save_file,
+        autosave_interval: autosave_interval,
+        gamepad_dead_zone: gamepad_dead_zone,
+        gamepad_swap_ab: matches.opt_present("gamepad-swap-ab"),
+        bindings: matches.opt_str("bindings"),
+        turbo_rate: turbo_rate,
+        frame_advance_rate: frame_advance_rate,
+        zapper: zapper,
+        warn_rom_writes: matches.opt_present("warn-rom-writes"),
+        four_score: matches.opt_present("four-score"),
+        start_fullscreen_borderless: matches.opt_present("start-fullscreen-borderless"),
     };
-    let mut nes = NES::new(rom, header, runtime_options);
-    nes.run()
+
+    if let (None, Some(message)) = (nsf.as_ref(), padding_warning(&rom_file_name, &rom, &header)) {
+        log::warn(message, &runtime_options);
+    }
+
+    let mut nes = match NES::new(rom, header, mapper, runtime_options) {
+        Ok(nes) => nes,
+        Err(e) => {
+            writeln!(stderr(), "nes-rs: {}", e).unwrap();
+            writeln!(stderr(), "Use --headless to run without a display.").unwrap();
+            return EXIT_SDL_INIT;
+        }
+    };
+    let code = nes.run();
+    *dropped_rom = nes.dropped_rom.take();
+    code
 }
 
 /// Entry point of the program and wrapper of init. Takes the exit code returned
 /// from init and exits with it.
 fn main() {
-    std::process::exit(init()); // Unwinding done, safe to exit.
+    // Start over with a rom dropped onto the window until emulation stops for
+    // any other reason.
+    let mut dropped_rom = None;
+    loop {
+        let code = init(&mut dropped_rom);
+        if dropped_rom.is_none() {
+            std::process::exit(code); // Unwinding done, safe to exit.
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+
+    #[test]
+    fn info_describes_the_cartridge() {
+        let rom = testing::nrom(&[]);
+        let header = INESHeader::new(&rom).unwrap();
+        let mut out = Vec::new();
+        print_rom_info(&mut out, "stub.nes", &rom, &header).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("File:      stub.nes\n"), "{}", out);
+        assert!(out.contains("Mapper:    0 (NROM)\n"), "{}", out);
+        assert!(out.contains("Supported: yes\n"), "{}", out);
+        assert!(out.contains("PRG-ROM:   32KB\n"), "{}", out);
+        assert!(out.contains("CHR-ROM:   0KB\n"), "{}", out);
+    }
+
+    #[test]
+    fn info_describes_the_music_in_an_nsf() {
+        let mut nsf = vec![0; NSF_HEADER_SIZE];
+        nsf[0x00..0x06].copy_from_slice(&[0x4E, 0x45, 0x53, 0x4D, 0x1A, 1]);
+        nsf[0x06] = 3;
+        nsf[0x07] = 2;
+        nsf[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        nsf[0x0E..0x13].copy_from_slice(b"Stub\0");
+        nsf.extend_from_slice(&[0x60]);
+        let header = NsfHeader::new(&nsf).unwrap();
+        let mut out = Vec::new();
+        print_nsf_info(&mut out, "stub.nsf", &nsf, &header).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("File:      stub.nsf\n"), "{}", out);
+        assert!(out.contains("Format:    NSF\n"), "{}", out);
+        assert!(out.contains("Name:      Stub\n"), "{}", out);
+        assert!(out.contains("Songs:     3 (starting at 2)\n"), "{}", out);
+        assert!(out.contains("Init:      $8003\n"), "{}", out);
+        assert!(!out.contains("Mapper:"), "{}", out);
+    }
+
+    #[test]
+    fn saves_and_movies_go_in_the_data_directory() {
+        let data_dir = testing::temp_path("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        let data_dir_name = Some(data_dir.to_string_lossy().into_owned());
+
+        let save_file = save_path(&data_dir_name, "roms/zelda.nes");
+        binutils::write_bin(&save_file, &[0x42; 0x2000]).unwrap();
+        let saved = data_dir.join("zelda.sav").exists();
+        let movie = data_path(&data_dir_name, Path::new("run.movie"));
+        let absolute = data_path(&data_dir_name, Path::new("/tmp/run.movie"));
+        fs::remove_dir_all(&data_dir).unwrap();
+
+        assert!(saved);
+        assert_eq!(Path::new(&movie), data_dir.join("run.movie").as_path());
+        assert_eq!(absolute, "/tmp/run.movie");
+
+        // Without a data directory saves stay next to the rom.
+        assert_eq!(save_path(&None, "roms/zelda.nes"), "roms/zelda.sav");
+    }
+
+    #[test]
+    fn truncated_roms_are_rejected_and_padded_ones_warned_about() {
+        let rom = testing::nrom(&[]);
+        let header = INESHeader::new(&rom).unwrap();
+        assert!(header.validate(&rom).is_ok());
+        assert_eq!(padding_warning("stub.nes", &rom, &header), None);
+
+        let truncated = &rom[..rom.len() - 1];
+        assert!(header.validate(truncated).is_err());
+        assert_eq!(padding_warning("stub.nes", truncated, &header), None);
+
+        let mut padded = rom.clone();
+        padded.extend_from_slice(&[0xFF; 0x80]);
+        assert!(header.validate(&padded).is_ok());
+        assert_eq!(
+            padding_warning("stub.nes", &padded, &header).unwrap(),
+            "stub.nes is 32912 bytes but the header describes 32784 bytes, ignoring the rest"
+        );
+    }
 }
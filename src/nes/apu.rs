@@ -0,0 +1,1355 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use nes::memory::Memory;
+use nes::memory::MiscRegisterStatus;
+use nes::nes::NESRuntimeOptions;
+
+// Relative addresses of I/O registers handled by the APU.
+const PULSE_1_CONTROL:    usize = 0x00;
+const PULSE_1_SWEEP:      usize = 0x01;
+const PULSE_1_TIMER_LOW:  usize = 0x02;
+const PULSE_1_TIMER_HIGH: usize = 0x03;
+const PULSE_2_CONTROL:    usize = 0x04;
+const PULSE_2_SWEEP:      usize = 0x05;
+const PULSE_2_TIMER_LOW:  usize = 0x06;
+const PULSE_2_TIMER_HIGH: usize = 0x07;
+const TRIANGLE_CONTROL:   usize = 0x08;
+const TRIANGLE_LENGTH:    usize = 0x0B;
+const NOISE_CONTROL:      usize = 0x0C;
+const NOISE_LENGTH:       usize = 0x0F;
+const DMC_CONTROL:        usize = 0x10;
+const DMC_OUTPUT_LEVEL:   usize = 0x11;
+const DMC_SAMPLE_ADDRESS: usize = 0x12;
+const DMC_SAMPLE_LENGTH:  usize = 0x13;
+const STATUS:             usize = 0x15;
+const FRAME_COUNTER:      usize = 0x17;
+
+// Bitmask values for pulse channel registers.
+const PULSE_CONTROL_DUTY:   u8 = 0b11000000;
+const PULSE_CONTROL_HALT:   u8 = 0b00100000;
+const PULSE_SWEEP_ENABLED:  u8 = 0b10000000;
+const PULSE_SWEEP_PERIOD:   u8 = 0b01110000;
+const PULSE_SWEEP_NEGATE:   u8 = 0b00001000;
+const PULSE_SWEEP_SHIFT:    u8 = 0b00000111;
+const PULSE_TIMER_HIGH:     u8 = 0b00000111;
+
+// Bitmask values for the halt flags of the triangle and noise channels.
+const TRIANGLE_CONTROL_HALT: u8 = 0b10000000;
+const NOISE_CONTROL_HALT:    u8 = 0b00100000;
+
+// Bitmask values for envelope settings, which share the control register of
+// the channel they belong to.
+const ENVELOPE_LOOP:            u8 = 0b00100000;
+const ENVELOPE_CONSTANT_VOLUME: u8 = 0b00010000;
+const ENVELOPE_VOLUME:          u8 = 0b00001111;
+
+// Bitmask values for DMC channel registers.
+const DMC_CONTROL_IRQ_ENABLED: u8 = 0b10000000;
+const DMC_CONTROL_LOOP:        u8 = 0b01000000;
+const DMC_CONTROL_RATE:        u8 = 0b00001111;
+const DMC_OUTPUT_LEVEL_MASK:   u8 = 0b01111111;
+
+// Bitmask values for the status register.
+const STATUS_PULSE_1:     u8 = 0b00000001;
+const STATUS_PULSE_2:     u8 = 0b00000010;
+const STATUS_TRIANGLE:    u8 = 0b00000100;
+const STATUS_NOISE:       u8 = 0b00001000;
+const STATUS_DMC_ENABLED: u8 = 0b00010000;
+const STATUS_FRAME_IRQ:   u8 = 0b01000000;
+const STATUS_DMC_IRQ:     u8 = 0b10000000;
+
+// Bitmask values for the frame counter register.
+const FRAME_COUNTER_MODE:        u8 = 0b10000000;
+const FRAME_COUNTER_IRQ_INHIBIT: u8 = 0b01000000;
+
+// CPU cycles into a frame counter sequence at which each step occurs (NTSC).
+// Both sequences share their first three steps. The 4-step sequence raises the
+// frame IRQ for the last three cycles before it wraps around.
+const FRAME_STEP_1:           u32 = 7457;
+const FRAME_STEP_2:           u32 = 14913;
+const FRAME_STEP_3:           u32 = 22371;
+const FRAME_FOUR_STEP_IRQ:    u32 = 29828;
+const FRAME_FOUR_STEP_4:      u32 = 29829;
+const FRAME_FOUR_STEP_LENGTH: u32 = 29830;
+const FRAME_FIVE_STEP_5:      u32 = 37281;
+const FRAME_FIVE_STEP_LENGTH: u32 = 37282;
+
+// CPU cycles stolen by a DMC sample fetch. Fetches that land during OAM DMA
+// only stall for the cycles that don't overlap with the DMA's own reads,
+// except near the end of the transfer where the DMC fetch either fits in the
+// final get/put pair or has to realign after it.
+const DMC_FETCH_CYCLES:                  u16 = 4;
+const DMC_FETCH_CYCLES_OAM_DMA:          u16 = 2;
+const DMC_FETCH_CYCLES_OAM_DMA_LAST_GET: u16 = 1;
+const DMC_FETCH_CYCLES_OAM_DMA_LAST_PUT: u16 = 3;
+
+// Values loaded into length counters, indexed by the top 5 bits written to a
+// channel's fourth register.
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Periods outside of this range silence a pulse channel.
+const PULSE_MIN_PERIOD: u16 = 0x008;
+const PULSE_MAX_PERIOD: u16 = 0x7FF;
+
+// Waveforms output by the pulse channel sequencer for each duty cycle. The
+// sequencer steps through one of these rows every time the timer expires.
+const DUTY_SEQUENCES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+// Number of CPU cycles between each bit output by the DMC for every rate
+// index (NTSC).
+const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// Sample addresses and lengths are stored in registers in a compressed form.
+const DMC_SAMPLE_ADDRESS_BASE: u16 = 0xC000;
+
+/// Length counters automatically silence a channel after a set amount of time.
+/// Every channel except the DMC has one, and they all behave the same way.
+pub struct LengthCounter {
+    // Set through $4015. A disabled length counter is forced to zero and
+    // can't be loaded.
+    enabled: bool,
+
+    // Halted length counters aren't decremented. Changes to the halt flag
+    // only take effect at the end of the cycle, so a half frame clock on the
+    // same cycle still sees the old value.
+    halt: bool,
+    new_halt: bool,
+
+    counter: u8,
+
+    // A reload written this cycle along with the counter value at the time of
+    // the write. The reload is dropped if a half frame clock changed the
+    // counter on the same cycle.
+    reload: Option<(u8, u8)>,
+}
+
+impl LengthCounter {
+    /// Creates a disabled length counter.
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            new_halt: false,
+            counter: 0,
+            reload: None,
+        }
+    }
+
+    /// Enables or disables the length counter through $4015. Disabling clears
+    /// the counter immediately.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+            self.reload = None;
+        }
+    }
+
+    /// Sets the halt flag, which takes effect at the end of the cycle.
+    pub fn set_halt(&mut self, halt: bool) {
+        self.new_halt = halt;
+    }
+
+    /// Loads the counter from the length table. The index is the top 5 bits of
+    /// the value written to the channel's fourth register. Loads are ignored
+    /// while the channel is disabled.
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.reload = Some((LENGTH_TABLE[index as usize & 0x1F], self.counter));
+        }
+    }
+
+    /// Decrements the counter unless it's halted or already zero. Called by
+    /// the frame counter on half frames.
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    /// Applies writes made during the cycle once all clocking is done.
+    pub fn end_cycle(&mut self) {
+        if let Some((value, previous)) = self.reload.take() {
+            if self.counter == previous {
+                self.counter = value;
+            }
+        }
+        self.halt = self.new_halt;
+    }
+
+    /// Returns true while the counter is nonzero and the channel is audible.
+    #[inline(always)]
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    /// Returns the current value of the counter.
+    pub fn counter(&self) -> u8 {
+        self.counter
+    }
+}
+
+/// Envelopes control the volume of the pulse and noise channels. They either
+/// output a constant volume or a saw envelope that decays from 15 to 0, which
+/// can optionally loop.
+pub struct Envelope {
+    // Set by writes to the channel's fourth register so the envelope restarts
+    // on the next quarter frame.
+    start: bool,
+
+    // Configuration set through the channel's control register. The volume
+    // doubles as the divider period when not in constant volume mode.
+    loop_flag: bool,
+    constant_volume: bool,
+    volume: u8,
+
+    divider: u8,
+    decay_level: u8,
+}
+
+impl Envelope {
+    /// Creates a silent envelope.
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            loop_flag: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay_level: 0,
+        }
+    }
+
+    /// Applies the envelope bits of a write to the channel's control register.
+    pub fn write_control(&mut self, value: u8) {
+        self.loop_flag = value & ENVELOPE_LOOP > 0;
+        self.constant_volume = value & ENVELOPE_CONSTANT_VOLUME > 0;
+        self.volume = value & ENVELOPE_VOLUME;
+    }
+
+    /// Restarts the envelope on the next quarter frame.
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocks the envelope. Called by the frame counter on quarter frames.
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+            return;
+        }
+
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.volume;
+
+        if self.decay_level > 0 {
+            self.decay_level -= 1;
+        } else if self.loop_flag {
+            self.decay_level = 15;
+        }
+    }
+
+    /// Returns the current 4-bit volume.
+    #[inline(always)]
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay_level
+        }
+    }
+}
+
+/// Identifies which of the two pulse channels a pulse unit belongs to. The
+/// channels are identical except for how their sweep units negate the period
+/// change amount.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PulseChannel {
+    One,
+    Two,
+}
+
+/// A square wave generator. The 2A03 contains two of these which share all of
+/// their logic except for the negate behavior in the sweep unit.
+///
+/// Some comments pertaining to APU functionality are courtesy of
+/// wiki.nesdev.com.
+pub struct Pulse {
+    // Which of the two pulse channels this unit emulates.
+    channel: PulseChannel,
+
+    // Selects the row in the duty sequence table used for the waveform.
+    duty: u8,
+
+    // Provides the volume output by the channel while the sequencer is high.
+    pub envelope: Envelope,
+
+    // The sweep unit periodically adjusts the timer period when enabled which
+    // is used for pitch slides. Writes to the sweep register set the reload
+    // flag so the divider is reloaded on the next half frame.
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+
+    // The 11-bit timer period is reloaded into the timer when it reaches zero,
+    // at which point the sequencer moves to the next step in the waveform.
+    timer_period: u16,
+    timer: u16,
+    sequence_step: u8,
+
+    pub length_counter: LengthCounter,
+}
+
+impl Pulse {
+    /// Creates a silent pulse unit for the given channel.
+    pub fn new(channel: PulseChannel) -> Self {
+        Pulse {
+            channel: channel,
+            duty: 0,
+            envelope: Envelope::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+            timer_period: 0,
+            timer: 0,
+            sequence_step: 0,
+            length_counter: LengthCounter::new(),
+        }
+    }
+
+    /// Handles writes to $4000 / $4004 which control duty, length counter halt,
+    /// and the envelope.
+    pub fn write_control(&mut self, value: u8) {
+        self.duty = (value & PULSE_CONTROL_DUTY) >> 6;
+        self.length_counter.set_halt(value & PULSE_CONTROL_HALT > 0);
+        self.envelope.write_control(value);
+    }
+
+    /// Handles writes to $4001 / $4005 which configure the sweep unit.
+    pub fn write_sweep(&mut self, value: u8) {
+        self.sweep_enabled = value & PULSE_SWEEP_ENABLED > 0;
+        self.sweep_period = (value & PULSE_SWEEP_PERIOD) >> 4;
+        self.sweep_negate = value & PULSE_SWEEP_NEGATE > 0;
+        self.sweep_shift = value & PULSE_SWEEP_SHIFT;
+        self.sweep_reload = true;
+    }
+
+    /// Handles writes to $4002 / $4006 which set the low 8 bits of the timer
+    /// period.
+    pub fn write_timer_low(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x700) | value as u16;
+    }
+
+    /// Handles writes to $4003 / $4007 which set the high 3 bits of the timer
+    /// period and load the length counter. The sequencer and envelope are also
+    /// restarted.
+    pub fn write_timer_high(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0xFF) | ((value & PULSE_TIMER_HIGH) as u16) << 8;
+        self.length_counter.load(value >> 3);
+        self.envelope.restart();
+        self.sequence_step = 0;
+    }
+
+    /// Calculates the period the sweep unit is currently targeting. When
+    /// negating, pulse 1 adds the one's complement of the change amount while
+    /// pulse 2 adds the two's complement, so pulse 1 always ends up one lower.
+    pub fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            match self.channel {
+                PulseChannel::One => self.timer_period.wrapping_sub(change).wrapping_sub(1),
+                PulseChannel::Two => self.timer_period.wrapping_sub(change),
+            }
+        } else {
+            self.timer_period.wrapping_add(change)
+        }
+    }
+
+    /// Returns true if the channel is silenced because the current period is
+    /// too small or the sweep target period would overflow 11 bits. This
+    /// happens regardless of whether the sweep unit is enabled.
+    #[inline(always)]
+    pub fn muted(&self) -> bool {
+        self.timer_period < PULSE_MIN_PERIOD || self.sweep_target_period() > PULSE_MAX_PERIOD
+    }
+
+    /// Clocks the sweep unit. When the divider expires the timer period is
+    /// updated to the target period, but only if the sweep is enabled, the
+    /// shift is nonzero, and the channel isn't muted. Called by the frame
+    /// counter on half frames.
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0
+            && self.sweep_enabled
+            && self.sweep_shift > 0
+            && !self.muted()
+        {
+            self.timer_period = self.sweep_target_period();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    /// Clocks the timer which advances the sequencer when it expires. Pulse
+    /// timers are clocked every other CPU cycle.
+    pub fn step_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Returns the current 4-bit output level of the channel.
+    pub fn output(&self) -> u8 {
+        let high = DUTY_SEQUENCES[self.duty as usize][self.sequence_step as usize] == 1;
+        if self.muted() || !self.length_counter.active() || !high {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+/// The delta modulation channel plays back 1-bit delta encoded samples that
+/// are fetched directly from CPU memory. Each bit of a sample byte moves the
+/// 7-bit output level up or down by 2. Games also write the output level
+/// directly for PCM playback.
+pub struct Dmc {
+    // Configuration set through $4010.
+    irq_enabled: bool,
+    loop_flag: bool,
+    rate: u16,
+    timer: u16,
+
+    // 7-bit level fed to the mixer.
+    output_level: u8,
+
+    // Where the sample starts and how many bytes it has as set through
+    // $4012 and $4013.
+    sample_address: u16,
+    sample_length: u16,
+
+    // The memory reader fetches bytes from the current address into the sample
+    // buffer whenever it's empty and there are bytes left.
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // The output unit shifts bits out of the shift register one at a time. If
+    // the sample buffer was empty when a new byte was needed, the output unit
+    // goes silent for a cycle of 8 bits.
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    // Raised when a non-looping sample finishes while IRQs are enabled. It
+    // stays raised until acknowledged through $4010 or $4015.
+    pub irq_flag: bool,
+}
+
+impl Dmc {
+    /// Creates a silent DMC with no sample loaded.
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            loop_flag: false,
+            rate: DMC_RATES[0],
+            timer: DMC_RATES[0],
+            output_level: 0,
+            sample_address: DMC_SAMPLE_ADDRESS_BASE,
+            sample_length: 1,
+            current_address: DMC_SAMPLE_ADDRESS_BASE,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq_flag: false,
+        }
+    }
+
+    /// Handles writes to $4010 which set the IRQ, loop, and rate settings.
+    pub fn write_control(&mut self, value: u8) {
+        self.irq_enabled = value & DMC_CONTROL_IRQ_ENABLED > 0;
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+        self.loop_flag = value & DMC_CONTROL_LOOP > 0;
+        self.rate = DMC_RATES[(value & DMC_CONTROL_RATE) as usize];
+    }
+
+    /// Handles writes to $4011 which load the output level directly.
+    pub fn write_output_level(&mut self, value: u8) {
+        self.output_level = value & DMC_OUTPUT_LEVEL_MASK;
+    }
+
+    /// Handles writes to $4012. The sample address is $C000 + (A * 64).
+    pub fn write_sample_address(&mut self, value: u8) {
+        self.sample_address = DMC_SAMPLE_ADDRESS_BASE + value as u16 * 64;
+    }
+
+    /// Handles writes to $4013. The sample length is (L * 16) + 1 bytes.
+    pub fn write_sample_length(&mut self, value: u8) {
+        self.sample_length = value as u16 * 16 + 1;
+    }
+
+    /// Enables or disables the channel through $4015. Disabling drops the
+    /// remaining bytes, while enabling restarts the sample only if it had
+    /// already finished. Either way the IRQ flag is acknowledged.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    /// Returns the number of sample bytes left to fetch.
+    pub fn bytes_remaining(&self) -> u16 {
+        self.bytes_remaining
+    }
+
+    /// Begins playing the sample from the start.
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Fills the sample buffer from memory if it's empty. Addresses wrap from
+    /// $FFFF back around to $8000 rather than to zero page. Returns true if a
+    /// byte was fetched.
+    fn fetch_sample(&mut self, memory: &mut Memory) -> bool {
+        if self.sample_buffer.is_some() || self.bytes_remaining == 0 {
+            return false;
+        }
+
+        self.sample_buffer = Some(memory.read_u8(self.current_address as usize));
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+        true
+    }
+
+    /// Clocks the output unit once the timer expires. The DMC timer is clocked
+    /// every CPU cycle. Returns true if the memory reader fetched a byte, which
+    /// stalls the CPU.
+    pub fn step_timer(&mut self, memory: &mut Memory) -> bool {
+        let fetched = self.fetch_sample(memory);
+
+        if self.timer > 0 {
+            self.timer -= 1;
+            return fetched;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        // Start a new output cycle with the next byte from the sample buffer.
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => {
+                    self.silence = true;
+                }
+            }
+        }
+        fetched
+    }
+
+    /// Returns the current 7-bit output level of the channel.
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+/// The two sequences the frame counter can run, selected by bit 7 of $4017.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameCounterMode {
+    FourStep,
+    FiveStep,
+}
+
+/// The frame counter drives the envelopes, length counters, and sweep units of
+/// the channels by generating quarter and half frame clocks. It's clocked by
+/// the CPU and can optionally raise an IRQ at the end of the 4-step sequence.
+pub struct FrameCounter {
+    mode: FrameCounterMode,
+
+    // CPU cycles elapsed in the current sequence.
+    cycle: u32,
+
+    // The frame IRQ is raised at the end of every 4-step sequence unless
+    // inhibited through $4017. Reading $4015 acknowledges it.
+    pub irq_flag: bool,
+    irq_inhibit: bool,
+
+    // Writes to $4017 don't reset the sequence right away. The value written
+    // is held here along with the number of CPU cycles left until it's
+    // applied.
+    pending_write: Option<(u8, u8)>,
+}
+
+impl FrameCounter {
+    /// Creates a frame counter at the start of the 4-step sequence.
+    pub fn new() -> Self {
+        FrameCounter {
+            mode: FrameCounterMode::FourStep,
+            cycle: 0,
+            irq_flag: false,
+            irq_inhibit: false,
+            pending_write: None,
+        }
+    }
+
+    /// Handles writes to $4017. The inhibit flag takes effect immediately and
+    /// clears a pending frame IRQ, while the mode change and sequence reset
+    /// happen 3 CPU cycles later if the write landed on an APU cycle, or 4
+    /// cycles later if it landed between APU cycles.
+    pub fn write(&mut self, value: u8, apu_cycle: bool) {
+        self.irq_inhibit = value & FRAME_COUNTER_IRQ_INHIBIT > 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+
+        let delay = if apu_cycle { 3 } else { 4 };
+        self.pending_write = Some((value, delay));
+    }
+
+    /// Raises the frame IRQ unless it's inhibited.
+    #[inline(always)]
+    fn raise_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+
+    /// Advances the frame counter by one CPU cycle. Returns whether a quarter
+    /// frame and a half frame clock were generated on this cycle.
+    pub fn step(&mut self) -> (bool, bool) {
+        self.cycle += 1;
+
+        let mut clocks = match (self.mode, self.cycle) {
+            (_, FRAME_STEP_1) => (true, false),
+            (_, FRAME_STEP_2) => (true, true),
+            (_, FRAME_STEP_3) => (true, false),
+            (FrameCounterMode::FourStep, FRAME_FOUR_STEP_IRQ) => {
+                self.raise_irq();
+                (false, false)
+            }
+            (FrameCounterMode::FourStep, FRAME_FOUR_STEP_4) => {
+                self.raise_irq();
+                (true, true)
+            }
+            (FrameCounterMode::FourStep, FRAME_FOUR_STEP_LENGTH) => {
+                self.raise_irq();
+                self.cycle = 0;
+                (false, false)
+            }
+            (FrameCounterMode::FiveStep, FRAME_FIVE_STEP_5) => (true, true),
+            (FrameCounterMode::FiveStep, FRAME_FIVE_STEP_LENGTH) => {
+                self.cycle = 0;
+                (false, false)
+            }
+            _ => (false, false),
+        };
+
+        if let Some((value, delay)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((value, delay - 1));
+            } else {
+                self.pending_write = None;
+                self.cycle = 0;
+                self.mode = if value & FRAME_COUNTER_MODE > 0 {
+                    FrameCounterMode::FiveStep
+                } else {
+                    FrameCounterMode::FourStep
+                };
+
+                // Switching to the 5-step sequence clocks all units right away.
+                if self.mode == FrameCounterMode::FiveStep {
+                    clocks = (true, true);
+                }
+            }
+        }
+
+        clocks
+    }
+}
+
+/// Combines the output of every channel into a single sample. The APU mixes
+/// channels non-linearly, so louder channels (such as a loud DMC) reduce the
+/// perceived volume of the others. Both formulas are precomputed into lookup
+/// tables since they're evaluated every CPU cycle.
+pub struct Mixer {
+    // Indexed by the sum of both pulse outputs (0-30).
+    pulse_table: [f32; 31],
+
+    // Indexed by triangle (0-15), noise (0-15), and DMC (0-127) outputs,
+    // flattened into a single vector.
+    tnd_table: Vec<f32>,
+}
+
+impl Mixer {
+    /// Builds the lookup tables.
+    pub fn new() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, entry) in pulse_table.iter_mut().enumerate() {
+            *entry = Mixer::pulse_out(n as f32);
+        }
+
+        let mut tnd_table = Vec::with_capacity(16 * 16 * 128);
+        for triangle in 0..16 {
+            for noise in 0..16 {
+                for dmc in 0..128 {
+                    tnd_table.push(Mixer::tnd_out(triangle as f32, noise as f32, dmc as f32));
+                }
+            }
+        }
+
+        Mixer {
+            pulse_table: pulse_table,
+            tnd_table: tnd_table,
+        }
+    }
+
+    /// pulse_out = 95.88 / (8128 / (pulse1 + pulse2) + 100)
+    fn pulse_out(pulse: f32) -> f32 {
+        if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        }
+    }
+
+    /// tnd_out = 159.79 / (1 / (triangle / 8227 + noise / 12241 + dmc / 22638) + 100)
+    fn tnd_out(triangle: f32, noise: f32, dmc: f32) -> f32 {
+        let sum = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+        if sum == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / sum + 100.0)
+        }
+    }
+
+    /// Mixes channel outputs into a sample between 0.0 and 1.0.
+    pub fn mix(&self, pulse_1: u8, pulse_2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = self.pulse_table[(pulse_1 + pulse_2) as usize];
+        let tnd_index = (triangle as usize * 16 + noise as usize) * 128 + dmc as usize;
+        pulse + self.tnd_table[tnd_index]
+    }
+}
+
+/// The sound channels that can be individually muted in the mixer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    /// Every channel in the order they're mixed.
+    pub const ALL: [Channel; 5] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+    ];
+
+    /// Looks up a channel by the name used on the command-line.
+    pub fn from_name(name: &str) -> Option<Channel> {
+        match name {
+            "pulse1" => Some(Channel::Pulse1),
+            "pulse2" => Some(Channel::Pulse2),
+            "triangle" => Some(Channel::Triangle),
+            "noise" => Some(Channel::Noise),
+            "dmc" => Some(Channel::Dmc),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+}
+
+/// This is an implementation of the audio processing unit built into the 2A03.
+/// It is made up of several sound channels that are mixed together and driven
+/// by the CPU clock.
+pub struct APU {
+    pub pulse_1: Pulse,
+    pub pulse_2: Pulse,
+    pub dmc: Dmc,
+    pub frame_counter: FrameCounter,
+
+    // The triangle and noise channels don't produce any sound yet, but their
+    // length counters run so $4015 reports them correctly to games that poll
+    // it.
+    pub triangle_length_counter: LengthCounter,
+    pub noise_length_counter: LengthCounter,
+
+    mixer: Mixer,
+
+    // Channels left out of the mix, indexed in the same order as Channel::ALL.
+    // These only affect what is heard, the channels keep running regardless.
+    muted: [bool; 5],
+
+    // Master volume applied after mixing, between 0.0 and 1.0.
+    volume: f32,
+
+    // Used to clock units that run at half of the CPU clock rate.
+    even_cycle: bool,
+}
+
+impl APU {
+    /// Initializes the APU with all channels silenced.
+    pub fn new(runtime_options: NESRuntimeOptions) -> Self {
+        APU {
+            pulse_1: Pulse::new(PulseChannel::One),
+            pulse_2: Pulse::new(PulseChannel::Two),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            triangle_length_counter: LengthCounter::new(),
+            noise_length_counter: LengthCounter::new(),
+            mixer: Mixer::new(),
+            muted: [runtime_options.mute; 5],
+            volume: runtime_options.volume as f32 / 100.0,
+            even_cycle: false,
+        }
+    }
+
+    /// Applies the reset button to the APU, which silences every channel as if
+    /// $4015 was cleared and acknowledges any pending IRQs.
+    pub fn reset(&mut self) {
+        self.write_status(0);
+        self.frame_counter.irq_flag = false;
+        self.dmc.irq_flag = false;
+    }
+
+    /// Forwards a register write to the channel that owns the register.
+    fn write_register(&mut self, index: usize, value: u8) {
+        match index {
+            PULSE_1_CONTROL => self.pulse_1.write_control(value),
+            PULSE_1_SWEEP => self.pulse_1.write_sweep(value),
+            PULSE_1_TIMER_LOW => self.pulse_1.write_timer_low(value),
+            PULSE_1_TIMER_HIGH => self.pulse_1.write_timer_high(value),
+            PULSE_2_CONTROL => self.pulse_2.write_control(value),
+            PULSE_2_SWEEP => self.pulse_2.write_sweep(value),
+            PULSE_2_TIMER_LOW => self.pulse_2.write_timer_low(value),
+            PULSE_2_TIMER_HIGH => self.pulse_2.write_timer_high(value),
+            TRIANGLE_CONTROL => {
+                let halt = value & TRIANGLE_CONTROL_HALT > 0;
+                self.triangle_length_counter.set_halt(halt);
+            }
+            TRIANGLE_LENGTH => self.triangle_length_counter.load(value >> 3),
+            NOISE_CONTROL => self.noise_length_counter.set_halt(value & NOISE_CONTROL_HALT > 0),
+            NOISE_LENGTH => self.noise_length_counter.load(value >> 3),
+            DMC_CONTROL => self.dmc.write_control(value),
+            DMC_OUTPUT_LEVEL => self.dmc.write_output_level(value),
+            DMC_SAMPLE_ADDRESS => self.dmc.write_sample_address(value),
+            DMC_SAMPLE_LENGTH => self.dmc.write_sample_length(value),
+            STATUS => self.write_status(value),
+            FRAME_COUNTER => self.frame_counter.write(value, self.even_cycle),
+            _ => {}
+        }
+    }
+
+    /// Handles writes to $4015 which enable and disable individual channels.
+    fn write_status(&mut self, value: u8) {
+        self.pulse_1.length_counter.set_enabled(value & STATUS_PULSE_1 > 0);
+        self.pulse_2.length_counter.set_enabled(value & STATUS_PULSE_2 > 0);
+        self.triangle_length_counter.set_enabled(value & STATUS_TRIANGLE > 0);
+        self.noise_length_counter.set_enabled(value & STATUS_NOISE > 0);
+        self.dmc.set_enabled(value & STATUS_DMC_ENABLED > 0);
+    }
+
+    /// Returns the value read from $4015.
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulse_1.length_counter.active() {
+            status |= STATUS_PULSE_1;
+        }
+        if self.pulse_2.length_counter.active() {
+            status |= STATUS_PULSE_2;
+        }
+        if self.triangle_length_counter.active() {
+            status |= STATUS_TRIANGLE;
+        }
+        if self.noise_length_counter.active() {
+            status |= STATUS_NOISE;
+        }
+        if self.dmc.bytes_remaining() > 0 {
+            status |= STATUS_DMC_ENABLED;
+        }
+        if self.frame_counter.irq_flag {
+            status |= STATUS_FRAME_IRQ;
+        }
+        if self.dmc.irq_flag {
+            status |= STATUS_DMC_IRQ;
+        }
+        status
+    }
+
+    /// Mixes the output of every unmuted channel into a single sample between
+    /// 0.0 and 1.0. The triangle and noise channels aren't implemented yet so
+    /// they are mixed in as silent.
+    pub fn output(&self) -> f32 {
+        let level = |channel: Channel, output: u8| {
+            if self.is_muted(channel) {
+                0
+            } else {
+                output
+            }
+        };
+        let pulse_1 = level(Channel::Pulse1, self.pulse_1.output());
+        let pulse_2 = level(Channel::Pulse2, self.pulse_2.output());
+        let dmc = level(Channel::Dmc, self.dmc.output());
+        self.mixer.mix(pulse_1, pulse_2, 0, 0, dmc) * self.volume
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    /// Toggles whether a channel is left out of the mix, returning true if the
+    /// channel is now muted.
+    pub fn toggle_mute(&mut self, channel: Channel) -> bool {
+        self.muted[channel as usize] = !self.muted[channel as usize];
+        self.muted[channel as usize]
+    }
+
+    /// Mutes every channel except the given one. Soloing a channel that is
+    /// already soloed unmutes everything again. Returns true if the channel is
+    /// now soloed.
+    pub fn toggle_solo(&mut self, channel: Channel) -> bool {
+        let soloed = Channel::ALL
+            .iter()
+            .all(|&other| self.is_muted(other) == (other != channel));
+        for &other in Channel::ALL.iter() {
+            self.muted[other as usize] = !soloed && other != channel;
+        }
+        !soloed
+    }
+
+    /// Returns true while any APU IRQ source is holding the CPU's IRQ line.
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+
+    /// Clocks the envelopes of every channel. Called by the frame counter four
+    /// times per frame.
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+    }
+
+    /// Clocks the length counters and sweep units of every channel. Called by
+    /// the frame counter twice per frame.
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length_counter.clock();
+        self.pulse_2.length_counter.clock();
+        self.triangle_length_counter.clock();
+        self.noise_length_counter.clock();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    /// Checks the status of the APU I/O registers and applies any reads or
+    /// writes made since the last APU cycle. Reading $4015 acknowledges the
+    /// frame IRQ, though the DMC IRQ stays raised.
+    fn check_apu_registers(&mut self, memory: &mut Memory) {
+        if memory.apu_status_read {
+            memory.apu_status_read = false;
+            self.frame_counter.irq_flag = false;
+        }
+
+        const APU_REGISTERS: [usize; 18] = [
+            PULSE_1_CONTROL,
+            PULSE_1_SWEEP,
+            PULSE_1_TIMER_LOW,
+            PULSE_1_TIMER_HIGH,
+            PULSE_2_CONTROL,
+            PULSE_2_SWEEP,
+            PULSE_2_TIMER_LOW,
+            PULSE_2_TIMER_HIGH,
+            TRIANGLE_CONTROL,
+            TRIANGLE_LENGTH,
+            NOISE_CONTROL,
+            NOISE_LENGTH,
+            DMC_CONTROL,
+            DMC_OUTPUT_LEVEL,
+            DMC_SAMPLE_ADDRESS,
+            DMC_SAMPLE_LENGTH,
+            STATUS,
+            FRAME_COUNTER,
+        ];
+
+        for &index in APU_REGISTERS.iter() {
+            match memory.misc_ctrl_registers_status[index] {
+                MiscRegisterStatus::Written => {
+                    let value = memory.misc_ctrl_registers[index];
+                    memory.misc_ctrl_registers_status[index] = MiscRegisterStatus::Untouched;
+                    self.write_register(index, value);
+                }
+                MiscRegisterStatus::Read => {
+                    memory.misc_ctrl_registers_status[index] = MiscRegisterStatus::Untouched;
+                }
+                MiscRegisterStatus::Untouched => {}
+            }
+        }
+    }
+
+    /// Executes a single APU cycle. The APU is stepped once for every CPU
+    /// cycle. Returns the CPU cycles stolen by DMC sample fetches, which
+    /// depend on how many cycles are left in an OAM DMA in progress, if any.
+    pub fn step(&mut self, memory: &mut Memory, oam_dma_cycles: u16) -> u16 {
+        self.check_apu_registers(memory);
+
+        let (quarter_frame, half_frame) = self.frame_counter.step();
+        if quarter_frame {
+            self.clock_quarter_frame();
+        }
+        if half_frame {
+            self.clock_half_frame();
+        }
+        self.pulse_1.length_counter.end_cycle();
+        self.pulse_2.length_counter.end_cycle();
+        self.triangle_length_counter.end_cycle();
+        self.noise_length_counter.end_cycle();
+
+        let stolen_cycles = if !self.dmc.step_timer(memory) {
+            0
+        } else {
+            match oam_dma_cycles {
+                0 => DMC_FETCH_CYCLES,
+                1 => DMC_FETCH_CYCLES_OAM_DMA_LAST_PUT,
+                2 => DMC_FETCH_CYCLES_OAM_DMA_LAST_GET,
+                _ => DMC_FETCH_CYCLES_OAM_DMA,
+            }
+        };
+
+        if self.even_cycle {
+            self.pulse_1.step_timer();
+            self.pulse_2.step_timer();
+        }
+        self.even_cycle = !self.even_cycle;
+
+        // Keep the status register up to date so reads see the current state
+        // of the channels and IRQ flags.
+        memory.misc_ctrl_registers[STATUS] = self.status();
+
+        stolen_cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+
+    #[test]
+    fn pulse_1_negates_with_ones_complement() {
+        let mut pulse_1 = Pulse::new(PulseChannel::One);
+        let mut pulse_2 = Pulse::new(PulseChannel::Two);
+        for pulse in [&mut pulse_1, &mut pulse_2].iter_mut() {
+            // Negated sweep with a shift of 1 on a period of $200.
+            pulse.write_sweep(0b10001001);
+            pulse.write_timer_low(0x00);
+            pulse.write_timer_high(0x02);
+        }
+
+        assert_eq!(pulse_1.sweep_target_period(), 0x0FF);
+        assert_eq!(pulse_2.sweep_target_period(), 0x100);
+    }
+
+    // Returns an enabled length counter loaded from the given table index.
+    fn loaded_length_counter(index: u8) -> LengthCounter {
+        let mut length_counter = LengthCounter::new();
+        length_counter.set_enabled(true);
+        length_counter.load(index);
+        length_counter.end_cycle();
+        length_counter
+    }
+
+    #[test]
+    fn length_counters_load_from_the_length_table() {
+        for &(index, length) in [(0x00, 10), (0x01, 254), (0x0E, 26), (0x1F, 30)].iter() {
+            assert_eq!(loaded_length_counter(index).counter(), length);
+        }
+
+        // Loads are ignored while the channel is disabled.
+        let mut length_counter = LengthCounter::new();
+        length_counter.load(0x01);
+        length_counter.end_cycle();
+        assert_eq!(length_counter.counter(), 0);
+    }
+
+    #[test]
+    fn halted_length_counters_are_not_clocked() {
+        let mut length_counter = loaded_length_counter(0x00);
+        length_counter.set_halt(true);
+        length_counter.end_cycle();
+        length_counter.clock();
+        assert_eq!(length_counter.counter(), 10);
+
+        // Clearing the halt flag only takes effect at the end of the cycle.
+        length_counter.set_halt(false);
+        length_counter.clock();
+        length_counter.end_cycle();
+        assert_eq!(length_counter.counter(), 10);
+        length_counter.clock();
+        assert_eq!(length_counter.counter(), 9);
+    }
+
+    #[test]
+    fn length_writes_are_dropped_when_clocked_on_the_same_cycle() {
+        let mut length_counter = loaded_length_counter(0x00);
+        length_counter.load(0x01);
+        length_counter.clock();
+        length_counter.end_cycle();
+        assert_eq!(length_counter.counter(), 9);
+
+        // The same write goes through on a cycle without a clock.
+        length_counter.load(0x01);
+        length_counter.end_cycle();
+        assert_eq!(length_counter.counter(), 254);
+    }
+
+    #[test]
+    fn envelope_start_flag_restarts_the_decay() {
+        let mut envelope = Envelope::new();
+        envelope.write_control(0x03);
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+
+        // The divider was reloaded with the period of 3, so the level only
+        // drops on every fourth clock.
+        for _ in 0..3 {
+            envelope.clock();
+            assert_eq!(envelope.output(), 15);
+        }
+        envelope.clock();
+        assert_eq!(envelope.output(), 14);
+
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+    }
+
+    #[test]
+    fn envelope_loops_only_when_asked_to() {
+        for &(control, last_level) in [(0x00, 0), (ENVELOPE_LOOP, 15)].iter() {
+            let mut envelope = Envelope::new();
+            envelope.write_control(control);
+            envelope.restart();
+            for _ in 0..17 {
+                envelope.clock();
+            }
+            assert_eq!(envelope.output(), last_level);
+        }
+    }
+
+    #[test]
+    fn constant_volume_ignores_the_decay() {
+        let mut envelope = Envelope::new();
+        envelope.write_control(ENVELOPE_CONSTANT_VOLUME | 0x07);
+        envelope.restart();
+        for _ in 0..4 {
+            envelope.clock();
+            assert_eq!(envelope.output(), 7);
+        }
+    }
+
+    // Returns a pulse unit with the given period and sweep register value.
+    fn pulse_with_period(period: u16, sweep: u8) -> Pulse {
+        let mut pulse = Pulse::new(PulseChannel::Two);
+        pulse.write_sweep(sweep);
+        pulse.write_timer_low(period as u8);
+        pulse.write_timer_high((period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn sweep_targets_the_period_plus_the_shifted_change() {
+        assert_eq!(pulse_with_period(0x100, 0b10000010).sweep_target_period(), 0x140);
+        assert_eq!(pulse_with_period(0x100, 0b10001010).sweep_target_period(), 0x0C0);
+        assert_eq!(pulse_with_period(0x3FF, 0b10000000).sweep_target_period(), 0x7FE);
+
+        // Each half frame clock moves the period to the target.
+        let mut pulse = pulse_with_period(0x100, 0b10000010);
+        pulse.clock_sweep();
+        assert_eq!(pulse.timer_period, 0x140);
+        pulse.clock_sweep();
+        assert_eq!(pulse.timer_period, 0x190);
+    }
+
+    #[test]
+    fn sweep_mutes_periods_out_of_range() {
+        assert!(pulse_with_period(0x007, 0).muted());
+        assert!(!pulse_with_period(0x008, 0).muted());
+
+        // Overflowing targets mute the channel even with the sweep disabled,
+        // and the period is left alone.
+        let mut pulse = pulse_with_period(0x600, 0b00000001);
+        assert!(pulse.muted());
+        pulse.write_sweep(0b10000001);
+        pulse.clock_sweep();
+        assert_eq!(pulse.timer_period, 0x600);
+
+        // Negated changes can't overflow.
+        assert!(!pulse_with_period(0x600, 0b00001001).muted());
+    }
+
+    #[test]
+    fn mixer_tables_follow_the_nonlinear_formulas() {
+        let mixer = Mixer::new();
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+
+        assert_eq!(mixer.pulse_table[0], 0.0);
+        for n in 1..31 {
+            let expected = 95.88 / (8128.0 / n as f32 + 100.0);
+            assert!(close(mixer.pulse_table[n], expected), "pulse entry {}", n);
+        }
+
+        assert_eq!(mixer.tnd_table[0], 0.0);
+        let levels = [(15, 0, 0), (0, 15, 0), (0, 0, 127), (15, 15, 127), (7, 3, 64)];
+        for &(triangle, noise, dmc) in levels.iter() {
+            let sum = triangle as f32 / 8227.0 + noise as f32 / 12241.0 + dmc as f32 / 22638.0;
+            let expected = 159.79 / (1.0 / sum + 100.0);
+            let mixed = mixer.mix(0, 0, triangle, noise, dmc);
+            assert!(close(mixed, expected), "tnd entry {} {} {}", triangle, noise, dmc);
+        }
+
+        // Full volume on every channel mixes to just under 1.0.
+        let loudest = mixer.mix(15, 15, 15, 15, 127);
+        assert!(loudest > 0.99 && loudest < 1.0);
+    }
+
+    #[test]
+    fn status_reports_channels_with_loaded_length_counters() {
+        let (_, mut memory) = testing::machine(&[]);
+        let mut apu = APU::new(testing::runtime_options());
+
+        // Pulse 1, pulse 2 and noise are enabled, so the triangle load is
+        // ignored.
+        memory.write_u8(0x4015, 0x0B);
+        apu.step(&mut memory, 0);
+        for &addr in [0x4003, 0x4007, 0x400B, 0x400F].iter() {
+            memory.write_u8(addr, 0x08);
+        }
+        apu.step(&mut memory, 0);
+        assert_eq!(memory.read_u8(0x4015), 0x0B);
+
+        // Disabling a channel clears its length counter right away.
+        memory.write_u8(0x4015, 0x09);
+        apu.step(&mut memory, 0);
+        assert_eq!(memory.read_u8(0x4015), 0x09);
+        assert_eq!(apu.pulse_1.length_counter.counter(), 254);
+        assert_eq!(apu.pulse_2.length_counter.counter(), 0);
+    }
+
+    #[test]
+    fn dmc_fetches_during_oam_dma_stall_for_fewer_cycles() {
+        // Cycles left in an OAM DMA when the fetch happens, and the cycles the
+        // fetch stalls the CPU for on top of the DMA.
+        let overlaps = [(0, 4), (300, 2), (2, 1), (1, 3)];
+        for &(oam_dma_cycles, stolen_cycles) in overlaps.iter() {
+            let (_, mut memory) = testing::machine(&[]);
+            let mut apu = APU::new(testing::runtime_options());
+
+            memory.write_u8(0x4015, STATUS_DMC_ENABLED);
+            assert_eq!(apu.step(&mut memory, oam_dma_cycles), stolen_cycles);
+            assert_eq!(apu.step(&mut memory, oam_dma_cycles), 0);
+        }
+    }
+
+    #[test]
+    fn port_4017_writes_reach_the_apu_and_reads_come_from_controller_2() {
+        let (_, mut memory) = testing::machine(&[]);
+        let mut apu = APU::new(testing::runtime_options());
+
+        // A and Right, which come out first and last.
+        memory.controller_2.buttons = 0x81;
+        memory.write_u8(0x4016, 1);
+        memory.write_u8(0x4016, 0);
+        assert_eq!(memory.read_u8(0x4017) & 1, 1);
+
+        // Setting the frame counter's IRQ inhibit doesn't restart the shift.
+        memory.write_u8(0x4017, FRAME_COUNTER_IRQ_INHIBIT);
+        for _ in 0..4 {
+            apu.step(&mut memory, 0);
+        }
+        assert!(apu.frame_counter.irq_inhibit);
+        let rest: Vec<u8> = (0..7).map(|_| memory.read_u8(0x4017) & 1).collect();
+        assert_eq!(rest, vec![0, 0, 0, 0, 0, 0, 1]);
+    }
+}
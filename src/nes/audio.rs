@@ -0,0 +1,216 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+use std::collections::VecDeque;
+use std::iter;
+use std::mem;
+
+// Rate the APU produces samples at, which is the NTSC CPU clock rate.
+const CPU_CLOCK_RATE: f64 = 1789773.0;
+
+// Sample rate requested from the host audio device.
+const SAMPLE_RATE: i32 = 44100;
+
+// Frames per second used to estimate how many samples the device consumes
+// between flushes.
+const FRAMES_PER_SECOND: u32 = 60;
+
+/// Sends APU output to the host's audio device through an SDL audio queue.
+/// Samples are buffered in a ring buffer sized by the configured latency and
+/// flushed to the device once per frame.
+pub struct Audio {
+    queue: AudioQueue<f32>,
+
+    // Samples produced since the last flush. When full the oldest samples are
+    // dropped so latency never grows past the configured amount.
+    samples: VecDeque<f32>,
+    capacity: usize,
+
+    // Rate the device actually runs at, which may differ from the requested
+    // rate.
+    sample_rate: u32,
+
+    resampler: Resampler,
+
+    // Repeated to fill the queue when emulation can't keep up, which sounds far
+    // better than letting the device pop.
+    last_sample: f32,
+
+    paused: bool,
+}
+
+impl Audio {
+    /// Opens the default audio device with a ring buffer large enough to hold
+    /// the given latency worth of samples. The pitch multiplier scales the rate
+    /// the APU is assumed to run at, so a value other than 1.0 shifts pitch.
+    pub fn new(
+        audio_subsystem: &AudioSubsystem,
+        latency_ms: u32,
+        pitch: f64,
+    ) -> Result<Self, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(1),
+            samples: None,
+        };
+        let queue = try!(audio_subsystem.open_queue::<f32, _>(None, &desired));
+        let sample_rate = queue.spec().freq as u32;
+        let capacity = buffer_capacity(sample_rate, latency_ms);
+        queue.resume();
+
+        Ok(Audio {
+            queue: queue,
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            sample_rate: sample_rate,
+            resampler: Resampler::new(CPU_CLOCK_RATE * pitch, sample_rate as f64),
+            last_sample: 0.0,
+            paused: false,
+        })
+    }
+
+    /// Called every CPU cycle with the current APU output, which is resampled
+    /// down to the host sample rate.
+    pub fn step(&mut self, sample: f32) {
+        let sample = match self.resampler.push(sample) {
+            Some(sample) => sample,
+            None => return,
+        };
+
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Sends buffered samples to the device. This is called once per frame. If
+    /// the device would run dry before the next flush, the last sample is
+    /// repeated to cover the gap. Samples that would push the device past the
+    /// configured latency are dropped.
+    pub fn flush(&mut self) {
+        if self.paused {
+            return;
+        }
+
+        let queued = self.queue.size() as usize / mem::size_of::<f32>();
+        let mut samples: Vec<f32> = self.samples.drain(..).collect();
+        if let Some(&sample) = samples.last() {
+            self.last_sample = sample;
+        }
+
+        let samples_per_frame = (self.sample_rate / FRAMES_PER_SECOND) as usize;
+        if queued + samples.len() < samples_per_frame {
+            let missing = samples_per_frame - queued - samples.len();
+            samples.extend(iter::repeat(self.last_sample).take(missing));
+        }
+
+        if queued < self.capacity {
+            let len = samples.len().min(self.capacity - queued);
+            self.queue.queue(&samples[..len]);
+        }
+    }
+
+    /// Pauses or resumes playback. Buffered samples are thrown away when
+    /// pausing so playback resumes without a burst of stale audio.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        self.paused = paused;
+
+        if paused {
+            self.queue.pause();
+            self.queue.clear();
+            self.samples.clear();
+        } else {
+            self.queue.resume();
+        }
+    }
+}
+
+/// Returns the number of samples it takes to hold the given latency at a
+/// sample rate. This is worked out in 64 bits since a few seconds of latency
+/// at a high sample rate doesn't fit in 32.
+fn buffer_capacity(sample_rate: u32, latency_ms: u32) -> usize {
+    (sample_rate as u64 * latency_ms as u64 / 1000) as usize
+}
+
+/// Converts a stream of samples from one rate to a lower one. Every output
+/// sample is the average of the input samples that fall within its period,
+/// which filters out most of the content above the output rate's Nyquist limit
+/// that plain decimation would alias back into the audible range.
+pub struct Resampler {
+    // Output samples produced per input sample.
+    step: f64,
+
+    // Fraction of the current output period that has been filled.
+    phase: f64,
+
+    sum: f32,
+    count: u32,
+}
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        Resampler {
+            step: output_rate / input_rate,
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Feeds one input sample to the resampler, returning an output sample
+    /// whenever an output period completes.
+    pub fn push(&mut self, sample: f32) -> Option<f32> {
+        self.sum += sample;
+        self.count += 1;
+        self.phase += self.step;
+        if self.phase < 1.0 {
+            return None;
+        }
+        self.phase -= 1.0;
+
+        let output = self.sum / self.count as f32;
+        self.sum = 0.0;
+        self.count = 0;
+        Some(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::u32;
+
+    #[test]
+    fn buffer_capacity_does_not_overflow() {
+        assert_eq!(buffer_capacity(44100, 50), 2205);
+        assert_eq!(buffer_capacity(48000, 100000), 4800000);
+        assert_eq!(buffer_capacity(u32::MAX, u32::MAX), 18446744065119617);
+    }
+
+    #[test]
+    fn resampling_keeps_the_pitch() {
+        // One second of a 1kHz square wave at the CPU clock rate.
+        let mut resampler = Resampler::new(CPU_CLOCK_RATE, SAMPLE_RATE as f64);
+        let output: Vec<f32> = (0..CPU_CLOCK_RATE as u64)
+            .filter_map(|cycle| {
+                let phase = cycle as f64 * 1000.0 / CPU_CLOCK_RATE;
+                resampler.push(if phase.fract() < 0.5 { 1.0 } else { -1.0 })
+            })
+            .collect();
+        assert!((output.len() as i32 - SAMPLE_RATE).abs() <= 1, "{}", output.len());
+
+        // Each period has one rising edge through zero.
+        let rising_edges = output.windows(2).filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0).count();
+        assert!(rising_edges >= 999 && rising_edges <= 1000, "{}", rising_edges);
+    }
+}
@@ -0,0 +1,543 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use io::log;
+use nes::controller::*;
+use nes::nes::NESRuntimeOptions;
+use sdl2::controller::Button;
+use sdl2::keyboard::{Keycode, Mod, LALTMOD, LCTRLMOD, LSHIFTMOD, RALTMOD, RCTRLMOD, RSHIFTMOD};
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+
+// Names of the controller buttons used in bindings files, in bit order.
+const BUTTON_NAMES: [&'static str; 8] =
+    ["a", "b", "select", "start", "up", "down", "left", "right"];
+
+// Names of the turbo buttons used in bindings files.
+const TURBO_A_NAME: &'static str = "turbo_a";
+const TURBO_B_NAME: &'static str = "turbo_b";
+
+// Names of the emulator actions used in the hotkeys section of bindings files.
+const ACTION_NAMES: [(&'static str, Action); 6] = [
+    ("pause", Action::Pause),
+    ("frame_advance", Action::FrameAdvance),
+    ("fast_forward", Action::FastForward),
+    ("reset", Action::Reset),
+    ("power_cycle", Action::PowerCycle),
+    ("fullscreen", Action::Fullscreen),
+];
+
+// Section headers in bindings files. Lines before the first header bind
+// controller buttons.
+const CONTROLS_SECTION: &'static str = "[controls]";
+const HOTKEYS_SECTION:  &'static str = "[hotkeys]";
+
+// Modifier keys a hotkey can require to be held. Either the left or right
+// key counts.
+const MOD_CTRL:  u8 = 0x1;
+const MOD_ALT:   u8 = 0x2;
+const MOD_SHIFT: u8 = 0x4;
+
+// Names of the modifiers used in bindings files, such as "ctrl+R".
+const MODIFIER_NAMES: [(&'static str, u8); 3] =
+    [("ctrl", MOD_CTRL), ("alt", MOD_ALT), ("shift", MOD_SHIFT)];
+
+// Number of players that can be bound, including the two on a Four Score.
+const PLAYERS: usize = 4;
+
+// Prefix marking a binding as a game controller button rather than a key.
+const PAD_PREFIX: &'static str = "pad:";
+
+/// Something on the host that can be bound to an NES controller button.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Input {
+    Key(Keycode),
+    Pad(Button),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Input::Key(keycode) => write!(f, "{}", keycode.name()),
+            Input::Pad(button) => write!(f, "{}{}", PAD_PREFIX, button.string()),
+        }
+    }
+}
+
+/// A host input bound to a button on one of the NES controllers. Turbo
+/// bindings press and release the button repeatedly while held.
+#[derive(Copy, Clone)]
+pub struct Binding {
+    pub input: Input,
+    pub port: usize,
+    pub button: u8,
+    pub turbo: bool,
+}
+
+/// Things the emulator itself can be told to do from a hotkey, as opposed to
+/// buttons pressed on an NES controller.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Action {
+    Pause,
+    FrameAdvance,
+    FastForward,
+    Reset,
+    PowerCycle,
+    Fullscreen,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = ACTION_NAMES
+            .iter()
+            .find(|&&(_, action)| action == *self)
+            .map(|&(name, _)| name)
+            .unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+/// A host input bound to an emulator action. Keys can require modifiers to
+/// be held, like Ctrl+R for reset. A game controller button triggers its
+/// action from every controller.
+#[derive(Copy, Clone)]
+pub struct Hotkey {
+    pub input: Input,
+    pub modifiers: u8,
+    pub action: Action,
+}
+
+impl fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(name, modifier) in MODIFIER_NAMES.iter() {
+            if self.modifiers & modifier != 0 {
+                try!(write!(f, "{}+", name));
+            }
+        }
+        write!(f, "{}", self.input)
+    }
+}
+
+/// Maps keyboard keys and game controller buttons to NES controller buttons
+/// and emulator actions. Game controller bindings apply to whichever game
+/// controller is driving that port.
+pub struct Bindings {
+    bindings: Vec<Binding>,
+    hotkeys: Vec<Hotkey>,
+}
+
+impl Bindings {
+    /// Returns the default bindings. The first controller is on the arrow keys
+    /// with X and Z for A and B (V and C for turbo), and the second is on WASD
+    /// with G and F (T and R for turbo). Players 3 and 4 only have game
+    /// controller bindings. The default hotkeys are bound as well.
+    pub fn new() -> Self {
+        let mut bindings = Bindings {
+            bindings: Vec::new(),
+            hotkeys: default_hotkeys(),
+        };
+        let keys = [
+            [
+                Keycode::X,
+                Keycode::Z,
+                Keycode::RShift,
+                Keycode::Return,
+                Keycode::Up,
+                Keycode::Down,
+                Keycode::Left,
+                Keycode::Right,
+            ],
+            [
+                Keycode::G,
+                Keycode::F,
+                Keycode::Q,
+                Keycode::E,
+                Keycode::W,
+                Keycode::S,
+                Keycode::A,
+                Keycode::D,
+            ],
+        ];
+        for (port, keys) in keys.iter().enumerate() {
+            for (bit, &keycode) in keys.iter().enumerate() {
+                bindings.bind(Input::Key(keycode), port, 1 << bit, false);
+            }
+        }
+        let turbo_keys = [(Keycode::V, Keycode::C), (Keycode::T, Keycode::R)];
+        for (port, &(turbo_a, turbo_b)) in turbo_keys.iter().enumerate() {
+            bindings.bind(Input::Key(turbo_a), port, BUTTON_A, true);
+            bindings.bind(Input::Key(turbo_b), port, BUTTON_B, true);
+        }
+
+        // The bottom and left face buttons press B, and the right and top ones
+        // press A, matching where they sit on an NES controller.
+        let pad = [
+            (Button::B, BUTTON_A),
+            (Button::Y, BUTTON_A),
+            (Button::A, BUTTON_B),
+            (Button::X, BUTTON_B),
+            (Button::Back, BUTTON_SELECT),
+            (Button::Start, BUTTON_START),
+            (Button::DPadUp, BUTTON_UP),
+            (Button::DPadDown, BUTTON_DOWN),
+            (Button::DPadLeft, BUTTON_LEFT),
+            (Button::DPadRight, BUTTON_RIGHT),
+        ];
+        for port in 0..PLAYERS {
+            for &(button, nes_button) in pad.iter() {
+                bindings.bind(Input::Pad(button), port, nes_button, false);
+            }
+            bindings.bind(Input::Pad(Button::RightShoulder), port, BUTTON_A, true);
+            bindings.bind(Input::Pad(Button::LeftShoulder), port, BUTTON_B, true);
+        }
+        bindings
+    }
+
+    /// Loads bindings from a file where each line is in the form
+    /// "PLAYER.BUTTON = INPUT", such as "p1.a = X" or "p2.start = pad:start".
+    /// Players are p1 to p4, where p3 and p4 are on a Four Score. The buttons
+    /// are a, b, select, start, up, down, left, right, turbo_a and turbo_b.
+    /// Inputs are SDL key names, or SDL game controller button names after
+    /// "pad:". Blank lines and lines starting with ; or # are ignored. Only
+    /// the inputs in the file are bound to buttons, so the defaults don't
+    /// apply.
+    ///
+    /// Lines after a [hotkeys] header are in the form "ACTION = INPUT", such
+    /// as "reset = ctrl+R", where keys can be prefixed with ctrl+, alt+ and
+    /// shift+. The actions are pause, frame_advance, fast_forward, reset,
+    /// power_cycle and fullscreen. Without a [hotkeys] section the default
+    /// hotkeys are kept, except for any on keys bound to buttons. Binding an
+    /// input to more than one action, or to an action and a button, is an
+    /// error. A [controls] header switches back to binding buttons.
+    ///
+    /// The default bindings are used if the file doesn't exist yet.
+    pub fn load(filename: &str, runtime_options: &NESRuntimeOptions) -> Result<Self, String> {
+        let mut contents = String::new();
+        let result = File::open(filename).and_then(|mut f| f.read_to_string(&mut contents));
+        match result {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let message = format!("{} doesn't exist, using the default bindings", filename);
+                log::init(message, runtime_options);
+                return Ok(Bindings::new());
+            }
+            Err(e) => return Err(format!("cannot open {}: {}", filename, e)),
+        }
+        let bindings = try!(
+            Bindings::parse(&contents).map_err(|e| format!("{}:{}", filename, e))
+        );
+
+        let message = format!(
+            "Loaded {} input bindings and {} hotkeys from {}",
+            bindings.bindings.len(),
+            bindings.hotkeys.len(),
+            filename
+        );
+        log::init(message, runtime_options);
+        Ok(bindings)
+    }
+
+    /// Parses the contents of a bindings file. Errors are prefixed with the
+    /// line number they were found on.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut bindings = Bindings {
+            bindings: Vec::new(),
+            hotkeys: Vec::new(),
+        };
+        let mut in_hotkeys = false;
+        let mut has_hotkeys = false;
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(";") || line.starts_with("#") {
+                continue;
+            }
+            if line.starts_with("[") {
+                match line.to_lowercase().as_str() {
+                    CONTROLS_SECTION => in_hotkeys = false,
+                    HOTKEYS_SECTION => {
+                        in_hotkeys = true;
+                        has_hotkeys = true;
+                    }
+                    _ => return Err(format!("{}: unknown section {}", index + 1, line)),
+                }
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=').map(|part| part.trim());
+            let target = parts.next().unwrap_or("");
+            let input = match parts.next() {
+                Some(input) if !input.is_empty() => input,
+                _ if in_hotkeys => return Err(format!("{}: expected ACTION = INPUT", index + 1)),
+                _ => return Err(format!("{}: expected PLAYER.BUTTON = INPUT", index + 1)),
+            };
+            let result = if in_hotkeys {
+                bindings.parse_hotkey(target, input)
+            } else {
+                bindings.parse_binding(target, input)
+            };
+            try!(result.map_err(|e| format!("{}: {}", index + 1, e)));
+        }
+
+        // Hotkeys on keys that were bound to buttons are left out rather than
+        // treated as conflicts, since the file doesn't mention them.
+        if !has_hotkeys {
+            for hotkey in default_hotkeys() {
+                if hotkey.modifiers != 0 || !bindings.is_bound(hotkey.input) {
+                    bindings.hotkeys.push(hotkey);
+                }
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// Parses and binds a "PLAYER.BUTTON = INPUT" line.
+    fn parse_binding(&mut self, target: &str, input: &str) -> Result<(), String> {
+        let (port, button, turbo) = match parse_target(target) {
+            Some(target) => target,
+            None => return Err(format!("unknown button {}", target)),
+        };
+        let input = match parse_input(input) {
+            Some(input) => input,
+            None => return Err(format!("unknown key or button {}", input)),
+        };
+        if let Some(hotkey) = self.hotkeys.iter().find(|h| h.input == input && h.modifiers == 0) {
+            return Err(format!("{} is already bound to {}", input, hotkey.action));
+        }
+        self.bind(input, port, button, turbo);
+        Ok(())
+    }
+
+    /// Parses and binds an "ACTION = INPUT" line from the hotkeys section.
+    fn parse_hotkey(&mut self, target: &str, input: &str) -> Result<(), String> {
+        let name = target.to_lowercase();
+        let action = match ACTION_NAMES.iter().find(|&&(action_name, _)| action_name == name) {
+            Some(&(_, action)) => action,
+            None => return Err(format!("unknown action {}", target)),
+        };
+        let (input, modifiers) = match parse_hotkey_input(input) {
+            Some(input) => input,
+            None => return Err(format!("unknown key or button {}", input)),
+        };
+        let hotkey = Hotkey {
+            input: input,
+            modifiers: modifiers,
+            action: action,
+        };
+        if modifiers == 0 && self.is_bound(input) {
+            return Err(format!("{} is already bound to a controller button", hotkey));
+        }
+        match self.hotkey(input, modifiers) {
+            Some(bound) if bound != action => {
+                Err(format!("{} is already bound to {}", hotkey, bound))
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.hotkeys.push(hotkey);
+                Ok(())
+            }
+        }
+    }
+
+    /// Binds an input to a button, replacing whatever it was bound to before.
+    /// Game controller buttons are bound separately for each port.
+    pub fn bind(&mut self, input: Input, port: usize, button: u8, turbo: bool) {
+        self.bindings.retain(|binding| match input {
+            Input::Key(_) => binding.input != input,
+            Input::Pad(_) => binding.input != input || binding.port != port,
+        });
+        self.bindings.push(Binding {
+            input: input,
+            port: port,
+            button: button,
+            turbo: turbo,
+        });
+    }
+
+    /// Returns the action bound to an input when pressed with the given
+    /// modifiers held, if any.
+    pub fn hotkey(&self, input: Input, modifiers: u8) -> Option<Action> {
+        self.hotkeys
+            .iter()
+            .find(|hotkey| hotkey.input == input && hotkey.modifiers == modifiers)
+            .map(|hotkey| hotkey.action)
+    }
+
+    /// Returns every action bound to an input whatever its modifiers, which
+    /// is used to stop held actions since the modifiers may have been let go
+    /// first.
+    pub fn actions(&self, input: Input) -> Vec<Action> {
+        self.hotkeys
+            .iter()
+            .filter(|hotkey| hotkey.input == input)
+            .map(|hotkey| hotkey.action)
+            .collect()
+    }
+
+    /// Returns true if an input is bound to a button on any controller.
+    fn is_bound(&self, input: Input) -> bool {
+        self.bindings.iter().any(|binding| binding.input == input)
+    }
+
+    /// Returns what a key is bound to if anything.
+    pub fn key(&self, keycode: Keycode) -> Option<Binding> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.input == Input::Key(keycode))
+            .cloned()
+    }
+
+    /// Returns what a game controller button is bound to on the given port if
+    /// anything.
+    pub fn pad(&self, port: usize, button: Button) -> Option<Binding> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.input == Input::Pad(button) && binding.port == port)
+            .cloned()
+    }
+}
+
+/// Formats the bindings in the same format load reads, so the output can be
+/// used as a starting point for a bindings file.
+impl fmt::Display for Bindings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for binding in self.bindings.iter() {
+            let name = match (binding.turbo, binding.button) {
+                (true, BUTTON_A) => TURBO_A_NAME,
+                (true, _) => TURBO_B_NAME,
+                (false, button) => BUTTON_NAMES[button.trailing_zeros() as usize],
+            };
+            try!(writeln!(f, "p{}.{} = {}", binding.port + 1, name, binding.input));
+        }
+        try!(writeln!(f, "{}", HOTKEYS_SECTION));
+        for hotkey in self.hotkeys.iter() {
+            try!(writeln!(f, "{} = {}", hotkey.action, hotkey));
+        }
+        Ok(())
+    }
+}
+
+/// Parses a "PLAYER.BUTTON" target such as "p1.start" into a controller port,
+/// button mask and whether it's a turbo button.
+fn parse_target(target: &str) -> Option<(usize, u8, bool)> {
+    let mut parts = target.splitn(2, '.');
+    let port = match parts.next().map(|player| player.to_lowercase()) {
+        Some(ref player) if player.starts_with("p") => match player[1..].parse::<usize>() {
+            Ok(player) if player >= 1 && player <= PLAYERS => player - 1,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let name = match parts.next() {
+        Some(name) => name.to_lowercase(),
+        None => return None,
+    };
+    if name == TURBO_A_NAME {
+        return Some((port, BUTTON_A, true));
+    } else if name == TURBO_B_NAME {
+        return Some((port, BUTTON_B, true));
+    }
+    BUTTON_NAMES
+        .iter()
+        .position(|&button| button == name)
+        .map(|bit| (port, 1 << bit, false))
+}
+
+/// Returns the default hotkeys. P and Pause pause, \ advances a frame while
+/// paused, Tab fast-forwards, Ctrl+R resets, Ctrl+T power cycles and
+/// Alt+Enter switches to fullscreen.
+fn default_hotkeys() -> Vec<Hotkey> {
+    let keys = [
+        (Keycode::P, 0, Action::Pause),
+        (Keycode::Pause, 0, Action::Pause),
+        (Keycode::Backslash, 0, Action::FrameAdvance),
+        (Keycode::Tab, 0, Action::FastForward),
+        (Keycode::R, MOD_CTRL, Action::Reset),
+        (Keycode::T, MOD_CTRL, Action::PowerCycle),
+        (Keycode::Return, MOD_ALT, Action::Fullscreen),
+    ];
+    keys.iter()
+        .map(|&(keycode, modifiers, action)| Hotkey {
+            input: Input::Key(keycode),
+            modifiers: modifiers,
+            action: action,
+        })
+        .collect()
+}
+
+/// Returns the modifiers held according to SDL, in the form hotkeys use.
+pub fn modifiers(keymod: Mod) -> u8 {
+    let mut modifiers = 0;
+    if keymod.intersects(LCTRLMOD | RCTRLMOD) {
+        modifiers |= MOD_CTRL;
+    }
+    if keymod.intersects(LALTMOD | RALTMOD) {
+        modifiers |= MOD_ALT;
+    }
+    if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+        modifiers |= MOD_SHIFT;
+    }
+    modifiers
+}
+
+/// Parses a hotkey input such as "ctrl+R" into the input and the modifiers
+/// it requires. Game controller buttons can't have modifiers.
+fn parse_hotkey_input(input: &str) -> Option<(Input, u8)> {
+    let mut input = input;
+    let mut modifiers = 0;
+    'prefixes: loop {
+        for &(name, modifier) in MODIFIER_NAMES.iter() {
+            let prefix = format!("{}+", name);
+            if input.len() > prefix.len() && input.to_lowercase().starts_with(&prefix) {
+                input = &input[prefix.len()..];
+                modifiers |= modifier;
+                continue 'prefixes;
+            }
+        }
+        break;
+    }
+    match parse_input(input) {
+        Some(Input::Pad(_)) if modifiers != 0 => None,
+        Some(input) => Some((input, modifiers)),
+        None => None,
+    }
+}
+
+/// Parses an SDL key name, or a game controller button name after "pad:".
+fn parse_input(input: &str) -> Option<Input> {
+    if input.starts_with(PAD_PREFIX) {
+        Button::from_string(&input[PAD_PREFIX.len()..]).map(Input::Pad)
+    } else {
+        Keycode::from_name(input).map(Input::Key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+
+    #[test]
+    fn default_bindings_survive_a_round_trip() {
+        let defaults = Bindings::new();
+        let saved = defaults.to_string();
+        let loaded = Bindings::parse(&saved).unwrap();
+
+        assert_eq!(loaded.bindings.len(), defaults.bindings.len());
+        assert_eq!(loaded.hotkeys.len(), defaults.hotkeys.len());
+        assert_eq!(loaded.to_string(), saved);
+    }
+
+    #[test]
+    fn missing_file_falls_back_to_the_defaults() {
+        let path = testing::temp_path("missing.bindings");
+        let runtime_options = testing::runtime_options();
+        let bindings = Bindings::load(path.to_str().unwrap(), &runtime_options).unwrap();
+        assert_eq!(bindings.to_string(), Bindings::new().to_string());
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Bitmask values for each button on a standard controller. The bits are in the
+// same order the buttons are reported by the controller's shift register.
+pub const BUTTON_A:      u8 = 0b00000001;
+pub const BUTTON_B:      u8 = 0b00000010;
+pub const BUTTON_SELECT: u8 = 0b00000100;
+pub const BUTTON_START:  u8 = 0b00001000;
+pub const BUTTON_UP:     u8 = 0b00010000;
+pub const BUTTON_DOWN:   u8 = 0b00100000;
+pub const BUTTON_LEFT:   u8 = 0b01000000;
+pub const BUTTON_RIGHT:  u8 = 0b10000000;
+
+/// A standard NES controller. The state of the buttons is fed from the
+/// keyboard or a movie file, and is read by the CPU one button at a time
+/// through a shift register.
+pub struct Controller {
+    pub buttons: u8,
+
+    // Buttons held down with turbo, which are pressed and released on a
+    // schedule rather than held.
+    turbo: u8,
+
+    // Buttons latched for the CPU to read, shifted right on every read. This
+    // is wider than a byte so a Four Score can chain another controller and
+    // its signature on the end.
+    shift: u32,
+
+    // While the strobe is high the shift register keeps reloading from the
+    // buttons, so reads always return the state of A.
+    strobe: bool,
+}
+
+impl Controller {
+    /// Returns a controller with no buttons held down.
+    pub fn new() -> Self {
+        Controller {
+            buttons: 0,
+            turbo: 0,
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    /// Handles a write to the controller port. Bit 0 sets the strobe, and the
+    /// buttons are latched when it is high.
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 1 == 1;
+        if self.strobe {
+            self.shift = self.buttons as u32 | 0xFFFFFF00;
+        }
+    }
+
+    /// Chains another controller's buttons and a signature byte after this
+    /// controller's buttons, like the Four Score does. This only has an effect
+    /// while the strobe is high, as that's when the buttons are latched.
+    pub fn chain(&mut self, buttons: u8, signature: u8) {
+        if self.strobe {
+            self.shift =
+                self.shift & 0xFF | (buttons as u32) << 8 | (signature as u32) << 16 | 0xFF000000;
+        }
+    }
+
+    /// Returns what the next read would, without shifting the register.
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & BUTTON_A
+        } else {
+            self.shift as u8 & 1
+        }
+    }
+
+    /// Reads the next button from the shift register into bit 0. Once all 8
+    /// buttons (or 24 bits with a Four Score) have been read, official
+    /// controllers report 1 from then on.
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & BUTTON_A;
+        }
+        let bit = self.shift as u8 & 1;
+        self.shift = self.shift >> 1 | 0x80000000;
+        bit
+    }
+
+    /// Marks the given button as held down.
+    #[inline(always)]
+    pub fn press(&mut self, button: u8) {
+        self.buttons |= button;
+    }
+
+    /// Marks the given button as released.
+    #[inline(always)]
+    pub fn release(&mut self, button: u8) {
+        self.buttons &= !button;
+    }
+
+    /// Marks the given button as held down with turbo. It isn't pressed until
+    /// the next time update_turbo is called.
+    pub fn press_turbo(&mut self, button: u8) {
+        self.turbo |= button;
+    }
+
+    /// Marks the given turbo button as released.
+    pub fn release_turbo(&mut self, button: u8) {
+        self.turbo &= !button;
+        self.buttons &= !button;
+    }
+
+    /// Presses or releases the buttons held down with turbo. This is called
+    /// once per frame so the pattern is the same from run to run.
+    pub fn update_turbo(&mut self, pressed: bool) {
+        if pressed {
+            self.buttons |= self.turbo;
+        } else {
+            self.buttons &= !self.turbo;
+        }
+    }
+}
+
+// Letters shown for held buttons by buttons_to_string, in bit order.
+const BUTTON_LETTERS: [char; 8] = ['A', 'B', 's', 'S', 'U', 'D', 'L', 'R'];
+
+/// Formats a button mask with a letter for each held button and a dot for
+/// each released one, in the order A, B, Select (s), Start (S), Up, Down,
+/// Left and Right. For example "A..S...R" has A, Start and Right held.
+pub fn buttons_to_string(buttons: u8) -> String {
+    BUTTON_LETTERS
+        .iter()
+        .enumerate()
+        .map(|(bit, &letter)| if buttons & (1 << bit) > 0 { letter } else { '.' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_buttons_are_shown_by_letter() {
+        assert_eq!(buttons_to_string(0), "........");
+        assert_eq!(buttons_to_string(0xFF), "ABsSUDLR");
+        assert_eq!(buttons_to_string(BUTTON_A | BUTTON_START | BUTTON_RIGHT), "A..S...R");
+        assert_eq!(buttons_to_string(BUTTON_B | BUTTON_SELECT), ".Bs.....");
+        assert_eq!(buttons_to_string(BUTTON_UP | BUTTON_DOWN | BUTTON_LEFT), "....UDL.");
+    }
+}
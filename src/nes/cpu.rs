@@ -6,7 +6,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use io::log;
+use io::log::{self, LogLevel};
 use nes::instruction::Instruction;
 use nes::memory::Memory;
 use nes::nes::NESRuntimeOptions;
@@ -30,9 +30,24 @@ pub const BREAK_COMMAND: u8 = 0x10;
 pub const OVERFLOW_FLAG: u8 = 0x40;
 pub const NEGATIVE_FLAG: u8 = 0x80;
 
+// ANSI escape codes used to color the pretty register panel.
+const ANSI_RESET: &'static str = "\x1b[0m";
+const ANSI_BOLD:  &'static str = "\x1b[1m";
+const ANSI_GREEN: &'static str = "\x1b[32m";
+
+// Number of CPU log mismatches kept around for the summary shown at shutdown
+// when continuing past mismatches.
+const MAX_RECORDED_MISMATCHES: usize = 10;
+
 // How long it takes for a cycle to complete.
 const CLOCK_SPEED: u32 = 559;
 
+// Number of cycles the reset sequence takes, the same as an interrupt.
+const RESET_CYCLES: u16 = 7;
+
+// Returned by step once there is nothing left in the CPU log to test against.
+const LOG_FINISHED: &'static str = "reached the end of the CPU log";
+
 /// This is an implementation of 2A03 processor used in the NES. The 2A03 is
 /// based off the 6502 processor with some minor changes such as having no
 /// binary-coded decimal mode. Currently only the NTSC variant of the chip is
@@ -106,6 +121,10 @@ pub struct CPU {
     // The flag can be explicitly set using 'Set Decimal Flag' (SED) and cleared
     // with 'Clear Decimal Flag' (CLD).
     //
+    // The 2A03 has the BCD circuitry cut out, so ADC and SBC always do binary
+    // arithmetic. The flag itself still works like any other though, and is
+    // pushed and pulled along with the rest of the status register.
+    //
     // Break Command:
     //
     // The break command bit is set when a BRK instruction has been executed and
@@ -140,29 +159,76 @@ pub struct CPU {
     // loaded into the program counter.
     pub irq: bool,
 
+    // NMI is set when the PPU signals a non-maskable interrupt (normally at
+    // the start of vblank). Unlike IRQ it cannot be disabled through the
+    // interrupt disable flag and is serviced before the next opcode is fetched.
+    pub nmi: bool,
+
+    // Level of the hardware IRQ line which is held by devices such as the APU
+    // for as long as they need servicing. Hardware IRQs are ignored while the
+    // interrupt disable flag is set.
+    pub irq_line: bool,
+
+    // CLI, SEI and PLP change the interrupt disable flag after IRQs have been
+    // polled for the next instruction, so that poll still sees the flag as it
+    // was before the change. This holds the old flag until then.
+    delayed_interrupt_disable: Option<bool>,
+
     // Options passed from the command-line that may influence how the CPU
     // behaves.
     runtime_options: NESRuntimeOptions,
 
+    // Prints every instruction as it executes like verbose mode does, but can
+    // be turned on and off from the debugger while running.
+    pub trace: bool,
+
     // This will contain an open file if the CPU is in testing mode. It will be
     // read during program execution and compared against.
     execution_log: Option<BufReader<File>>,
+
+    // Comparison against the execution log is held off until the program
+    // counter reaches this address, at which point the log is skipped ahead
+    // to the first line for it. That line is kept here until it's compared.
+    log_compare_start: Option<u16>,
+    skipped_log_line: Option<String>,
+
+    // The current line in the execution log and whether the end of it has been
+    // reached, at which point there is nothing left to test against.
+    execution_log_line: usize,
+    pub execution_log_finished: bool,
+
+    // Mismatches found while continuing past them. Only the first few are
+    // recorded, but all of them are counted.
+    pub mismatches: Vec<Mismatch>,
+    pub mismatch_count: usize,
 }
 
 impl CPU {
-    pub fn new(runtime_options: NESRuntimeOptions, pc: u16) -> CPU {
+    /// Returns a CPU that hasn't been powered on yet. The registers are set up
+    /// by power_on, which should be called before the first step.
+    pub fn new(runtime_options: NESRuntimeOptions) -> CPU {
         CPU {
-            pc: pc,
-            sp: 0xFD,
+            pc: 0,
+            sp: 0,
             a: 0,
             x: 0,
             y: 0,
-            p: 0x24,
+            p: 0,
             cycles: 0,
             ppu_dots: 0,
             irq: false,
+            nmi: false,
+            irq_line: false,
+            delayed_interrupt_disable: None,
             runtime_options: runtime_options,
+            trace: false,
             execution_log: None,
+            log_compare_start: None,
+            skipped_log_line: None,
+            execution_log_line: 0,
+            execution_log_finished: false,
+            mismatches: Vec::new(),
+            mismatch_count: 0,
         }
     }
 
@@ -185,7 +251,7 @@ impl CPU {
     }
 
     /// Sets the decimal mode flag in the status register.
-    /// NOTE: This flag is disabled in the 2A03 variation of the 6502.
+    /// NOTE: The 2A03 variation of the 6502 ignores this flag in ADC and SBC.
     #[inline(always)]
     pub fn set_decimal_mode(&mut self) {
         self.p |= DECIMAL_MODE;
@@ -229,7 +295,7 @@ impl CPU {
     }
 
     /// Unsets the decimal mode flag in the status register.
-    /// NOTE: This flag is disabled in the 2A03 variation of the 6502.
+    /// NOTE: The 2A03 variation of the 6502 ignores this flag in ADC and SBC.
     #[inline(always)]
     pub fn unset_decimal_mode(&mut self) {
         self.p &= !DECIMAL_MODE;
@@ -237,6 +303,7 @@ impl CPU {
 
     /// Un-sets the break command flag in the status register.
     #[inline(always)]
+    #[allow(dead_code)]
     pub fn unset_break_command(&mut self) {
         self.p &= !BREAK_COMMAND;
     }
@@ -333,15 +400,47 @@ impl CPU {
     /// execution to the passed Nintendulator log.
     pub fn begin_testing(&mut self, log: BufReader<File>) {
         self.execution_log = Some(log);
+        self.log_compare_start = self.runtime_options.log_compare_start;
+    }
+
+    /// Reads lines from the execution log until one for the given address is
+    /// found, which is kept to be compared next. Returns false if the end of
+    /// the log is reached first.
+    fn skip_log_to(&mut self, addr: u16) -> bool {
+        let execution_log = match self.execution_log {
+            Some(ref mut execution_log) => execution_log,
+            None => return false,
+        };
+        loop {
+            let mut line = String::new();
+            if execution_log.read_line(&mut line).unwrap() == 0 {
+                return false;
+            }
+            if line.len() >= 4 && u16::from_str_radix(&line[0..4], 16) == Ok(addr) {
+                self.skipped_log_line = Some(line);
+                return true;
+            }
+            self.execution_log_line += 1;
+        }
     }
 
     /// Sleeps the CPU for an amount of time corresponding to the passed cycles.
-    /// Time is determined by multiplying the cycles by the clock speed.
-    pub fn sleep(&mut self, cycles: u16) {
-        let nanos = CLOCK_SPEED * cycles as u32;
+    /// Time is determined by multiplying the cycles by the clock speed and
+    /// dividing by the given emulation speed multiplier.
+    pub fn sleep(&mut self, cycles: u16, speed: f64) {
+        let nanos = (CLOCK_SPEED * cycles as u32) as f64 / speed;
+        let nanos = nanos as u32;
         thread::sleep(Duration::new(0, nanos));
     }
 
+    /// Makes the IRQ poll before the next instruction ignore a change to the
+    /// interrupt disable flag. Called by CLI, SEI and PLP before they change
+    /// the flag, so an IRQ pending during CLI isn't taken until after the
+    /// following instruction, and one pending during SEI is still taken.
+    pub fn delay_interrupt_disable(&mut self) {
+        self.delayed_interrupt_disable = Some(self.interrupt_disable_set());
+    }
+
     /// Checks the IRQ status and sets the program counter to the IRQ handler if
     /// set. IRQ can be triggered through hardware and the BRK instruction.
     pub fn poll_irq(&mut self, memory: &mut Memory) {
@@ -351,58 +450,159 @@ impl CPU {
         }
     }
 
+    /// Puts the CPU in the state it's in when the console is switched on and
+    /// runs the reset sequence. The registers are cleared and the stack
+    /// pointer starts at 0, which reset moves down to 0xFD.
+    ///
+    /// Returns the number of cycles the reset sequence took.
+    pub fn power_on(&mut self, memory: &mut Memory) -> u16 {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.sp = 0;
+        self.p = 0x20;
+        self.reset(memory)
+    }
+
+    /// Runs the reset sequence, which is an interrupt whose stack writes are
+    /// turned into reads. The stack pointer still moves down past the program
+    /// counter and status register, interrupts are disabled, and execution
+    /// continues at the address stored at 0xFFFC (this allows ROMs to specify
+    /// an entry point). If a program counter was specified on the
+    /// command-line, that one is used instead.
+    ///
+    /// Returns the number of cycles the reset sequence took. The PPU dot
+    /// counter used in logs isn't advanced, since Nintendulator logs count
+    /// from the end of the reset sequence.
+    pub fn reset(&mut self, memory: &mut Memory) -> u16 {
+        self.sp = self.sp.wrapping_sub(3);
+        self.p |= 0x20;
+        self.set_interrupt_disable();
+        self.pc = match self.runtime_options.program_counter {
+            Some(pc) => pc,
+            None => memory.read_u16(0xFFFC),
+        };
+        self.nmi = false;
+        self.delayed_interrupt_disable = None;
+        self.cycles = RESET_CYCLES;
+        self.cycles
+    }
+
+    /// Services a hardware interrupt by pushing the program counter and status
+    /// register to the stack and jumping to the handler stored at the given
+    /// vector (0xFFFA for NMI and 0xFFFE for IRQ).
+    fn interrupt(&mut self, memory: &mut Memory, vector: usize) {
+        let pc = self.pc;
+        let p = (self.p & !BREAK_COMMAND) | 0x20;
+        memory.stack_push_u16(self, pc);
+        memory.stack_push_u8(self, p);
+        self.set_interrupt_disable();
+        self.pc = memory.read_u16(vector);
+        self.cycles += 7;
+    }
+
     /// Parse an instruction from memory at the address the program counter
     /// currently points execute it. All instruction logic is in instruction.rs.
     ///
     /// This function also returns the number of cycles passed once execution is
     /// completed. This is useful for the caller to have since it can use this to
-    /// synchronize PPU state.
-    pub fn step(&mut self, memory: &mut Memory) -> u16 {
-        let instr = Instruction::parse(self.pc as usize, memory);
+    /// synchronize PPU state. An error is returned if the program counter points
+    /// at an illegal or unimplemented opcode, or when testing against a CPU log
+    /// once the end of the log is reached. execution_log_finished tells the two
+    /// apart.
+    pub fn step(&mut self, memory: &mut Memory) -> Result<u16, String> {
+        // A pending interrupt takes the place of the next instruction. NMI
+        // takes priority over IRQ.
+        let interrupt_disable = match self.delayed_interrupt_disable.take() {
+            Some(interrupt_disable) => interrupt_disable,
+            None => self.interrupt_disable_set(),
+        };
+        if self.nmi || (self.irq_line && !interrupt_disable) {
+            self.cycles = 0;
+            if self.nmi {
+                self.nmi = false;
+                self.interrupt(memory, 0xFFFA);
+            } else {
+                self.interrupt(memory, 0xFFFE);
+            }
+            self.ppu_dots = (self.ppu_dots + (self.cycles * 3)) % 341;
+            return Ok(self.cycles);
+        }
+
+        let instr = try!(Instruction::parse(self.pc as usize, memory));
+
+        // Nothing is compared until the start address is reached, which lets
+        // boot code that isn't in the log run first.
+        if let Some(start) = self.log_compare_start {
+            if self.pc == start {
+                self.log_compare_start = None;
+                if !self.skip_log_to(start) {
+                    self.execution_log_finished = true;
+                    return Err(LOG_FINISHED.to_string());
+                }
+            }
+        }
+        let comparing = self.execution_log.is_some() && self.log_compare_start.is_none();
 
-        if self.runtime_options.verbose || self.execution_log.is_some() {
+        let verbose = log::enabled(LogLevel::Debug, &self.runtime_options);
+        if verbose || self.trace || comparing {
             let raw_fragment = instr.log(self, memory);
 
-            // Print the log fragment only if verbose mode is enabled. Logs are
-            // formatted like Nintendulator logs.
-            if self.runtime_options.verbose {
+            // Print the log fragment only if verbose mode or tracing is
+            // enabled. Logs are formatted like Nintendulator logs.
+            if verbose {
                 log::log("cpu", format!("{}", raw_fragment), &self.runtime_options);
+            } else if self.trace {
+                log::trace("cpu", raw_fragment.as_str(), &self.runtime_options);
             }
 
             // Compare the current state of the emulator against the next log
             // line if a Nintendulator log was passed in.
-            if let Some(ref mut execution_log) = self.execution_log {
-                let mut log_fragment = String::new();
-                execution_log.read_line(&mut log_fragment).unwrap();
-
-                if CPUFrame::parse(raw_fragment.as_str()) != CPUFrame::parse(log_fragment.as_str())
-                {
-                    log::log(
-                        "error",
-                        "FATAL ERROR: Mismatched CPU frames:",
-                        &self.runtime_options,
-                    );
-                    log::log(
-                        "error",
-                        format!("Emulator Frame: {}", raw_fragment),
-                        &self.runtime_options,
-                    );
-                    log::log(
-                        "error",
-                        format!("Log Frame:      {}", log_fragment),
-                        &self.runtime_options,
-                    );
+            if let (true, Some(ref mut execution_log)) = (comparing, self.execution_log.as_mut()) {
+                let log_fragment = match self.skipped_log_line.take() {
+                    Some(line) => line,
+                    None => {
+                        let mut line = String::new();
+                        if execution_log.read_line(&mut line).unwrap() == 0 {
+                            self.execution_log_finished = true;
+                            return Err(LOG_FINISHED.to_string());
+                        }
+                        line
+                    }
+                };
+                self.execution_log_line += 1;
+
+                let emulator_frame = CPUFrame::parse(raw_fragment.as_str());
+                let log_frame = CPUFrame::parse(log_fragment.as_str());
+                if emulator_frame != log_frame && self.runtime_options.continue_on_mismatch {
+                    self.mismatch_count += 1;
+                    if self.mismatches.len() < MAX_RECORDED_MISMATCHES {
+                        let fields = match (emulator_frame, log_frame) {
+                            (Ok(emulator_frame), Ok(log_frame)) => emulator_frame.diff(&log_frame),
+                            _ => vec!["frame could not be parsed".to_string()],
+                        };
+                        self.mismatches.push(Mismatch {
+                            line: self.execution_log_line,
+                            fields: fields,
+                            emulator_frame: raw_fragment.clone(),
+                            log_frame: log_fragment.trim_right().to_string(),
+                        });
+                    }
+                } else if emulator_frame != log_frame {
+                    log::error("FATAL ERROR: Mismatched CPU frames:", &self.runtime_options);
+                    log::error(format!("Emulator Frame: {}", raw_fragment), &self.runtime_options);
+                    log::error(format!("Log Frame:      {}", log_fragment), &self.runtime_options);
                     panic!("Mismatched CPU frames");
                 }
             }
         }
 
         self.cycles = 0;
-        instr.execute(self, memory);
+        try!(instr.execute(self, memory));
 
         self.ppu_dots = (self.ppu_dots + (self.cycles * 3)) % 341;
 
-        return self.cycles;
+        return Ok(self.cycles);
     }
 
     /// Returns "SET" if the passed boolean is true, otherwise "UNSET". This
@@ -414,6 +614,56 @@ impl CPU {
             "UNSET"
         }
     }
+
+    /// Formats the status register as a row of flag letters (nv-bdizc) where
+    /// set flags are uppercased. Bit 5 is unused so it's always shown as "-".
+    pub fn fmt_flags(p: u8) -> String {
+        const LETTERS: [char; 8] = ['n', 'v', '-', 'b', 'd', 'i', 'z', 'c'];
+
+        LETTERS
+            .iter()
+            .enumerate()
+            .map(|(i, &letter)| {
+                if letter != '-' && p & (0x80 >> i) > 0 {
+                    letter.to_ascii_uppercase()
+                } else {
+                    letter
+                }
+            })
+            .collect()
+    }
+
+    /// Returns a compact, column-aligned panel of the CPU registers and flags
+    /// along with the given PPU position. Unlike the crash state display this
+    /// is meant to be printed repeatedly while the emulator runs.
+    pub fn panel(&self, scanline: u16, dot: u16) -> String {
+        let flags: String = CPU::fmt_flags(self.p)
+            .chars()
+            .map(|letter| {
+                if letter.is_uppercase() {
+                    format!("{}{}{}", ANSI_GREEN, letter, ANSI_RESET)
+                } else {
+                    letter.to_string()
+                }
+            })
+            .collect();
+
+        format!(
+            "{}PC    A   X   Y   SP  P   FLAGS     SCANLINE  DOT{}\n\
+             {:04X}  {:02X}  {:02X}  {:02X}  {:02X}  {:02X}  {}  {:>8}  {:>3}",
+            ANSI_BOLD,
+            ANSI_RESET,
+            self.pc,
+            self.a,
+            self.x,
+            self.y,
+            self.sp,
+            self.p,
+            flags,
+            scanline,
+            dot
+        )
+    }
 }
 
 impl fmt::Display for CPU {
@@ -474,6 +724,19 @@ impl fmt::Display for CPU {
     }
 }
 
+/// A divergence between the emulator and a CPU log that was recorded instead of
+/// halting execution.
+pub struct Mismatch {
+    // Line in the CPU log that didn't match (starting at 1).
+    pub line: usize,
+
+    // Each field that differs, formatted as "FIELD: expected X, got Y".
+    pub fields: Vec<String>,
+
+    pub emulator_frame: String,
+    pub log_frame: String,
+}
+
 /// CPU state for use during automated CPU testing. These values are contained
 /// inside of Nintendulator logs and used for comparing log frames to test CPU
 /// accuracy.
@@ -510,15 +773,214 @@ impl CPUFrame {
             y: try!(u8::from_str_radix(&frame[60..62], 16)),
             p: try!(u8::from_str_radix(&frame[65..67], 16)),
             sp: try!(u8::from_str_radix(&frame[71..73], 16)),
-            cycles: try!(u16::from_str_radix(&frame[78..81].trim(), 10)),
+            cycles: try!(frame[78..81].trim().parse::<u16>()),
         })
     }
 
+    /// Returns a description of every field that differs between this frame and
+    /// the expected frame from a log.
+    fn diff(&self, expected: &CPUFrame) -> Vec<String> {
+        let mut fields = Vec::new();
+        if self.instruction != expected.instruction {
+            fields.push(format!(
+                "Instruction: expected {:?}, got {:?}",
+                expected.instruction, self.instruction
+            ));
+        }
+        if self.disassembly != expected.disassembly {
+            fields.push(format!(
+                "Disassembly: expected \"{}\", got \"{}\"",
+                expected.disassembly.trim(),
+                self.disassembly.trim()
+            ));
+        }
+        if self.pc != expected.pc {
+            fields.push(format!("PC: expected {:04X}, got {:04X}", expected.pc, self.pc));
+        }
+        if self.a != expected.a {
+            fields.push(format!("A: expected {:02X}, got {:02X}", expected.a, self.a));
+        }
+        if self.x != expected.x {
+            fields.push(format!("X: expected {:02X}, got {:02X}", expected.x, self.x));
+        }
+        if self.y != expected.y {
+            fields.push(format!("Y: expected {:02X}, got {:02X}", expected.y, self.y));
+        }
+        if self.p != expected.p {
+            fields.push(format!("P: expected {:02X}, got {:02X}", expected.p, self.p));
+        }
+        if self.sp != expected.sp {
+            fields.push(format!("SP: expected {:02X}, got {:02X}", expected.sp, self.sp));
+        }
+        if self.cycles != expected.cycles {
+            fields.push(format!("CYC: expected {}, got {}", expected.cycles, self.cycles));
+        }
+        fields
+    }
+
     /// Parses a hex encoded 8-bit integer.
     fn extract_word(slice: &str) -> u8 {
-        match u8::from_str_radix(slice, 16) {
-            Ok(num) => num,
-            Err(_) => 0,
+        u8::from_str_radix(slice, 16).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    // LDA #$01, LDX #$02, LDY #$03.
+    const PROGRAM: [u8; 6] = [0xA9, 0x01, 0xA2, 0x02, 0xA0, 0x03];
+
+    /// Returns the CPU log lines the emulator writes while running PROGRAM.
+    fn log_program() -> Vec<String> {
+        let (mut cpu, mut memory) = testing::machine(&PROGRAM);
+        (0..3)
+            .map(|_| {
+                let instr = Instruction::parse(cpu.pc as usize, &mut memory).unwrap();
+                let line = instr.log(&cpu, &mut memory);
+                cpu.step(&mut memory).unwrap();
+                line
+            })
+            .collect()
+    }
+
+    #[test]
+    fn power_on_and_reset_leave_the_documented_state() {
+        let mut memory = testing::machine(&[]).1;
+        let mut cpu = CPU::new(testing::runtime_options());
+        cpu.a = 0x12;
+        cpu.x = 0x34;
+        cpu.y = 0x56;
+        cpu.p = 0xFF;
+
+        assert_eq!(cpu.power_on(&mut memory), 7);
+        assert_eq!((cpu.a, cpu.x, cpu.y), (0, 0, 0));
+        assert_eq!(cpu.sp, 0xFD);
+        assert_eq!(cpu.p, INTERRUPT_DISABLE | 0x20);
+        assert_eq!(cpu.pc, 0x8000);
+
+        // Resetting keeps the registers but moves the stack pointer down again
+        // and disables interrupts.
+        cpu.a = 0x12;
+        cpu.p = CARRY_FLAG;
+        assert_eq!(cpu.reset(&mut memory), 7);
+        assert_eq!(cpu.a, 0x12);
+        assert_eq!(cpu.sp, 0xFA);
+        assert_eq!(cpu.p, CARRY_FLAG | INTERRUPT_DISABLE | 0x20);
+        assert_eq!(cpu.pc, 0x8000);
+    }
+
+    #[test]
+    fn decimal_flag_is_kept_but_adc_stays_binary() {
+        // SED; PHP; LDA #$09; CLC; ADC #$01; PLP
+        let program = [0xF8, 0x08, 0xA9, 0x09, 0x18, 0x69, 0x01, 0x28];
+        let (mut cpu, mut memory) = testing::machine(&program);
+        for _ in 0..6 {
+            cpu.step(&mut memory).unwrap();
+        }
+
+        // A BCD add would have given $10.
+        assert_eq!(cpu.a, 0x0A);
+        assert!(cpu.decimal_mode_set());
+        assert!(cpu.to_string().contains("Decimal Mode:      SET"));
+    }
+
+    #[test]
+    fn irq_waits_for_the_instruction_after_cli() {
+        // CLI; NOP; NOP, with the IRQ handler at $8010.
+        let mut rom = testing::nrom(&[0x58, 0xEA, 0xEA]);
+        testing::set_vector(&mut rom, 0xFFFE, 0x8010);
+        let mut memory = Memory::new(testing::runtime_options(), testing::mapper(&rom));
+        let mut cpu = CPU::new(testing::runtime_options());
+        cpu.power_on(&mut memory);
+        cpu.irq_line = true;
+
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0x8001);
+        assert!(!cpu.interrupt_disable_set());
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, 0x8002);
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), 7);
+        assert_eq!(cpu.pc, 0x8010);
+        assert!(cpu.interrupt_disable_set());
+        let return_addr = memory.read_u16(0x100 + cpu.sp as usize + 2);
+        assert_eq!(return_addr, 0x8002);
+    }
+
+    #[test]
+    fn comparison_starts_at_the_log_compare_address() {
+        // Break the accumulator column of the first line, which comes before
+        // the start address, and the last line, which comes after it.
+        let mut lines = log_program();
+        for &index in [0, 2].iter() {
+            lines[index] = format!("{}FF{}", &lines[index][..50], &lines[index][52..]);
+        }
+        let path = testing::temp_path("compare-start.log");
+        {
+            let mut file = File::create(&path).unwrap();
+            for line in lines.iter() {
+                writeln!(file, "{}", line).unwrap();
+            }
+        }
+
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.continue_on_mismatch = true;
+        runtime_options.log_compare_start = Some(0x8002);
+        let (mut cpu, mut memory) = testing::machine_with_options(runtime_options, &PROGRAM);
+        cpu.begin_testing(BufReader::new(File::open(&path).unwrap()));
+        for _ in 0..3 {
+            cpu.step(&mut memory).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(cpu.mismatch_count, 1);
+        assert_eq!(cpu.mismatches[0].line, 3);
+        assert_eq!(cpu.mismatches[0].fields, vec!["A: expected FF, got 01".to_string()]);
+    }
+
+    #[test]
+    fn continue_on_mismatch_reports_every_divergence() {
+        // Break the accumulator column of the first and last lines.
+        let mut lines = log_program();
+        for &index in [0, 2].iter() {
+            lines[index] = format!("{}FF{}", &lines[index][..50], &lines[index][52..]);
+        }
+        let path = testing::temp_path("mismatch.log");
+        {
+            let mut file = File::create(&path).unwrap();
+            for line in lines.iter() {
+                writeln!(file, "{}", line).unwrap();
+            }
+        }
+
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.continue_on_mismatch = true;
+        let (mut cpu, mut memory) = testing::machine_with_options(runtime_options, &PROGRAM);
+        cpu.begin_testing(BufReader::new(File::open(&path).unwrap()));
+        for _ in 0..3 {
+            cpu.step(&mut memory).unwrap();
         }
+        let finished = cpu.step(&mut memory);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(finished, Err(LOG_FINISHED.to_string()));
+        assert!(cpu.execution_log_finished);
+        assert_eq!(cpu.mismatch_count, 2);
+        let mismatched_lines: Vec<usize> = cpu.mismatches.iter().map(|m| m.line).collect();
+        assert_eq!(mismatched_lines, vec![1, 3]);
+        assert_eq!(cpu.mismatches[0].fields, vec!["A: expected FF, got 00".to_string()]);
+        assert_eq!(cpu.mismatches[1].fields, vec!["A: expected FF, got 01".to_string()]);
+    }
+
+    #[test]
+    fn flags_are_uppercase_when_set() {
+        assert_eq!(CPU::fmt_flags(0x00), "nv-bdizc");
+        assert_eq!(CPU::fmt_flags(0x24), "nv-bdIzc");
+        assert_eq!(CPU::fmt_flags(0xC3), "NV-bdiZC");
+        assert_eq!(CPU::fmt_flags(0xFF), "NV-BDIZC");
     }
 }
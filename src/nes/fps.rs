@@ -0,0 +1,99 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Number of recent frames the frame rate is averaged over.
+const WINDOW_FRAMES: usize = 60;
+
+/// Measures the rate frames are actually produced at, averaged over the last
+/// second or so of frames so a single slow frame doesn't make it jump around.
+pub struct FpsCounter {
+    timestamps: VecDeque<Instant>,
+    last_report: Option<Instant>,
+}
+
+impl FpsCounter {
+    pub fn new() -> Self {
+        FpsCounter {
+            timestamps: VecDeque::with_capacity(WINDOW_FRAMES + 1),
+            last_report: None,
+        }
+    }
+
+    /// Records a frame finishing at the given time. Once a second the average
+    /// frame rate is returned so it can be shown.
+    pub fn frame(&mut self, now: Instant) -> Option<f64> {
+        self.timestamps.push_back(now);
+        if self.timestamps.len() > WINDOW_FRAMES + 1 {
+            self.timestamps.pop_front();
+        }
+
+        let last_report = *self.last_report.get_or_insert(now);
+        if now.duration_since(last_report) < Duration::from_secs(1) {
+            return None;
+        }
+        self.last_report = Some(now);
+        self.average()
+    }
+
+    /// Forgets the frames recorded so far, so time spent paused doesn't drag
+    /// the average down.
+    pub fn reset(&mut self) {
+        self.timestamps.clear();
+        self.last_report = None;
+    }
+
+    /// Returns the average frame rate across the recorded frames, which needs
+    /// at least two frames to measure.
+    fn average(&self) -> Option<f64> {
+        let (first, last) = match (self.timestamps.front(), self.timestamps.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return None,
+        };
+
+        let elapsed = last.duration_since(*first);
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        if seconds > 0.0 {
+            Some((self.timestamps.len() - 1) as f64 / seconds)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rate_is_averaged_over_recent_frames() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut fps = FpsCounter::new();
+
+        // 50 frames 20ms apart is 50 FPS, reported once a second has passed.
+        for frame in 0..50 {
+            assert_eq!(fps.frame(at(frame * 20)), None);
+        }
+        assert_eq!(fps.frame(at(1000)), Some(50.0));
+
+        // Speeding up to 10ms frames only counts the last 60 frames.
+        for frame in 1..100 {
+            assert_eq!(fps.frame(at(1000 + frame * 10)), None);
+        }
+        assert_eq!(fps.frame(at(2000)), Some(100.0));
+
+        // Nothing is reported until a second after a reset.
+        fps.reset();
+        assert_eq!(fps.frame(at(3000)), None);
+        assert_eq!(fps.frame(at(3500)), None);
+        assert_eq!(fps.frame(at(4000)), Some(2.0));
+    }
+}
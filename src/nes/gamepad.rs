@@ -0,0 +1,188 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use io::log;
+use nes::bindings::{Binding, Bindings};
+use nes::controller::*;
+use nes::nes::NESRuntimeOptions;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::GameControllerSubsystem;
+use std::i16;
+
+// Number of players game controllers can be mapped to, which includes the
+// two on a Four Score.
+const PLAYERS: usize = 4;
+
+/// Game controllers plugged into the host. They drive the NES controllers in
+/// the order they were connected, alongside the keyboard.
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+
+    // Open controllers, in the order of the players they drive. Any past the
+    // fourth are kept open but ignored.
+    controllers: Vec<GameController>,
+
+    // The d-pad buttons each controller's left stick is holding down, so
+    // moving the stick back only releases what it pressed.
+    sticks: Vec<u8>,
+
+    // How far a stick has to be pushed before it counts as a d-pad press.
+    dead_zone: i16,
+
+    // Swaps which face buttons press A and B.
+    swap_ab: bool,
+}
+
+impl Gamepads {
+    /// Opens every game controller already plugged in.
+    pub fn new(subsystem: GameControllerSubsystem, runtime_options: &NESRuntimeOptions) -> Self {
+        let dead_zone = i16::MAX as i32 * runtime_options.gamepad_dead_zone as i32 / 100;
+        let mut gamepads = Gamepads {
+            subsystem: subsystem,
+            controllers: Vec::new(),
+            sticks: Vec::new(),
+            dead_zone: dead_zone as i16,
+            swap_ab: runtime_options.gamepad_swap_ab,
+        };
+        let count = gamepads.subsystem.num_joysticks().unwrap_or(0);
+        for index in 0..count {
+            gamepads.add(index, runtime_options);
+        }
+        gamepads
+    }
+
+    /// Opens the joystick at the given index if SDL recognizes it as a game
+    /// controller. SDL also reports controllers that were plugged in at
+    /// startup as added, so ones already open are skipped.
+    pub fn add(&mut self, index: u32, runtime_options: &NESRuntimeOptions) {
+        if !self.subsystem.is_game_controller(index) {
+            return;
+        }
+        let controller = match self.subsystem.open(index) {
+            Ok(controller) => controller,
+            Err(e) => {
+                let message = format!("cannot open game controller {}: {}", index, e);
+                log::warn(message, runtime_options);
+                return;
+            }
+        };
+        let id = controller.instance_id();
+        if self.controllers.iter().any(|open| open.instance_id() == id) {
+            return;
+        }
+        let message = format!(
+            "Connected {} as player {}",
+            controller.name(),
+            self.controllers.len() + 1
+        );
+        log::log("input", message, runtime_options);
+        self.controllers.push(controller);
+        self.sticks.push(0);
+    }
+
+    /// Closes a controller that was unplugged. Controllers connected after it
+    /// move up a player.
+    pub fn remove(&mut self, id: i32, runtime_options: &NESRuntimeOptions) {
+        if let Some(position) = self.controllers.iter().position(|c| c.instance_id() == id) {
+            let controller = self.controllers.remove(position);
+            self.sticks.remove(position);
+            log::log("input", format!("Disconnected {}", controller.name()), runtime_options);
+        }
+    }
+
+    /// Returns the player (counting from 0) driven by a game controller.
+    pub fn player(&self, id: i32) -> Option<usize> {
+        match self.controllers.iter().position(|c| c.instance_id() == id) {
+            Some(player) if player < PLAYERS => Some(player),
+            _ => None,
+        }
+    }
+
+    /// Returns what a game controller button is bound to on the given port if
+    /// anything, with A and B swapped if asked to.
+    pub fn button(&self, bindings: &Bindings, port: usize, button: Button) -> Option<Binding> {
+        bindings.pad(port, button).map(|mut binding| {
+            binding.button = match binding.button {
+                BUTTON_A if self.swap_ab => BUTTON_B,
+                BUTTON_B if self.swap_ab => BUTTON_A,
+                button => button,
+            };
+            binding
+        })
+    }
+
+    /// Returns the d-pad buttons to press and release when a player's left
+    /// stick moves along an axis. Inside the dead zone the direction the
+    /// stick was pushed in is released, while a d-pad or key held down for
+    /// the other direction is left alone.
+    pub fn axis(&mut self, player: usize, axis: Axis, value: i16) -> Option<(u8, u8)> {
+        let (negative, positive) = match axis {
+            Axis::LeftX => (BUTTON_LEFT, BUTTON_RIGHT),
+            Axis::LeftY => (BUTTON_UP, BUTTON_DOWN),
+            _ => return None,
+        };
+        let dead_zone = self.dead_zone;
+        self.sticks
+            .get_mut(player)
+            .map(|held| move_stick(held, negative, positive, value, dead_zone))
+    }
+}
+
+/// Moves a stick along the axis between the negative and positive d-pad
+/// buttons, updating the buttons it holds. Returns the buttons to press and
+/// release, which are only ever ones the stick itself pressed.
+fn move_stick(held: &mut u8, negative: u8, positive: u8, value: i16, dead_zone: i16) -> (u8, u8) {
+    let direction = if value < -dead_zone {
+        negative
+    } else if value > dead_zone {
+        positive
+    } else {
+        0
+    };
+    let axis_held = *held & (negative | positive);
+    *held = (*held & !axis_held) | direction;
+    (direction & !axis_held, axis_held & !direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEAD_ZONE: i16 = 1000;
+
+    #[test]
+    fn stick_only_releases_what_it_pressed() {
+        let mut held = 0;
+        let pressed = move_stick(&mut held, BUTTON_LEFT, BUTTON_RIGHT, -5000, DEAD_ZONE);
+        assert_eq!(pressed, (BUTTON_LEFT, 0));
+
+        // Pushing the stick further doesn't press anything again.
+        let pushed = move_stick(&mut held, BUTTON_LEFT, BUTTON_RIGHT, -9000, DEAD_ZONE);
+        assert_eq!(pushed, (0, 0));
+
+        let centered = move_stick(&mut held, BUTTON_LEFT, BUTTON_RIGHT, 0, DEAD_ZONE);
+        assert_eq!(centered, (0, BUTTON_LEFT));
+        let centered = move_stick(&mut held, BUTTON_LEFT, BUTTON_RIGHT, 0, DEAD_ZONE);
+        assert_eq!(centered, (0, 0));
+        assert_eq!(held, 0);
+    }
+
+    #[test]
+    fn stick_switches_directions_and_axes_independently() {
+        let mut held = 0;
+        move_stick(&mut held, BUTTON_UP, BUTTON_DOWN, -5000, DEAD_ZONE);
+        move_stick(&mut held, BUTTON_LEFT, BUTTON_RIGHT, 5000, DEAD_ZONE);
+        assert_eq!(held, BUTTON_UP | BUTTON_RIGHT);
+
+        let flipped = move_stick(&mut held, BUTTON_LEFT, BUTTON_RIGHT, -5000, DEAD_ZONE);
+        assert_eq!(flipped, (BUTTON_LEFT, BUTTON_RIGHT));
+        let centered = move_stick(&mut held, BUTTON_LEFT, BUTTON_RIGHT, 0, DEAD_ZONE);
+        assert_eq!(centered, (0, BUTTON_LEFT));
+        assert_eq!(held, BUTTON_UP);
+    }
+}
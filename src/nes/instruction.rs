@@ -7,10 +7,10 @@
 // except according to those terms.
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use nes::cpu::CPU;
+use nes::cpu::{CPU, NEGATIVE_FLAG, OVERFLOW_FLAG};
 use nes::memory::Memory;
 use nes::opcode::Opcode::*;
-use nes::opcode::{decode_opcode, opcode_len, Opcode};
+use nes::opcode::{base_cycles, decode_opcode, opcode_len, Opcode};
 use std::io::Cursor;
 use utils::arithmetic::add_relative;
 use utils::paging::{page_cross, PageCross};
@@ -23,16 +23,68 @@ pub struct Instruction(pub u8, pub u8, pub u8);
 
 impl Instruction {
     /// Parses an instruction from memory at the address of the program counter.
-    pub fn parse(pc: usize, memory: &mut Memory) -> Instruction {
-        let raw_opcode = memory.read_u8(pc);
-        let opcode = decode_opcode(raw_opcode);
-        let len = opcode_len(&opcode);
+    /// An error is returned if the opcode at that address is illegal.
+    pub fn parse(pc: usize, memory: &mut Memory) -> Result<Instruction, String> {
+        Instruction::parse_with(pc, memory, Memory::read_u8)
+    }
+
+    /// Parses an instruction like parse, but without the side effects of
+    /// reading memory so the debugger can disassemble anywhere. Every address
+    /// is safe to peek: RAM, PRG-RAM and PRG-ROM are read directly, the
+    /// controller ports don't shift, PPU and APU registers aren't marked as
+    /// read (so $2002 keeps its vblank flag), and MMC2 latches don't flip.
+    pub fn parse_unrestricted(pc: usize, memory: &mut Memory) -> Result<Instruction, String> {
+        Instruction::parse_with(pc, memory, Memory::read_u8_unrestricted)
+    }
 
-        match len {
+    fn parse_with(
+        pc: usize,
+        memory: &mut Memory,
+        read: fn(&mut Memory, usize) -> u8,
+    ) -> Result<Instruction, String> {
+        let raw_opcode = read(memory, pc);
+        let len = match decode_opcode(raw_opcode).and_then(|opcode| opcode_len(&opcode)) {
+            Some(len) => len,
+            None => return Err(format!("Illegal opcode {:02X} at ${:04X}", raw_opcode, pc)),
+        };
+
+        Ok(match len {
             1 => Instruction(raw_opcode, 0, 0),
-            2 => Instruction(raw_opcode, memory.read_u8(pc + 1), 0),
-            3 => Instruction(raw_opcode, memory.read_u8(pc + 1), memory.read_u8(pc + 2)),
-            _ => panic!("Invalid instruction length returned"),
+            2 => Instruction(raw_opcode, read(memory, pc + 1), 0),
+            _ => Instruction(raw_opcode, read(memory, pc + 1), read(memory, pc + 2)),
+        })
+    }
+
+    /// Returns the number of bytes the instruction takes up in memory. Illegal
+    /// opcodes are treated as a single byte.
+    pub fn size(&self) -> u16 {
+        self.opcode().and_then(|opcode| opcode_len(&opcode)).unwrap_or(1) as u16
+    }
+
+    /// Returns true if the instruction calls a subroutine that returns to the
+    /// instruction after it.
+    pub fn is_subroutine_call(&self) -> bool {
+        self.opcode() == Some(JSRAbs)
+    }
+
+    /// Returns the address the instruction operates on when it's known without
+    /// looking at registers or memory, which is the case for absolute and
+    /// relative addressing. The instruction is assumed to be at the given
+    /// address.
+    pub fn operand_address(&self, pc: u16) -> Option<u16> {
+        let opcode = match self.opcode() {
+            Some(opcode) => opcode,
+            None => return None,
+        };
+
+        match opcode {
+            BCCRel | BCSRel | BEQRel | BMIRel | BNERel | BPLRel | BVCRel | BVSRel => {
+                Some(add_relative(pc.wrapping_add(2), self.relative()))
+            }
+            _ => match opcode_len(&opcode) {
+                Some(3) => Some((self.2 as u16) << 8 | self.1 as u16),
+                _ => None,
+            },
         }
     }
 
@@ -41,8 +93,11 @@ impl Instruction {
     /// print function mimic Nintendulator and are used during CPU log
     /// comparisons.
     pub fn disassemble(&self, cpu: &CPU, memory: &mut Memory) -> String {
-        let opcode = self.opcode();
-        let len = opcode_len(&opcode);
+        let opcode = match self.opcode() {
+            Some(opcode) => opcode,
+            None => return "GARBAGE".to_string(),
+        };
+        let len = opcode_len(&opcode).unwrap();
 
         match opcode {
             ANDImm => self.disassemble_immediate("AND"),
@@ -196,10 +251,32 @@ impl Instruction {
             TXAImp => self.disassemble_implied("TXA"),
             TXSImp => self.disassemble_implied("TXS"),
             TYAImp => self.disassemble_implied("TYA"),
+            AHXAbsY => self.disassemble_absolute_y("AHX", memory, cpu),
+            AHXIndY => self.disassemble_indirect_y("AHX", memory, cpu),
+            SHXAbsY => self.disassemble_absolute_y("SHX", memory, cpu),
+            SHYAbsX => self.disassemble_absolute_x("SHY", memory, cpu),
+            TASAbsY => self.disassemble_absolute_y("TAS", memory, cpu),
             _ => "GARBAGE".to_string(),
         }
     }
 
+    /// Returns the bytes making up the instruction. Illegal opcodes are a
+    /// single byte.
+    pub fn bytes(&self) -> Vec<u8> {
+        let bytes = [self.0, self.1, self.2];
+        let len = self.opcode().and_then(|opcode| opcode_len(&opcode)).unwrap_or(1);
+        bytes[..len as usize].to_vec()
+    }
+
+    /// Returns human readable hex of the instruction bytes, padded to the
+    /// width of the longest instruction. Bytes that do not exist in an
+    /// instruction are left blank (rather than displaying the default struct
+    /// value 0) to keep the output consistent with Nintendulator's logs.
+    pub fn hex(&self) -> String {
+        let hex: Vec<String> = self.bytes().iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("{:8}", hex.join(" "))
+    }
+
     /// Logs a human-readable representation of the instruction along with the
     /// CPU state in an easy to parse format.
     ///
@@ -207,18 +284,7 @@ impl Instruction {
     /// checked. Also it may be more appropriate to move this function into the
     /// CPU.
     pub fn log(&self, cpu: &CPU, memory: &mut Memory) -> String {
-        let opcode = self.opcode();
-
-        // Get human readable hex of the instruction bytes. A pattern match is
-        // used as bytes that do not exist in an instruction should not be
-        // displayed (rather than displaying the default struct value 0). This
-        // is to keep the logs consistent with Nintendulator's logs.
-        let instr_str = match opcode_len(&opcode) {
-            1 => format!("{:02X}      ", self.0),
-            2 => format!("{:02X} {:02X}   ", self.0, self.1),
-            3 => format!("{:02X} {:02X} {:02X}", self.0, self.1, self.2),
-            _ => panic!("Invalid instruction length given"),
-        };
+        let instr_str = self.hex();
 
         // Prints the CPU state and disassembled instruction in a nice parsable
         // format. In the future this output will be used for automatically
@@ -237,11 +303,20 @@ impl Instruction {
 
     /// Execute the instruction with a routine that corresponds with it's
     /// opcode. All routines for every instruction in the 6502 instruction set
-    /// are present here.
+    /// are present here. An error is returned if the opcode is illegal or has
+    /// no routine yet, in which case the CPU is left untouched.
     #[inline(always)]
-    pub fn execute(&self, cpu: &mut CPU, memory: &mut Memory) {
-        let opcode = self.opcode();
-        let len = opcode_len(&opcode) as u16;
+    pub fn execute(&self, cpu: &mut CPU, memory: &mut Memory) -> Result<(), String> {
+        let (opcode, len) = match self.opcode().and_then(|o| opcode_len(&o).map(|l| (o, l))) {
+            Some((opcode, len)) => (opcode, len as u16),
+            None => {
+                return Err(format!("Illegal opcode {:02X} at ${:04X}", self.0, cpu.pc));
+            }
+        };
+
+        // Every instruction takes at least its base number of cycles. Page
+        // crossings and taken branches add to that below.
+        cpu.cycles += base_cycles(&opcode).unwrap() as u16;
 
         match opcode {
             ANDImm => {
@@ -249,7 +324,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             ANDZero => {
@@ -257,7 +331,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             ANDZeroX => {
@@ -265,7 +338,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ANDAbs => {
@@ -273,7 +345,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ANDAbsX => {
@@ -282,7 +353,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 4;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -294,7 +364,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 4;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -305,7 +374,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             ANDIndY => {
@@ -314,74 +382,32 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 5;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
                 cpu.pc += len;
             }
             BCCRel => {
-                if !cpu.carry_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = !cpu.carry_flag_set();
+                self.branch(cpu, memory, taken);
             }
             BCSRel => {
-                if cpu.carry_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = cpu.carry_flag_set();
+                self.branch(cpu, memory, taken);
             }
             BEQRel => {
-                if cpu.zero_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = cpu.zero_flag_set();
+                self.branch(cpu, memory, taken);
             }
             BMIRel => {
-                if cpu.negative_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = cpu.negative_flag_set();
+                self.branch(cpu, memory, taken);
             }
             EORImm => {
                 let result = cpu.a ^ self.immediate();
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             EORZero => {
@@ -389,7 +415,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             EORZeroX => {
@@ -397,7 +422,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             EORAbs => {
@@ -405,7 +429,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             EORAbsX => {
@@ -414,7 +437,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -426,7 +448,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -437,7 +458,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             EORIndY => {
@@ -446,7 +466,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 5;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -457,7 +476,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             ORAZero => {
@@ -465,7 +483,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             ORAZeroX => {
@@ -473,7 +490,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ORAAbs => {
@@ -481,7 +497,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ORAAbsX => {
@@ -490,7 +505,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -502,7 +516,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -513,7 +526,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             ORAIndY => {
@@ -522,7 +534,6 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 5;
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
@@ -530,96 +541,45 @@ impl Instruction {
             }
             BITZero => {
                 let byte = self.dereference_zero_page(memory);
-                let result = byte & cpu.a;
-                cpu.toggle_zero_flag(result);
-                let mask = 0xC0;
-                cpu.p = (cpu.p & !mask) | (byte & mask);
-                cpu.cycles += 3;
+                self.bit(cpu, byte);
                 cpu.pc += len;
             }
             BITAbs => {
                 let byte = self.dereference_absolute(memory);
-                let result = byte & cpu.a;
-                cpu.toggle_zero_flag(result);
-                let mask = 0xC0;
-                cpu.p = (cpu.p & !mask) | (byte & mask);
-                cpu.cycles += 4;
+                self.bit(cpu, byte);
                 cpu.pc += len;
             }
             BNERel => {
-                if !cpu.zero_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = !cpu.zero_flag_set();
+                self.branch(cpu, memory, taken);
             }
             BPLRel => {
-                if !cpu.negative_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = !cpu.negative_flag_set();
+                self.branch(cpu, memory, taken);
             }
             BVCRel => {
-                if !cpu.overflow_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = !cpu.overflow_flag_set();
+                self.branch(cpu, memory, taken);
             }
             BVSRel => {
-                if cpu.overflow_flag_set() {
-                    let old_pc = cpu.pc as usize;
-                    cpu.pc = add_relative(cpu.pc, self.relative());
-                    cpu.cycles += 1;
-                    if page_cross(old_pc.wrapping_add(len as usize), cpu.pc as usize)
-                        != PageCross::Same
-                    {
-                        cpu.cycles += 2;
-                    }
-                }
-                cpu.cycles += 2;
-                cpu.pc += len;
+                let taken = cpu.overflow_flag_set();
+                self.branch(cpu, memory, taken);
             }
             CLCImp => {
                 cpu.unset_carry_flag();
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             CLDImp => {
                 cpu.unset_decimal_mode();
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             CLIImp => {
+                cpu.delay_interrupt_disable();
                 cpu.unset_interrupt_disable();
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             CLVImp => {
                 cpu.unset_overflow_flag();
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             ADCImm => {
@@ -643,7 +603,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             ADCZero => {
@@ -667,7 +626,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             ADCZeroX => {
@@ -691,7 +649,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ADCAbs => {
@@ -715,7 +672,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ADCAbsX => {
@@ -743,7 +699,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ADCAbsY => {
@@ -771,7 +726,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             ADCIndX => {
@@ -795,7 +749,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             ADCIndY => {
@@ -823,7 +776,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             SBCImm => {
@@ -847,7 +799,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(!overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             SBCZero => {
@@ -871,7 +822,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(!overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             SBCZeroX => {
@@ -895,7 +845,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(!overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             SBCAbs => {
@@ -919,7 +868,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(!overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             SBCAbsX => {
@@ -947,7 +895,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             SBCAbsY => {
@@ -975,7 +922,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             SBCIndX => {
@@ -999,7 +945,6 @@ impl Instruction {
                 cpu.toggle_carry_flag(!overflow);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             SBCIndY => {
@@ -1027,7 +972,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             CMPImm => {
@@ -1044,7 +988,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             CMPZero => {
@@ -1061,7 +1004,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             CMPZeroX => {
@@ -1078,7 +1020,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             CMPAbs => {
@@ -1095,7 +1036,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             CMPAbsX => {
@@ -1116,7 +1056,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             CMPAbsY => {
@@ -1137,7 +1076,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             CMPIndX => {
@@ -1154,7 +1092,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             CMPIndY => {
@@ -1175,7 +1112,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             CPXImm => {
@@ -1192,7 +1128,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             CPXZero => {
@@ -1209,7 +1144,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             CPXAbs => {
@@ -1226,7 +1160,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             CPYImm => {
@@ -1243,7 +1176,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             CPYZero => {
@@ -1260,7 +1192,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             CPYAbs => {
@@ -1277,7 +1208,6 @@ impl Instruction {
                     cpu.unset_zero_flag();
                 }
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             INCZero => {
@@ -1286,7 +1216,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             INCZeroX => {
@@ -1295,7 +1224,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             INCAbs => {
@@ -1304,7 +1232,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             INCAbsX => {
@@ -1313,7 +1240,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 7;
                 cpu.pc += len;
             }
             INXImp => {
@@ -1321,7 +1247,6 @@ impl Instruction {
                 cpu.x = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             INYImp => {
@@ -1329,7 +1254,6 @@ impl Instruction {
                 cpu.y = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             DECZero => {
@@ -1338,7 +1262,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             DECZeroX => {
@@ -1347,7 +1270,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             DECAbs => {
@@ -1356,7 +1278,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             DECAbsX => {
@@ -1365,7 +1286,6 @@ impl Instruction {
                 memory.write_u8(addr, result);
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 7;
                 cpu.pc += len;
             }
             DEXImp => {
@@ -1373,7 +1293,6 @@ impl Instruction {
                 cpu.x = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             DEYImp => {
@@ -1381,7 +1300,6 @@ impl Instruction {
                 cpu.y = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             ASLAcc => {
@@ -1391,7 +1309,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 cpu.a = result;
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             ASLZero => {
@@ -1403,7 +1320,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             ASLZeroX => {
@@ -1415,7 +1331,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             ASLAbs => {
@@ -1427,7 +1342,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             ASLAbsX => {
@@ -1439,7 +1353,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 7;
                 cpu.pc += len;
             }
             LSRAcc => {
@@ -1449,7 +1362,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 cpu.a = result;
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             LSRZero => {
@@ -1461,7 +1373,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             LSRZeroX => {
@@ -1473,7 +1384,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             LSRAbs => {
@@ -1485,7 +1395,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             LSRAbsX => {
@@ -1497,7 +1406,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 7;
                 cpu.pc += len;
             }
             RORAcc => {
@@ -1507,7 +1415,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 cpu.a = result;
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             RORZero => {
@@ -1519,7 +1426,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             RORZeroX => {
@@ -1531,7 +1437,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             RORAbs => {
@@ -1543,7 +1448,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             RORAbsX => {
@@ -1555,7 +1459,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 7;
                 cpu.pc += len;
             }
             ROLAcc => {
@@ -1565,7 +1468,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 cpu.a = result;
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             ROLZero => {
@@ -1577,7 +1479,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             ROLZeroX => {
@@ -1589,7 +1490,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             ROLAbs => {
@@ -1601,7 +1501,6 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             ROLAbsX => {
@@ -1613,12 +1512,10 @@ impl Instruction {
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
                 memory.write_u8(addr, result);
-                cpu.cycles += 7;
                 cpu.pc += len;
             }
             JMPAbs => {
                 cpu.pc = self.absolute() as u16;
-                cpu.cycles += 3;
             }
             JMPInd => {
                 // A special version of indirect addressing is implemented here
@@ -1626,20 +1523,17 @@ impl Instruction {
                 // https://github.com/Reshurum/nes-rs/issues/3
                 let arg = self.arg_u16() as usize;
                 cpu.pc = memory.read_u16_wrapped_msb(arg);
-                cpu.cycles += 5;
             }
             JSRAbs => {
                 let pc = cpu.pc;
                 memory.stack_push_u16(cpu, pc + len - 1);
                 cpu.pc = self.absolute() as u16;
-                cpu.cycles += 6;
             }
             LDAImm => {
                 cpu.a = self.immediate();
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             LDAZero => {
@@ -1647,7 +1541,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             LDAZeroX => {
@@ -1655,7 +1548,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDAAbs => {
@@ -1663,7 +1555,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDAAbsX => {
@@ -1675,7 +1566,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDAAbsY => {
@@ -1687,7 +1577,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDAIndX => {
@@ -1696,7 +1585,6 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             LDAIndY => {
@@ -1708,7 +1596,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             LDXImm => {
@@ -1716,7 +1603,6 @@ impl Instruction {
                 let x = cpu.x;
                 cpu.toggle_zero_flag(x);
                 cpu.toggle_negative_flag(x);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             LDXZero => {
@@ -1724,7 +1610,6 @@ impl Instruction {
                 let x = cpu.x;
                 cpu.toggle_zero_flag(x);
                 cpu.toggle_negative_flag(x);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             LDXZeroY => {
@@ -1732,7 +1617,6 @@ impl Instruction {
                 let x = cpu.x;
                 cpu.toggle_zero_flag(x);
                 cpu.toggle_negative_flag(x);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDXAbs => {
@@ -1740,7 +1624,6 @@ impl Instruction {
                 let x = cpu.x;
                 cpu.toggle_zero_flag(x);
                 cpu.toggle_negative_flag(x);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDXAbsY => {
@@ -1752,7 +1635,6 @@ impl Instruction {
                 let x = cpu.x;
                 cpu.toggle_zero_flag(x);
                 cpu.toggle_negative_flag(x);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDYImm => {
@@ -1760,7 +1642,6 @@ impl Instruction {
                 let y = cpu.y;
                 cpu.toggle_zero_flag(y);
                 cpu.toggle_negative_flag(y);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             LDYZero => {
@@ -1768,7 +1649,6 @@ impl Instruction {
                 let y = cpu.y;
                 cpu.toggle_zero_flag(y);
                 cpu.toggle_negative_flag(y);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             LDYZeroX => {
@@ -1776,7 +1656,6 @@ impl Instruction {
                 let y = cpu.y;
                 cpu.toggle_zero_flag(y);
                 cpu.toggle_negative_flag(y);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDYAbs => {
@@ -1784,7 +1663,6 @@ impl Instruction {
                 let y = cpu.y;
                 cpu.toggle_zero_flag(y);
                 cpu.toggle_negative_flag(y);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             LDYAbsX => {
@@ -1796,7 +1674,6 @@ impl Instruction {
                 if page_cross != PageCross::Same {
                     cpu.cycles += 1;
                 }
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             BRKImp => {
@@ -1806,24 +1683,20 @@ impl Instruction {
                 memory.stack_push_u16(cpu, pc);
                 memory.stack_push_u8(cpu, p);
                 cpu.set_break_command();
-                cpu.cycles += 7;
                 cpu.pc = pc;
             }
             NOPImp => {
                 // This is the most difficult instruction to implement.
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             PHAImp => {
                 let a = cpu.a;
                 memory.stack_push_u8(cpu, a);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             PHPImp => {
-                let p = cpu.p | 0x10; // Ensure bit 5 is always set.
+                let p = cpu.p | 0x10; // The break flag is always set when pushed.
                 memory.stack_push_u8(cpu, p);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             PLAImp => {
@@ -1831,106 +1704,89 @@ impl Instruction {
                 let a = cpu.a;
                 cpu.toggle_zero_flag(a);
                 cpu.toggle_negative_flag(a);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             PLPImp => {
-                let old_flags = cpu.p;
-                let p = (memory.stack_pop_u8(cpu) & 0xEF) | (old_flags & 0x20);
+                // Bit 5 always reads as set and the break flag doesn't exist
+                // in the register itself, it's only present on the stack.
+                let p = (memory.stack_pop_u8(cpu) & 0xEF) | 0x20;
+                cpu.delay_interrupt_disable();
                 cpu.p = p;
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             RTIImp => {
-                let result = (memory.stack_pop_u8(cpu) & 0xEF) | (cpu.p & 0x20);
+                let result = (memory.stack_pop_u8(cpu) & 0xEF) | 0x20;
                 cpu.p = result;
                 cpu.pc = memory.stack_pop_u16(cpu);
-                cpu.cycles += 6;
             }
             RTSImp => {
                 cpu.pc = memory.stack_pop_u16(cpu) + len;
-                cpu.cycles += 6;
             }
             SECImp => {
                 cpu.set_carry_flag();
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             SEDImp => {
                 cpu.set_decimal_mode();
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             SEIImp => {
+                cpu.delay_interrupt_disable();
                 cpu.set_interrupt_disable();
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             STAZero => {
                 memory.write_u8(self.zero_page(), cpu.a);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             STAZeroX => {
                 memory.write_u8(self.zero_page_x(cpu), cpu.a);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             STAAbs => {
                 memory.write_u8(self.absolute(), cpu.a);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             STAAbsX => {
                 memory.write_u8(self.absolute_x(cpu).0, cpu.a);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             STAAbsY => {
                 memory.write_u8(self.absolute_y(cpu).0, cpu.a);
-                cpu.cycles += 5;
                 cpu.pc += len;
             }
             STAIndX => {
                 let addr = self.indirect_x(cpu, memory).0;
                 memory.write_u8(addr, cpu.a);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             STAIndY => {
                 let addr = self.indirect_y(cpu, memory).0;
                 memory.write_u8(addr, cpu.a);
-                cpu.cycles += 6;
                 cpu.pc += len;
             }
             STXZero => {
                 memory.write_u8(self.zero_page(), cpu.x);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             STXZeroY => {
                 memory.write_u8(self.zero_page_y(cpu), cpu.x);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             STXAbs => {
                 memory.write_u8(self.absolute(), cpu.x);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             STYZero => {
                 memory.write_u8(self.zero_page(), cpu.y);
-                cpu.cycles += 3;
                 cpu.pc += len;
             }
             STYZeroX => {
                 memory.write_u8(self.zero_page_x(cpu), cpu.y);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             STYAbs => {
                 memory.write_u8(self.absolute(), cpu.y);
-                cpu.cycles += 4;
                 cpu.pc += len;
             }
             TAXImp => {
@@ -1938,7 +1794,6 @@ impl Instruction {
                 cpu.x = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             TAYImp => {
@@ -1946,7 +1801,6 @@ impl Instruction {
                 cpu.y = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             TSXImp => {
@@ -1954,7 +1808,6 @@ impl Instruction {
                 cpu.x = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             TXAImp => {
@@ -1962,13 +1815,11 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             TXSImp => {
                 let result = cpu.x;
                 cpu.sp = result;
-                cpu.cycles += 2;
                 cpu.pc += len;
             }
             TYAImp => {
@@ -1976,20 +1827,52 @@ impl Instruction {
                 cpu.a = result;
                 cpu.toggle_zero_flag(result);
                 cpu.toggle_negative_flag(result);
-                cpu.cycles += 2;
+                cpu.pc += len;
+            }
+            AHXAbsY => {
+                let value = cpu.a & cpu.x;
+                let base_addr = self.arg_u16();
+                self.store_high_byte_and(memory, base_addr, cpu.y, value);
+                cpu.pc += len;
+            }
+            AHXIndY => {
+                let value = cpu.a & cpu.x;
+                let base_addr = memory.read_u16_wrapped_msb(self.arg_u8() as usize);
+                self.store_high_byte_and(memory, base_addr, cpu.y, value);
+                cpu.pc += len;
+            }
+            SHXAbsY => {
+                let base_addr = self.arg_u16();
+                self.store_high_byte_and(memory, base_addr, cpu.y, cpu.x);
+                cpu.pc += len;
+            }
+            SHYAbsX => {
+                let base_addr = self.arg_u16();
+                self.store_high_byte_and(memory, base_addr, cpu.x, cpu.y);
+                cpu.pc += len;
+            }
+            TASAbsY => {
+                cpu.sp = cpu.a & cpu.x;
+                let value = cpu.sp;
+                let base_addr = self.arg_u16();
+                self.store_high_byte_and(memory, base_addr, cpu.y, value);
                 cpu.pc += len;
             }
             _ => {
-                panic!("Unimplemented opcode found: {:?}", opcode);
+                return Err(format!(
+                    "Unimplemented opcode {:?} ({:02X}) at ${:04X}",
+                    opcode, self.0, cpu.pc
+                ));
             }
         };
 
         cpu.poll_irq(memory); // Poll IRQ after execution.
+        Ok(())
     }
 
-    /// Obtain the opcode of the instruction.
+    /// Obtain the opcode of the instruction, or None if it's illegal.
     #[inline(always)]
-    fn opcode(&self) -> Opcode {
+    fn opcode(&self) -> Option<Opcode> {
         decode_opcode(self.0)
     }
 
@@ -2008,12 +1891,14 @@ impl Instruction {
 
     /// Dereferences a zero page address in the instruction.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_u8(&self, memory: &mut Memory) -> u8 {
         memory.read_u8(self.arg_u8() as usize)
     }
 
     /// Dereferences a memory address in the instruction.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_u16(&self, memory: &mut Memory) -> u8 {
         memory.read_u8(self.arg_u16() as usize)
     }
@@ -2025,6 +1910,7 @@ impl Instruction {
     /// Accumulator addressing simply gets values from the accumulator register
     /// rather than from the instruction.
     #[inline(always)]
+    #[allow(dead_code)]
     fn accumulator(&self, cpu: &CPU) -> u8 {
         cpu.a
     }
@@ -2125,6 +2011,64 @@ impl Instruction {
         (addr, page_cross)
     }
 
+    /// Tests bits of a byte in memory against the accumulator. The zero flag
+    /// is set if they have no bits in common, and the negative and overflow
+    /// flags are copied from bits 7 and 6 of the byte. The accumulator and
+    /// the other flags are left alone.
+    #[inline(always)]
+    fn bit(&self, cpu: &mut CPU, byte: u8) {
+        let result = byte & cpu.a;
+        cpu.toggle_zero_flag(result);
+        if byte & NEGATIVE_FLAG == NEGATIVE_FLAG {
+            cpu.set_negative_flag();
+        } else {
+            cpu.unset_negative_flag();
+        }
+        if byte & OVERFLOW_FLAG == OVERFLOW_FLAG {
+            cpu.set_overflow_flag();
+        } else {
+            cpu.unset_overflow_flag();
+        }
+    }
+
+    /// Executes a branch instruction. Branches take their 2 base cycles, plus
+    /// 1 if the branch is taken and 1 more if the target is on a different
+    /// page than the following instruction. When the page is crossed the CPU
+    /// first reads from the target address with the old page's high byte
+    /// before fixing it.
+    #[inline(always)]
+    fn branch(&self, cpu: &mut CPU, memory: &mut Memory, taken: bool) {
+        let next_pc = cpu.pc.wrapping_add(2);
+        if !taken {
+            cpu.pc = next_pc;
+            return;
+        }
+
+        let target = add_relative(next_pc, self.relative());
+        cpu.cycles += 1;
+        if page_cross(next_pc as usize, target as usize) != PageCross::Same {
+            let uncorrected = (next_pc & 0xFF00) | (target & 0x00FF);
+            memory.read_u8(uncorrected as usize);
+            cpu.cycles += 1;
+        }
+        cpu.pc = target;
+    }
+
+    /// Performs the store used by the unofficial SHX, SHY, AHX, and TAS
+    /// instructions. The value stored is ANDed with the high byte of the base
+    /// address plus one. If indexing crosses a page boundary the high byte of
+    /// the target address is corrupted and replaced with the stored value.
+    #[inline(always)]
+    fn store_high_byte_and(&self, memory: &mut Memory, base_addr: u16, index: u8, value: u8) {
+        let addr = base_addr.wrapping_add(index as u16);
+        let result = value & ((base_addr >> 8) as u8).wrapping_add(1);
+        let addr = match page_cross(base_addr as usize, addr as usize) {
+            PageCross::Same => addr,
+            _ => (addr & 0x00FF) | ((result as u16) << 8),
+        };
+        memory.write_u8(addr as usize, result);
+    }
+
     /// Dereferences a zero page address.
     #[inline(always)]
     fn dereference_zero_page(&self, memory: &mut Memory) -> u8 {
@@ -2141,6 +2085,7 @@ impl Instruction {
 
     /// Dereferences a zero page y address.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_zero_page_y(&self, memory: &mut Memory, cpu: &CPU) -> u8 {
         let addr = self.zero_page_y(cpu);
         memory.read_u8(addr)
@@ -2155,6 +2100,7 @@ impl Instruction {
 
     /// Dereferences an absolute x address.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_absolute_x(&self, memory: &mut Memory, cpu: &CPU) -> u8 {
         let addr = self.absolute_x(cpu).0;
         memory.read_u8(addr)
@@ -2162,6 +2108,7 @@ impl Instruction {
 
     /// Dereferences an absolute y address.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_absolute_y(&self, memory: &mut Memory, cpu: &CPU) -> u8 {
         let addr = self.absolute_y(cpu).0;
         memory.read_u8(addr)
@@ -2169,6 +2116,7 @@ impl Instruction {
 
     /// Dereferences an indirect address.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_indirect(&self, memory: &mut Memory) -> u8 {
         let addr = self.indirect(memory);
         memory.read_u8(addr)
@@ -2183,6 +2131,7 @@ impl Instruction {
 
     /// Dereferences an indirect y address.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_indirect_y(&self, memory: &mut Memory, cpu: &CPU) -> u8 {
         let addr = self.indirect_y(cpu, memory).0;
         memory.read_u8(addr)
@@ -2232,6 +2181,7 @@ impl Instruction {
 
     /// Dereferences an indirect address.
     #[inline(always)]
+    #[allow(dead_code)]
     fn dereference_indirect_unrestricted(&self, memory: &mut Memory) -> u8 {
         let addr = self.indirect(memory);
         memory.read_u8_unrestricted(addr)
@@ -2387,3 +2337,118 @@ impl Instruction {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nes::cpu::{BREAK_COMMAND, CARRY_FLAG, DECIMAL_MODE, INTERRUPT_DISABLE, NEGATIVE_FLAG,
+                   OVERFLOW_FLAG, ZERO_FLAG};
+    use nes::testing;
+
+    #[test]
+    fn bit_copies_the_top_bits_and_tests_against_the_accumulator() {
+        // BIT $10
+        let (mut cpu, mut memory) = testing::machine(&[0x24, 0x10]);
+        memory.write_u8(0x10, 0x80);
+        cpu.a = 0x7F;
+        cpu.p = OVERFLOW_FLAG | CARRY_FLAG | DECIMAL_MODE | INTERRUPT_DISABLE | 0x20;
+        cpu.step(&mut memory).unwrap();
+
+        // N comes from bit 7 and V from bit 6 of the operand, and Z is set as
+        // the operand and accumulator have no bits in common. Nothing else
+        // changes, including the accumulator.
+        assert_eq!(
+            cpu.p,
+            NEGATIVE_FLAG | ZERO_FLAG | CARRY_FLAG | DECIMAL_MODE | INTERRUPT_DISABLE | 0x20
+        );
+        assert_eq!(cpu.a, 0x7F);
+        assert_eq!(memory.read_u8(0x10), 0x80);
+    }
+
+    #[test]
+    fn read_modify_write_abs_x_has_no_page_cross_penalty() {
+        // LDX #$01; ASL $00FE,X; ASL $00FF,X
+        let program = [0xA2, 0x01, 0x1E, 0xFE, 0x00, 0x1E, 0xFF, 0x00];
+        let (mut cpu, mut memory) = testing::machine(&program);
+        cpu.step(&mut memory).unwrap();
+
+        assert_eq!(cpu.step(&mut memory).unwrap(), 7);
+        assert_eq!(cpu.step(&mut memory).unwrap(), 7);
+    }
+
+    #[test]
+    fn branches_take_a_cycle_when_taken_and_another_across_pages() {
+        // Opcode, the flag it tests, and whether it branches when it's set.
+        let branches = [
+            (0x10, NEGATIVE_FLAG, false),
+            (0x30, NEGATIVE_FLAG, true),
+            (0x50, OVERFLOW_FLAG, false),
+            (0x70, OVERFLOW_FLAG, true),
+            (0x90, CARRY_FLAG, false),
+            (0xB0, CARRY_FLAG, true),
+            (0xD0, ZERO_FLAG, false),
+            (0xF0, ZERO_FLAG, true),
+        ];
+        for &(opcode, flag, taken_when_set) in branches.iter() {
+            // The branch at $8000 stays on its page, the one at $80F0 doesn't.
+            let mut program = vec![0xEA; 0x100];
+            program[0x00..0x02].copy_from_slice(&[opcode, 0x10]);
+            program[0xF0..0xF2].copy_from_slice(&[opcode, 0x20]);
+            let (mut cpu, mut memory) = testing::machine(&program);
+            let taken = if taken_when_set { flag } else { 0 };
+            let not_taken = flag ^ taken;
+
+            let cases = [
+                (0x8000, not_taken, 2, 0x8002),
+                (0x8000, taken, 3, 0x8012),
+                (0x80F0, not_taken, 2, 0x80F2),
+                (0x80F0, taken, 4, 0x8112),
+            ];
+            for &(pc, p, cycles, target) in cases.iter() {
+                cpu.pc = pc;
+                cpu.p = p | INTERRUPT_DISABLE;
+                assert_eq!(cpu.step(&mut memory).unwrap(), cycles, "{:02X} at {:04X}", opcode, pc);
+                assert_eq!(cpu.pc, target, "{:02X} at {:04X}", opcode, pc);
+            }
+        }
+    }
+
+    #[test]
+    fn pulled_status_has_bit_5_set_and_bit_4_clear() {
+        // PLP; RTI
+        let (mut cpu, mut memory) = testing::machine(&[0x28, 0x40]);
+        for &value in [0x00, 0x10, 0x20, 0x30, 0xCF, 0xFF].iter() {
+            let expected = (value | 0x20) & !BREAK_COMMAND;
+
+            cpu.pc = 0x8000;
+            cpu.sp = 0xFC;
+            memory.write_u8(0x1FD, value);
+            cpu.step(&mut memory).unwrap();
+            assert_eq!(cpu.p, expected, "PLP of {:02X}", value);
+
+            // RTI pulls the status and then the return address.
+            cpu.pc = 0x8001;
+            cpu.sp = 0xFA;
+            memory.write_u8(0x1FB, value);
+            memory.write_u16(0x1FC, 0x8000);
+            cpu.step(&mut memory).unwrap();
+            assert_eq!(cpu.p, expected, "RTI of {:02X}", value);
+            assert_eq!(cpu.pc, 0x8000);
+        }
+    }
+
+    #[test]
+    fn shx_stores_x_and_high_byte_plus_one_and_corrupts_crossed_addresses() {
+        // LDX #$05; LDY #$20; SHX $0200,Y; SHX $02F0,Y
+        let program = [0xA2, 0x05, 0xA0, 0x20, 0x9E, 0x00, 0x02, 0x9E, 0xF0, 0x02];
+        let (mut cpu, mut memory) = testing::machine(&program);
+        for _ in 0..4 {
+            cpu.step(&mut memory).unwrap();
+        }
+
+        // Both store X & ($02 + 1). The second crosses into page $03, so the
+        // stored value replaces the high byte of the address.
+        assert_eq!(memory.read_u8(0x0220), 0x01);
+        assert_eq!(memory.read_u8(0x0310), 0x00);
+        assert_eq!(memory.read_u8(0x0110), 0x01);
+    }
+}
@@ -0,0 +1,1284 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use io::binutils::{
+    INESHeader, MirrorType, CHR_ROM_BANK_SIZE, PRG_RAM_BANK_SIZE, PRG_ROM_BANK_SIZE,
+};
+use io::nsf::{self, NsfHeader, NSF_HEADER_SIZE};
+use nes::memory::{PRG_ROM_START, SRAM_START};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Builds a mapper from a cartridge's PRG-ROM and CHR-ROM, using the header
+/// for anything else the board needs to know.
+type Constructor = fn(Vec<u8>, Vec<u8>, &INESHeader) -> Result<Box<Mapper>, String>;
+
+/// An entry in the registry of mappers this build can emulate.
+pub struct MapperInfo {
+    pub number: u8,
+    pub name: &'static str,
+    constructor: Constructor,
+}
+
+/// Every implemented mapper by iNES mapper number. The cartridge loader only
+/// knows about mappers listed here.
+pub const MAPPERS: [MapperInfo; 3] = [
+    MapperInfo {
+        number: 0,
+        name: "NROM",
+        constructor: new_nrom,
+    },
+    MapperInfo {
+        number: 1,
+        name: "MMC1",
+        constructor: new_mmc1,
+    },
+    MapperInfo {
+        number: 9,
+        name: "MMC2",
+        constructor: new_mmc2,
+    },
+];
+
+/// Looks up an implemented mapper by its iNES mapper number.
+pub fn find(number: u8) -> Option<&'static MapperInfo> {
+    MAPPERS.iter().find(|info| info.number == number)
+}
+
+/// A mapper shared between the CPU memory map and the PPU, as both buses are
+/// wired to the cartridge.
+pub type SharedMapper = Rc<RefCell<Box<Mapper>>>;
+
+/// A window of the CPU or PPU address space along with the bank of cartridge
+/// memory currently mapped into it. Banks are numbered in units of the window
+/// size.
+pub struct Bank {
+    pub start: usize,
+    pub end: usize,
+    pub bank: usize,
+}
+
+/// Mappers are the hardware on the cartridge that decide what the CPU sees at
+/// $8000-$FFFF and what the PPU sees at $0000-$1FFF. Most of them allow banks
+/// to be switched by writing to the ROM area, and some control name table
+/// mirroring and raise IRQs.
+pub trait Mapper {
+    /// Reads a byte from the PRG-ROM window ($8000-$FFFF).
+    fn read_prg(&mut self, addr: usize) -> u8;
+
+    /// Reads a byte from the PRG-ROM window without the side effects of a CPU
+    /// read, for the debugger.
+    fn peek_prg(&self, addr: usize) -> u8;
+
+    /// Handles a CPU write to the PRG-ROM window. ROM can't be written so
+    /// these are usually mapper register writes.
+    fn write_prg(&mut self, addr: usize, value: u8);
+
+    /// Returns true if a write to the given address in the PRG-ROM window
+    /// reaches a mapper register. Writes anywhere else are dropped.
+    fn is_register(&self, _addr: usize) -> bool {
+        false
+    }
+
+    /// Handles a CPU write to the expansion area ($4020-$5FFF), where a few
+    /// boards have registers.
+    fn write_expansion(&mut self, _addr: usize, _value: u8) {}
+
+    /// Reads a byte from the pattern tables ($0000-$1FFF on the PPU bus).
+    /// Some mappers watch these reads and switch banks in response.
+    fn read_chr(&mut self, addr: usize) -> u8;
+
+    /// Reads a byte from the pattern tables without the side effects of a PPU
+    /// fetch, for the debugger and anything else looking in from outside.
+    fn peek_chr(&self, addr: usize) -> u8;
+
+    /// Writes a byte to the pattern tables, which only sticks for CHR-RAM.
+    fn write_chr(&mut self, addr: usize, value: u8);
+
+    /// Returns how the name tables are currently mirrored.
+    fn mirroring(&self) -> MirrorType;
+
+    /// Returns the name of the mapper board.
+    fn name(&self) -> &'static str;
+
+    /// Returns which PRG-ROM banks are currently mapped into CPU memory.
+    fn prg_banks(&self) -> Vec<Bank>;
+
+    /// Returns which CHR banks are currently mapped into the pattern tables.
+    fn chr_banks(&self) -> Vec<Bank>;
+
+    /// Reads a byte from PRG-RAM at $6000-$7FFF. None is returned when the
+    /// cart has no PRG-RAM or it's disabled, in which case the bus is open.
+    fn read_prg_ram(&mut self, _addr: usize) -> Option<u8> {
+        None
+    }
+
+    /// Writes a byte to PRG-RAM if the cart has any and it's enabled.
+    fn write_prg_ram(&mut self, _addr: usize, _value: u8) {}
+
+    /// Returns the amount of PRG-RAM on the cart in bytes.
+    fn prg_ram_size(&self) -> usize {
+        0
+    }
+
+    /// Returns the contents of PRG-RAM so battery-backed saves can be written
+    /// to disk.
+    fn prg_ram(&self) -> &[u8] {
+        &[]
+    }
+
+    /// Replaces the contents of PRG-RAM with a save loaded from disk.
+    fn load_prg_ram(&mut self, _data: &[u8]) {}
+
+    /// Returns true while the mapper is holding the CPU's IRQ line.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Returns the mapper's internal state (bank registers, latches, CHR-RAM
+    /// and so on) so it can be stored in a save state. ROM is not included.
+    ///
+    /// Nothing writes save states yet, so only the tests call this and
+    /// deserialize for now.
+    #[allow(dead_code)]
+    fn serialize(&self) -> Vec<u8>;
+
+    /// Restores state previously returned by serialize. An error is returned
+    /// if the data doesn't belong to this mapper.
+    #[allow(dead_code)]
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+/// Encodes name table mirroring as a single byte for save states.
+fn mirroring_to_u8(mirroring: MirrorType) -> u8 {
+    match mirroring {
+        MirrorType::Horizontal => 0,
+        MirrorType::Vertical => 1,
+        MirrorType::Both => 2,
+        MirrorType::SingleLower => 3,
+        MirrorType::SingleUpper => 4,
+    }
+}
+
+/// Decodes name table mirroring stored by mirroring_to_u8.
+fn mirroring_from_u8(value: u8) -> Result<MirrorType, String> {
+    match value {
+        0 => Ok(MirrorType::Horizontal),
+        1 => Ok(MirrorType::Vertical),
+        2 => Ok(MirrorType::Both),
+        3 => Ok(MirrorType::SingleLower),
+        4 => Ok(MirrorType::SingleUpper),
+        _ => Err(format!("invalid mirroring in mapper state: {}", value)),
+    }
+}
+
+/// Work RAM on the cartridge mapped at $6000-$7FFF, which is often battery
+/// backed for saves. Some mappers can disable it, in which case it reads as
+/// open bus like it isn't there at all.
+pub struct PrgRam {
+    data: Vec<u8>,
+    pub enabled: bool,
+}
+
+impl PrgRam {
+    pub fn new(size: usize) -> Self {
+        PrgRam {
+            data: vec![0; size],
+            enabled: true,
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> Option<u8> {
+        if self.enabled && !self.data.is_empty() {
+            Some(self.data[(addr - SRAM_START) % self.data.len()])
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, value: u8) {
+        if self.enabled && !self.data.is_empty() {
+            let len = self.data.len();
+            self.data[(addr - SRAM_START) % len] = value;
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Copies in as much of the given data as fits.
+    pub fn load(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+// CHR is switched in units of 1KB pages, the smallest bank size any mapper
+// uses.
+const CHR_PAGE_SIZE: usize = 0x400;
+const CHR_PAGES: usize = 8;
+
+/// CHR memory on the cartridge, either ROM or 8KB of RAM when the cart has no
+/// CHR-ROM. The pattern tables are split into eight 1KB slots that can each
+/// point at any 1KB page of it, so mappers can switch 1KB, 2KB, 4KB or 8KB
+/// banks. At power on the first 8KB is mapped straight through.
+pub struct ChrMemory {
+    data: Vec<u8>,
+    is_ram: bool,
+
+    // Page of CHR memory mapped into each 1KB slot of the pattern tables.
+    pages: [usize; CHR_PAGES],
+}
+
+impl ChrMemory {
+    pub fn new(chr_rom: Vec<u8>) -> Self {
+        let is_ram = chr_rom.is_empty();
+        let data = if is_ram {
+            vec![0; CHR_ROM_BANK_SIZE]
+        } else {
+            chr_rom
+        };
+
+        ChrMemory {
+            data: data,
+            is_ram: is_ram,
+            pages: [0, 1, 2, 3, 4, 5, 6, 7],
+        }
+    }
+
+    /// Maps a bank of the given size in bytes into a window of the pattern
+    /// tables. Windows are numbered in units of the bank size, so the upper
+    /// pattern table is window 1 for 4KB banks. Banks past the end of CHR
+    /// memory wrap around like they do on hardware with unconnected lines.
+    pub fn switch(&mut self, size: usize, window: usize, bank: usize) {
+        let pages_per_bank = size / CHR_PAGE_SIZE;
+        let bank = bank % (self.data.len() / size).max(1);
+        for page in 0..pages_per_bank {
+            let slot = (window * pages_per_bank + page) % CHR_PAGES;
+            self.pages[slot] = bank * pages_per_bank + page;
+        }
+    }
+
+    /// Returns the bank of the given size mapped into a window, as set by
+    /// switch.
+    pub fn bank(&self, size: usize, window: usize) -> usize {
+        self.pages[window * size / CHR_PAGE_SIZE] * CHR_PAGE_SIZE / size
+    }
+
+    /// Returns the contents of CHR-RAM, or nothing for CHR-ROM.
+    pub fn ram(&self) -> &[u8] {
+        if self.is_ram {
+            &self.data
+        } else {
+            &[]
+        }
+    }
+
+    /// Replaces the contents of CHR-RAM. The data must be the same size.
+    pub fn load_ram(&mut self, data: &[u8]) {
+        if self.is_ram {
+            self.data.copy_from_slice(data);
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        self.data[self.offset(addr)]
+    }
+
+    pub fn write(&mut self, addr: usize, value: u8) {
+        if self.is_ram {
+            let offset = self.offset(addr);
+            self.data[offset] = value;
+        }
+    }
+
+    /// Translates a pattern table address into an offset in CHR memory.
+    fn offset(&self, addr: usize) -> usize {
+        let page = self.pages[(addr / CHR_PAGE_SIZE) % CHR_PAGES];
+        (page * CHR_PAGE_SIZE + addr % CHR_PAGE_SIZE) % self.data.len()
+    }
+}
+
+/// Builds the mapper used by the cartridge in the given iNES ROM, loading its
+/// PRG-ROM and CHR-ROM from the file. The ROM must have been validated against
+/// its header first.
+pub fn load_cartridge(rom: &[u8], header: &INESHeader) -> Result<Box<Mapper>, String> {
+    if nsf::is_nsf(rom) {
+        return load_nsf(rom);
+    }
+    if header.prg_rom_size == 0 {
+        return Err("ROM has no PRG-ROM".to_string());
+    }
+
+    let prg_rom_end = header.chr_rom_offset();
+    let chr_rom_end = prg_rom_end + header.chr_rom_size as usize * CHR_ROM_BANK_SIZE;
+    let prg_rom = rom[header.prg_rom_offset()..prg_rom_end].to_vec();
+    let chr_rom = rom[prg_rom_end..chr_rom_end].to_vec();
+
+    match find(header.mapper_number()) {
+        Some(info) => (info.constructor)(prg_rom, chr_rom, header),
+        None => Err(format!("ROM uses unimplemented mapper: {}", header.mapper_number())),
+    }
+}
+
+/// Builds the pretend cartridge an NSF's music data is played from.
+fn load_nsf(buf: &[u8]) -> Result<Box<Mapper>, String> {
+    let header = try!(NsfHeader::new(buf).map_err(|e| e.to_string()));
+    Ok(Box::new(Nsf::new(&header, &buf[NSF_HEADER_SIZE..])))
+}
+
+fn new_nrom(
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    header: &INESHeader,
+) -> Result<Box<Mapper>, String> {
+    // iNES headers can't tell a board without PRG-RAM apart from one with the
+    // default 8KB, so NROM only gets it when the header hints at it.
+    let has_prg_ram =
+        header.has_persistent_ram() || header.has_trainer() || header.prg_ram_size > 0;
+    let prg_ram = PrgRam::new(if has_prg_ram { header.prg_ram_bytes() } else { 0 });
+    Ok(Box::new(Nrom::new(prg_rom, chr_rom, prg_ram, header.mirror_type())))
+}
+
+fn new_mmc1(
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    header: &INESHeader,
+) -> Result<Box<Mapper>, String> {
+    let prg_ram = PrgRam::new(header.prg_ram_bytes());
+    let four_screen = header.mirror_type() == MirrorType::Both;
+    Ok(Box::new(Mmc1::new(prg_rom, chr_rom, prg_ram, four_screen)))
+}
+
+fn new_mmc2(
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    _header: &INESHeader,
+) -> Result<Box<Mapper>, String> {
+    if prg_rom.len() < 3 * MMC2_PRG_BANK_SIZE || chr_rom.is_empty() {
+        return Err("MMC2 ROM needs at least 24KB of PRG-ROM and some CHR-ROM".to_string());
+    }
+    Ok(Box::new(Mmc2::new(prg_rom, chr_rom)))
+}
+
+/// NROM (mapper 0) has no bank switching. 16KB of PRG-ROM is mirrored into
+/// both halves of the PRG-ROM window, and carts without CHR-ROM have 8KB of
+/// CHR-RAM instead. A few boards (like Family BASIC) add PRG-RAM.
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: ChrMemory,
+    mirroring: MirrorType,
+}
+
+impl Nrom {
+    pub fn new(
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        prg_ram: PrgRam,
+        mirroring: MirrorType,
+    ) -> Self {
+        Nrom {
+            prg_rom: prg_rom,
+            prg_ram: prg_ram,
+            chr: ChrMemory::new(chr_rom),
+            mirroring: mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&mut self, addr: usize) -> u8 {
+        self.peek_prg(addr)
+    }
+
+    fn peek_prg(&self, addr: usize) -> u8 {
+        self.prg_rom[(addr - PRG_ROM_START) % self.prg_rom.len()]
+    }
+
+    fn write_prg(&mut self, _addr: usize, _value: u8) {}
+
+    fn read_prg_ram(&mut self, addr: usize) -> Option<u8> {
+        self.prg_ram.read(addr)
+    }
+
+    fn write_prg_ram(&mut self, addr: usize, value: u8) {
+        self.prg_ram.write(addr, value);
+    }
+
+    fn prg_ram_size(&self) -> usize {
+        self.prg_ram.size()
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.data()
+    }
+
+    fn load_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn read_chr(&mut self, addr: usize) -> u8 {
+        self.peek_chr(addr)
+    }
+
+    fn peek_chr(&self, addr: usize) -> u8 {
+        self.chr.read(addr)
+    }
+
+    fn write_chr(&mut self, addr: usize, value: u8) {
+        self.chr.write(addr, value);
+    }
+
+    fn mirroring(&self) -> MirrorType {
+        self.mirroring
+    }
+
+    fn name(&self) -> &'static str {
+        "NROM"
+    }
+
+    fn prg_banks(&self) -> Vec<Bank> {
+        // Carts with a single 16KB bank mirror it into the upper half.
+        let last_bank = self.prg_rom.len() / PRG_ROM_BANK_SIZE - 1;
+        vec![
+            Bank {
+                start: 0x8000,
+                end: 0xBFFF,
+                bank: 0,
+            },
+            Bank {
+                start: 0xC000,
+                end: 0xFFFF,
+                bank: last_bank,
+            },
+        ]
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        vec![Bank {
+            start: 0x0000,
+            end: 0x1FFF,
+            bank: 0,
+        }]
+    }
+
+    // NROM has no registers, so only PRG-RAM and CHR-RAM need saving.
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = self.prg_ram.data.clone();
+        data.extend_from_slice(self.chr.ram());
+        data
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        let prg_ram_size = self.prg_ram.size();
+        let chr_ram_size = self.chr.ram().len();
+        if data.len() != prg_ram_size + chr_ram_size {
+            return Err(format!(
+                "NROM state should be {} bytes but is {} bytes",
+                prg_ram_size + chr_ram_size,
+                data.len()
+            ));
+        }
+        self.prg_ram.data.copy_from_slice(&data[..prg_ram_size]);
+        self.chr.load_ram(&data[prg_ram_size..]);
+        Ok(())
+    }
+}
+
+const MMC1_PRG_BANK_SIZE: usize = 0x4000;
+const MMC1_CHR_BANK_SIZE: usize = 0x1000;
+
+// Writes with bit 7 set clear the shift register, and every other write
+// shifts bit 0 in. The fifth write copies the shifted value into a register.
+const MMC1_RESET:        u8 = 0b10000000;
+const MMC1_SHIFT_WRITES: u8 = 5;
+
+// Control register bits. The PRG mode picks between switching 32KB at once,
+// or switching 16KB at one end with the other end fixed.
+const MMC1_CONTROL_MIRRORING: u8 = 0b00000011;
+const MMC1_CONTROL_PRG_MODE:  u8 = 0b00001100;
+const MMC1_CONTROL_CHR_4KB:   u8 = 0b00010000;
+
+// PRG bank register bits.
+const MMC1_PRG_BANK:         u8 = 0b00001111;
+const MMC1_PRG_RAM_DISABLED: u8 = 0b00010000;
+
+/// MMC1 (mapper 1), used by The Legend of Zelda, Metroid and many others.
+/// Registers are written one bit at a time through a serial shift register.
+/// PRG-ROM is switched in 16KB or 32KB banks and CHR in 4KB or 8KB banks, and
+/// the mapper also controls mirroring and can disable PRG-RAM.
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: ChrMemory,
+
+    shift_register: u8,
+    shift_writes: u8,
+
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+
+    // Set when the header asks for four-screen mirroring. The board's extra
+    // VRAM takes over the name tables, so the mirroring bits do nothing.
+    four_screen: bool,
+}
+
+impl Mmc1 {
+    /// Creates an MMC1 in the state it powers on in, with the last PRG-ROM
+    /// bank fixed at $C000.
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, prg_ram: PrgRam, four_screen: bool) -> Self {
+        let mut mmc1 = Mmc1 {
+            prg_rom: prg_rom,
+            prg_ram: prg_ram,
+            chr: ChrMemory::new(chr_rom),
+            shift_register: 0,
+            shift_writes: 0,
+            control: MMC1_CONTROL_PRG_MODE,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            four_screen: four_screen,
+        };
+        mmc1.update_banks();
+        mmc1
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / MMC1_PRG_BANK_SIZE
+    }
+
+    /// Returns the 16KB PRG-ROM bank mapped at the given CPU address.
+    fn prg_bank_at(&self, addr: usize) -> usize {
+        let count = self.prg_bank_count();
+        let bank = (self.prg_bank & MMC1_PRG_BANK) as usize;
+        let upper = addr >= 0xC000;
+        let bank = match ((self.control & MMC1_CONTROL_PRG_MODE) >> 2, upper) {
+            (0, _) | (1, _) => (bank & !1) + upper as usize,
+            (2, false) => 0,
+            (2, true) => bank,
+            (_, false) => bank,
+            (_, true) => count - 1,
+        };
+        bank % count
+    }
+
+    /// Copies the value in the shift register into the register selected by
+    /// bits 13 and 14 of the address written to.
+    fn write_register(&mut self, addr: usize, value: u8) {
+        match addr {
+            0x8000...0x9FFF => self.control = value,
+            0xA000...0xBFFF => self.chr_bank_0 = value,
+            0xC000...0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+        self.update_banks();
+    }
+
+    /// Maps the selected CHR banks into the pattern tables and enables or
+    /// disables PRG-RAM. In 8KB mode the low bit of the first CHR bank is
+    /// ignored.
+    fn update_banks(&mut self) {
+        if self.control & MMC1_CONTROL_CHR_4KB > 0 {
+            self.chr.switch(MMC1_CHR_BANK_SIZE, 0, self.chr_bank_0 as usize);
+            self.chr.switch(MMC1_CHR_BANK_SIZE, 1, self.chr_bank_1 as usize);
+        } else {
+            self.chr.switch(MMC1_CHR_BANK_SIZE * 2, 0, self.chr_bank_0 as usize >> 1);
+        }
+        self.prg_ram.enabled = self.prg_bank & MMC1_PRG_RAM_DISABLED == 0;
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_prg(&mut self, addr: usize) -> u8 {
+        self.peek_prg(addr)
+    }
+
+    fn peek_prg(&self, addr: usize) -> u8 {
+        let bank = self.prg_bank_at(addr);
+        self.prg_rom[bank * MMC1_PRG_BANK_SIZE + (addr & (MMC1_PRG_BANK_SIZE - 1))]
+    }
+
+    fn is_register(&self, _addr: usize) -> bool {
+        true
+    }
+
+    fn write_prg(&mut self, addr: usize, value: u8) {
+        if value & MMC1_RESET > 0 {
+            self.shift_register = 0;
+            self.shift_writes = 0;
+            self.control |= MMC1_CONTROL_PRG_MODE;
+            self.update_banks();
+            return;
+        }
+
+        self.shift_register = (self.shift_register >> 1) | ((value & 1) << 4);
+        self.shift_writes += 1;
+        if self.shift_writes == MMC1_SHIFT_WRITES {
+            let value = self.shift_register;
+            self.shift_register = 0;
+            self.shift_writes = 0;
+            self.write_register(addr, value);
+        }
+    }
+
+    fn read_prg_ram(&mut self, addr: usize) -> Option<u8> {
+        self.prg_ram.read(addr)
+    }
+
+    fn write_prg_ram(&mut self, addr: usize, value: u8) {
+        self.prg_ram.write(addr, value);
+    }
+
+    fn prg_ram_size(&self) -> usize {
+        self.prg_ram.size()
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        self.prg_ram.data()
+    }
+
+    fn load_prg_ram(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+
+    fn read_chr(&mut self, addr: usize) -> u8 {
+        self.peek_chr(addr)
+    }
+
+    fn peek_chr(&self, addr: usize) -> u8 {
+        self.chr.read(addr)
+    }
+
+    fn write_chr(&mut self, addr: usize, value: u8) {
+        self.chr.write(addr, value);
+    }
+
+    fn mirroring(&self) -> MirrorType {
+        if self.four_screen {
+            return MirrorType::Both;
+        }
+        match self.control & MMC1_CONTROL_MIRRORING {
+            0 => MirrorType::SingleLower,
+            1 => MirrorType::SingleUpper,
+            2 => MirrorType::Vertical,
+            _ => MirrorType::Horizontal,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "MMC1"
+    }
+
+    fn prg_banks(&self) -> Vec<Bank> {
+        vec![
+            Bank {
+                start: 0x8000,
+                end: 0xBFFF,
+                bank: self.prg_bank_at(0x8000),
+            },
+            Bank {
+                start: 0xC000,
+                end: 0xFFFF,
+                bank: self.prg_bank_at(0xC000),
+            },
+        ]
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        (0..2)
+            .map(|table| {
+                let start = table * MMC1_CHR_BANK_SIZE;
+                Bank {
+                    start: start,
+                    end: start + MMC1_CHR_BANK_SIZE - 1,
+                    bank: self.chr.bank(MMC1_CHR_BANK_SIZE, table),
+                }
+            })
+            .collect()
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = vec![
+            self.shift_register,
+            self.shift_writes,
+            self.control,
+            self.chr_bank_0,
+            self.chr_bank_1,
+            self.prg_bank,
+        ];
+        data.extend_from_slice(self.prg_ram.data());
+        data.extend_from_slice(self.chr.ram());
+        data
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        let size = 6 + self.prg_ram.size() + self.chr.ram().len();
+        if data.len() != size {
+            return Err(format!("MMC1 state should be {} bytes but is {} bytes", size, data.len()));
+        }
+        if data[1] >= MMC1_SHIFT_WRITES {
+            return Err("invalid shift register in MMC1 state".to_string());
+        }
+
+        self.shift_register = data[0] & 0x1F;
+        self.shift_writes = data[1];
+        self.control = data[2] & 0x1F;
+        self.chr_bank_0 = data[3] & 0x1F;
+        self.chr_bank_1 = data[4] & 0x1F;
+        self.prg_bank = data[5] & 0x1F;
+        let prg_ram_end = 6 + self.prg_ram.size();
+        self.prg_ram.load(&data[6..prg_ram_end]);
+        self.chr.load_ram(&data[prg_ram_end..]);
+        self.update_banks();
+        Ok(())
+    }
+}
+
+const MMC2_PRG_BANK_SIZE: usize = 0x2000;
+const MMC2_CHR_BANK_SIZE: usize = 0x1000;
+
+// CHR bank latch values. The latches are set by the PPU fetching tile $FD or
+// $FE from the matching pattern table.
+const MMC2_LATCH_FD: usize = 0;
+const MMC2_LATCH_FE: usize = 1;
+
+/// MMC2 (mapper 9), used by Punch-Out!!. An 8KB PRG-ROM bank is switchable at
+/// $8000 with the last three 8KB banks fixed above it. Each 4KB half of the
+/// pattern tables has two CHR banks selected by a latch, which flips when the
+/// PPU reads the last row of tile $FD or $FE from that half. This lets a game
+/// switch graphics partway down the screen without any CPU involvement.
+pub struct Mmc2 {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    prg_bank: usize,
+
+    // CHR banks indexed by pattern table then latch value.
+    chr_banks: [[usize; 2]; 2],
+    latches: [usize; 2],
+    mirroring: MirrorType,
+}
+
+impl Mmc2 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let mut mmc2 = Mmc2 {
+            prg_rom: prg_rom,
+            chr: ChrMemory::new(chr_rom),
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [MMC2_LATCH_FE; 2],
+            mirroring: MirrorType::Vertical,
+        };
+        mmc2.update_chr_banks();
+        mmc2
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / MMC2_PRG_BANK_SIZE
+    }
+
+    /// Returns the 8KB PRG-ROM bank mapped at the given CPU address.
+    fn prg_bank_at(&self, addr: usize) -> usize {
+        let count = self.prg_bank_count();
+        match addr {
+            0x8000...0x9FFF => self.prg_bank % count,
+            0xA000...0xBFFF => count - 3,
+            0xC000...0xDFFF => count - 2,
+            _ => count - 1,
+        }
+    }
+
+    /// Maps the CHR bank selected by each latch into its pattern table.
+    fn update_chr_banks(&mut self) {
+        for table in 0..2 {
+            let bank = self.chr_banks[table][self.latches[table]];
+            self.chr.switch(MMC2_CHR_BANK_SIZE, table, bank);
+        }
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn read_prg(&mut self, addr: usize) -> u8 {
+        self.peek_prg(addr)
+    }
+
+    fn peek_prg(&self, addr: usize) -> u8 {
+        let bank = self.prg_bank_at(addr);
+        self.prg_rom[bank * MMC2_PRG_BANK_SIZE + (addr & (MMC2_PRG_BANK_SIZE - 1))]
+    }
+
+    fn is_register(&self, addr: usize) -> bool {
+        addr >= 0xA000
+    }
+
+    fn write_prg(&mut self, addr: usize, value: u8) {
+        let value = value as usize;
+        match addr {
+            0xA000...0xAFFF => self.prg_bank = value & 0x0F,
+            0xB000...0xBFFF => self.chr_banks[0][MMC2_LATCH_FD] = value & 0x1F,
+            0xC000...0xCFFF => self.chr_banks[0][MMC2_LATCH_FE] = value & 0x1F,
+            0xD000...0xDFFF => self.chr_banks[1][MMC2_LATCH_FD] = value & 0x1F,
+            0xE000...0xEFFF => self.chr_banks[1][MMC2_LATCH_FE] = value & 0x1F,
+            0xF000...0xFFFF => {
+                self.mirroring = if value & 1 == 0 {
+                    MirrorType::Vertical
+                } else {
+                    MirrorType::Horizontal
+                };
+            }
+            _ => {}
+        }
+        self.update_chr_banks();
+    }
+
+    fn read_chr(&mut self, addr: usize) -> u8 {
+        let value = self.peek_chr(addr);
+
+        // The latch switches after the triggering fetch, so the read above still
+        // comes from the old bank. The lower table only reacts to one address
+        // while the upper one reacts to a range.
+        match addr & 0x1FFF {
+            0x0FD8 => self.latches[0] = MMC2_LATCH_FD,
+            0x0FE8 => self.latches[0] = MMC2_LATCH_FE,
+            0x1FD8...0x1FDF => self.latches[1] = MMC2_LATCH_FD,
+            0x1FE8...0x1FEF => self.latches[1] = MMC2_LATCH_FE,
+            _ => return value,
+        }
+        self.update_chr_banks();
+
+        value
+    }
+
+    fn peek_chr(&self, addr: usize) -> u8 {
+        self.chr.read(addr)
+    }
+
+    // CHR is always ROM on MMC2 boards.
+    fn write_chr(&mut self, _addr: usize, _value: u8) {}
+
+    fn mirroring(&self) -> MirrorType {
+        self.mirroring
+    }
+
+    fn name(&self) -> &'static str {
+        "MMC2"
+    }
+
+    fn prg_banks(&self) -> Vec<Bank> {
+        (0..4)
+            .map(|window| {
+                let start = 0x8000 + window * MMC2_PRG_BANK_SIZE;
+                Bank {
+                    start: start,
+                    end: start + MMC2_PRG_BANK_SIZE - 1,
+                    bank: self.prg_bank_at(start),
+                }
+            })
+            .collect()
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        (0..2)
+            .map(|table| {
+                let start = table * MMC2_CHR_BANK_SIZE;
+                Bank {
+                    start: start,
+                    end: start + MMC2_CHR_BANK_SIZE - 1,
+                    bank: self.chr.bank(MMC2_CHR_BANK_SIZE, table),
+                }
+            })
+            .collect()
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![
+            self.prg_bank as u8,
+            self.chr_banks[0][MMC2_LATCH_FD] as u8,
+            self.chr_banks[0][MMC2_LATCH_FE] as u8,
+            self.chr_banks[1][MMC2_LATCH_FD] as u8,
+            self.chr_banks[1][MMC2_LATCH_FE] as u8,
+            self.latches[0] as u8,
+            self.latches[1] as u8,
+            mirroring_to_u8(self.mirroring),
+        ]
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != 8 {
+            return Err(format!("MMC2 state should be 8 bytes but is {} bytes", data.len()));
+        }
+        if data[5] > 1 || data[6] > 1 {
+            return Err("invalid CHR latch in MMC2 state".to_string());
+        }
+
+        self.mirroring = try!(mirroring_from_u8(data[7]));
+        self.prg_bank = data[0] as usize & 0x0F;
+        self.chr_banks[0][MMC2_LATCH_FD] = data[1] as usize & 0x1F;
+        self.chr_banks[0][MMC2_LATCH_FE] = data[2] as usize & 0x1F;
+        self.chr_banks[1][MMC2_LATCH_FD] = data[3] as usize & 0x1F;
+        self.chr_banks[1][MMC2_LATCH_FE] = data[4] as usize & 0x1F;
+        self.latches = [data[5] as usize, data[6] as usize];
+        self.update_chr_banks();
+        Ok(())
+    }
+}
+
+const NSF_BANK_SIZE: usize = 0x1000;
+const NSF_BANKS:     usize = 8;
+
+// Bank registers for each 4KB window of $8000-$FFFF.
+const NSF_BANK_REGISTERS_START: usize = 0x5FF8;
+const NSF_BANK_REGISTERS_END:   usize = 0x5FFF;
+
+/// The cartridge NSF music data is played from. Music that isn't bank switched
+/// is loaded at its load address. Bank switched music is split into 4KB banks
+/// (padded at the start so the load address lines up with a bank boundary)
+/// which are mapped into $8000-$FFFF by writing to $5FF8-$5FFF. There's also
+/// 8KB of work RAM at $6000-$7FFF.
+pub struct Nsf {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: ChrMemory,
+
+    // Bank mapped into each 4KB window.
+    banks: [usize; NSF_BANKS],
+}
+
+impl Nsf {
+    pub fn new(header: &NsfHeader, data: &[u8]) -> Self {
+        let load_addr = header.load_addr as usize;
+        let padding = if header.uses_bankswitching() {
+            load_addr & (NSF_BANK_SIZE - 1)
+        } else {
+            load_addr - PRG_ROM_START
+        };
+        let mut prg_rom = vec![0; padding];
+        prg_rom.extend_from_slice(data);
+        let size = if header.uses_bankswitching() {
+            (prg_rom.len() + NSF_BANK_SIZE - 1) / NSF_BANK_SIZE * NSF_BANK_SIZE
+        } else {
+            NSF_BANK_SIZE * NSF_BANKS
+        };
+        prg_rom.resize(size, 0);
+
+        let mut nsf = Nsf {
+            prg_rom: prg_rom,
+            prg_ram: PrgRam::new(PRG_RAM_BANK_SIZE),
+            chr: ChrMemory::new(Vec::new()),
+            banks: [0, 1, 2, 3, 4, 5, 6, 7],
+        };
+        if header.uses_bankswitching() {
+            for (window, &bank) in header.bankswitch.iter().enumerate() {
+                nsf.switch(window, bank);
+            }
+        }
+        nsf
+    }
+
+    /// Maps a 4KB bank into a window of $8000-$FFFF. Banks past the end of the
+    /// music data wrap around.
+    fn switch(&mut self, window: usize, bank: u8) {
+        let count = self.prg_rom.len() / NSF_BANK_SIZE;
+        self.banks[window] = bank as usize % count;
+    }
+}
+
+impl Mapper for Nsf {
+    fn read_prg(&mut self, addr: usize) -> u8 {
+        self.peek_prg(addr)
+    }
+
+    fn peek_prg(&self, addr: usize) -> u8 {
+        let bank = self.banks[(addr - PRG_ROM_START) / NSF_BANK_SIZE];
+        self.prg_rom[bank * NSF_BANK_SIZE + (addr & (NSF_BANK_SIZE - 1))]
+    }
+
+    fn write_prg(&mut self, _addr: usize, _value: u8) {}
+
+    fn write_expansion(&mut self, addr: usize, value: u8) {
+        if addr >= NSF_BANK_REGISTERS_START && addr <= NSF_BANK_REGISTERS_END {
+            self.switch(addr - NSF_BANK_REGISTERS_START, value);
+        }
+    }
+
+    fn read_prg_ram(&mut self, addr: usize) -> Option<u8> {
+        self.prg_ram.read(addr)
+    }
+
+    fn write_prg_ram(&mut self, addr: usize, value: u8) {
+        self.prg_ram.write(addr, value);
+    }
+
+    fn prg_ram_size(&self) -> usize {
+        self.prg_ram.size()
+    }
+
+    fn read_chr(&mut self, addr: usize) -> u8 {
+        self.peek_chr(addr)
+    }
+
+    fn peek_chr(&self, addr: usize) -> u8 {
+        self.chr.read(addr)
+    }
+
+    fn write_chr(&mut self, addr: usize, value: u8) {
+        self.chr.write(addr, value);
+    }
+
+    fn mirroring(&self) -> MirrorType {
+        MirrorType::Vertical
+    }
+
+    fn name(&self) -> &'static str {
+        "NSF"
+    }
+
+    fn prg_banks(&self) -> Vec<Bank> {
+        (0..NSF_BANKS)
+            .map(|window| {
+                let start = PRG_ROM_START + window * NSF_BANK_SIZE;
+                Bank {
+                    start: start,
+                    end: start + NSF_BANK_SIZE - 1,
+                    bank: self.banks[window],
+                }
+            })
+            .collect()
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        vec![Bank {
+            start: 0x0000,
+            end: 0x1FFF,
+            bank: 0,
+        }]
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = self.banks.iter().map(|&bank| bank as u8).collect();
+        data.extend_from_slice(self.prg_ram.data());
+        data.extend_from_slice(self.chr.ram());
+        data
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        let size = NSF_BANKS + self.prg_ram.size() + self.chr.ram().len();
+        if data.len() != size {
+            return Err(format!("NSF state should be {} bytes but is {} bytes", size, data.len()));
+        }
+        for window in 0..NSF_BANKS {
+            self.switch(window, data[window]);
+        }
+        let prg_ram_end = NSF_BANKS + self.prg_ram.size();
+        self.prg_ram.load(&data[NSF_BANKS..prg_ram_end]);
+        self.chr.load_ram(&data[prg_ram_end..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::binutils::HEADER_SIZE;
+    use nes::testing;
+
+    /// Builds an MMC1 image with 8 PRG-ROM banks, each starting with its own
+    /// bank number, and 8KB of CHR-RAM.
+    fn mmc1_rom() -> Vec<u8> {
+        let mut rom = vec![0; HEADER_SIZE + 8 * PRG_ROM_BANK_SIZE];
+        rom[0..7].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A, 8, 0, 0x10]);
+        for bank in 0..8 {
+            rom[HEADER_SIZE + bank * PRG_ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    /// Writes a value to an MMC1 register one bit at a time.
+    fn write_mmc1(mapper: &mut Mapper, addr: usize, value: u8) {
+        for bit in 0..5 {
+            mapper.write_prg(addr, value >> bit);
+        }
+    }
+
+    #[test]
+    fn loading_mmc1_state_restores_the_bank() {
+        let mapper = testing::mapper(&mmc1_rom());
+        let mut mapper = mapper.borrow_mut();
+        assert_eq!(mapper.name(), "MMC1");
+        assert_eq!(mapper.read_prg(0xC000), 7);
+
+        write_mmc1(&mut **mapper, 0xE000, 2);
+        assert_eq!(mapper.read_prg(0x8000), 2);
+        let state = mapper.serialize();
+
+        write_mmc1(&mut **mapper, 0xE000, 5);
+        assert_eq!(mapper.read_prg(0x8000), 5);
+
+        mapper.deserialize(&state).unwrap();
+        assert_eq!(mapper.read_prg(0x8000), 2);
+        assert_eq!(mapper.read_prg(0xC000), 7);
+        assert!(mapper.deserialize(&state[1..]).is_err());
+    }
+
+    #[test]
+    fn every_registered_mapper_answers_basic_reads() {
+        for info in MAPPERS.iter() {
+            let mut rom = vec![0; HEADER_SIZE + 2 * PRG_ROM_BANK_SIZE + CHR_ROM_BANK_SIZE];
+            rom[0..8].copy_from_slice(&[
+                0x4E, 0x45, 0x53, 0x1A, 2, 1, info.number << 4, info.number & 0xF0
+            ]);
+            rom[HEADER_SIZE + 2 * PRG_ROM_BANK_SIZE - 1] = 0x42;
+            rom[HEADER_SIZE + 2 * PRG_ROM_BANK_SIZE] = 0x24;
+
+            let mapper = testing::mapper(&rom);
+            let mut mapper = mapper.borrow_mut();
+            assert_eq!(mapper.name(), info.name);
+            assert_eq!(mapper.read_prg(0xFFFF), 0x42, "{}", info.name);
+            assert_eq!(mapper.read_chr(0x0000), 0x24, "{}", info.name);
+        }
+    }
+
+    #[test]
+    fn mmc2_latches_switch_chr_banks_after_the_trigger_fetch() {
+        // Four 4KB CHR banks, each filled with its own bank number.
+        let mut rom = vec![0; HEADER_SIZE + 2 * PRG_ROM_BANK_SIZE + 2 * CHR_ROM_BANK_SIZE];
+        rom[0..8].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A, 2, 2, 0x90, 0x00]);
+        let chr_start = HEADER_SIZE + 2 * PRG_ROM_BANK_SIZE;
+        for bank in 0..4 {
+            let start = chr_start + bank * 0x1000;
+            for byte in &mut rom[start..start + 0x1000] {
+                *byte = bank as u8;
+            }
+        }
+
+        let mapper = testing::mapper(&rom);
+        let mut mapper = mapper.borrow_mut();
+        mapper.write_prg(0xB000, 0);
+        mapper.write_prg(0xC000, 1);
+        mapper.write_prg(0xD000, 2);
+        mapper.write_prg(0xE000, 3);
+
+        // Both latches power on holding $FE.
+        assert_eq!(mapper.read_chr(0x0000), 1);
+        assert_eq!(mapper.read_chr(0x1000), 3);
+
+        // The lower table only reacts to $0FD8 and $0FE8, and the fetch that
+        // flips the latch still comes from the old bank.
+        assert_eq!(mapper.read_chr(0x0FD9), 1);
+        assert_eq!(mapper.read_chr(0x0FD8), 1);
+        assert_eq!(mapper.read_chr(0x0000), 0);
+        assert_eq!(mapper.read_chr(0x0FE9), 0);
+        assert_eq!(mapper.read_chr(0x0FE8), 0);
+        assert_eq!(mapper.read_chr(0x0000), 1);
+
+        // The upper table reacts to the whole last row of the tile.
+        for addr in 0x1FD8..0x1FE0 {
+            mapper.read_chr(0x1FE8);
+            mapper.read_chr(addr);
+            assert_eq!(mapper.read_chr(0x1000), 2, "${:04X}", addr);
+        }
+        for addr in 0x1FE8..0x1FF0 {
+            mapper.read_chr(0x1FD8);
+            mapper.read_chr(addr);
+            assert_eq!(mapper.read_chr(0x1000), 3, "${:04X}", addr);
+        }
+        assert_eq!(mapper.read_chr(0x0000), 1);
+    }
+
+    /// A mapper that switches a 1KB CHR window whenever PRG-ROM is written,
+    /// with the window in the low bits of the address and the bank as the
+    /// value.
+    struct ChrSwitcher {
+        chr: ChrMemory,
+    }
+
+    impl Mapper for ChrSwitcher {
+        fn read_prg(&mut self, _addr: usize) -> u8 {
+            0
+        }
+
+        fn peek_prg(&self, _addr: usize) -> u8 {
+            0
+        }
+
+        fn write_prg(&mut self, addr: usize, value: u8) {
+            self.chr.switch(0x400, addr & 0x07, value as usize);
+        }
+
+        fn read_chr(&mut self, addr: usize) -> u8 {
+            self.peek_chr(addr)
+        }
+
+        fn peek_chr(&self, addr: usize) -> u8 {
+            self.chr.read(addr)
+        }
+
+        fn write_chr(&mut self, addr: usize, value: u8) {
+            self.chr.write(addr, value);
+        }
+
+        fn mirroring(&self) -> MirrorType {
+            MirrorType::Vertical
+        }
+
+        fn name(&self) -> &'static str {
+            "CHR switcher"
+        }
+
+        fn prg_banks(&self) -> Vec<Bank> {
+            Vec::new()
+        }
+
+        fn chr_banks(&self) -> Vec<Bank> {
+            Vec::new()
+        }
+
+        fn serialize(&self) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn deserialize(&mut self, _data: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chr_banks_switched_between_fetches_take_effect_immediately() {
+        // 16 1KB banks, each filled with its own bank number.
+        let chr_rom: Vec<u8> = (0..16 * 0x400).map(|offset| (offset / 0x400) as u8).collect();
+        let mut mapper = ChrSwitcher {
+            chr: ChrMemory::new(chr_rom),
+        };
+        let fetches: Vec<u8> = (0..8).map(|window| mapper.read_chr(window * 0x400)).collect();
+        assert_eq!(fetches, vec![0, 1, 2, 3, 4, 5, 6, 7]);
+
+        mapper.write_prg(0x8003, 12);
+        assert_eq!(mapper.read_chr(0x0C00), 12);
+        assert_eq!(mapper.read_chr(0x0FFF), 12);
+        mapper.write_prg(0x8003, 9);
+        assert_eq!(mapper.read_chr(0x0C00), 9);
+
+        // Neighbouring windows and larger banks see the same switches.
+        assert_eq!(mapper.read_chr(0x0800), 2);
+        assert_eq!(mapper.read_chr(0x1000), 4);
+        assert_eq!(mapper.chr.bank(0x400, 3), 9);
+        mapper.chr.switch(0x1000, 1, 2);
+        assert_eq!(mapper.read_chr(0x1C00), 11);
+
+        // Banks past the end wrap around.
+        mapper.write_prg(0x8000, 17);
+        assert_eq!(mapper.read_chr(0x0000), 1);
+    }
+}
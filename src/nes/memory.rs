@@ -7,7 +7,12 @@
 // except according to those terms.
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use io::log;
+use nes::controller::Controller;
 use nes::cpu::CPU;
+use nes::mapper::SharedMapper;
+use nes::nes::NESRuntimeOptions;
+use nes::zapper::Zapper;
 use std::io::Cursor;
 
 // Memory partition sizes (physical).
@@ -16,8 +21,6 @@ pub const RAM_SIZE: usize = 0x800;
 pub const PPU_CTRL_REGISTERS_SIZE: usize = 0x8;
 pub const MISC_CTRL_REGISTERS_SIZE: usize = 0x20;
 pub const EXPANSION_ROM_SIZE: usize = 0x1FE0;
-pub const SRAM_SIZE: usize = 0x2000;
-pub const PRG_ROM_SIZE: usize = 0x4000;
 
 // Partitioned virtual memory map bounds.
 pub const RAM_START_ADDR: usize = 0x0;
@@ -34,18 +37,38 @@ pub const EXPANSION_ROM_START: usize = 0x4020;
 pub const EXPANSION_ROM_END: usize = 0x5FFF;
 pub const SRAM_START: usize = 0x6000;
 pub const SRAM_END: usize = 0x7FFF;
-pub const PRG_ROM_1_START: usize = 0x8000;
-pub const PRG_ROM_1_END: usize = 0xBFFF;
-pub const PRG_ROM_2_START: usize = 0xC000;
-pub const PRG_ROM_2_END: usize = 0xFFFF;
+pub const PRG_ROM_START: usize = 0x8000;
+pub const PRG_ROM_END: usize = 0xFFFF;
 
 // Constants for additional structures.
 pub const TRAINER_START: usize = 0x7000;
-pub const TRAINER_SIZE: usize = 512;
 
 // Location of the DMA register for copying sprite data to the PPU.
+#[allow(dead_code)]
 pub const DMA_REGISTER: usize = 0x4014;
 
+// Location of the first controller port. Reads shift out one button at a time
+// so they can't go through the passive register array. Writes strobe both
+// controllers.
+pub const CONTROLLER_1_PORT: usize = 0x4016;
+
+// Location of the second controller port. Only reads go to the controller, as
+// writes to the same address set up the APU frame counter instead.
+pub const CONTROLLER_2_PORT: usize = 0x4017;
+
+// Signatures the Four Score sends after the second controller on each port,
+// which games use to detect it. They're shifted out LSB first like the
+// buttons, so the single 1 bit arrives on read 20 of $4016 and read 19 of
+// $4017.
+const FOUR_SCORE_SIGNATURE_1: u8 = 0x08;
+const FOUR_SCORE_SIGNATURE_2: u8 = 0x04;
+
+// Bits of the controller ports that aren't driven and read back as open bus.
+// The last byte on the bus before the read is the high byte of the address
+// being read, so these are almost always $40 and games like Paperboy rely on
+// that. Bits 1-4 are driven low by a standard controller.
+const CONTROLLER_OPEN_BUS_BITS: u8 = 0xE0;
+
 // Location of the first byte on the bottom of the stack. The stack starts on
 // memory page 2 (0x100).
 const STACK_OFFSET: usize = 0x100;
@@ -70,6 +93,23 @@ pub enum PPURegisterStatus {
     Untouched,
 }
 
+/// Whether a recorded bus access was a read or a write.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A read or write made through the CPU's bus, recorded for the debugger's
+/// watchpoints. For writes, old is what the address held beforehand.
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+    pub addr: usize,
+    pub kind: AccessKind,
+    pub old: u8,
+    pub value: u8,
+}
+
 /// Possible states of the misc registers.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MiscRegisterStatus {
@@ -103,80 +143,239 @@ pub struct Memory {
     // Current read / write status of all misc registers stored in memory.
     pub misc_ctrl_registers_status: [MiscRegisterStatus; MISC_CTRL_REGISTERS_SIZE],
 
+    // Set whenever the APU status register ($4015) is read. Reads have side
+    // effects, so they are latched here separately from the register status
+    // which a write in the same instruction would otherwise overwrite. The APU
+    // clears this once it has handled the read.
+    pub apu_status_read: bool,
+
     // TODO: Add ring buffer for double write register values.
     expansion_rom: [u8; EXPANSION_ROM_SIZE],
-    sram: [u8; SRAM_SIZE],
 
-    // Read-only ROM which contains executable code and assets.
-    prg_rom_1: [u8; PRG_ROM_SIZE],
-    prg_rom_2: [u8; PRG_ROM_SIZE],
+    // The cartridge's mapper decides what is at $6000-$FFFF, which is usually
+    // a bank of read-only ROM containing executable code and assets, and
+    // sometimes PRG-RAM.
+    mapper: SharedMapper,
+
+    // The standard controllers plugged into the first and second ports.
+    pub controller_1: Controller,
+    pub controller_2: Controller,
+
+    // A Zapper plugged into the second port instead of a controller.
+    pub zapper: Option<Zapper>,
+
+    // Controllers for players 3 and 4, which are only read when a Four Score
+    // is plugged in. Player 3 is chained after player 1 on the first port, and
+    // player 4 after player 2 on the second.
+    pub controller_3: Controller,
+    pub controller_4: Controller,
+    four_score: bool,
+
+    // Set whenever PRG-RAM is written so battery-backed saves are only
+    // flushed to disk when they've changed.
+    pub sram_dirty: bool,
+
+    // The last value seen on the data bus. Reading from an address nothing
+    // responds to returns this.
+    open_bus: u8,
+
+    // Reads and writes made since this was last cleared, which the debugger
+    // checks its watchpoints against. Nothing is recorded while this is None
+    // so normal emulation doesn't pay for it.
+    pub accesses: Option<Vec<Access>>,
+
+    // Options passed from the command-line. Used to decide whether stack
+    // pointer wrapping should be reported.
+    runtime_options: NESRuntimeOptions,
 }
 
 impl Memory {
-    /// Returns an instance of memory with all banks initialized.
-    pub fn new() -> Self {
+    /// Returns an instance of memory with all banks initialized and the
+    /// cartridge's mapper plugged in.
+    pub fn new(runtime_options: NESRuntimeOptions, mapper: SharedMapper) -> Self {
         Memory {
             ram: [0; RAM_SIZE],
             ppu_ctrl_registers: [0; PPU_CTRL_REGISTERS_SIZE],
             ppu_ctrl_registers_status: [PPURegisterStatus::Untouched; PPU_CTRL_REGISTERS_SIZE],
             misc_ctrl_registers: [0; MISC_CTRL_REGISTERS_SIZE],
             misc_ctrl_registers_status: [MiscRegisterStatus::Untouched; MISC_CTRL_REGISTERS_SIZE],
+            apu_status_read: false,
             expansion_rom: [0; EXPANSION_ROM_SIZE],
-            sram: [0; SRAM_SIZE],
-            prg_rom_1: [0; PRG_ROM_SIZE],
-            prg_rom_2: [0; PRG_ROM_SIZE],
+            mapper: mapper,
+            controller_1: Controller::new(),
+            controller_2: Controller::new(),
+            zapper: None,
+            controller_3: Controller::new(),
+            controller_4: Controller::new(),
+            four_score: runtime_options.four_score,
+            sram_dirty: false,
+            open_bus: 0,
+            accesses: None,
+            runtime_options: runtime_options,
+        }
+    }
+
+    /// Returns the controller for the given player, counting from 0. Players
+    /// 3 and 4 are only read with a Four Score.
+    pub fn controller_mut(&mut self, port: usize) -> &mut Controller {
+        match port {
+            0 => &mut self.controller_1,
+            1 => &mut self.controller_2,
+            2 => &mut self.controller_3,
+            _ => &mut self.controller_4,
         }
     }
 
     /// Reads an unsigned 8-bit byte value located at the given virtual address.
     #[inline(always)]
     pub fn read_u8(&mut self, addr: usize) -> u8 {
-        let mapping_result = self.map(addr, MemoryOperation::Read);
-        if mapping_result.readable {
-            mapping_result.bank[mapping_result.addr]
+        // Internal RAM is accessed far more than anything else, so its mirrors
+        // are folded here rather than going through the full mapping.
+        let value = if addr <= RAM_MIRROR_END {
+            self.ram[addr & (RAM_SIZE - 1)]
+        } else if addr == CONTROLLER_1_PORT {
+            self.controller_1.read() | (self.open_bus & CONTROLLER_OPEN_BUS_BITS)
+        } else if addr == CONTROLLER_2_PORT {
+            self.read_port_2() | (self.open_bus & CONTROLLER_OPEN_BUS_BITS)
+        } else if addr >= PRG_ROM_START {
+            self.mapper.borrow_mut().read_prg(addr)
+        } else if addr >= SRAM_START {
+            self.read_prg_ram(addr)
         } else {
-            0
+            let open_bus = self.open_bus;
+            let mapping_result = self.map(addr, MemoryOperation::Read);
+            if mapping_result.readable {
+                mapping_result.bank[mapping_result.addr]
+            } else {
+                open_bus
+            }
+        };
+        self.open_bus = value;
+        if let Some(ref mut accesses) = self.accesses {
+            accesses.push(Access {
+                addr: addr,
+                kind: AccessKind::Read,
+                old: value,
+                value: value,
+            });
         }
+        value
     }
 
     /// Writes an unsigned 8-bit byte value to the given virtual address.
     #[inline(always)]
     pub fn write_u8(&mut self, addr: usize, val: u8) {
+        if self.accesses.is_some() {
+            let old = self.read_u8_unrestricted(addr);
+            self.accesses.as_mut().unwrap().push(Access {
+                addr: addr,
+                kind: AccessKind::Write,
+                old: old,
+                value: val,
+            });
+        }
+        self.open_bus = val;
+        if addr <= RAM_MIRROR_END {
+            self.ram[addr & (RAM_SIZE - 1)] = val;
+            return;
+        } else if addr == CONTROLLER_1_PORT {
+            self.controller_1.write(val);
+            self.controller_2.write(val);
+            if self.four_score {
+                self.controller_1.chain(self.controller_3.buttons, FOUR_SCORE_SIGNATURE_1);
+                self.controller_2.chain(self.controller_4.buttons, FOUR_SCORE_SIGNATURE_2);
+            }
+        } else if addr >= PRG_ROM_START {
+            self.warn_rom_write(addr, val);
+            self.mapper.borrow_mut().write_prg(addr, val);
+            return;
+        } else if addr >= SRAM_START {
+            self.mapper.borrow_mut().write_prg_ram(addr, val);
+            self.sram_dirty = true;
+            return;
+        } else if addr >= EXPANSION_ROM_START {
+            self.mapper.borrow_mut().write_expansion(addr, val);
+            return;
+        }
         let mapping_result = self.map(addr, MemoryOperation::Write);
         if mapping_result.writable {
             mapping_result.bank[mapping_result.addr] = val;
         }
     }
 
-    /// Reads an unsigned 8-bit byte value located at the given virtual address.
+    /// Returns the value a read at the given virtual address would, without
+    /// any of the side effects of reading. This is meant for the debugger and
+    /// disassembler, which need to look at memory without disturbing it.
+    ///
+    /// RAM, PRG-RAM and PRG-ROM are peeked directly. The controller port shows
+    /// the next button without shifting, and I/O registers show the value they
+    /// currently hold for reading, without marking them as read. Registers that
+    /// can't be read show open bus like a real read would.
     #[inline(always)]
     pub fn read_u8_unrestricted(&mut self, addr: usize) -> u8 {
+        if addr <= RAM_MIRROR_END {
+            return self.ram[addr & (RAM_SIZE - 1)];
+        } else if addr == CONTROLLER_1_PORT {
+            return self.controller_1.peek() | (self.open_bus & CONTROLLER_OPEN_BUS_BITS);
+        } else if addr == CONTROLLER_2_PORT {
+            let value = match self.zapper {
+                Some(ref zapper) => zapper.read(),
+                None => self.controller_2.peek(),
+            };
+            return value | (self.open_bus & CONTROLLER_OPEN_BUS_BITS);
+        } else if addr >= PRG_ROM_START {
+            return self.mapper.borrow().peek_prg(addr);
+        } else if addr >= SRAM_START {
+            return self.read_prg_ram(addr);
+        }
+        let open_bus = self.open_bus;
         let mapping_result = self.map(addr, MemoryOperation::Nop);
-        mapping_result.bank[mapping_result.addr]
+        if mapping_result.readable {
+            mapping_result.bank[mapping_result.addr]
+        } else {
+            open_bus
+        }
     }
 
-    /// Writes an unsigned 8-bit byte value to the given virtual address.
+    /// Writes an unsigned 8-bit byte value to the given virtual address. ROM
+    /// behind the mapper can't be written, so writes there are dropped rather
+    /// than being mistaken for mapper register writes.
     #[inline(always)]
     pub fn write_u8_unrestricted(&mut self, addr: usize, val: u8) {
+        if addr >= PRG_ROM_START {
+            return;
+        } else if addr >= SRAM_START {
+            self.mapper.borrow_mut().write_prg_ram(addr, val);
+            self.sram_dirty = true;
+            return;
+        }
         let mapping_result = self.map(addr, MemoryOperation::Nop);
         mapping_result.bank[mapping_result.addr] = val;
     }
 
-    /// Reads an unsigned 16-bit byte value at the given virtual address
-    /// (little-endian).
+    /// Reads from whatever is plugged into the second port.
     #[inline(always)]
-    pub fn read_u16(&mut self, addr: usize) -> u16 {
-        // Reads two bytes starting at the given address and parses them.
-        let mut reader = Cursor::new(vec![self.read_u8(addr), self.read_u8(addr + 1)]);
-        reader.read_u16::<LittleEndian>().unwrap()
+    fn read_port_2(&mut self) -> u8 {
+        match self.zapper {
+            Some(ref zapper) => zapper.read(),
+            None => self.controller_2.read(),
+        }
+    }
+
+    /// Reads from the cartridge's PRG-RAM, which reads as open bus if the cart
+    /// doesn't have any or it's disabled.
+    #[inline(always)]
+    fn read_prg_ram(&mut self, addr: usize) -> u8 {
+        let open_bus = self.open_bus;
+        self.mapper.borrow_mut().read_prg_ram(addr).unwrap_or(open_bus)
     }
 
     /// Reads an unsigned 16-bit byte value at the given virtual address
     /// (little-endian).
     #[inline(always)]
-    pub fn read_u16_alt(&mut self, addr: usize) -> u16 {
+    pub fn read_u16(&mut self, addr: usize) -> u16 {
         // Reads two bytes starting at the given address and parses them.
-        let mut reader = Cursor::new(vec![self.read_u8(addr - 1), self.read_u8(addr)]);
+        let mut reader = Cursor::new(vec![self.read_u8(addr), self.read_u8(addr + 1)]);
         reader.read_u16::<LittleEndian>().unwrap()
     }
 
@@ -198,27 +397,10 @@ impl Memory {
         reader.read_u16::<LittleEndian>().unwrap()
     }
 
-    /// Reads an unsigned 16-bit byte value at the given virtual address
-    /// (little-endian) where the MSB is read at page start if the LSB is at
-    /// the end of a page. This exists to properly emulate a hardware bug in the
-    /// 2A03 where indirect jumps cannot fetch addresses outside it's own page.
-    #[inline(always)]
-    pub fn read_u16_wrapped_msb_alt(&mut self, addr: usize) -> u16 {
-        let lsb = self.read_u8(addr - 1);
-        let msb = if addr & 0xFF == 0xFF {
-            self.read_u8(addr - 0xFF)
-        } else {
-            self.read_u8(addr)
-        };
-
-        // Reads two bytes starting at the given address and parses them.
-        let mut reader = Cursor::new(vec![lsb, msb]);
-        reader.read_u16::<LittleEndian>().unwrap()
-    }
-
     /// Writes an unsigned 16-bit byte value to the given virtual address
     /// (little-endian)
     #[inline(always)]
+    #[allow(dead_code)]
     pub fn write_u16(&mut self, addr: usize, val: u16) {
         let mut writer = vec![];
         writer.write_u16::<LittleEndian>(val).unwrap();
@@ -226,16 +408,6 @@ impl Memory {
         self.write_u8(addr + 1, writer[1]);
     }
 
-    /// Writes an unsigned 16-bit byte value to the given virtual address
-    /// (little-endian)
-    #[inline(always)]
-    pub fn write_u16_alt(&mut self, addr: usize, val: u16) {
-        let mut writer = vec![];
-        writer.write_u16::<LittleEndian>(val).unwrap();
-        self.write_u8(addr - 1, writer[0]);
-        self.write_u8(addr, writer[1]);
-    }
-
     /// Dumps the contents of a slice starting at a given address.
     pub fn memdump(&mut self, addr: usize, buf: &[u8]) {
         for i in 0..buf.len() {
@@ -248,6 +420,9 @@ impl Memory {
     /// Pushes an 8-bit number onto the stack.
     #[inline(always)]
     pub fn stack_push_u8(&mut self, cpu: &mut CPU, value: u8) {
+        if cpu.sp < 1 {
+            self.warn_stack_wrap(cpu, "overflow");
+        }
         self.write_u8(STACK_OFFSET + cpu.sp as usize, value);
         cpu.sp = cpu.sp.wrapping_sub(1);
     }
@@ -255,22 +430,66 @@ impl Memory {
     /// Pops an 8-bit number off the stack.
     #[inline(always)]
     pub fn stack_pop_u8(&mut self, cpu: &mut CPU) -> u8 {
+        if cpu.sp > 0xFE {
+            self.warn_stack_wrap(cpu, "underflow");
+        }
         cpu.sp = cpu.sp.wrapping_add(1);
         self.read_u8(STACK_OFFSET + cpu.sp as usize)
     }
 
-    /// Pushes a 16-bit number (usually an address) onto the stack.
+    /// Pushes a 16-bit number (usually an address) onto the stack. The high
+    /// byte is pushed first so the number ends up little-endian in memory, and
+    /// each byte wraps around within the stack page like on hardware.
     #[inline(always)]
     pub fn stack_push_u16(&mut self, cpu: &mut CPU, value: u16) {
-        self.write_u16_alt(STACK_OFFSET + cpu.sp as usize, value);
-        cpu.sp = cpu.sp.wrapping_sub(2);
+        self.stack_push_u8(cpu, (value >> 8) as u8);
+        self.stack_push_u8(cpu, value as u8);
     }
 
-    /// Pops a 16-bit number (usually an address) off the stack.
+    /// Pops a 16-bit number (usually an address) off the stack, low byte
+    /// first.
     #[inline(always)]
     pub fn stack_pop_u16(&mut self, cpu: &mut CPU) -> u16 {
-        cpu.sp = cpu.sp.wrapping_add(2);
-        self.read_u16_alt(STACK_OFFSET + cpu.sp as usize)
+        let lsb = self.stack_pop_u8(cpu) as u16;
+        let msb = self.stack_pop_u8(cpu) as u16;
+        msb << 8 | lsb
+    }
+
+    /// Warns that the stack pointer is about to wrap around when stack guarding
+    /// is enabled. The stack still wraps like it does on hardware, as this is
+    /// only meant to point out runaway code.
+    fn warn_stack_wrap(&self, cpu: &CPU, kind: &str) {
+        if let Some(message) = self.stack_wrap_warning(cpu, kind) {
+            log::warn(message, &self.runtime_options);
+        }
+    }
+
+    /// Returns the warning shown for a stack wrap, if stack guarding is on.
+    fn stack_wrap_warning(&self, cpu: &CPU, kind: &str) -> Option<String> {
+        if self.runtime_options.guard_stack {
+            Some(format!("stack {} at ${:04X} (SP = ${:02X})", kind, cpu.pc, cpu.sp))
+        } else {
+            None
+        }
+    }
+
+    /// Warns about writes to PRG-ROM that don't reach a mapper register when
+    /// asked to. These are dropped, and often mean the game expects a mapper
+    /// register that isn't emulated.
+    fn warn_rom_write(&self, addr: usize, val: u8) {
+        if let Some(message) = self.rom_write_warning(addr, val) {
+            log::warn(message, &self.runtime_options);
+        }
+    }
+
+    /// Returns the warning shown for a write to PRG-ROM, if the write doesn't
+    /// reach a mapper register and warnings were asked for.
+    fn rom_write_warning(&self, addr: usize, val: u8) -> Option<String> {
+        if self.runtime_options.warn_rom_writes && !self.mapper.borrow().is_register(addr) {
+            Some(format!("write of ${:02X} to ROM at ${:04X} was dropped", val, addr))
+        } else {
+            None
+        }
     }
 
     /// Update the register status so the PPU knows which registers were touched
@@ -302,7 +521,11 @@ impl Memory {
     /// Returns PPU register read/write permissions for use with the I/O
     /// functions. Register status is also updated depending on the operation.
     #[inline(always)]
-    fn map_ppu_registers(&mut self, addr: usize, operation: MemoryOperation) -> MappingResult {
+    fn map_ppu_registers<'a>(
+        &'a mut self,
+        addr: usize,
+        operation: MemoryOperation,
+    ) -> MappingResult<'a> {
         self.update_ppu_register_status(addr, operation);
 
         let registers = &mut self.ppu_ctrl_registers;
@@ -372,6 +595,10 @@ impl Memory {
     /// being written to again, set the status to WrittenTwice.
     #[inline(always)]
     fn update_misc_register_status(&mut self, addr: usize, operation: MemoryOperation) {
+        if addr == 0x15 && operation == MemoryOperation::Read {
+            self.apu_status_read = true;
+        }
+
         let registers_status = &mut self.misc_ctrl_registers_status;
         registers_status[addr] = match operation {
             MemoryOperation::Read => MiscRegisterStatus::Read,
@@ -383,7 +610,11 @@ impl Memory {
     /// Returns misc register read/write permissions for use with the I/O
     /// functions. Register status is also updated depending on the operation.
     #[inline(always)]
-    fn map_misc_registers(&mut self, addr: usize, operation: MemoryOperation) -> MappingResult {
+    fn map_misc_registers<'a>(
+        &'a mut self,
+        addr: usize,
+        operation: MemoryOperation,
+    ) -> MappingResult<'a> {
         self.update_misc_register_status(addr, operation);
 
         // FIXME: Double-check permissions on these I/O registers.
@@ -406,10 +637,9 @@ impl Memory {
 
     /// Maps a given virtual address to a physical address internal to the
     /// emulator. Returns a memory buffer and index for physical memory access.
-    ///
-    /// TODO: Switch all references to struct members to functions so this
-    /// mapper implementation can be shared between ROM mappers.
-    fn map(&mut self, addr: usize, operation: MemoryOperation) -> MappingResult {
+    /// The cartridge's PRG-RAM and PRG-ROM windows are handled by the mapper
+    /// instead.
+    fn map<'a>(&'a mut self, addr: usize, operation: MemoryOperation) -> MappingResult<'a> {
         match addr {
             RAM_START_ADDR...RAM_END_ADDR => MappingResult {
                 bank: &mut self.ram,
@@ -439,24 +669,6 @@ impl Memory {
                 readable: true,
                 writable: false,
             },
-            SRAM_START...SRAM_END => MappingResult {
-                bank: &mut self.sram,
-                addr: addr - SRAM_START,
-                readable: true,
-                writable: true,
-            },
-            PRG_ROM_1_START...PRG_ROM_1_END => MappingResult {
-                bank: &mut self.prg_rom_1,
-                addr: addr - PRG_ROM_1_START,
-                readable: true,
-                writable: false,
-            },
-            PRG_ROM_2_START...PRG_ROM_2_END => MappingResult {
-                bank: &mut self.prg_rom_2,
-                addr: addr - PRG_ROM_2_START,
-                readable: true,
-                writable: false,
-            },
             _ => panic!(
                 "Unable to map virtual address {:#X} to any physical address",
                 addr
@@ -471,3 +683,164 @@ struct MappingResult<'a> {
     readable: bool,
     writable: bool,
 }
+
+/// Returns the address a mirrored address is a mirror of, so accesses through
+/// any of the mirrors of RAM or the PPU registers can be matched up.
+pub fn canonical_address(addr: usize) -> usize {
+    if addr <= RAM_MIRROR_END {
+        addr & (RAM_SIZE - 1)
+    } else if addr <= PPU_CTRL_REGISTERS_MIRROR_END {
+        PPU_CTRL_REGISTERS_START + (addr - PPU_CTRL_REGISTERS_START) % PPU_CTRL_REGISTERS_SIZE
+    } else {
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+
+    #[test]
+    fn write_only_registers_read_back_open_bus() {
+        let (_, mut memory) = testing::machine(&[]);
+
+        memory.write_u8(0x2000, 0x5A);
+        assert_eq!(memory.read_u8(0x2000), 0x5A);
+        memory.write_u8(0x2001, 0xA5);
+        assert_eq!(memory.read_u8(0x2000), 0xA5);
+    }
+
+    #[test]
+    fn four_score_signature_follows_the_chained_controllers() {
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.four_score = true;
+        let (_, mut memory) = testing::machine_with_options(runtime_options, &[]);
+
+        memory.write_u8(CONTROLLER_1_PORT, 1);
+        memory.write_u8(CONTROLLER_1_PORT, 0);
+        let port_1: Vec<u8> = (0..24).map(|_| memory.read_u8(CONTROLLER_1_PORT) & 1).collect();
+        let port_2: Vec<u8> = (0..24).map(|_| memory.read_u8(CONTROLLER_2_PORT) & 1).collect();
+
+        // Nothing is held down, so the only 1 on each port is the signature.
+        let ones = |bits: &[u8]| -> Vec<usize> {
+            (0..bits.len()).filter(|&read| bits[read] == 1).map(|read| read + 1).collect()
+        };
+        assert_eq!(ones(&port_1), vec![20]);
+        assert_eq!(ones(&port_2), vec![19]);
+    }
+
+    #[test]
+    fn guarded_stack_warns_but_still_wraps() {
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.guard_stack = true;
+        let (mut cpu, mut memory) = testing::machine_with_options(runtime_options, &[]);
+
+        // Pushing with SP at $00 writes $0100 and wraps to the top.
+        cpu.sp = 0x00;
+        assert_eq!(
+            memory.stack_wrap_warning(&cpu, "overflow").unwrap(),
+            "stack overflow at $8000 (SP = $00)"
+        );
+        memory.stack_push_u8(&mut cpu, 0x42);
+        assert_eq!(cpu.sp, 0xFF);
+        assert_eq!(memory.read_u8(0x0100), 0x42);
+
+        // Pulling with SP at $FF wraps back around to read $0100.
+        assert_eq!(
+            memory.stack_wrap_warning(&cpu, "underflow").unwrap(),
+            "stack underflow at $8000 (SP = $FF)"
+        );
+        assert_eq!(memory.stack_pop_u8(&mut cpu), 0x42);
+        assert_eq!(cpu.sp, 0x00);
+
+        // Nothing is shown without the guard.
+        let (cpu, memory) = testing::machine(&[]);
+        assert_eq!(memory.stack_wrap_warning(&cpu, "overflow"), None);
+    }
+
+    #[test]
+    fn prg_ram_reads_open_bus_on_carts_without_it() {
+        // LDA #$42; STA $6000; LDA $6000
+        let program = [0xA9, 0x42, 0x8D, 0x00, 0x60, 0xAD, 0x00, 0x60];
+        let (mut cpu, mut memory) = testing::machine(&program);
+        for _ in 0..3 {
+            cpu.step(&mut memory).unwrap();
+        }
+
+        // The write goes nowhere, and the read returns the high byte of the
+        // address, which was the last thing on the bus.
+        assert_eq!(cpu.a, 0x60);
+
+        // A battery-backed cart keeps what's written.
+        let mut rom = testing::nrom(&program);
+        rom[6] |= 0x02;
+        let mut memory = Memory::new(testing::runtime_options(), testing::mapper(&rom));
+        cpu.power_on(&mut memory);
+        for _ in 0..3 {
+            cpu.step(&mut memory).unwrap();
+        }
+        assert_eq!(cpu.a, 0x42);
+    }
+
+    #[test]
+    fn internal_ram_is_mirrored_every_2kb() {
+        let (_, mut memory) = testing::machine(&[]);
+
+        memory.write_u8(0x0000, 0x11);
+        memory.write_u8(0x1FFF, 0x22);
+        for &mirror in [0x0000, 0x0800, 0x1000, 0x1800].iter() {
+            assert_eq!(memory.read_u8(mirror), 0x11, "${:04X}", mirror);
+            assert_eq!(memory.read_u8(mirror + 0x7FF), 0x22, "${:04X}", mirror + 0x7FF);
+            assert_eq!(memory.read_u8_unrestricted(mirror), 0x11, "${:04X}", mirror);
+        }
+    }
+
+    #[test]
+    fn controller_reads_keep_the_open_bus_bits() {
+        // LDX #$01; STX $4016; DEX; STX $4016; LDA $4016; LDY $4017
+        let program = [
+            0xA2, 0x01, 0x8E, 0x16, 0x40, 0xCA, 0x8E, 0x16, 0x40, 0xAD, 0x16, 0x40, 0xAC, 0x17,
+            0x40,
+        ];
+        let (mut cpu, mut memory) = testing::machine(&program);
+        memory.controller_1.buttons = 0x01;
+        for _ in 0..6 {
+            cpu.step(&mut memory).unwrap();
+        }
+
+        // The high byte of the address was last on the bus, so bit 6 is set.
+        assert_eq!(cpu.a, 0x41);
+        assert_eq!(cpu.y, 0x40);
+
+        // Only the top 3 bits come from the bus.
+        memory.write_u8(0x0000, 0xFF);
+        assert_eq!(memory.read_u8(CONTROLLER_1_PORT), CONTROLLER_OPEN_BUS_BITS);
+    }
+
+    #[test]
+    fn rom_writes_are_dropped_with_a_warning() {
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.warn_rom_writes = true;
+        let (_, mut memory) = testing::machine_with_options(runtime_options, &[0xEA]);
+
+        assert_eq!(
+            memory.rom_write_warning(0x8000, 0x42).unwrap(),
+            "write of $42 to ROM at $8000 was dropped"
+        );
+        memory.write_u8(0x8000, 0x42);
+        assert_eq!(memory.read_u8(0x8000), 0xEA);
+
+        // Writes that reach a mapper register aren't warned about.
+        let mut rom = testing::nrom(&[]);
+        rom[6] |= 0x10;
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.warn_rom_writes = true;
+        let memory = Memory::new(runtime_options, testing::mapper(&rom));
+        assert_eq!(memory.rom_write_warning(0x8000, 0x42), None);
+
+        // Nor is anything without the option.
+        let (_, memory) = testing::machine(&[]);
+        assert_eq!(memory.rom_write_warning(0x8000, 0x42), None);
+    }
+}
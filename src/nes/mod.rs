@@ -6,10 +6,24 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-mod cpu;
-mod instruction;
+mod audio;
+mod controller;
+mod fps;
+mod gamepad;
+mod nsf;
 mod opcode;
-mod ppu;
+mod zapper;
 
+pub mod apu;
+pub mod bindings;
+pub mod cpu;
+pub mod instruction;
+pub mod mapper;
 pub mod memory;
 pub mod nes;
+pub mod palette;
+pub mod picker;
+pub mod ppu;
+
+#[cfg(test)]
+pub mod testing;
@@ -7,32 +7,54 @@
 // except according to those terms.
 
 use debugger::debugger::Debugger;
-use io::binutils::INESHeader;
+use debugger::symbols::Symbols;
+use io::binutils::{self, INESHeader, HEADER_SIZE, TRAINER_SIZE};
 use io::errors::*;
-use io::log;
+use io::fm2;
+use io::log::{self, LogLevel};
+use io::movie::{Movie, MOVIE_COMMAND_NONE, MOVIE_COMMAND_POWER, MOVIE_COMMAND_RESET};
+use io::nsf::{self, NsfHeader};
+use nes::apu::APU;
+use nes::audio::Audio;
+use nes::bindings::{self, Action, Binding, Bindings, Input};
+use nes::controller;
 use nes::cpu::CPU;
+use nes::fps::FpsCounter;
+use nes::gamepad::Gamepads;
+use nes::nsf::NsfPlayer;
+use nes::palette;
 use nes::ppu::PPU;
+use nes::zapper::Zapper;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use sdl2;
-use sdl2::event::Event;
-use sdl2::pixels::Color;
-use sdl2::render;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::mouse::MouseButton;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
 use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::video::{FullscreenType, Window};
 use sdl2::EventPump;
+use sdl2::VideoSubsystem;
+use std::cmp;
+use std::env;
 use std::fs::File;
-use std::io::{self, stdin, BufRead, BufReader, Read, Write};
+use std::io::{BufReader, ErrorKind};
 use std::sync::mpsc::{self, Receiver, SyncSender};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{panic, thread};
 
-use nes::memory::{
-    Memory, PRG_ROM_1_START, PRG_ROM_2_START, PRG_ROM_SIZE, TRAINER_SIZE, TRAINER_START,
-};
+use nes::mapper::{self, Mapper, SharedMapper};
+use nes::memory::{Memory, TRAINER_START};
+use std::cell::RefCell;
+use std::rc::Rc;
 
 const HISTORY_FILE: &'static str = ".nes-rs-history.txt";
 
+// Size of the picture the PPU outputs.
+pub const SCREEN_WIDTH:  u32 = 256;
+pub const SCREEN_HEIGHT: u32 = 240;
+
 /// The NES struct owns all hardware peripherals and lends them when needed. The
 /// runtime cost of this should be removed with optimized builds (untested).
 pub struct NES {
@@ -41,108 +63,281 @@ pub struct NES {
 
     pub cpu: CPU,
     pub ppu: PPU,
+    pub apu: APU,
     pub memory: Memory,
+    pub mapper: SharedMapper,
+
+    // Labels loaded from a symbol file, used to annotate disassembly in the
+    // debugger.
+    pub symbols: Symbols,
+
+    // Keyboard keys and game controller buttons bound to each controller.
+    pub bindings: Bindings,
 
     pub canvas: Canvas<Window>,
+
+    // Where in the window the picture is drawn, which is kept up to date as
+    // the window is resized or goes fullscreen.
+    pub viewport: Rect,
     pub event_pump: EventPump,
+
+    // Audio output is optional since the host may not have a sound device.
+    pub audio: Option<Audio>,
+
+    // Game controllers are optional as well, and aren't used when headless.
+    gamepads: Option<Gamepads>,
+
+    // Kept around so additional windows (such as debugger views) can be
+    // opened after initialization.
+    pub video_subsystem: VideoSubsystem,
+
+    // Number of frames emulated so far. A frame ends when the PPU enters
+    // vblank.
+    pub frame: u64,
+
+    // Total CPU cycles run since power on, used to find the parity of the
+    // cycle OAM DMA starts on.
+    cpu_cycles: u64,
+
+    // CPU cycles left in the OAM DMA transfer in progress, if any.
+    oam_dma_cycles: u16,
+
+    // Set while paused from the keyboard. This is separate from the debugger
+    // stopping execution, and either one keeps the NES from being stepped.
+    pub paused: bool,
+
+    // When the next frame is due to be advanced while the frame advance key
+    // is held down.
+    frame_advance: Option<Instant>,
+
+    // Set while the fast-forward key is held, which runs emulation at the
+    // fast-forward speed with audio muted.
+    pub fast_forward: bool,
+
+    // True while skipping the wait at startup before the game first turns on
+    // rendering, which is usually spent waiting on vblank and clearing RAM.
+    booting: bool,
+
+    // Measures the real frame rate when it's shown in the window title.
+    fps: Option<FpsCounter>,
+
+    // Number of CPU steps taken so far, used to pace the pretty register
+    // panel.
+    steps: u64,
+
+    // The loaded ROM, which the cartridge is loaded from again when power
+    // cycling.
+    rom: Vec<u8>,
+
+    // Checksum of the loaded ROM which is stored in recorded movies.
+    rom_crc: u32,
+
+    // When battery-backed PRG-RAM was last written to the save file.
+    last_autosave: Instant,
+
+    // Buttons held at the last frame, used to only log input when it changes.
+    last_buttons: u8,
+
+    // Controller input is either recorded to a movie or played back from one
+    // depending on the runtime options.
+    recording: Option<Movie>,
+    playback: Option<Movie>,
+
+    // Calls the routines that play music when an NSF was loaded instead of a
+    // ROM.
+    nsf: Option<NsfPlayer>,
+
+    // Reset or power cycle pressed on the keyboard, which is carried out at
+    // the start of the next frame so it can be recorded.
+    pending_command: u8,
+
+    // A rom dropped onto the window, which stops emulation so the rom can be
+    // loaded in place of the current one.
+    pub dropped_rom: Option<String>,
 }
 
 impl NES {
-    /// Initializes the NES emulator by dumping the ROM into memory and
-    /// initializing the initial hardware state.
-    pub fn new(rom: Vec<u8>, header: INESHeader, runtime_options: NESRuntimeOptions) -> Self {
-        // An offset is used when copying from the ROM into RAM as the presence
-        // of a trainer will shift the locations of other structures.
-        let mut cursor: usize = 0x10;
-
-        // Spew out some useful metadata about the rom when verbose is on.
-        log::log(
-            "init",
-            format!("Using {:?} mapper", header.mapper()),
+    /// Initializes the NES emulator with the cartridge's mapper plugged into
+    /// the CPU and PPU buses and sets up the initial hardware state. An error
+    /// is returned if SDL can't open a window, which is common on machines
+    /// without a display where --headless should be used instead.
+    pub fn new(
+        rom: Vec<u8>,
+        header: INESHeader,
+        mapper: Box<Mapper>,
+        runtime_options: NESRuntimeOptions,
+    ) -> Result<Self, String> {
+        // Spew out some useful metadata about the rom when verbose or
+        // verbose-init is on.
+        log::init(
+            format!(
+                "Using mapper {} ({})",
+                header.mapper_number(),
+                binutils::mapper_name(header.mapper_number())
+            ),
             &runtime_options,
         );
-        log::log(
-            "init",
-            format!("Using {:?} mirroring", header.mirror_type()),
-            &runtime_options,
-        );
-
-        // Copy the trainer data to 0x7000 if it exists and adjust the cursor
-        // size to accommodate. Trainer data will offset the location of ROM
-        // data in the INES ROM file.
-        let mut memory = Memory::new();
-        if header.has_trainer() {
-            log::log("init", "Trainer data found", &runtime_options);
-            memory.memdump(TRAINER_START, &rom[0x10..0x210]);
-            cursor += TRAINER_SIZE;
-        }
+        log::init(format!("Using {:?} mirroring", header.mirror_type()), &runtime_options);
+        log::init(format!("{} PRG-ROM banks detected", header.prg_rom_size), &runtime_options);
 
-        // Copy PRG-ROM into memory so it can be addressed by the chosen memory
-        // mapper. PRG-ROM bank 1 begins at 0x8000 and bank 2 begins at 0xC000.
-        //
-        // In the event that there are 2 PRG-ROM banks, make both banks
-        // addressable at their respective locations. However if there's only
-        // one bank, make PRG-ROM bank 1 addressable starting from both
-        // addresses.
-        //
-        // NOTE: Should this be moved to mapper code?
-        if header.prg_rom_size == 2 {
-            log::log("init", "2 PRG-ROM banks detected", &runtime_options);
-            let prg_rom_1_addr = cursor;
-            let prg_rom_2_addr = cursor + PRG_ROM_SIZE;
-            memory.memdump(
-                PRG_ROM_1_START,
-                &rom[prg_rom_1_addr..prg_rom_1_addr + PRG_ROM_SIZE],
-            );
-            memory.memdump(
-                PRG_ROM_2_START,
-                &rom[prg_rom_2_addr..prg_rom_2_addr + PRG_ROM_SIZE],
-            );
-        } else {
-            log::log("init", "1 PRG-ROM bank detected", &runtime_options);
-            let prg_rom_1_addr = cursor;
-            memory.memdump(
-                PRG_ROM_1_START,
-                &rom[prg_rom_1_addr..prg_rom_1_addr + PRG_ROM_SIZE],
-            );
-            memory.memdump(
-                PRG_ROM_2_START,
-                &rom[prg_rom_1_addr..prg_rom_1_addr + PRG_ROM_SIZE],
-            );
-        }
+        // Both the CPU and PPU buses are wired to the cartridge, so they share
+        // the mapper.
+        let mapper = Rc::new(RefCell::new(mapper));
 
-        // Set the initial program counter to the address stored at 0xFFFC (this
-        // allows ROMs to specify entry point). If a program counter was
-        // specified on the command-line, use that one instead.
-        let pc = match runtime_options.program_counter {
-            Some(pc) => pc,
-            None => memory.read_u16(0xFFFC),
-        };
+        let memory = build_memory(&rom, &header, mapper.clone(), &runtime_options);
 
-        // Create an SDL window that represents the display.
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem
-            .window("nes-rs", 256, 240)
-            .position_centered()
-            .build()
-            .unwrap();
+        // Create an SDL window that represents the display. In headless mode
+        // SDL's dummy video driver is used so no display is needed.
+        if runtime_options.headless {
+            env::set_var("SDL_VIDEODRIVER", "dummy");
+        }
+        let sdl_context = try!(sdl2::init().map_err(|e| format!("cannot initialize SDL: {}", e)));
+        let video_subsystem = try!(
+            sdl_context
+                .video()
+                .map_err(|e| format!("cannot initialize video: {}", e))
+        );
+        let mut window = try!(
+            video_subsystem
+                .window("nes-rs", SCREEN_WIDTH, SCREEN_HEIGHT)
+                .position_centered()
+                .resizable()
+                .build()
+                .map_err(|e| format!("cannot open window: {}", e))
+        );
+        if runtime_options.start_fullscreen_borderless {
+            if let Err(e) = window.set_fullscreen(FullscreenType::Desktop) {
+                log::warn(format!("cannot go fullscreen: {}", e), &runtime_options);
+            }
+        }
+        let (width, height) = window.size();
+        let viewport = presentation_rect(width, height);
+        let event_pump = try!(
+            sdl_context
+                .event_pump()
+                .map_err(|e| format!("cannot read events: {}", e))
+        );
 
         // Create a canvas that is scaled up a bit.
-        let mut canvas = window.into_canvas().build().unwrap();
+        let mut canvas = try!(
+            window
+                .into_canvas()
+                .build()
+                .map_err(|e| format!("cannot create canvas: {}", e))
+        );
         canvas.set_draw_color(Color::RGB(255, 0, 0));
         canvas.clear();
         canvas.present();
 
-        NES {
+        // Open an audio device unless audio was disabled. Emulation carries on
+        // without sound if no device is available.
+        let audio = if runtime_options.no_audio || runtime_options.headless {
+            None
+        } else {
+            let latency = runtime_options.audio_latency;
+
+            // Fast-forwarding only changes pitch when asked to. Otherwise the
+            // extra samples are dropped once the audio buffer fills up.
+            let pitch = if runtime_options.speed_pitch {
+                runtime_options.speed
+            } else {
+                1.0
+            };
+            match sdl_context
+                .audio()
+                .and_then(|audio_subsystem| Audio::new(&audio_subsystem, latency, pitch))
+            {
+                Ok(audio) => Some(audio),
+                Err(e) => {
+                    log::warn(format!("cannot open audio device: {}", e), &runtime_options);
+                    None
+                }
+            }
+        };
+
+        let gamepads = if runtime_options.headless {
+            None
+        } else {
+            match sdl_context.game_controller() {
+                Ok(subsystem) => Some(Gamepads::new(subsystem, &runtime_options)),
+                Err(e) => {
+                    let message = format!("cannot open game controllers: {}", e);
+                    log::warn(message, &runtime_options);
+                    None
+                }
+            }
+        };
+
+        let rom_crc = binutils::crc32(&rom[0x10..]);
+        let booting = runtime_options.boot_animation_skip;
+        let fps = if runtime_options.show_fps {
+            Some(FpsCounter::new())
+        } else {
+            None
+        };
+
+        let ppu = PPU::new(runtime_options.clone(), mapper.clone());
+
+        let nsf = if nsf::is_nsf(&rom) {
+            NsfHeader::new(&rom).ok().map(|header| {
+                let message = format!(
+                    "Playing song {} of {} from {} by {} ({})",
+                    header.starting_song, header.songs, header.name, header.artist, header.copyright
+                );
+                log::init(message, &runtime_options);
+                if header.extra_chips != 0 {
+                    let message = "NSF uses expansion audio, which isn't emulated";
+                    log::warn(message, &runtime_options);
+                }
+                NsfPlayer::new(&header)
+            })
+        } else {
+            None
+        };
+
+        let mut nes = NES {
             header: header,
-            cpu: CPU::new(runtime_options.clone(), pc),
-            ppu: PPU::new(runtime_options.clone()),
+            cpu: CPU::new(runtime_options.clone()),
+            ppu: ppu,
+            mapper: mapper,
+            symbols: Symbols::new(),
+            bindings: Bindings::new(),
+            apu: APU::new(runtime_options.clone()),
             runtime_options: runtime_options,
             memory: memory,
             canvas: canvas,
-            event_pump: sdl_context.event_pump().unwrap(),
-        }
+            viewport: viewport,
+            event_pump: event_pump,
+            audio: audio,
+            gamepads: gamepads,
+            video_subsystem: video_subsystem,
+            frame: 0,
+            cpu_cycles: 0,
+            oam_dma_cycles: 0,
+            paused: false,
+            frame_advance: None,
+            fast_forward: false,
+            booting: booting,
+            fps: fps,
+            steps: 0,
+            rom: rom,
+            rom_crc: rom_crc,
+            last_autosave: Instant::now(),
+            last_buttons: 0,
+            recording: None,
+            playback: None,
+            nsf: nsf,
+            pending_command: MOVIE_COMMAND_NONE,
+            dropped_rom: None,
+        };
+
+        // The rest of the system keeps running while the CPU goes through its
+        // reset sequence, so startup takes as many cycles as it does on
+        // hardware.
+        let reset_cycles = nes.cpu.power_on(&mut nes.memory);
+        nes.run_cycles(reset_cycles);
+        nes.start_nsf();
+        Ok(nes)
     }
 
     /// Starts the execution loop and starts executing PRG-ROM.
@@ -154,14 +349,98 @@ impl NES {
             Some(ref filename) => match File::open(filename) {
                 Ok(f) => self.cpu.begin_testing(BufReader::new(f)),
                 Err(e) => {
-                    let mut stderr = io::stderr();
-                    writeln!(stderr, "nes-rs: cannot open {}: {}", filename, e).unwrap();
+                    let message = format!("cannot open {}: {}", filename, e);
+                    log::error(message, &self.runtime_options);
                     return EXIT_CPU_LOG_NOT_FOUND;
                 }
             },
             None => {}
         }
 
+        if let Some(ref filename) = self.runtime_options.symbols {
+            match Symbols::load(filename, &self.runtime_options) {
+                Ok(symbols) => self.symbols = symbols,
+                Err(e) => {
+                    log::error(e, &self.runtime_options);
+                    return EXIT_INVALID_SYMBOLS;
+                }
+            }
+        }
+
+        if let Some(ref filename) = self.runtime_options.bindings {
+            match Bindings::load(filename, &self.runtime_options) {
+                Ok(bindings) => self.bindings = bindings,
+                Err(e) => {
+                    log::error(e, &self.runtime_options);
+                    return EXIT_INVALID_BINDINGS;
+                }
+            }
+        }
+
+        if let Some(ref data_dir) = self.runtime_options.data_dir {
+            let message = format!("Writing saves and movies to {}", data_dir);
+            log::init(message, &self.runtime_options);
+        }
+
+        // Load the movie to play back, making sure it was recorded against the
+        // same ROM, or start a new recording.
+        if let Some(ref filename) = self.runtime_options.playback_input {
+            let buf = match binutils::read_bin(filename) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    let message = format!("cannot open {}: {}", filename, e);
+                    log::error(message, &self.runtime_options);
+                    return EXIT_INVALID_MOVIE;
+                }
+            };
+            let movie = if fm2::is_fm2(&buf) {
+                fm2::parse(&buf)
+            } else {
+                Movie::parse(&buf).map_err(|e| e.to_string())
+            };
+            let movie = match movie {
+                Ok(movie) => movie,
+                Err(e) => {
+                    let message = format!("cannot parse {}: {}", filename, e);
+                    log::error(message, &self.runtime_options);
+                    return EXIT_INVALID_MOVIE;
+                }
+            };
+            match movie.rom_crc {
+                Some(rom_crc) if rom_crc != self.rom_crc => {
+                    let message = format!(
+                        "{} was recorded with a different ROM (CRC32 {:08X}, expected {:08X})",
+                        filename, rom_crc, self.rom_crc
+                    );
+                    log::error(message, &self.runtime_options);
+                    return EXIT_INVALID_MOVIE;
+                }
+                Some(_) => {}
+                None => {
+                    let message = format!("{} can't be checked against the ROM", filename);
+                    log::warn(message, &self.runtime_options);
+                }
+            }
+            if movie.region != self.header.timing() {
+                let message = format!(
+                    "{} was recorded as {}, but the ROM runs as {}",
+                    filename,
+                    binutils::region_name(movie.region),
+                    self.header.region()
+                );
+                log::warn(message, &self.runtime_options);
+            }
+            log::init(
+                format!("Playing back {} frames of input", movie.len()),
+                &self.runtime_options,
+            );
+            self.playback = Some(movie);
+        }
+        if self.runtime_options.record_input.is_some() {
+            let controllers = self.controller_count();
+            self.recording = Some(Movie::new(self.rom_crc, self.header.timing(), controllers));
+        }
+
         // Start cycling the CPU and PPU and add a panic catcher so crash
         // information can be shown if the CPU panics.The PPU ticks three times
         // every CPU cycle, though there may need to be changes made for PAL
@@ -177,83 +456,684 @@ impl NES {
         // access virtual machine state. Another thread is also setup that waits
         // for input on stdin that sends input to the debugger for the debugger
         // subshell.
-        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| -> Result<(), String> {
             if self.runtime_options.debugging {
                 let (tx, rx): (SyncSender<String>, Receiver<String>) = mpsc::sync_channel(1);
                 let (mtx, mrx): (SyncSender<u8>, Receiver<u8>) = mpsc::sync_channel(1);
 
+                // Halt before the first instruction if asked to so nothing is
+                // missed.
+                let break_on_start = self.runtime_options.debug_break_on_start;
+                if break_on_start {
+                    let message =
+                        format!("Stopped at ${:04X}, type continue to start...", self.cpu.pc);
+                    log::info(message, &self.runtime_options);
+                    log::info(format!("{}", self.cpu), &self.runtime_options);
+                }
+
                 // Input is read on another thread, so spin one up.
                 self.setup_readline_thread(tx, mrx);
 
                 // Execute until shutdown signal is received from debugger.
-                let mut debugger = Debugger::new(mtx, rx);
+                let mut debugger = Debugger::new(
+                    mtx,
+                    rx,
+                    !break_on_start,
+                    self.runtime_options.clone(),
+                );
                 while !debugger.step(self) {
-                    let quit = self.poll_sdl_events();
-                    if quit {
+                    let quit = try!(self.poll_sdl_events());
+                    if quit || self.reached_frame_limit() {
                         break;
                     }
                 }
+                Ok(())
             } else {
                 loop {
-                    let quit = self.poll_sdl_events();
-                    if quit {
-                        break;
+                    let quit = try!(self.poll_sdl_events());
+                    if quit || self.reached_frame_limit() {
+                        return Ok(());
+                    }
+                    let result = if self.paused {
+                        if !self.frame_advance_due() {
+                            thread::sleep(Duration::from_millis(16));
+                            continue;
+                        }
+                        self.step_frame()
+                    } else {
+                        self.step()
+                    };
+
+                    // Running out of CPU log to test against is the normal end
+                    // of a test run rather than a failure.
+                    match result {
+                        Err(_) if self.cpu.execution_log_finished => return Ok(()),
+                        result => try!(result),
                     }
-                    self.step();
                 }
             }
         }));
 
+        // Battery-backed saves are written out even after a crash, since they
+        // are only ever written to by the game itself.
+        self.save_sram();
+
+        // Save the recorded input even if the emulator crashed, as the movie
+        // may be useful for reproducing the crash.
+        if let Some(ref movie) = self.recording {
+            let filename = self.runtime_options.record_input.clone().unwrap();
+            match binutils::write_bin(&filename, &movie.to_bytes()) {
+                Ok(_) => {
+                    let message = format!("Saved {} frames of input to {}", movie.len(), filename);
+                    log::info(message, &self.runtime_options);
+                }
+                Err(e) => {
+                    let message = format!("cannot save {}: {}", filename, e);
+                    log::error(message, &self.runtime_options);
+                }
+            }
+        }
+
         // Unwinding point with shutdown code. In the event of a panic, we want
         // to display some diagnostic information to the user that can be sent
         // to the developer.
+        if self.runtime_options.frame_hash {
+            let message = format!("Frame {} hash: {:016X}", self.frame, self.ppu.frame_hash());
+            log::info(message, &self.runtime_options);
+        }
+
+        if self.runtime_options.continue_on_mismatch && self.runtime_options.cpu_log.is_some() {
+            self.print_mismatch_summary();
+        }
+
         match result {
-            Ok(_) => {
-                println!("Shutting down nes-rs, happy emulating!");
+            Ok(Ok(_)) => {
+                log::info("Shutting down nes-rs, happy emulating!", &self.runtime_options);
+                if self.cpu.mismatch_count > 0 {
+                    return EXIT_CPU_LOG_MISMATCH;
+                }
                 return EXIT_SUCCESS; // Success exit code.
             }
+            Ok(Err(e)) => {
+                // The CPU halted without panicking, so there is no need to wait
+                // for panic output to be flushed.
+                log::error(e, &self.runtime_options);
+                log::info(format!("{}", self.cpu), &self.runtime_options);
+                return EXIT_RUNTIME_FAILURE;
+            }
             Err(_) => {
                 thread::sleep(Duration::from_millis(16));
-                println!("{}", self.cpu);
+                log::info(format!("{}", self.cpu), &self.runtime_options);
                 return EXIT_RUNTIME_FAILURE; // Runtime failure exit code.
             }
         }
     }
 
     /// Executes a CPU instruction and steps the PPU 3 times per CPU cycle. This
-    /// works since the PPU and CPU clocks are synchronized 1 to 3.
-    pub fn step(&mut self) {
-        let mut cycles = self.cpu.step(&mut self.memory);
-        self.cpu.sleep(cycles);
+    /// works since the PPU and CPU clocks are synchronized 1 to 3. The APU is
+    /// driven directly by the CPU clock so it is stepped once per cycle.
+    ///
+    /// An error is returned if the CPU can't execute the next instruction, in
+    /// which case nothing else is stepped.
+    pub fn step(&mut self) -> Result<(), String> {
+        if let Some(ref mut nsf) = self.nsf {
+            nsf.step(&mut self.cpu, &mut self.memory, self.cpu_cycles);
+        }
+        let remaining_cycles = try!(self.cpu.step(&mut self.memory));
+
+        self.steps += 1;
+        if let Some(interval) = self.runtime_options.pretty {
+            if self.steps % interval == 0 {
+                let panel = self.cpu.panel(self.ppu.scanline(), self.ppu.dot());
+                log::info(format!("{}\n", panel), &self.runtime_options);
+            }
+        }
+
+        let cycles = self.run_cycles(remaining_cycles);
+
+        if throttled(&mut self.booting, &self.ppu, &self.runtime_options) {
+            let speed = if self.fast_forward {
+                self.runtime_options.ff_speed
+            } else {
+                self.runtime_options.speed
+            };
+            self.cpu.sleep(cycles, speed);
+        }
+
+        if self.ppu.poll_frame() {
+            try!(self.begin_frame());
+        }
+        Ok(())
+    }
+
+    /// Runs the PPU and APU for the given number of CPU cycles, stepping the
+    /// PPU 3 times per cycle. Returns the number of cycles that were run,
+    /// which includes any the CPU was stalled for by DMA.
+    fn run_cycles(&mut self, mut remaining_cycles: u16) -> u16 {
+        let mut cycles = 0;
+
+        // DMA stalls the CPU while the rest of the system keeps running, so
+        // stolen cycles are added to the cycles left to run.
+        while remaining_cycles > 0 {
+            remaining_cycles += step_devices(
+                &mut self.ppu,
+                &mut self.apu,
+                &mut self.memory,
+                &mut self.oam_dma_cycles,
+                self.cpu_cycles,
+            );
+            if self.ppu.poll_nmi() {
+                self.cpu.nmi = true;
+            }
+            if let Some(ref mut audio) = self.audio {
+                audio.step(self.apu.output());
+            }
+            self.cpu_cycles += 1;
+            remaining_cycles -= 1;
+            cycles += 1;
+        }
+        self.cpu.irq_line = self.apu.irq() || self.mapper.borrow().irq_pending();
+        if let Some(ref mut zapper) = self.memory.zapper {
+            zapper.sense(self.ppu.framebuffer(), self.ppu.scanline());
+        }
+        cycles
+    }
+
+    /// Presses the reset button. The CPU goes through its reset sequence, and
+    /// the PPU and APU clear the registers reset clears on hardware.
+    fn reset(&mut self) {
+        log::log("nes", format!("Reset at frame {}", self.frame), &self.runtime_options);
+        self.ppu.reset();
+        self.apu.reset();
+        let reset_cycles = self.cpu.reset(&mut self.memory);
+        self.run_cycles(reset_cycles);
+        self.start_nsf();
+    }
+
+    /// Switches the console off and on again. The cartridge is loaded from
+    /// the ROM again and the rest of the hardware starts over, while the
+    /// window, audio, input devices, movies and debugger carry on. Battery
+    /// saves are written out first and loaded back in.
+    fn power_cycle(&mut self) {
+        log::log("nes", format!("Power cycle at frame {}", self.frame), &self.runtime_options);
+        self.save_sram();
+        let mapper = match mapper::load_cartridge(&self.rom, &self.header) {
+            Ok(mapper) => Rc::new(RefCell::new(mapper)),
+            Err(e) => {
+                log::error(format!("cannot power cycle: {}", e), &self.runtime_options);
+                return;
+            }
+        };
+        self.memory = build_memory(&self.rom, &self.header, mapper.clone(), &self.runtime_options);
+        self.ppu = PPU::new(self.runtime_options.clone(), mapper.clone());
+        self.apu = APU::new(self.runtime_options.clone());
+        self.mapper = mapper;
+
+        self.cpu.irq_line = false;
+        self.cpu_cycles = 0;
+        self.oam_dma_cycles = 0;
+        let reset_cycles = self.cpu.power_on(&mut self.memory);
+        self.run_cycles(reset_cycles);
+        self.start_nsf();
+    }
+
+    /// Starts playing the song when an NSF was loaded, once the console has
+    /// been powered on or reset.
+    fn start_nsf(&mut self) {
+        if let Some(ref mut nsf) = self.nsf {
+            nsf.start(&mut self.cpu, &mut self.memory, self.cpu_cycles);
+        }
+    }
+
+    /// Pauses or resumes emulation. Audio stops while paused, and the frame
+    /// rate is measured afresh on resuming. Emulation picks up where it left
+    /// off rather than catching up on the time spent paused.
+    fn set_paused(&mut self, paused: bool) -> Result<(), String> {
+        self.paused = paused;
+        if let Some(ref mut audio) = self.audio {
+            audio.set_paused(paused || self.fast_forward);
+        }
+        if let Some(ref mut fps) = self.fps {
+            fps.reset();
+        }
+        let title = if paused { "nes-rs - Paused" } else { "nes-rs" };
+        try!(set_title(self.canvas.window_mut(), title));
+        log::log("nes", if paused { "Paused" } else { "Resumed" }, &self.runtime_options);
+        Ok(())
+    }
+
+    /// Starts or stops fast-forwarding. Audio is muted while fast-forwarding
+    /// rather than letting it skip as samples are dropped.
+    fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+        if let Some(ref mut audio) = self.audio {
+            audio.set_paused(fast_forward || self.paused);
+        }
+        let message = if fast_forward {
+            "Fast-forwarding"
+        } else {
+            "Running at normal speed"
+        };
+        log::log("nes", message, &self.runtime_options);
+    }
+
+    /// Carries out an emulator action when its hotkey is pressed or released.
+    /// Frame advance and fast-forward last for as long as their hotkeys are
+    /// held, while the rest happen on press. The console's buttons can't be
+    /// pressed while a movie is being played back.
+    fn run_hotkey(&mut self, action: Action, pressed: bool) -> Result<(), String> {
+        let playing_back = self.playback.is_some();
+        match (action, pressed) {
+            (Action::Pause, true) => {
+                let paused = !self.paused;
+                try!(self.set_paused(paused));
+            }
+            (Action::FrameAdvance, true) if self.paused => {
+                self.frame_advance = Some(Instant::now());
+            }
+            (Action::FrameAdvance, false) => self.frame_advance = None,
+            (Action::FastForward, _) if pressed != self.fast_forward => {
+                self.set_fast_forward(pressed);
+            }
+            (Action::Reset, true) if !playing_back => self.pending_command = MOVIE_COMMAND_RESET,
+            (Action::PowerCycle, true) if !playing_back => {
+                self.pending_command = MOVIE_COMMAND_POWER;
+            }
+            (Action::Fullscreen, true) => self.toggle_fullscreen(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Switches between windowed and borderless fullscreen. The viewport is
+    /// updated once the window reports its new size.
+    fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let mode = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(e) = window.set_fullscreen(mode) {
+            let message = format!("cannot switch window mode: {}", e);
+            log::warn(message, &self.runtime_options);
+        }
+    }
+
+    /// Runs until the PPU finishes the next frame and enters vblank, so
+    /// exactly one frame of video is emulated when starting from the start of
+    /// a frame.
+    pub fn step_frame(&mut self) -> Result<(), String> {
+        let frame = self.frame;
+        while self.frame == frame {
+            try!(self.step());
+        }
+        Ok(())
+    }
+
+    /// Returns true if a frame should be advanced while paused. The first
+    /// frame is advanced as soon as the frame advance key is pressed, and more
+    /// follow at the frame advance rate for as long as it's held.
+    fn frame_advance_due(&mut self) -> bool {
+        let due = match self.frame_advance {
+            Some(due) => due,
+            None => return false,
+        };
+        let now = Instant::now();
+        if now < due {
+            return false;
+        }
+        let interval = Duration::from_secs(1) / self.runtime_options.frame_advance_rate;
+        self.frame_advance = Some(now + interval);
+        true
+    }
+
+    /// Returns the number of controllers plugged in, which is four with a Four
+    /// Score.
+    fn controller_count(&self) -> usize {
+        if self.runtime_options.four_score {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Returns true once the number of frames requested on the command-line
+    /// have been emulated.
+    fn reached_frame_limit(&self) -> bool {
+        match self.runtime_options.frames {
+            Some(frames) => self.frame >= frames,
+            None => false,
+        }
+    }
+
+    /// Prints the mismatches recorded while testing against a CPU log.
+    fn print_mismatch_summary(&self) {
+        if self.cpu.mismatch_count == 0 {
+            log::info("No mismatches found against the CPU log.", &self.runtime_options);
+            return;
+        }
+
+        let message = format!(
+            "Found {} mismatches against the CPU log, showing the first {}:",
+            self.cpu.mismatch_count,
+            self.cpu.mismatches.len()
+        );
+        log::info(message, &self.runtime_options);
+        for mismatch in self.cpu.mismatches.iter() {
+            log::info("", &self.runtime_options);
+            log::info(format!("Line {}:", mismatch.line), &self.runtime_options);
+            for field in mismatch.fields.iter() {
+                log::info(format!("  {}", field), &self.runtime_options);
+            }
+            let frames = format!(
+                "  Emulator Frame: {}\n  Log Frame:      {}",
+                mismatch.emulator_frame, mismatch.log_frame
+            );
+            log::info(frames, &self.runtime_options);
+        }
+        log::info("", &self.runtime_options);
+    }
+
+    /// Called at every frame boundary. This is the one point controller input
+    /// is latched each frame, so turbo, movie recording and movie playback all
+    /// see the same state the game does for the whole frame.
+    ///
+    /// An error is returned if the frame can't be drawn to the window.
+    fn begin_frame(&mut self) -> Result<(), String> {
+        self.frame += 1;
+
+        if !self.runtime_options.headless {
+            try!(self.present());
+        }
+
+        if let Some(ref mut audio) = self.audio {
+            audio.flush();
+        }
 
-        while cycles > 0 {
-            for _ in 0..3 {
-                // *Should* unroll.
-                self.ppu.step(&mut self.memory);
+        if let Some(ref mut fps) = self.fps {
+            let window = self.canvas.window_mut();
+            try!(show_fps(fps, Instant::now(), |title| set_title(window, title)));
+        }
+
+        // Resets and power cycles happen between frames, either as pressed on
+        // the keyboard or as played back from a movie.
+        let index = self.frame as usize - 1;
+        let command = match self.playback {
+            Some(ref movie) => movie.command(index),
+            None => self.pending_command,
+        };
+        self.pending_command = MOVIE_COMMAND_NONE;
+        match command {
+            MOVIE_COMMAND_RESET => self.reset(),
+            MOVIE_COMMAND_POWER => self.power_cycle(),
+            _ => {}
+        }
+
+        // Turbo buttons are pressed and released here rather than as keys
+        // change, so recorded movies capture the pattern the game saw.
+        let controllers = self.controller_count();
+        update_turbo(&mut self.memory, controllers, self.frame, self.runtime_options.turbo_rate);
+
+        let mut playback_finished = false;
+        if let Some(ref movie) = self.playback {
+            match movie.frame(index) {
+                Some(buttons) => {
+                    for (port, &buttons) in buttons.iter().enumerate() {
+                        self.memory.controller_mut(port).buttons = buttons;
+                    }
+                }
+                None => playback_finished = true,
+            }
+        }
+        if playback_finished {
+            log::log("movie", "Playback finished", &self.runtime_options);
+            for port in 0..controllers {
+                self.memory.controller_mut(port).buttons = 0;
             }
-            cycles -= 1;
+            self.playback = None;
+        }
+
+        let buttons: Vec<u8> = (0..controllers)
+            .map(|port| self.memory.controller_mut(port).buttons)
+            .collect();
+        if let Some(ref mut movie) = self.recording {
+            movie.push_frame(&buttons, command);
         }
+
+        autosave(
+            &mut self.memory,
+            &self.mapper,
+            &self.runtime_options,
+            &mut self.last_autosave,
+        );
+
+        let buttons = self.memory.controller_1.buttons;
+        if self.runtime_options.input_log && buttons != self.last_buttons {
+            let message = format!(
+                "Frame {}: P1: {}",
+                self.frame,
+                controller::buttons_to_string(buttons)
+            );
+            log::trace("input", message, &self.runtime_options);
+        }
+        self.last_buttons = buttons;
+        Ok(())
+    }
+
+    /// Draws the last frame the PPU rendered into the viewport, scaled up and
+    /// centered in the window with black bars around it.
+    fn present(&mut self) -> Result<(), String> {
+        let pixels: Vec<u8> = self
+            .ppu
+            .framebuffer()
+            .iter()
+            .flat_map(|&color| {
+                let (r, g, b) = palette::rgb(color);
+                vec![r, g, b]
+            })
+            .collect();
+
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = try!(
+            texture_creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_WIDTH, SCREEN_HEIGHT)
+                .map_err(|e| format!("cannot create texture: {}", e))
+        );
+        try!(
+            texture
+                .update(None, &pixels, SCREEN_WIDTH as usize * 3)
+                .map_err(|e| format!("cannot draw frame: {}", e))
+        );
+
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        try!(
+            self.canvas
+                .copy(&texture, None, Some(self.viewport))
+                .map_err(|e| format!("cannot draw frame: {}", e))
+        );
+        self.canvas.present();
+        Ok(())
+    }
+
+    /// Writes battery-backed PRG-RAM to the save file if it has been written
+    /// to since the last time it was saved.
+    fn save_sram(&mut self) {
+        self.last_autosave = Instant::now();
+        write_save_file(&mut self.memory, &self.mapper, &self.runtime_options);
     }
 
     /// Polls for SDL events, inparticular the quit one. A boolean is returned
-    /// which if true will stop emulation.
-    fn poll_sdl_events(&mut self) -> bool {
+    /// which if true will stop emulation. Controller input from the keyboard
+    /// and game controllers is ignored while a movie is being played back,
+    /// though hotkeys still work. Hotkeys are run once every event is handled,
+    /// and an error is returned if one of them fails to update the window.
+    fn poll_sdl_events(&mut self) -> Result<bool, String> {
+        let playing_back = self.playback.is_some();
+        let mut hotkeys = Vec::new();
         for event in self.event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => {
-                    return true;
+                    return Ok(true);
+                }
+                Event::DropFile { filename, .. } => {
+                    // The debugger's input thread can't be handed over to a
+                    // new machine, so roms can only be swapped without it.
+                    if self.runtime_options.debugging {
+                        let message = format!("cannot load {} while debugging", filename);
+                        log::warn(message, &self.runtime_options);
+                    } else {
+                        let read = |filename: &str| binutils::read_bin(filename);
+                        self.dropped_rom =
+                            accept_dropped_rom(filename, read, &self.runtime_options);
+                        if self.dropped_rom.is_some() {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(width, height),
+                    ..
+                } => {
+                    self.viewport = presentation_rect(width as u32, height as u32);
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat,
+                    ..
+                } => {
+                    // Hotkeys take priority over controller buttons, which
+                    // only matters when they need modifiers held.
+                    let input = Input::Key(keycode);
+                    match self.bindings.hotkey(input, bindings::modifiers(keymod)) {
+                        Some(action) if !repeat => hotkeys.push((action, true)),
+                        Some(_) => {}
+                        None if !playing_back => {
+                            if let Some(binding) = self.bindings.key(keycode) {
+                                press_binding(&mut self.memory, binding, true);
+                            }
+                        }
+                        None => {}
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    for action in self.bindings.actions(Input::Key(keycode)) {
+                        hotkeys.push((action, false));
+                    }
+                    if !playing_back {
+                        if let Some(binding) = self.bindings.key(keycode) {
+                            press_binding(&mut self.memory, binding, false);
+                        }
+                    }
+                }
+                Event::MouseMotion { x, y, .. } => {
+                    if let Some(ref mut zapper) = self.memory.zapper {
+                        let (x, y) = window_to_screen(self.viewport, x, y);
+                        zapper.aim_at(x, y);
+                    }
+                }
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } => {
+                    if let Some(ref mut zapper) = self.memory.zapper {
+                        let (x, y) = window_to_screen(self.viewport, x, y);
+                        zapper.aim_at(x, y);
+                        zapper.trigger = true;
+                    }
+                }
+                Event::MouseButtonUp {
+                    mouse_btn: MouseButton::Left,
+                    ..
+                } => {
+                    if let Some(ref mut zapper) = self.memory.zapper {
+                        zapper.trigger = false;
+                    }
+                }
+                Event::Window {
+                    win_event: WindowEvent::Leave,
+                    ..
+                } => {
+                    if let Some(ref mut zapper) = self.memory.zapper {
+                        zapper.aim = None;
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Some(ref mut gamepads) = self.gamepads {
+                        gamepads.add(which, &self.runtime_options);
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(ref mut gamepads) = self.gamepads {
+                        gamepads.remove(which, &self.runtime_options);
+                    }
+                }
+                Event::ControllerButtonDown { which, button, .. } => {
+                    if let Some(action) = self.bindings.hotkey(Input::Pad(button), 0) {
+                        hotkeys.push((action, true));
+                        continue;
+                    }
+                    if playing_back {
+                        continue;
+                    }
+                    if let Some(ref gamepads) = self.gamepads {
+                        if let Some(player) = gamepads.player(which) {
+                            if let Some(binding) = gamepads.button(&self.bindings, player, button) {
+                                press_binding(&mut self.memory, binding, true);
+                            }
+                        }
+                    }
+                }
+                Event::ControllerButtonUp { which, button, .. } => {
+                    for action in self.bindings.actions(Input::Pad(button)) {
+                        hotkeys.push((action, false));
+                    }
+                    if playing_back {
+                        continue;
+                    }
+                    if let Some(ref gamepads) = self.gamepads {
+                        if let Some(player) = gamepads.player(which) {
+                            if let Some(binding) = gamepads.button(&self.bindings, player, button) {
+                                press_binding(&mut self.memory, binding, false);
+                            }
+                        }
+                    }
+                }
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } if !playing_back => {
+                    if let Some(ref mut gamepads) = self.gamepads {
+                        let player = gamepads.player(which);
+                        let buttons = player.and_then(|player| gamepads.axis(player, axis, value));
+                        if let (Some(player), Some((pressed, released))) = (player, buttons) {
+                            let controller = self.memory.controller_mut(player);
+                            controller.release(released);
+                            controller.press(pressed);
+                        }
+                    }
                 }
                 _ => {}
             }
         }
 
-        return false;
+        for (action, pressed) in hotkeys {
+            try!(self.run_hotkey(action, pressed));
+        }
+
+        return Ok(false);
     }
 
     /// Creates a readline loop on another thread and sends commands to the
     /// debugger over a synchronous rust channel. Offers quality of life features
     /// such as history built into the library used.
     fn setup_readline_thread(&self, tx: SyncSender<String>, rx: Receiver<u8>) {
+        let runtime_options = self.runtime_options.clone();
         thread::spawn(move || {
             let mut rl = Editor::<()>::new();
             if let Err(_) = rl.load_history(HISTORY_FILE) {
@@ -283,32 +1163,647 @@ impl NES {
                         }
                     }
                     Err(ReadlineError::Interrupted) => {
-                        tx.send("exit".to_string()).unwrap();
-                        break;
+                        // Ctrl-C halts execution rather than quitting so a
+                        // runaway "until" can be interrupted. Ctrl-D quits.
+                        tx.send("stop".to_string()).unwrap();
+                        match rx.recv() {
+                            Ok(1) | Err(_) => break,
+                            Ok(_) => {}
+                        }
                     }
                     Err(ReadlineError::Eof) => {
                         tx.send("exit".to_string()).unwrap();
                         break;
                     }
                     Err(err) => {
-                        println!("Error: {:?}", err);
+                        log::error(format!("{:?}", err), &runtime_options);
                         tx.send("exit".to_string()).unwrap();
                         break;
                     }
                 };
             }
 
-            println!("Saving debugger history...");
+            log::info("Saving debugger history...", &runtime_options);
             rl.save_history(HISTORY_FILE).unwrap();
         });
     }
 }
 
+/// Runs the PPU and APU for the CPU cycle with the given number, stepping the
+/// PPU 3 times. Returns the CPU cycles stolen by DMA started on this cycle.
+/// OAM DMA takes an extra cycle when it starts on an odd CPU cycle, and the
+/// cycles left in it are counted down so DMC fetches made during the transfer
+/// only stall for the cycles that don't overlap with it.
+fn step_devices(
+    ppu: &mut PPU,
+    apu: &mut APU,
+    memory: &mut Memory,
+    oam_dma_cycles: &mut u16,
+    cpu_cycle: u64,
+) -> u16 {
+    let mut stolen_cycles = 0;
+    for _ in 0..3 {
+        // *Should* unroll.
+        let oam_dma = ppu.step(memory);
+        if oam_dma > 0 {
+            *oam_dma_cycles = oam_dma + (cpu_cycle % 2) as u16;
+            stolen_cycles += *oam_dma_cycles;
+        }
+    }
+    stolen_cycles += apu.step(memory, *oam_dma_cycles);
+    if *oam_dma_cycles > 0 {
+        *oam_dma_cycles -= 1;
+    }
+    stolen_cycles
+}
+
+/// Returns true if emulation should wait to run at real time after a step.
+/// Headless runs go as fast as possible so their results don't depend on how
+/// fast the host is. Only the waiting is skipped while booting, everything is
+/// still emulated the same way, and booting ends once rendering is enabled.
+fn throttled(booting: &mut bool, ppu: &PPU, runtime_options: &NESRuntimeOptions) -> bool {
+    if *booting && ppu.rendering_enabled() {
+        log::log("nes", "Rendering enabled, running at normal speed", runtime_options);
+        *booting = false;
+    }
+    !runtime_options.headless && !*booting
+}
+
+/// Plugs the cartridge into the CPU bus, along with whatever is in the
+/// controller ports. Battery-backed PRG-RAM is loaded from the save file, and
+/// then trainer data is copied to 0x7000 if it exists.
+fn build_memory(
+    rom: &[u8],
+    header: &INESHeader,
+    mapper: SharedMapper,
+    runtime_options: &NESRuntimeOptions,
+) -> Memory {
+    load_save_file(&mapper, runtime_options);
+    let mut memory = Memory::new(runtime_options.clone(), mapper);
+    if runtime_options.zapper {
+        log::init("Zapper plugged into port 2", runtime_options);
+        memory.zapper = Some(Zapper::new());
+    }
+    if header.has_trainer() {
+        log::init("Trainer data found", runtime_options);
+        memory.memdump(TRAINER_START, &rom[HEADER_SIZE..HEADER_SIZE + TRAINER_SIZE]);
+
+        // The trainer is part of the ROM rather than something the game
+        // saved, so it isn't written back to the save file.
+        memory.sram_dirty = false;
+    }
+    memory
+}
+
+/// Loads battery-backed PRG-RAM from the save file if the cart has one. A
+/// missing save file just means the game hasn't been saved yet.
+fn load_save_file(mapper: &SharedMapper, runtime_options: &NESRuntimeOptions) {
+    let filename = match runtime_options.save_file {
+        Some(ref filename) => filename,
+        None => return,
+    };
+    match binutils::read_bin(filename) {
+        Ok(buf) => {
+            let prg_ram_size = mapper.borrow().prg_ram_size();
+            if buf.len() != prg_ram_size {
+                let message = format!(
+                    "{} is {} bytes but the cart has {} bytes of PRG-RAM",
+                    filename,
+                    buf.len(),
+                    prg_ram_size
+                );
+                log::warn(message, runtime_options);
+            }
+            mapper.borrow_mut().load_prg_ram(&buf);
+            log::init(format!("Loaded save from {}", filename), runtime_options);
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            let message = format!("cannot open {}: {}", filename, e);
+            log::warn(message, runtime_options);
+        }
+    }
+}
+
+/// Writes battery-backed PRG-RAM to the save file if it has been written to
+/// since the last time it was saved.
+fn write_save_file(
+    memory: &mut Memory,
+    mapper: &SharedMapper,
+    runtime_options: &NESRuntimeOptions,
+) {
+    if !memory.sram_dirty {
+        return;
+    }
+    let filename = match runtime_options.save_file {
+        Some(ref filename) => filename,
+        None => return,
+    };
+    match binutils::write_bin(filename, mapper.borrow().prg_ram()) {
+        Ok(_) => {
+            memory.sram_dirty = false;
+            log::log("sram", format!("Saved to {}", filename), runtime_options);
+        }
+        Err(e) => {
+            let message = format!("cannot save {}: {}", filename, e);
+            log::error(message, runtime_options);
+        }
+    }
+}
+
+/// Writes the save file once the autosave interval has passed since the last
+/// save, so a crash doesn't lose more than that much progress.
+fn autosave(
+    memory: &mut Memory,
+    mapper: &SharedMapper,
+    runtime_options: &NESRuntimeOptions,
+    last_autosave: &mut Instant,
+) {
+    let interval = match runtime_options.autosave_interval {
+        Some(interval) => interval,
+        None => return,
+    };
+    if last_autosave.elapsed() >= Duration::from_secs(interval) {
+        *last_autosave = Instant::now();
+        write_save_file(memory, mapper, runtime_options);
+    }
+}
+
+/// Checks that a file dropped onto the window can be played, reading it with
+/// the given function. Returns the file to load in place of the current one,
+/// or None after warning about why it can't be, so a bad drop doesn't end the
+/// game being played.
+fn accept_dropped_rom<F>(
+    filename: String,
+    read: F,
+    runtime_options: &NESRuntimeOptions,
+) -> Option<String>
+where
+    F: FnOnce(&str) -> std::io::Result<Vec<u8>>,
+{
+    let checked = read(&filename)
+        .map_err(|e| e.to_string())
+        .and_then(|rom| check_rom(&rom));
+    match checked {
+        Ok(_) => Some(filename),
+        Err(e) => {
+            log::warn(format!("cannot load {}: {}", filename, e), runtime_options);
+            None
+        }
+    }
+}
+
+/// Returns an error if a rom or NSF can't be played, because its header is
+/// invalid, it's cut short, or its mapper isn't supported.
+fn check_rom(rom: &[u8]) -> Result<(), String> {
+    if nsf::is_nsf(rom) {
+        return NsfHeader::new(rom).map(|_| ()).map_err(|e| e.to_string());
+    }
+    let header = try!(INESHeader::new(rom).map_err(|e| e.to_string()));
+    try!(header.validate(rom).map_err(|e| e.to_string()));
+    header.mapper().map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Sets the title of the window, describing why if it can't be.
+fn set_title(window: &mut Window, title: &str) -> Result<(), String> {
+    window
+        .set_title(title)
+        .map_err(|e| format!("cannot set window title: {}", e))
+}
+
+/// Records a frame finishing at the given time, and shows the frame rate in
+/// the window title with the given function whenever there's a new measurement.
+fn show_fps<F>(fps: &mut FpsCounter, now: Instant, set_title: F) -> Result<(), String>
+where
+    F: FnOnce(&str) -> Result<(), String>,
+{
+    match fps.frame(now) {
+        Some(fps) => set_title(&format!("nes-rs - {:.1} FPS", fps)),
+        None => Ok(()),
+    }
+}
+
+/// Returns where the picture is drawn in a window of the given size. It's
+/// scaled up by the largest whole number that fits so pixels stay square and
+/// evenly sized, and centered in the window.
+fn presentation_rect(width: u32, height: u32) -> Rect {
+    let scale = cmp::max(1, cmp::min(width / SCREEN_WIDTH, height / SCREEN_HEIGHT));
+    let (scaled_width, scaled_height) = (SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale);
+    Rect::new(
+        (width as i32 - scaled_width as i32) / 2,
+        (height as i32 - scaled_height as i32) / 2,
+        scaled_width,
+        scaled_height,
+    )
+}
+
+/// Maps a position in the window back to a pixel of the picture drawn in the
+/// given viewport. Positions outside of the picture are outside the screen.
+fn window_to_screen(viewport: Rect, x: i32, y: i32) -> (i32, i32) {
+    let scale = (viewport.width() / SCREEN_WIDTH) as i32;
+    let (x, y) = (x - viewport.x(), y - viewport.y());
+    if x < 0 || y < 0 {
+        return (-1, -1);
+    }
+    (x / scale, y / scale)
+}
+
+/// Presses or releases the held turbo buttons of every controller for the
+/// given frame. Turbo buttons are pressed for the first part of every turbo
+/// period and released for the rest.
+fn update_turbo(memory: &mut Memory, controllers: usize, frame: u64, turbo_rate: (u64, u64)) {
+    let (turbo_on, turbo_off) = turbo_rate;
+    let turbo_pressed = frame % (turbo_on + turbo_off) < turbo_on;
+    for port in 0..controllers {
+        memory.controller_mut(port).update_turbo(turbo_pressed);
+    }
+}
+
+/// Presses or releases the controller button an input is bound to.
+fn press_binding(memory: &mut Memory, binding: Binding, pressed: bool) {
+    let controller = memory.controller_mut(binding.port);
+    match (binding.turbo, pressed) {
+        (false, true) => controller.press(binding.button),
+        (false, false) => controller.release(binding.button),
+        (true, true) => controller.press_turbo(binding.button),
+        (true, false) => controller.release_turbo(binding.button),
+    }
+}
+
 /// Flags and other information set through command-line arguments.
 #[derive(Clone, Debug)]
 pub struct NESRuntimeOptions {
     pub program_counter: Option<u16>,
     pub cpu_log: Option<String>,
-    pub verbose: bool,
+    pub log_compare_start: Option<u16>,
+    pub log_level: LogLevel,
+    pub verbose_init: bool,
     pub debugging: bool,
+    pub debug_break_on_start: bool,
+    pub record_input: Option<String>,
+    pub playback_input: Option<String>,
+    pub pretty: Option<u64>,
+    pub continue_on_mismatch: bool,
+    pub no_audio: bool,
+    pub audio_latency: u32,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub frame_hash: bool,
+    pub speed: f64,
+    pub speed_pitch: bool,
+    pub ff_speed: f64,
+    pub mute: bool,
+    pub volume: u8,
+    pub guard_stack: bool,
+    pub show_fps: bool,
+    pub boot_animation_skip: bool,
+    pub input_log: bool,
+    pub symbols: Option<String>,
+    pub data_dir: Option<String>,
+    pub save_file: Option<String>,
+    pub autosave_interval: Option<u64>,
+    pub gamepad_dead_zone: u8,
+    pub gamepad_swap_ab: bool,
+    pub bindings: Option<String>,
+    pub turbo_rate: (u64, u64),
+    pub frame_advance_rate: u32,
+    pub zapper: bool,
+    pub warn_rom_writes: bool,
+    pub four_score: bool,
+    pub start_fullscreen_borderless: bool,
+}
+
+impl Default for NESRuntimeOptions {
+    /// Returns the options the emulator runs with when it's given a ROM and
+    /// nothing else on the command line.
+    fn default() -> NESRuntimeOptions {
+        NESRuntimeOptions {
+            program_counter: None,
+            cpu_log: None,
+            log_compare_start: None,
+            log_level: LogLevel::Info,
+            verbose_init: false,
+            debugging: false,
+            debug_break_on_start: false,
+            record_input: None,
+            playback_input: None,
+            pretty: None,
+            continue_on_mismatch: false,
+            no_audio: false,
+            audio_latency: ::DEFAULT_AUDIO_LATENCY,
+            headless: false,
+            frames: None,
+            frame_hash: false,
+            speed: 1.0,
+            speed_pitch: false,
+            ff_speed: ::DEFAULT_FF_SPEED,
+            mute: false,
+            volume: 100,
+            guard_stack: false,
+            show_fps: false,
+            boot_animation_skip: false,
+            input_log: false,
+            symbols: None,
+            data_dir: None,
+            save_file: None,
+            autosave_interval: None,
+            gamepad_dead_zone: ::DEFAULT_GAMEPAD_DEAD_ZONE,
+            gamepad_swap_ab: false,
+            bindings: None,
+            turbo_rate: ::DEFAULT_TURBO_RATE,
+            frame_advance_rate: ::DEFAULT_FRAME_ADVANCE_RATE,
+            zapper: false,
+            warn_rom_writes: false,
+            four_score: false,
+            start_fullscreen_borderless: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::testing;
+    use std::fs;
+    use std::path::Path;
+    use utils::hash;
+
+    #[test]
+    fn only_playable_drops_replace_the_rom() {
+        let runtime_options = testing::runtime_options();
+        let rom = testing::nrom(&[]);
+
+        let read = |filename: &str| {
+            assert_eq!(filename, "game.nes");
+            Ok(rom.clone())
+        };
+        let dropped = accept_dropped_rom("game.nes".to_string(), read, &runtime_options);
+        assert_eq!(dropped, Some("game.nes".to_string()));
+
+        let read = |_: &str| Ok(rom[..0x1000].to_vec());
+        assert_eq!(accept_dropped_rom("cut.nes".to_string(), read, &runtime_options), None);
+
+        let mut unsupported = rom.clone();
+        unsupported[6] |= 0xF0;
+        unsupported[7] |= 0xF0;
+        let read = |_: &str| Ok(unsupported.clone());
+        assert_eq!(accept_dropped_rom("mapper255.nes".to_string(), read, &runtime_options), None);
+
+        let read = |_: &str| Err(std::io::Error::new(ErrorKind::NotFound, "not found"));
+        assert_eq!(accept_dropped_rom("gone.nes".to_string(), read, &runtime_options), None);
+    }
+
+    #[test]
+    fn picture_is_scaled_by_whole_numbers_and_centered() {
+        // A window at the default scale fits the picture exactly.
+        assert_eq!(presentation_rect(768, 720), Rect::new(0, 0, 768, 720));
+
+        // A 1080p fullscreen window is limited by its height to a scale of 4,
+        // leaving bars on every side.
+        assert_eq!(presentation_rect(1920, 1080), Rect::new(448, 60, 1024, 960));
+
+        // Windows smaller than the picture still draw it at its actual size.
+        assert_eq!(presentation_rect(200, 100), Rect::new(-28, -70, 256, 240));
+    }
+
+    // Battery and trainer flags in byte 6 of an iNES header.
+    const BATTERY: u8 = 0x02;
+    const TRAINER: u8 = 0x04;
+
+    /// Returns runtime options that save PRG-RAM to the given file.
+    fn save_options(path: &Path) -> NESRuntimeOptions {
+        let mut runtime_options = testing::runtime_options();
+        runtime_options.save_file = Some(path.to_str().unwrap().to_string());
+        runtime_options
+    }
+
+    #[test]
+    fn autosave_writes_sram_once_the_interval_passes() {
+        let path = testing::temp_path("autosave.sav");
+        let mut runtime_options = save_options(&path);
+        runtime_options.autosave_interval = Some(60);
+
+        // LDA #$42; STA $6000
+        let mut rom = testing::nrom(&[0xA9, 0x42, 0x8D, 0x00, 0x60]);
+        rom[6] |= BATTERY;
+        let header = INESHeader::new(&rom).unwrap();
+        let mapper = testing::mapper(&rom);
+        let mut memory = build_memory(&rom, &header, mapper.clone(), &runtime_options);
+        let mut cpu = CPU::new(runtime_options.clone());
+        cpu.power_on(&mut memory);
+        cpu.step(&mut memory).unwrap();
+        cpu.step(&mut memory).unwrap();
+        assert!(memory.sram_dirty);
+
+        let mut last_autosave = Instant::now();
+        autosave(&mut memory, &mapper, &runtime_options, &mut last_autosave);
+        assert!(!path.exists());
+
+        let mut last_autosave = Instant::now() - Duration::from_secs(61);
+        autosave(&mut memory, &mapper, &runtime_options, &mut last_autosave);
+        let save = binutils::read_bin(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(save[0], 0x42);
+        assert!(!memory.sram_dirty);
+    }
+
+    #[test]
+    fn trainer_is_copied_over_the_save_without_dirtying_it() {
+        let path = testing::temp_path("trainer.sav");
+        let runtime_options = save_options(&path);
+        binutils::write_bin(&path, &[0x11; 0x2000]).unwrap();
+
+        let mut rom = testing::nrom(&[]);
+        rom[6] |= BATTERY | TRAINER;
+        let trainer = [0x77; TRAINER_SIZE];
+        rom.splice(HEADER_SIZE..HEADER_SIZE, trainer.iter().cloned());
+        let header = INESHeader::new(&rom).unwrap();
+        let mapper = testing::mapper(&rom);
+        let mut memory = build_memory(&rom, &header, mapper, &runtime_options);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(memory.read_u8_unrestricted(0x6000), 0x11);
+        assert_eq!(memory.read_u8_unrestricted(TRAINER_START), 0x77);
+        assert!(!memory.sram_dirty);
+    }
+
+    // Writes $4014 on the CPU cycle with the given number, and enables the DMC
+    // once the given number of OAM DMA cycles are left, or right away for 0.
+    // Returns how many CPU cycles the write took along with the stall.
+    fn dma_stall(cpu_cycle: u64, dmc_fetch_at: u16) -> u64 {
+        let mapper = testing::mapper(&testing::nrom(&[]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        let mut apu = APU::new(testing::runtime_options());
+        let mut oam_dma_cycles = 0;
+        let mut dmc_enabled = false;
+
+        memory.write_u8(0x4014, 0x02);
+        let (mut remaining_cycles, mut cycles) = (1, 0);
+        while remaining_cycles > 0 {
+            if !dmc_enabled && oam_dma_cycles == dmc_fetch_at {
+                memory.write_u8(0x4015, 0x10);
+                dmc_enabled = true;
+            }
+            remaining_cycles += step_devices(
+                &mut ppu,
+                &mut apu,
+                &mut memory,
+                &mut oam_dma_cycles,
+                cpu_cycle + cycles,
+            );
+            remaining_cycles -= 1;
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn dmc_fetches_during_oam_dma_steal_the_documented_cycles() {
+        // The write itself, the 513 or 514 cycle transfer, and the extra
+        // cycles the DMC fetch stalls for, which are 2 except at the very end
+        // of the transfer.
+        assert_eq!(dma_stall(0, 0), 1 + 513 + 2);
+        assert_eq!(dma_stall(1, 0), 1 + 514 + 2);
+        assert_eq!(dma_stall(0, 2), 1 + 513 + 1);
+        assert_eq!(dma_stall(0, 1), 1 + 513 + 3);
+    }
+
+    #[test]
+    fn window_errors_are_returned_from_the_frame() {
+        let mut fps = FpsCounter::new();
+        let start = Instant::now();
+        let fail = |_: &str| Err("window is gone".to_string());
+
+        // The title is only touched once a second has been measured.
+        assert_eq!(show_fps(&mut fps, start, fail), Ok(()));
+        let later = start + Duration::from_secs(1);
+        assert_eq!(show_fps(&mut fps, later, fail), Err("window is gone".to_string()));
+
+        let mut title = String::new();
+        let later = start + Duration::from_secs(2);
+        let shown = show_fps(&mut fps, later, |shown| {
+            title = shown.to_string();
+            Ok(())
+        });
+        assert_eq!(shown, Ok(()));
+        assert_eq!(title, "nes-rs - 1.0 FPS");
+    }
+
+    #[test]
+    fn turbo_buttons_follow_the_turbo_rate_in_controller_reads() {
+        let (_, mut memory) = testing::machine(&[]);
+        memory.controller_1.press_turbo(controller::BUTTON_A);
+
+        let mut pattern = Vec::new();
+        for frame in 1..9 {
+            update_turbo(&mut memory, 1, frame, (2, 2));
+            memory.write_u8(0x4016, 1);
+            memory.write_u8(0x4016, 0);
+            pattern.push(memory.read_u8(0x4016) & 0x01);
+        }
+        assert_eq!(pattern, [1, 0, 0, 1, 1, 0, 0, 1]);
+    }
+
+    // Sets the backdrop to $21, turns on the background and then loops:
+    //
+    //         LDA #$3F
+    //         STA $2006
+    //         LDA #$00
+    //         STA $2006
+    //         LDA #$21
+    //         STA $2007
+    //         LDA #$08
+    //         STA $2001
+    // loop:   JMP loop
+    const BACKDROP_PROGRAM: [u8; 23] = [
+        0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, 0xA9, 0x21, 0x8D, 0x07, 0x20,
+        0xA9, 0x08, 0x8D, 0x01, 0x20, 0x4C, 0x14, 0x80,
+    ];
+
+    /// Runs a program for the given number of frames and returns the hash of
+    /// the last frame, like --frame-hash prints.
+    fn frame_hash_after(program: &[u8], frames: u64) -> u64 {
+        let mapper = testing::mapper(&testing::nrom(program));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        let mut apu = APU::new(testing::runtime_options());
+        let mut cpu = CPU::new(testing::runtime_options());
+        cpu.power_on(&mut memory);
+
+        let (mut oam_dma_cycles, mut cpu_cycle, mut frame) = (0, 0, 0);
+        while frame < frames {
+            let mut remaining_cycles = cpu.step(&mut memory).unwrap();
+            while remaining_cycles > 0 {
+                remaining_cycles +=
+                    step_devices(&mut ppu, &mut apu, &mut memory, &mut oam_dma_cycles, cpu_cycle);
+                remaining_cycles -= 1;
+                cpu_cycle += 1;
+            }
+            if ppu.poll_frame() {
+                frame += 1;
+            }
+        }
+        ppu.frame_hash()
+    }
+
+    #[test]
+    fn frame_hash_is_stable_across_runs() {
+        let hash = frame_hash_after(&BACKDROP_PROGRAM, 3);
+        assert_eq!(frame_hash_after(&BACKDROP_PROGRAM, 3), hash);
+
+        // The whole 256x240 picture is the backdrop color.
+        assert_eq!(hash, hash::fnv1a(&vec![0x21; 256 * 240]));
+        assert!(hash != frame_hash_after(&[], 3));
+    }
+
+    /// Runs the backdrop program with a counter in $00 for a couple of frames,
+    /// returning RAM and whether each step would have waited for real time.
+    fn boot(boot_animation_skip: bool) -> (Vec<u8>, Vec<bool>) {
+        let mut program = BACKDROP_PROGRAM.to_vec();
+        program.splice(20.., [0xE6, 0x00, 0x4C, 0x14, 0x80].iter().cloned());
+        let runtime_options = NESRuntimeOptions {
+            headless: false,
+            boot_animation_skip: boot_animation_skip,
+            ..testing::runtime_options()
+        };
+        let mapper = testing::mapper(&testing::nrom(&program));
+        let mut memory = Memory::new(runtime_options.clone(), mapper.clone());
+        let mut ppu = PPU::new(runtime_options.clone(), mapper);
+        let mut apu = APU::new(runtime_options.clone());
+        let mut cpu = CPU::new(runtime_options.clone());
+        cpu.power_on(&mut memory);
+
+        let (mut oam_dma_cycles, mut cpu_cycle, mut frame) = (0, 0, 0);
+        let mut booting = boot_animation_skip;
+        let mut waits = Vec::new();
+        while frame < 2 {
+            let mut remaining_cycles = cpu.step(&mut memory).unwrap();
+            while remaining_cycles > 0 {
+                remaining_cycles +=
+                    step_devices(&mut ppu, &mut apu, &mut memory, &mut oam_dma_cycles, cpu_cycle);
+                remaining_cycles -= 1;
+                cpu_cycle += 1;
+            }
+            waits.push(throttled(&mut booting, &ppu, &runtime_options));
+            if ppu.poll_frame() {
+                frame += 1;
+            }
+        }
+        ((0..0x800).map(|addr| memory.read_u8(addr)).collect(), waits)
+    }
+
+    #[test]
+    fn boot_skip_only_skips_waiting_until_rendering_is_enabled() {
+        let (ram, waits) = boot(false);
+        let (skipped_ram, skipped_waits) = boot(true);
+
+        assert!(ram[0x00] != 0);
+        assert_eq!(skipped_ram, ram);
+        assert!(waits.iter().all(|&wait| wait));
+
+        // The first 7 instructions run before STA $2001 turns on rendering.
+        assert!(skipped_waits[..7].iter().all(|&wait| !wait));
+        assert!(skipped_waits[7..].iter().all(|&wait| wait));
+    }
 }
@@ -0,0 +1,146 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use io::nsf::NsfHeader;
+use nes::cpu::CPU;
+use nes::memory::Memory;
+
+// The init and play routines return to an idle loop here, which is in the
+// expansion area where nothing else is mapped. The CPU spins on it between
+// calls so the rest of the system keeps running.
+const NSF_IDLE_ADDRESS: u16 = 0x5FF0;
+const NSF_IDLE_LOOP: [u8; 3] = [0x4C, 0xF0, 0x5F]; // JMP $5FF0
+
+// CPU clock rates in Hz, used to turn the play rate into cycles.
+const NTSC_CLOCK_RATE: u64 = 1789773;
+const PAL_CLOCK_RATE:  u64 = 1662607;
+
+// APU registers cleared before a song starts, and the values the channels and
+// frame counter are then set up with.
+const APU_REGISTERS_START: usize = 0x4000;
+const APU_REGISTERS_END:   usize = 0x4013;
+const APU_STATUS:          usize = 0x4015;
+const APU_FRAME_COUNTER:   usize = 0x4017;
+
+/// Plays the music in an NSF by calling its routines the way an NSF player
+/// cartridge would. The init routine is called once to set up the song, then
+/// the play routine is called at the rate given in the header.
+pub struct NsfPlayer {
+    init_addr: u16,
+    play_addr: u16,
+    song: u8,
+    pal: bool,
+
+    // CPU cycles between calls to the play routine, and the cycle the next
+    // call is due on.
+    period: u64,
+    next_play: u64,
+}
+
+impl NsfPlayer {
+    /// Returns a player for the starting song in an NSF.
+    pub fn new(header: &NsfHeader) -> Self {
+        let clock_rate = if header.is_pal() {
+            PAL_CLOCK_RATE
+        } else {
+            NTSC_CLOCK_RATE
+        };
+        NsfPlayer {
+            init_addr: header.init_addr,
+            play_addr: header.play_addr,
+            song: header.starting_song,
+            pal: header.is_pal(),
+            period: header.speed() as u64 * clock_rate / 1000000,
+            next_play: 0,
+        }
+    }
+
+    /// Sets up the APU and calls the init routine with the song number in A
+    /// and the region in X, like an NSF player does once the console has been
+    /// powered on.
+    pub fn start(&mut self, cpu: &mut CPU, memory: &mut Memory, cpu_cycles: u64) {
+        memory.memdump(NSF_IDLE_ADDRESS as usize, &NSF_IDLE_LOOP);
+        for addr in APU_REGISTERS_START..APU_REGISTERS_END + 1 {
+            memory.write_u8(addr, 0);
+        }
+        memory.write_u8(APU_STATUS, 0x0F);
+        memory.write_u8(APU_FRAME_COUNTER, 0x40);
+
+        cpu.a = self.song - 1;
+        cpu.x = if self.pal { 1 } else { 0 };
+        self.call(cpu, memory, self.init_addr);
+        self.next_play = cpu_cycles + self.period;
+    }
+
+    /// Calls the play routine if it's due and the last routine called has
+    /// returned. Should be called before every CPU step.
+    pub fn step(&mut self, cpu: &mut CPU, memory: &mut Memory, cpu_cycles: u64) {
+        if cpu.pc != NSF_IDLE_ADDRESS || cpu_cycles < self.next_play {
+            return;
+        }
+        self.call(cpu, memory, self.play_addr);
+        self.next_play += self.period;
+    }
+
+    /// Jumps to a routine with the idle loop as its return address, as if it
+    /// was called with JSR.
+    fn call(&self, cpu: &mut CPU, memory: &mut Memory, addr: u16) {
+        memory.stack_push_u16(cpu, NSF_IDLE_ADDRESS - 1);
+        cpu.pc = addr;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::nsf::NSF_HEADER_SIZE;
+    use nes::mapper;
+    use nes::testing;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Builds an NSF with 3 songs starting on the second one, loaded at $8000
+    /// with its init routine at $8003 and play routine at $8006. Both routines
+    /// return straight away.
+    fn nsf() -> Vec<u8> {
+        let mut buf = vec![0; NSF_HEADER_SIZE];
+        buf[0x00..0x06].copy_from_slice(&[0x4E, 0x45, 0x53, 0x4D, 0x1A, 1]);
+        buf[0x06] = 3;
+        buf[0x07] = 2;
+        buf[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        buf.extend_from_slice(&[0xEA, 0xEA, 0xEA, 0x60, 0xEA, 0xEA, 0x60]);
+        buf
+    }
+
+    #[test]
+    fn start_calls_init_with_the_song_and_returns_to_the_idle_loop() {
+        let buf = nsf();
+        let header = NsfHeader::new(&buf).unwrap();
+        let cartridge = mapper::load_cartridge(&buf, &header.ines_header()).unwrap();
+        let mut memory = Memory::new(testing::runtime_options(), Rc::new(RefCell::new(cartridge)));
+        let mut cpu = CPU::new(testing::runtime_options());
+        cpu.power_on(&mut memory);
+
+        let mut player = NsfPlayer::new(&header);
+        player.start(&mut cpu, &mut memory, 0);
+        assert_eq!(cpu.pc, 0x8003);
+        assert_eq!(cpu.a, 1);
+        assert_eq!(cpu.x, 0);
+
+        // Init returns to the idle loop, which spins until play is due.
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, NSF_IDLE_ADDRESS);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.pc, NSF_IDLE_ADDRESS);
+        player.step(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.pc, NSF_IDLE_ADDRESS);
+
+        player.step(&mut cpu, &mut memory, player.period);
+        assert_eq!(cpu.pc, 0x8006);
+    }
+}
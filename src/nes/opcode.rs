@@ -163,25 +163,32 @@ enum_from_primitive! {
         TXSImp   = 0x9A,
         TYAImp   = 0x98,
 
+        // Unofficial opcodes.
+        AHXAbsY  = 0x9F,
+        AHXIndY  = 0x93,
+        SHXAbsY  = 0x9E,
+        SHYAbsX  = 0x9C,
+        TASAbsY  = 0x9B,
+
         PatternWorkaround = 0xFF,
     }
 }
 
-/// Decodes an opcode by converting an opcode number to an enum value.
-pub fn decode_opcode(opcode: u8) -> Opcode {
+/// Decodes an opcode by converting an opcode number to an enum value. None is
+/// returned for illegal opcodes and opcodes that aren't implemented yet.
+pub fn decode_opcode(opcode: u8) -> Option<Opcode> {
     match Opcode::from_u8(opcode) {
-        Some(opcode) => opcode,
-        None => {
-            panic!("Unimplemented opcode detected: {:2X}", opcode);
-        }
+        Some(Opcode::PatternWorkaround) | None => None,
+        Some(opcode) => Some(opcode),
     }
 }
 
-/// Determine the length of an instruction with the given opcode.
-pub fn opcode_len(opcode: &Opcode) -> u8 {
+/// Determine the length of an instruction with the given opcode. None is
+/// returned for opcodes that don't map to a real instruction.
+pub fn opcode_len(opcode: &Opcode) -> Option<u8> {
     use self::Opcode::*;
 
-    match *opcode {
+    let len = match *opcode {
         ADCImm => 2,
         ADCZero => 2,
         ADCZeroX => 2,
@@ -333,6 +340,214 @@ pub fn opcode_len(opcode: &Opcode) -> u8 {
         TXAImp => 1,
         TXSImp => 1,
         TYAImp => 1,
-        PatternWorkaround => 0,
+        AHXAbsY => 3,
+        AHXIndY => 2,
+        SHXAbsY => 3,
+        SHYAbsX => 3,
+        TASAbsY => 3,
+        PatternWorkaround => return None,
+    };
+    Some(len)
+}
+
+/// Determine the number of cycles an instruction with the given opcode takes
+/// at the least. Reading across a page boundary with an indexed or indirect
+/// read adds a cycle, and so does taking a branch (plus another if it lands on
+/// a different page), which is left to the instruction. Stores and
+/// read-modify-write instructions always take the page crossing cycle since
+/// they do a dummy read at the uncorrected address first, so it's already
+/// counted here. None is returned for opcodes that don't map to a real
+/// instruction.
+pub fn base_cycles(opcode: &Opcode) -> Option<u8> {
+    use self::Opcode::*;
+
+    let cycles = match *opcode {
+        ADCImm => 2,
+        ADCZero => 3,
+        ADCZeroX => 4,
+        ADCAbs => 4,
+        ADCAbsX => 4,
+        ADCAbsY => 4,
+        ADCIndX => 6,
+        ADCIndY => 5,
+        ANDImm => 2,
+        ANDZero => 3,
+        ANDZeroX => 4,
+        ANDAbs => 4,
+        ANDAbsX => 4,
+        ANDAbsY => 4,
+        ANDIndX => 6,
+        ANDIndY => 5,
+        ASLAcc => 2,
+        ASLZero => 5,
+        ASLZeroX => 6,
+        ASLAbs => 6,
+        ASLAbsX => 7,
+        BCCRel => 2,
+        BCSRel => 2,
+        BEQRel => 2,
+        BITZero => 3,
+        BITAbs => 4,
+        BMIRel => 2,
+        BNERel => 2,
+        BPLRel => 2,
+        BRKImp => 7,
+        BVCRel => 2,
+        BVSRel => 2,
+        CLCImp => 2,
+        CLDImp => 2,
+        CLIImp => 2,
+        CLVImp => 2,
+        CMPImm => 2,
+        CMPZero => 3,
+        CMPZeroX => 4,
+        CMPAbs => 4,
+        CMPAbsX => 4,
+        CMPAbsY => 4,
+        CMPIndX => 6,
+        CMPIndY => 5,
+        CPXImm => 2,
+        CPXZero => 3,
+        CPXAbs => 4,
+        CPYImm => 2,
+        CPYZero => 3,
+        CPYAbs => 4,
+        DECZero => 5,
+        DECZeroX => 6,
+        DECAbs => 6,
+        DECAbsX => 7,
+        DEXImp => 2,
+        DEYImp => 2,
+        EORImm => 2,
+        EORZero => 3,
+        EORZeroX => 4,
+        EORAbs => 4,
+        EORAbsX => 4,
+        EORAbsY => 4,
+        EORIndX => 6,
+        EORIndY => 5,
+        INCZero => 5,
+        INCZeroX => 6,
+        INCAbs => 6,
+        INCAbsX => 7,
+        INXImp => 2,
+        INYImp => 2,
+        JMPAbs => 3,
+        JMPInd => 5,
+        JSRAbs => 6,
+        LDAImm => 2,
+        LDAZero => 3,
+        LDAZeroX => 4,
+        LDAAbs => 4,
+        LDAAbsX => 4,
+        LDAAbsY => 4,
+        LDAIndX => 6,
+        LDAIndY => 5,
+        LDXImm => 2,
+        LDXZero => 3,
+        LDXZeroY => 4,
+        LDXAbs => 4,
+        LDXAbsY => 4,
+        LDYImm => 2,
+        LDYZero => 3,
+        LDYZeroX => 4,
+        LDYAbs => 4,
+        LDYAbsX => 4,
+        LSRAcc => 2,
+        LSRZero => 5,
+        LSRZeroX => 6,
+        LSRAbs => 6,
+        LSRAbsX => 7,
+        NOPImp => 2,
+        ORAImm => 2,
+        ORAZero => 3,
+        ORAZeroX => 4,
+        ORAAbs => 4,
+        ORAAbsX => 4,
+        ORAAbsY => 4,
+        ORAIndX => 6,
+        ORAIndY => 5,
+        PHAImp => 3,
+        PHPImp => 3,
+        PLAImp => 4,
+        PLPImp => 4,
+        ROLAcc => 2,
+        ROLZero => 5,
+        ROLZeroX => 6,
+        ROLAbs => 6,
+        ROLAbsX => 7,
+        RORAcc => 2,
+        RORZero => 5,
+        RORZeroX => 6,
+        RORAbs => 6,
+        RORAbsX => 7,
+        RTIImp => 6,
+        RTSImp => 6,
+        SBCImm => 2,
+        SBCZero => 3,
+        SBCZeroX => 4,
+        SBCAbs => 4,
+        SBCAbsX => 4,
+        SBCAbsY => 4,
+        SBCIndX => 6,
+        SBCIndY => 5,
+        SECImp => 2,
+        SEDImp => 2,
+        SEIImp => 2,
+        STAZero => 3,
+        STAZeroX => 4,
+        STAAbs => 4,
+        STAAbsX => 5,
+        STAAbsY => 5,
+        STAIndX => 6,
+        STAIndY => 6,
+        STXZero => 3,
+        STXZeroY => 4,
+        STXAbs => 4,
+        STYZero => 3,
+        STYZeroX => 4,
+        STYAbs => 4,
+        TAXImp => 2,
+        TAYImp => 2,
+        TSXImp => 2,
+        TXAImp => 2,
+        TXSImp => 2,
+        TYAImp => 2,
+        AHXAbsY => 5,
+        AHXIndY => 6,
+        SHXAbsY => 5,
+        SHYAbsX => 5,
+        TASAbsY => 5,
+        PatternWorkaround => return None,
+    };
+    Some(cycles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Opcode::*;
+
+    #[test]
+    fn shifts_and_rotates_take_the_documented_cycles() {
+        // Timings from the 6502 reference. Read-modify-write instructions
+        // always take the extra cycle for indexing, so abs,X is a flat 7.
+        let reference = [
+            (ASLAcc, 2), (ASLZero, 5), (ASLZeroX, 6), (ASLAbs, 6), (ASLAbsX, 7),
+            (LSRAcc, 2), (LSRZero, 5), (LSRZeroX, 6), (LSRAbs, 6), (LSRAbsX, 7),
+            (ROLAcc, 2), (ROLZero, 5), (ROLZeroX, 6), (ROLAbs, 6), (ROLAbsX, 7),
+            (RORAcc, 2), (RORZero, 5), (RORZeroX, 6), (RORAbs, 6), (RORAbsX, 7),
+        ];
+        for &(ref opcode, cycles) in reference.iter() {
+            assert_eq!(base_cycles(opcode), Some(cycles), "{:?}", opcode);
+        }
+    }
+
+    #[test]
+    fn unmapped_bytes_decode_to_nothing() {
+        for &byte in [0x02, 0x12, 0x80, 0xFA].iter() {
+            assert_eq!(decode_opcode(byte), None, "{:02X}", byte);
+        }
+        assert_eq!(decode_opcode(0xA9), Some(LDAImm));
     }
 }
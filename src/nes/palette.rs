@@ -0,0 +1,34 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// RGB values of the 64 colors the 2C02 can output, indexed by the values
+// stored in palette RAM. Only the lower 6 bits of an index are used.
+const MASTER_PALETTE: [(u8, u8, u8); 64] = [
+    (84, 84, 84),    (0, 30, 116),    (8, 16, 144),    (48, 0, 136),
+    (68, 0, 100),    (92, 0, 48),     (84, 4, 0),      (60, 24, 0),
+    (32, 42, 0),     (8, 58, 0),      (0, 64, 0),      (0, 60, 0),
+    (0, 50, 60),     (0, 0, 0),       (0, 0, 0),       (0, 0, 0),
+    (152, 150, 152), (8, 76, 196),    (48, 50, 236),   (92, 30, 228),
+    (136, 20, 176),  (160, 20, 100),  (152, 34, 32),   (120, 60, 0),
+    (84, 90, 0),     (40, 114, 0),    (8, 124, 0),     (0, 118, 40),
+    (0, 102, 120),   (0, 0, 0),       (0, 0, 0),       (0, 0, 0),
+    (236, 238, 236), (76, 154, 236),  (120, 124, 236), (176, 98, 236),
+    (228, 84, 236),  (236, 88, 180),  (236, 106, 100), (212, 136, 32),
+    (160, 170, 0),   (116, 196, 0),   (76, 208, 32),   (56, 204, 108),
+    (56, 180, 204),  (60, 60, 60),    (0, 0, 0),       (0, 0, 0),
+    (236, 238, 236), (168, 204, 236), (188, 188, 236), (212, 178, 236),
+    (236, 174, 236), (236, 174, 212), (236, 180, 176), (228, 196, 144),
+    (204, 210, 120), (180, 222, 120), (168, 226, 144), (152, 226, 180),
+    (160, 214, 228), (160, 162, 160), (0, 0, 0),       (0, 0, 0),
+];
+
+/// Resolves a color index from palette RAM to the RGB color the PPU outputs
+/// for it.
+pub fn rgb(index: u8) -> (u8, u8, u8) {
+    MASTER_PALETTE[(index & 0x3F) as usize]
+}
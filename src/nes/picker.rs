@@ -0,0 +1,66 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use nes::nes::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use sdl2;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::pixels::Color;
+
+/// Opens an empty window and waits for a rom to be dragged onto it, which is
+/// how nes-rs starts when it's launched without one (e.g. from a file
+/// manager). Returns the path of the dropped file, or None if the window was
+/// closed first. SDL is shut down before returning so the emulator can set it
+/// up again.
+pub fn wait_for_rom() -> Result<Option<String>, String> {
+    let sdl_context = try!(sdl2::init().map_err(|e| format!("cannot initialize SDL: {}", e)));
+    let video_subsystem = try!(
+        sdl_context
+            .video()
+            .map_err(|e| format!("cannot initialize video: {}", e))
+    );
+    let window = try!(
+        video_subsystem
+            .window("nes-rs - drop a rom here to play it", SCREEN_WIDTH * 2, SCREEN_HEIGHT * 2)
+            .position_centered()
+            .resizable()
+            .build()
+            .map_err(|e| format!("cannot open window: {}", e))
+    );
+    let mut event_pump = try!(
+        sdl_context
+            .event_pump()
+            .map_err(|e| format!("cannot read events: {}", e))
+    );
+    let mut canvas = try!(
+        window
+            .into_canvas()
+            .build()
+            .map_err(|e| format!("cannot create canvas: {}", e))
+    );
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
+    canvas.present();
+
+    // Nothing is drawn, so there's no need to wake up until something
+    // happens.
+    for event in event_pump.wait_iter() {
+        match event {
+            Event::DropFile { filename, .. } => return Ok(Some(filename)),
+            Event::Quit { .. } => return Ok(None),
+            Event::Window {
+                win_event: WindowEvent::Exposed,
+                ..
+            } => {
+                canvas.clear();
+                canvas.present();
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
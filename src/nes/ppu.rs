@@ -6,22 +6,62 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use io::binutils::MirrorType;
+use nes::mapper::SharedMapper;
 use nes::memory::Memory;
 use nes::memory::MiscRegisterStatus;
 use nes::memory::PPURegisterStatus;
 use nes::nes::NESRuntimeOptions;
-
-use nes::memory::{
-    PPU_CTRL_REGISTERS_SIZE,
-    MISC_CTRL_REGISTERS_SIZE,
-};
-
-const SPR_RAM_SIZE: usize = 0x00FF;
+use std::fmt;
+use utils::arithmetic;
+use utils::hash;
+
+// OAM holds 64 sprites that are 4 bytes each.
+const SPR_RAM_SIZE: usize = 0x0100;
+
+// CPU cycles stolen by an OAM DMA transfer. One more cycle is stolen when the
+// transfer starts on an odd CPU cycle.
+const OAM_DMA_CYCLES: u16 = 513;
+
+// Every frame is made up of 262 scanlines of 341 dots each (NTSC). The PPU
+// outputs a single dot every PPU cycle.
+pub const DOTS_PER_SCANLINE:   u16 = 341;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+
+// Sprites are fetched on the visible scanlines and the pre-render scanline
+// while rendering is enabled. Secondary OAM is cleared over the first 64 dots
+// of each of those scanlines.
+const VISIBLE_SCANLINES:       u16 = 240;
+const PRE_RENDER_SCANLINE:     u16 = 261;
+const SECONDARY_OAM_CLEAR_END: u16 = 64;
+
+// A pixel is output on each of the first 256 dots after the idle dot 0. The
+// first 2 background tiles of the next scanline are fetched ahead of time near
+// the end of the current one.
+const VISIBLE_DOTS:        u16 = 256;
+const PREFETCH_START:      u16 = 321;
+const PREFETCH_END:        u16 = 336;
+
+// The vertical scroll bits are copied from t into v over these dots of the
+// pre-render scanline.
+const COPY_VERTICAL_START: u16 = 280;
+const COPY_VERTICAL_END:   u16 = 304;
+
+// Vblank starts on the second dot of the scanline after the post-render one,
+// which is where a frame ends, and lasts until the same dot of the pre-render
+// scanline.
+const VBLANK_SCANLINE: u16 = 241;
+
+// Only 8 sprites can be drawn on a single scanline.
+const SPRITES_PER_SCANLINE: usize = 8;
+
+// Size of the picture the PPU outputs.
+const FRAMEBUFFER_SIZE: usize = VISIBLE_DOTS as usize * VISIBLE_SCANLINES as usize;
 
 // Memory map section sizes.
-const PATTERN_TABLES_SIZE: usize = 0x2000;
-const NAME_TABLES_SIZE:    usize = 0x1000;
-const PALETTES_SIZE:       usize = 0x0020;
+const NAME_TABLES_SIZE: usize = 0x1000;
+const CIRAM_SIZE:       usize = 0x0800;
+const PALETTES_SIZE:    usize = 0x0020;
 
 // Memory map bounds.
 const PATTERN_TABLES_START:     usize = 0x0000;
@@ -57,6 +97,7 @@ const INITIAL_OAMDATA:   u8 = 0b00000000;
 const INITIAL_PPUSCROLL: u8 = 0b00000000;
 const INITIAL_PPUADDR:   u8 = 0b00000000;
 const INITIAL_PPUDATA:   u8 = 0b00000000;
+#[allow(dead_code)]
 const INITIAL_OAMDMA:    u8 = 0b00000000;
 
 // Bitmask values for PPU registers.
@@ -79,6 +120,14 @@ const PPUSTATUS_REGISTER_BITS:                  u8 = 0b00011111;
 const PPUSTATUS_SPRITE_OVERFLOW:                u8 = 0b00100000;
 const PPUSTATUS_SPRITE_0_HIT:                   u8 = 0b01000000;
 const PPUSTATUS_VBLANK:                         u8 = 0b10000000;
+const SPRITE_ATTRIBUTE_PALETTE:                 u8 = 0b00000011;
+const SPRITE_ATTRIBUTE_UNUSED:                  u8 = 0b00011100;
+const SPRITE_ATTRIBUTE_BEHIND_BACKGROUND:       u8 = 0b00100000;
+const SPRITE_ATTRIBUTE_FLIP_HORIZONTAL:         u8 = 0b01000000;
+const SPRITE_ATTRIBUTE_FLIP_VERTICAL:           u8 = 0b10000000;
+
+// Sprites use the upper half of palette RAM.
+const SPRITE_PALETTES_OFFSET: u8 = 0x10;
 
 /// SpriteSize is used by flag reading functions when sprite size information is
 /// required at runtime.
@@ -93,6 +142,52 @@ enum MasterSlaveSelect {
     OutputColor,
 }
 
+/// A single entry in OAM describing where and how a sprite is drawn.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Sprite {
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    pub x: u8,
+}
+
+impl Sprite {
+    /// Returns which of the four sprite palettes the sprite is drawn with.
+    pub fn palette(&self) -> u8 {
+        self.attributes & SPRITE_ATTRIBUTE_PALETTE
+    }
+
+    /// Returns true if the sprite is drawn behind opaque background pixels.
+    pub fn behind_background(&self) -> bool {
+        self.attributes & SPRITE_ATTRIBUTE_BEHIND_BACKGROUND > 0
+    }
+
+    /// Returns true if the sprite is mirrored left to right.
+    pub fn flip_horizontal(&self) -> bool {
+        self.attributes & SPRITE_ATTRIBUTE_FLIP_HORIZONTAL > 0
+    }
+
+    /// Returns true if the sprite is mirrored top to bottom.
+    pub fn flip_vertical(&self) -> bool {
+        self.attributes & SPRITE_ATTRIBUTE_FLIP_VERTICAL > 0
+    }
+}
+
+/// A sprite found in OAM while evaluating sprites for a scanline, along with
+/// the row of its pattern that's drawn on that scanline.
+#[derive(Debug, Clone, Copy)]
+struct LineSprite {
+    sprite: Sprite,
+
+    // Both bit planes of the pattern row, already flipped so the leftmost
+    // pixel is in bit 7.
+    pattern_low: u8,
+    pattern_high: u8,
+
+    // Sprite 0 is the only sprite that can set the sprite 0 hit flag.
+    sprite_zero: bool,
+}
+
 /// This is an implementation of the 2C02 PPU used in the NES. This piece of
 /// hardware is responsible for drawing graphics to the television the console
 /// is hooked up to; however in our case we draw to an SDL surface.
@@ -121,18 +216,62 @@ pub struct PPU {
     ppu_addr: u8,
     ppu_data: u8,
 
+    // Current VRAM address (15 bits) used by PPUDATA accesses.
+    v: u16,
+
+    // Temporary VRAM address (15 bits). Bits 10-11 hold the nametable select
+    // written through PPUCTRL, the rest is filled in by PPUSCROLL and PPUADDR.
+    t: u16,
+
+    // Fine X scroll (3 bits), which picks the pixel within the current
+    // background tile.
+    x: u8,
+
+    // Write toggle shared by PPUSCROLL and PPUADDR which tracks whether the
+    // next write is the first or second. Reading PPUSTATUS resets it.
+    w: bool,
+
+    // PPUDATA reads outside of palette memory return the contents of this
+    // buffer, which is then refilled from the current VRAM address.
+    read_buffer: u8,
+
+    // Set when the PPU wants to signal an NMI to the CPU. The NES clears this
+    // once the NMI has been delivered.
+    nmi_occurred: bool,
+
+    // Set when a frame has been output and vblank starts. The NES clears this
+    // once it has moved on to the next frame.
+    frame_complete: bool,
+
+    // The scanline and dot the PPU is currently outputting. Scanline 261 is
+    // the pre-render scanline.
+    scanline: u16,
+    dot: u16,
+
     // The runtime options contain some useful information such as television
     // standard which affect the clock rate of the PPU.
+    #[allow(dead_code)]
     runtime_options: NESRuntimeOptions,
 
     // The PPU has 2 pattern tables which store 8x8 pixel tiles which can be
-    // drawn to the screen.
-    pattern_tables: [u8; PATTERN_TABLES_SIZE],
+    // drawn to the screen. They live on the cartridge, so accesses go through
+    // the mapper.
+    mapper: SharedMapper,
 
     // The name tables are matrices of numbers that point to tiles stored in the
     // pattern tables. Each name table has an associated attribute table, which
     // contains the upper 2 bits of colors for each of the associated tiles.
-    name_tables: [u8; NAME_TABLES_SIZE],
+    //
+    // The console only has enough RAM (CIRAM) for 2 name tables, which are
+    // mirrored to fill the 4 logical ones. Four screen carts bring another 2KB
+    // of VRAM for the other 2 tables, otherwise this is left empty.
+    ciram: [u8; CIRAM_SIZE],
+    cartridge_vram: Vec<u8>,
+
+    // Decides which physical name table each of the 4 logical name tables
+    // maps to. Starts out as set in the ROM header, but some mappers change it
+    // at runtime so it's kept in sync with the mapper every step.
+    mirroring: MirrorType,
 
     // The PPU has 2 color palettes each containing 16 entires selected from the
     // PPU total selection of 52 colors. Because of this all possible colors the
@@ -145,11 +284,36 @@ pub struct PPU {
 
     // Where sprites are stored (different bus).
     spr_ram: [u8; SPR_RAM_SIZE],
+
+    // Latches holding the parts of the next background tile as they're
+    // fetched. The attribute latch only keeps the 2 bits for the tile.
+    nametable_latch: u8,
+    attribute_latch: u8,
+    pattern_low_latch: u8,
+    pattern_high_latch: u8,
+
+    // Background pixels waiting to be drawn, 4 bits each, with the attribute
+    // bits above the pattern bits. The upper 32 bits hold the tile being drawn
+    // and the lower 32 bits hold the next one.
+    tile_data: u64,
+
+    // Sprites drawn on the current scanline, in OAM order.
+    line_sprites: Vec<LineSprite>,
+
+    // The picture being drawn, as indices into the NES palette in row-major
+    // order.
+    framebuffer: Vec<u8>,
 }
 
 impl PPU {
     /// Initializes the PPU and it's internal memory.
-    pub fn new(runtime_options: NESRuntimeOptions) -> Self {
+    pub fn new(runtime_options: NESRuntimeOptions, mapper: SharedMapper) -> Self {
+        let mirroring = mapper.borrow().mirroring();
+        let cartridge_vram = if mirroring == MirrorType::Both {
+            vec![0; NAME_TABLES_SIZE - CIRAM_SIZE]
+        } else {
+            Vec::new()
+        };
         PPU {
             ppu_ctrl: INITIAL_PPUCTRL,
             ppu_mask: INITIAL_PPUMASK,
@@ -159,50 +323,130 @@ impl PPU {
             ppu_scroll: INITIAL_PPUSCROLL,
             ppu_addr: INITIAL_PPUADDR,
             ppu_data: INITIAL_PPUDATA,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            nmi_occurred: false,
+            frame_complete: false,
+            scanline: 0,
+            dot: 0,
             runtime_options: runtime_options,
-            pattern_tables: [0; PATTERN_TABLES_SIZE],
-            name_tables: [0; NAME_TABLES_SIZE],
+            mapper: mapper,
+            ciram: [0; CIRAM_SIZE],
+            cartridge_vram: cartridge_vram,
+            mirroring: mirroring,
             palettes: [0; PALETTES_SIZE],
             spr_ram: [0; SPR_RAM_SIZE],
+            nametable_latch: 0,
+            attribute_latch: 0,
+            pattern_low_latch: 0,
+            pattern_high_latch: 0,
+            tile_data: 0,
+            line_sprites: Vec::with_capacity(SPRITES_PER_SCANLINE),
+            framebuffer: vec![0; FRAMEBUFFER_SIZE],
         }
     }
 
+    /// Applies the reset button to the PPU. PPUCTRL and PPUMASK are cleared
+    /// along with the write toggle and read buffer, while memory and the
+    /// current position in the frame are left alone.
+    pub fn reset(&mut self) {
+        self.ppu_ctrl = INITIAL_PPUCTRL;
+        self.ppu_mask = INITIAL_PPUMASK;
+        self.w = false;
+        self.read_buffer = 0;
+        self.nmi_occurred = false;
+    }
+
     /// Maps a PPU virtual addresses to a physical address used internally by
-    /// the PPU emulator.
+    /// the PPU emulator. The pattern tables are on the cartridge, so they are
+    /// handled by the mapper instead.
     fn map(&mut self, addr: usize) -> (&mut [u8], usize) {
         match addr {
-            PATTERN_TABLES_START...PATTERN_TABLES_END =>
-                (&mut self.pattern_tables, addr),
-            NAME_TABLES_START...NAME_TABLES_END =>
-                (&mut self.name_tables, addr - NAME_TABLES_START),
-            NAME_TABLES_MIRROR_START...NAME_TABLES_MIRROR_END =>
-                (&mut self.name_tables, (addr - NAME_TABLES_START) % NAME_TABLES_SIZE),
-            PALETTES_START...PALETTES_END =>
-                (&mut self.palettes, addr - PALETTES_START),
-            PALETTES_MIRROR_START...PALETTES_MIRROR_END =>
-                (&mut self.palettes, (addr - PALETTES_START) % PALETTES_SIZE),
+            NAME_TABLES_START...NAME_TABLES_END
+            | NAME_TABLES_MIRROR_START...NAME_TABLES_MIRROR_END => {
+                let offset = PPU::fold_name_table_address(self.mirroring, addr);
+                if offset < CIRAM_SIZE || self.cartridge_vram.is_empty() {
+                    (&mut self.ciram, offset % CIRAM_SIZE)
+                } else {
+                    (&mut self.cartridge_vram, offset - CIRAM_SIZE)
+                }
+            }
+            PALETTES_START...PALETTES_END | PALETTES_MIRROR_START...PALETTES_MIRROR_END =>
+                (&mut self.palettes, PPU::fold_palette_address(addr)),
             MIRROR_START...MIRROR_END =>
                 self.map(addr - MIRROR_START), // Lazy recursion to share nested mirror logic ^^^.
             _ => { panic!("Unable to map virtual address {:#X} to any physical address", addr) },
         }
     }
 
+    /// Maps an address in one of the 4 logical name tables to an offset into
+    /// name table memory using the given mirroring mode. Only four screen
+    /// mirroring uses all 4 physical tables, the other modes share 1 or 2.
+    fn fold_name_table_address(mirroring: MirrorType, addr: usize) -> usize {
+        let offset = (addr - NAME_TABLES_START) % NAME_TABLES_SIZE;
+        let table = offset / 0x400;
+        let physical_table = match mirroring {
+            MirrorType::Horizontal => table / 2,
+            MirrorType::Vertical => table % 2,
+            MirrorType::Both => table,
+            MirrorType::SingleLower => 0,
+            MirrorType::SingleUpper => 1,
+        };
+        physical_table * 0x400 + offset % 0x400
+    }
+
+    /// Maps an address in palette RAM or its mirrors to an offset into palette
+    /// memory. The first entry of each sprite palette ($3F10/$3F14/$3F18/$3F1C)
+    /// is shared with the matching background palette entry at $3F00/$3F04/
+    /// $3F08/$3F0C.
+    fn fold_palette_address(addr: usize) -> usize {
+        let offset = (addr - PALETTES_START) % PALETTES_SIZE;
+        if offset & 0x13 == 0x10 {
+            offset & 0x0F
+        } else {
+            offset
+        }
+    }
+
+    /// Changes how the logical name tables are mirrored. This is called with
+    /// the mirroring from the ROM header at power on, and by mappers that
+    /// control mirroring when their mirroring register is written.
+    pub fn set_mirroring(&mut self, mode: MirrorType) {
+        self.mirroring = mode;
+    }
+
     /// Reads a byte from PPU memory at the given virtual address.
     #[inline(always)]
     fn read_u8(&mut self, addr: usize) -> u8 {
-        let (bank, addr) = self.map(addr);
-        bank[addr]
+        match addr & 0x3FFF {
+            PATTERN_TABLES_START...PATTERN_TABLES_END =>
+                self.mapper.borrow_mut().read_chr(addr & 0x3FFF),
+            _ => {
+                let (bank, addr) = self.map(addr);
+                bank[addr]
+            }
+        }
     }
 
     /// Writes a byte to PPU memory at the given virtual address.
     #[inline(always)]
     fn write_u8(&mut self, addr: usize, value: u8) {
-        let (bank, addr) = self.map(addr);
-        bank[addr] = value;
+        match addr & 0x3FFF {
+            PATTERN_TABLES_START...PATTERN_TABLES_END =>
+                self.mapper.borrow_mut().write_chr(addr & 0x3FFF, value),
+            _ => {
+                let (bank, addr) = self.map(addr);
+                bank[addr] = value;
+            }
+        }
     }
 
     /// Returns the base nametable address currently set in PPUCTRL.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_ctrl_base_nametable_address(&self) -> usize {
         match self.ppu_ctrl & PPUCTRL_BASE_NAMETABLE_ADDRESS {
             0 => 0x2000,
@@ -251,6 +495,7 @@ impl PPU {
 
     /// Returns the PPU master slave select state.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_ctrl_master_slave_select(&self) -> MasterSlaveSelect {
         match self.ppu_ctrl & PPUCTRL_MASTER_SLAVE_SELECT {
             0 => MasterSlaveSelect::ReadBackdrop,
@@ -296,36 +541,42 @@ impl PPU {
 
     /// Returns the state of the PPUMASK_EMPHASIZE_RED flag.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_mask_emphasize_red(&self) -> bool {
         self.ppu_mask & PPUMASK_EMPHASIZE_RED > 0
     }
 
     /// Returns the state of the PPUMASK_EMPHASIZE_GREEN flag.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_mask_emphasize_green(&self) -> bool {
         self.ppu_mask & PPUMASK_EMPHASIZE_GREEN > 0
     }
 
     /// Returns the state of the PPUMASK_EMPHASIZE_BLUE flag.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_mask_emphasize_blue(&self) -> bool {
         self.ppu_mask & PPUMASK_EMPHASIZE_BLUE > 0
     }
 
     /// Returns the state of the PPUSTATUS_REGISTER_BITS flag.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_status_register_bits(&self) -> u8 {
         self.ppu_status & PPUSTATUS_REGISTER_BITS
     }
 
     /// Returns the state of the PPUSTATUS_SPRITE_OVERFLOW flag.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_status_sprite_overflow(&self) -> bool {
         self.ppu_status & PPUSTATUS_SPRITE_OVERFLOW > 0
     }
 
     /// Returns the state of the PPUSTATUS_SPRITE_0_HIT flag.
     #[inline(always)]
+    #[allow(dead_code)]
     fn ppu_status_sprite_0_hit(&self) -> bool {
         self.ppu_status & PPUSTATUS_SPRITE_0_HIT > 0
     }
@@ -336,110 +587,572 @@ impl PPU {
         self.ppu_status & PPUSTATUS_VBLANK > 0
     }
 
-    /// Copy data from main memory to the PPU's internal sprite memory.
-    /// TODO: Implement me!
-    fn exec_dma(&mut self, register: u8) {
-        println!("{:02X}", register);
-        panic!("DMA unimplemented");
+    /// Decodes an 8x8 tile from a pattern table into 2-bit color indices in
+    /// row-major order. Each tile is 16 bytes where the first 8 bytes hold the
+    /// low bit plane and the last 8 bytes hold the high bit plane.
+    pub fn decode_tile(&mut self, pattern_table: usize, tile: u8) -> [u8; 64] {
+        let base = pattern_table + tile as usize * 16;
+        let mut pixels = [0; 64];
+
+        // Tiles are peeked so viewing them doesn't trip mapper latches.
+        let mapper = self.mapper.borrow();
+        for row in 0..8 {
+            let low = mapper.peek_chr(base + row);
+            let high = mapper.peek_chr(base + row + 8);
+            for col in 0..8 {
+                let bit = 7 - col;
+                pixels[row * 8 + col] = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+            }
+        }
+
+        pixels
+    }
+
+    /// Returns the tile indices stored in one of the four nametables as a
+    /// 32x30 grid in row-major order. The attribute table is not included.
+    pub fn nametable_tiles(&mut self, nametable: usize) -> Vec<u8> {
+        let base = NAME_TABLES_START + (nametable & 0x3) * 0x400;
+        (0..32 * 30).map(|offset| self.read_u8(base + offset)).collect()
+    }
+
+    /// Returns the pattern table currently used for background tiles.
+    pub fn background_pattern_table(&self) -> usize {
+        self.ppu_ctrl_background_pattern_table_address()
+    }
+
+    /// Returns the pattern table currently used for 8x8 sprites.
+    pub fn sprite_pattern_table(&self) -> usize {
+        self.ppu_ctrl_sprite_pattern_table_address()
+    }
+
+    /// Returns true if either the background or sprites are being drawn.
+    pub fn rendering_enabled(&self) -> bool {
+        self.ppu_mask_show_background() || self.ppu_mask_show_sprites()
+    }
+
+    /// Returns true if sprites are currently 8x16 pixels rather than 8x8.
+    pub fn tall_sprites(&self) -> bool {
+        match self.ppu_ctrl_sprite_size() {
+            SpriteSize::Bounds8x8 => false,
+            SpriteSize::Bounds8x16 => true,
+        }
+    }
+
+    /// Returns the picture the PPU has drawn as 256x240 indices into the NES
+    /// palette in row-major order. Scanlines that haven't been drawn yet this
+    /// frame still hold the last frame.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Returns a hash of the picture in the framebuffer, which stays the same
+    /// from run to run as long as the game draws the same thing.
+    pub fn frame_hash(&self) -> u64 {
+        hash::fnv1a(&self.framebuffer)
+    }
+
+    /// Returns true if the background is drawn at the given X position.
+    fn background_visible(&self, x: u8) -> bool {
+        self.ppu_mask_show_background() && (x >= 8 || self.ppu_mask_show_background_left())
+    }
+
+    /// Returns true if sprites are drawn at the given X position.
+    fn sprites_visible(&self, x: u8) -> bool {
+        self.ppu_mask_show_sprites() && (x >= 8 || self.ppu_mask_show_sprites_left())
+    }
+
+    /// Picks the pixel that ends up on screen at the given X position out of
+    /// the background and sprite pixels for that dot, returning its index into
+    /// palette RAM. Both pixels are 4-bit palette indices where the low 2 bits
+    /// are the pattern color, and a pattern color of 0 is transparent.
+    ///
+    /// Layers that are disabled, or clipped in the leftmost 8 pixels, count as
+    /// transparent. When both pixels are opaque the sprite wins unless it has
+    /// its behind background attribute set. If neither is opaque the backdrop
+    /// color at index 0 is shown.
+    pub fn multiplex_pixel(&self, x: u8, background: u8, sprite: u8, behind: bool) -> u8 {
+        let background_opaque = self.background_visible(x) && background & 0x03 != 0;
+        let sprite_opaque = self.sprites_visible(x) && sprite & 0x03 != 0;
+
+        match (background_opaque, sprite_opaque) {
+            (false, false) => 0,
+            (true, false) => background & 0x0F,
+            (false, true) => SPRITE_PALETTES_OFFSET | (sprite & 0x0F),
+            (true, true) if behind => background & 0x0F,
+            (true, true) => SPRITE_PALETTES_OFFSET | (sprite & 0x0F),
+        }
+    }
+
+    /// Returns the contents of palette RAM. The first 16 bytes hold the
+    /// background palettes and the last 16 hold the sprite palettes.
+    pub fn palettes(&self) -> &[u8; PALETTES_SIZE] {
+        &self.palettes
+    }
+
+    /// Returns every sprite currently stored in OAM.
+    pub fn sprites(&self) -> Vec<Sprite> {
+        self.spr_ram
+            .chunks(4)
+            .map(|entry| Sprite {
+                y: entry[0],
+                tile: entry[1],
+                attributes: entry[2],
+                x: entry[3],
+            })
+            .collect()
+    }
+
+    /// Copy a full page of main memory ($XX00-$XXFF) to the PPU's internal
+    /// sprite memory. The copy starts at OAMADDR and wraps around within OAM so
+    /// all 256 bytes are always written. Returns the CPU cycles stolen by the
+    /// copy.
+    fn exec_dma(&mut self, page: u8, memory: &mut Memory) -> u16 {
+        let base_addr = (page as usize) << 8;
+        for offset in 0..SPR_RAM_SIZE {
+            let oam_addr = self.oam_address.wrapping_add(offset as u8) as usize;
+            self.spr_ram[oam_addr] = memory.read_u8(base_addr + offset);
+        }
+        OAM_DMA_CYCLES
     }
 
     /// Reads the contents of the DMA register and executes DMA if written since
-    /// the last PPU cycle.
-    /// TODO: Implement me!
-    fn handle_dma_register(&mut self, index: usize, memory: &mut Memory) {
+    /// the last PPU cycle. Returns the CPU cycles stolen by DMA, if any.
+    fn handle_dma_register(&mut self, index: usize, memory: &mut Memory) -> u16 {
         let state = memory.misc_ctrl_registers_status[index];
         if state != MiscRegisterStatus::Written {
-            return;
+            return 0;
         }
         let register = memory.misc_ctrl_registers[index];
-        self.exec_dma(register);
+        memory.misc_ctrl_registers_status[index] = MiscRegisterStatus::Untouched;
+        self.exec_dma(register, memory)
     }
 
     /// Updates the internal PPUCTRL register when the I/O register was written
-    /// since the last PPU cycle.
-    /// FIXME: Make accurate.
+    /// since the last PPU cycle. The nametable select bits are copied into bits
+    /// 10-11 of the temporary VRAM address.
+    ///
+    /// Enabling NMI while the vblank flag is already set causes an NMI to fire
+    /// immediately, which some games rely on.
     fn handle_ppu_ctrl(&mut self, index: usize, memory: &mut Memory) {
         let state = memory.ppu_ctrl_registers_status[index];
-        if state != PPURegisterStatus::Written || state != PPURegisterStatus::WrittenTwice {
+        if state != PPURegisterStatus::Written && state != PPURegisterStatus::WrittenTwice {
             return;
         }
+        let nmi_was_enabled = self.ppu_ctrl_nmi_enabled();
         self.ppu_ctrl = memory.ppu_ctrl_registers[index];
         memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
 
-        panic!("Implement PPUCTRL write handling");
+        let nametable = (self.ppu_ctrl & PPUCTRL_BASE_NAMETABLE_ADDRESS) as u16;
+        self.t = (self.t & !0x0C00) | (nametable << 10);
+
+        if !nmi_was_enabled && self.ppu_ctrl_nmi_enabled() && self.ppu_status_vblank() {
+            self.nmi_occurred = true;
+        }
     }
 
     /// Updates the internal PPUMASK register when the I/O register was written
     /// since the last PPU cycle.
-    /// FIXME: Make accurate.
     fn handle_ppu_mask(&mut self, index: usize, memory: &mut Memory) {
         let state = memory.ppu_ctrl_registers_status[index];
-        if state != PPURegisterStatus::Written || state != PPURegisterStatus::WrittenTwice {
+        if state != PPURegisterStatus::Written && state != PPURegisterStatus::WrittenTwice {
             return;
         }
         self.ppu_mask = memory.ppu_ctrl_registers[index];
         memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
-
-        panic!("Implement PPUMASK write handling");
     }
 
-    /// FIXME: Make accurate.
+    /// Reading PPUSTATUS clears the vblank flag after the read and resets the
+    /// write toggle shared by PPUSCROLL and PPUADDR.
     fn handle_ppu_status(&mut self, index: usize, memory: &mut Memory) {
-        // panic!("Implement PPUSTATUS handling");
+        let state = memory.ppu_ctrl_registers_status[index];
+        if state != PPURegisterStatus::Read {
+            return;
+        }
+        memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
+        self.ppu_status &= !PPUSTATUS_VBLANK;
+        self.w = false;
     }
 
-    /// Updates the internal OAMADDR registers with data in the I/O register.
-    /// FIXME: Make accurate.
+    /// Updates the internal OAMADDR register with data in the I/O register.
     fn handle_oam_addr(&mut self, index: usize, memory: &mut Memory) {
         let state = memory.ppu_ctrl_registers_status[index];
-        if state != PPURegisterStatus::Written || state != PPURegisterStatus::WrittenTwice {
+        if state != PPURegisterStatus::Written && state != PPURegisterStatus::WrittenTwice {
             return;
         }
         self.oam_address = memory.ppu_ctrl_registers[index];
         memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
-
-        panic!("Implement OAMADDR write handling");
     }
 
-    /// Updates the internal OAMADDR registers with data in the I/O register.
-    /// FIXME: Make accurate.
+    /// Handles reads and writes to OAMDATA. Writes store to OAM at OAMADDR and
+    /// increment it, while reads leave OAMADDR alone. While sprites are being
+    /// fetched OAM is busy, so writes don't reach it and instead bump OAMADDR
+    /// to the next sprite.
     fn handle_oam_data(&mut self, index: usize, memory: &mut Memory) {
         let state = memory.ppu_ctrl_registers_status[index];
-        if state != PPURegisterStatus::Written || state != PPURegisterStatus::WrittenTwice {
-            return;
+        match state {
+            PPURegisterStatus::Written | PPURegisterStatus::WrittenTwice => {
+                self.oam_data = memory.ppu_ctrl_registers[index];
+                if self.fetching_sprites() {
+                    self.oam_address = self.oam_address.wrapping_add(4);
+                } else {
+                    self.spr_ram[self.oam_address as usize] = self.oam_data;
+                    self.oam_address = self.oam_address.wrapping_add(1);
+                }
+                memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
+            }
+            PPURegisterStatus::Read => {
+                memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
+            }
+            PPURegisterStatus::Untouched => {}
         }
-        self.oam_data = memory.ppu_ctrl_registers[index];
-        self.oam_address = self.oam_address.wrapping_add(1);
-        memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
 
-        panic!("Implement OAMDATA write handling");
+        // What a read returns depends on where the PPU is in the frame, so
+        // the register is kept up to date every cycle.
+        self.prepare_oam_data_read(memory);
+    }
+
+    /// Places the value the next OAMDATA read should return into the I/O
+    /// register. While secondary OAM is being cleared reads return $FF, and
+    /// for the rest of sprite fetching they return whatever OAMADDR points at.
+    /// The unused bits of sprite attributes don't exist in OAM, so they always
+    /// read back as 0.
+    fn prepare_oam_data_read(&mut self, memory: &mut Memory) {
+        let clearing = self.dot >= 1 && self.dot <= SECONDARY_OAM_CLEAR_END;
+        memory.ppu_ctrl_registers[OAMDATA] = if self.fetching_sprites() && clearing {
+            0xFF
+        } else if self.oam_address & 0x03 == 2 {
+            self.spr_ram[self.oam_address as usize] & !SPRITE_ATTRIBUTE_UNUSED
+        } else {
+            self.spr_ram[self.oam_address as usize]
+        };
+    }
+
+    /// Returns true while the PPU is fetching sprites for the next scanline,
+    /// which is when OAM is busy.
+    fn fetching_sprites(&self) -> bool {
+        self.rendering_enabled()
+            && (self.scanline < VISIBLE_SCANLINES || self.scanline == PRE_RENDER_SCANLINE)
     }
 
-    /// FIXME: Make accurate.
+    /// Handles writes to PPUSCROLL. The first write sets the coarse X scroll
+    /// in the temporary VRAM address along with fine X, and the second sets
+    /// the coarse and fine Y scroll.
     fn handle_ppu_scroll(&mut self, index: usize, memory: &mut Memory) {
         let state = memory.ppu_ctrl_registers_status[index];
-        if state != PPURegisterStatus::WrittenTwice {
+        if state != PPURegisterStatus::Written && state != PPURegisterStatus::WrittenTwice {
             return;
         }
-        panic!("Implement PPUSCROLL write handling");
+        self.ppu_scroll = memory.ppu_ctrl_registers[index];
+        memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
+
+        let value = self.ppu_scroll as u16;
+        if !self.w {
+            self.t = (self.t & !0x001F) | (value >> 3);
+            self.x = self.ppu_scroll & 0x07;
+        } else {
+            self.t = (self.t & !0x73E0) | ((value & 0x07) << 12) | ((value & 0xF8) << 2);
+        }
+        self.w = !self.w;
     }
 
-    /// FIXME: Make accurate.
+    /// Handles writes to PPUADDR. The first write sets the high 6 bits of the
+    /// temporary VRAM address and the second sets the low 8 bits, after which
+    /// the temporary address is copied into the current VRAM address.
     fn handle_ppu_address(&mut self, index: usize, memory: &mut Memory) {
         let state = memory.ppu_ctrl_registers_status[index];
-        if state != PPURegisterStatus::WrittenTwice {
+        if state != PPURegisterStatus::Written && state != PPURegisterStatus::WrittenTwice {
             return;
         }
-        panic!("Implement PPUADDR write handling");
+        self.ppu_addr = memory.ppu_ctrl_registers[index];
+        memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
+
+        if !self.w {
+            self.t = (self.t & 0x00FF) | ((self.ppu_addr as u16 & 0x3F) << 8);
+        } else {
+            self.t = (self.t & 0x7F00) | self.ppu_addr as u16;
+            self.v = self.t;
+            self.prepare_ppu_data_read(memory);
+        }
+        self.w = !self.w;
     }
 
-    /// FIXME: Make accurate.
+    /// Handles reads and writes to PPUDATA. Either way the current VRAM
+    /// address is incremented by 1 or 32 depending on PPUCTRL, wrapping within
+    /// the 15-bit address space.
     fn handle_ppu_data(&mut self, index: usize, memory: &mut Memory) {
         let state = memory.ppu_ctrl_registers_status[index];
-        if state != PPURegisterStatus::Written || state != PPURegisterStatus::WrittenTwice {
+        let addr = (self.v & 0x3FFF) as usize;
+        match state {
+            PPURegisterStatus::Written | PPURegisterStatus::WrittenTwice => {
+                self.ppu_data = memory.ppu_ctrl_registers[index];
+                let value = self.ppu_data;
+                self.write_u8(addr, value);
+            }
+            PPURegisterStatus::Read => {
+                // Palette reads aren't buffered, but the buffer is still filled
+                // with the nametable data "underneath" the palettes.
+                self.read_buffer = if addr >= PALETTES_START {
+                    self.read_u8(addr - 0x1000)
+                } else {
+                    self.read_u8(addr)
+                };
+            }
+            PPURegisterStatus::Untouched => return,
+        }
+        memory.ppu_ctrl_registers_status[index] = PPURegisterStatus::Untouched;
+
+        let increment = self.ppu_ctrl_vram_address_increment() as u16;
+        self.v = self.v.wrapping_add(increment) & 0x7FFF;
+        self.prepare_ppu_data_read(memory);
+    }
+
+    /// Places the value the next PPUDATA read should return into the I/O
+    /// register. Palette memory is returned directly while everything else
+    /// comes from the read buffer.
+    fn prepare_ppu_data_read(&mut self, memory: &mut Memory) {
+        let addr = (self.v & 0x3FFF) as usize;
+        memory.ppu_ctrl_registers[PPUDATA] = if addr >= PALETTES_START {
+            self.read_u8(addr)
+        } else {
+            self.read_buffer
+        };
+    }
+
+    /// Does the rendering work for the current dot while rendering is enabled.
+    /// Background tiles are fetched 2 tiles ahead of the pixel being drawn,
+    /// and the sprites for the next scanline are found and fetched once the
+    /// visible part of the current one is done. Everything is fetched through
+    /// the mapper so mappers that watch the PPU's fetches see them.
+    fn render_dot(&mut self) {
+        let visible_scanline = self.scanline < VISIBLE_SCANLINES;
+        if !visible_scanline && self.scanline != PRE_RENDER_SCANLINE {
             return;
         }
-        panic!("Implement PPUDATA write handling");
+
+        let visible_dot = self.dot >= 1 && self.dot <= VISIBLE_DOTS;
+        let prefetch_dot = self.dot >= PREFETCH_START && self.dot <= PREFETCH_END;
+        if visible_scanline && visible_dot {
+            self.output_pixel();
+        }
+        if visible_dot || prefetch_dot {
+            self.fetch_background();
+        }
+
+        if self.dot == VISIBLE_DOTS {
+            self.increment_y();
+        } else if self.dot == VISIBLE_DOTS + 1 {
+            self.copy_horizontal_scroll();
+            if visible_scanline {
+                self.evaluate_sprites();
+            } else {
+                // Sprites are never drawn on the first visible scanline.
+                self.line_sprites.clear();
+            }
+        }
+
+        if !visible_scanline && self.dot >= COPY_VERTICAL_START && self.dot <= COPY_VERTICAL_END {
+            self.copy_vertical_scroll();
+        }
+    }
+
+    /// Draws the pixel for the current dot into the framebuffer. The
+    /// background and sprite pixels at this position are picked between by
+    /// multiplex_pixel, and sprite 0 hits are detected along the way.
+    fn output_pixel(&mut self) {
+        let x = (self.dot - 1) as u8;
+        let background = self.background_pixel();
+        let (sprite, behind, sprite_zero) = self.sprite_pixel(x);
+
+        // Sprite 0 hits only happen where both layers are drawn and opaque,
+        // and never on the last pixel of the scanline.
+        if sprite_zero
+            && x != 255
+            && background & 0x03 != 0
+            && self.background_visible(x)
+            && self.sprites_visible(x)
+        {
+            self.ppu_status |= PPUSTATUS_SPRITE_0_HIT;
+        }
+
+        let pixel = self.multiplex_pixel(x, background, sprite, behind);
+        let mut color = self.palettes[pixel as usize] & 0x3F;
+        if self.ppu_mask_greyscale() {
+            color &= 0x30;
+        }
+        let offset = self.scanline as usize * VISIBLE_DOTS as usize + x as usize;
+        self.framebuffer[offset] = color;
+    }
+
+    /// Returns the background pixel at the current dot as a 4-bit palette
+    /// index, taking the fine X scroll into account.
+    fn background_pixel(&self) -> u8 {
+        let tile = (self.tile_data >> 32) as u32;
+        ((tile >> ((7 - self.x) * 4)) & 0x0F) as u8
+    }
+
+    /// Returns the sprite pixel at the given X position as a 4-bit palette
+    /// index, along with whether it's behind the background and whether it
+    /// belongs to sprite 0. Sprites earlier in OAM are in front of later ones,
+    /// even when they're behind the background.
+    fn sprite_pixel(&self, x: u8) -> (u8, bool, bool) {
+        for line_sprite in self.line_sprites.iter() {
+            let sprite = line_sprite.sprite;
+            if x < sprite.x || x - sprite.x >= 8 {
+                continue;
+            }
+            let bit = 7 - (x - sprite.x);
+            let low = (line_sprite.pattern_low >> bit) & 1;
+            let high = (line_sprite.pattern_high >> bit) & 1;
+            let color = high << 1 | low;
+            if color != 0 {
+                let pixel = sprite.palette() << 2 | color;
+                return (pixel, sprite.behind_background(), line_sprite.sprite_zero);
+            }
+        }
+        (0, false, false)
+    }
+
+    /// Shifts the background pixels along by one and fetches the part of the
+    /// next tile that's due on this dot. Each of the nametable, attribute and
+    /// 2 pattern fetches take 2 dots, after which the tile is loaded into the
+    /// shift register.
+    fn fetch_background(&mut self) {
+        self.tile_data <<= 4;
+        match self.dot % 8 {
+            1 => {
+                let addr = NAME_TABLES_START | (self.v & 0x0FFF) as usize;
+                self.nametable_latch = self.read_u8(addr);
+            }
+            3 => {
+                let v = self.v as usize;
+                let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                self.attribute_latch = (self.read_u8(addr) >> shift) & 0x03;
+            }
+            5 => {
+                let addr = self.background_pattern_address();
+                self.pattern_low_latch = self.read_u8(addr);
+            }
+            7 => {
+                let addr = self.background_pattern_address() + 8;
+                self.pattern_high_latch = self.read_u8(addr);
+            }
+            0 => {
+                self.load_tile();
+                self.increment_coarse_x();
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the address of the low bit plane of the current row of the
+    /// background tile in the nametable latch.
+    fn background_pattern_address(&self) -> usize {
+        let fine_y = (self.v >> 12) as usize & 0x07;
+        self.ppu_ctrl_background_pattern_table_address()
+            + self.nametable_latch as usize * 16
+            + fine_y
+    }
+
+    /// Moves the fetched background tile into the lower half of the shift
+    /// register as 8 pixels.
+    fn load_tile(&mut self) {
+        let mut data: u32 = 0;
+        for col in 0..8 {
+            let bit = 7 - col;
+            let low = (self.pattern_low_latch >> bit) & 1;
+            let high = (self.pattern_high_latch >> bit) & 1;
+            data = (data << 4) | (self.attribute_latch << 2 | high << 1 | low) as u32;
+        }
+        self.tile_data |= data as u64;
+    }
+
+    /// Moves v to the next tile horizontally, switching to the horizontally
+    /// adjacent nametable when wrapping around.
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    /// Moves v to the next row of pixels, switching to the vertically adjacent
+    /// nametable after the 30th row of tiles. Coarse Y values past that, which
+    /// point into the attribute table, wrap without switching.
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let coarse_y = match (self.v & 0x03E0) >> 5 {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            }
+            31 => 0,
+            coarse_y => coarse_y + 1,
+        };
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    /// Copies the horizontal scroll bits of t into v.
+    fn copy_horizontal_scroll(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    /// Copies the vertical scroll bits of t into v.
+    fn copy_vertical_scroll(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    /// Finds the first 8 sprites in OAM on the next scanline and fetches their
+    /// patterns. The sprite overflow flag is set if there are more than that.
+    fn evaluate_sprites(&mut self) {
+        let height = if self.tall_sprites() { 16 } else { 8 };
+        self.line_sprites.clear();
+        for (index, sprite) in self.sprites().into_iter().enumerate() {
+            let row = self.scanline.wrapping_sub(sprite.y as u16);
+            if row >= height {
+                continue;
+            }
+            if self.line_sprites.len() == SPRITES_PER_SCANLINE {
+                self.ppu_status |= PPUSTATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            let (pattern_low, pattern_high) = self.fetch_sprite_row(sprite, row);
+            self.line_sprites.push(LineSprite {
+                sprite: sprite,
+                pattern_low: pattern_low,
+                pattern_high: pattern_high,
+                sprite_zero: index == 0,
+            });
+        }
+    }
+
+    /// Fetches both bit planes of a row of a sprite's pattern, flipped the way
+    /// the sprite is. 8x16 sprites pick their pattern table with bit 0 of the
+    /// tile number and are made of that tile and the one after it.
+    fn fetch_sprite_row(&mut self, sprite: Sprite, row: u16) -> (u8, u8) {
+        let height = if self.tall_sprites() { 16 } else { 8 };
+        let row = if sprite.flip_vertical() { height - 1 - row } else { row } as usize;
+        let addr = if self.tall_sprites() {
+            let pattern_table = (sprite.tile as usize & 0x01) * 0x1000;
+            let tile = (sprite.tile as usize & 0xFE) + row / 8;
+            pattern_table + tile * 16 + row % 8
+        } else {
+            self.ppu_ctrl_sprite_pattern_table_address() + sprite.tile as usize * 16 + row
+        };
+
+        let low = self.read_u8(addr);
+        let high = self.read_u8(addr + 8);
+        if sprite.flip_horizontal() {
+            (arithmetic::reverse_bits(low), arithmetic::reverse_bits(high))
+        } else {
+            (low, high)
+        }
     }
 
     /// Checks the status of PPU I/O registers and executes PPU functionality
@@ -470,20 +1183,45 @@ impl PPU {
     }
 
     /// Checks the status of misc I/O registers and executes PPU functionality
-    /// depending on their states.
-    fn check_misc_registers(&mut self, memory: &mut Memory) {
-        for index in 0x0..0x20 {
-            match index {
-                OAMDMA => self.handle_dma_register(index, memory),
+    /// depending on their states. The remaining misc registers belong to the
+    /// APU and controllers so they are left alone here.
+    fn check_misc_registers(&mut self, memory: &mut Memory) -> u16 {
+        self.handle_dma_register(OAMDMA, memory)
+    }
 
-                // FIXME: PPU does not need to handle all misc I/O registers.
-                // Remove this panic later.
-                _ => {
-                    if memory.misc_ctrl_registers_status[index] != MiscRegisterStatus::Untouched {
-                        panic!("Unsupported misc register touched: 0x{:02X}", index);
-                    }
-                },
-            };
+    /// Returns true if an NMI was raised since the last poll. The pending NMI
+    /// is cleared so it is only delivered to the CPU once.
+    pub fn poll_nmi(&mut self) -> bool {
+        let nmi_occurred = self.nmi_occurred;
+        self.nmi_occurred = false;
+        nmi_occurred
+    }
+
+    /// Returns true if a frame was finished since the last poll. Like NMIs,
+    /// each frame is only reported once.
+    pub fn poll_frame(&mut self) -> bool {
+        let frame_complete = self.frame_complete;
+        self.frame_complete = false;
+        frame_complete
+    }
+
+    /// Returns the scanline the PPU is currently on.
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// Returns the dot the PPU is currently outputting on the scanline.
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    /// Moves on to the next dot, wrapping to the next scanline and frame.
+    #[inline(always)]
+    fn advance_dot(&mut self) {
+        self.dot += 1;
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline = (self.scanline + 1) % SCANLINES_PER_FRAME;
         }
     }
 
@@ -492,8 +1230,424 @@ impl PPU {
     pub fn step(&mut self, memory: &mut Memory) -> u16 {
         // Check the dirty state of each of the I/O registers used by the PPU.
         self.check_ppu_registers(memory);
-        self.check_misc_registers(memory);
 
-        0 // TODO: Throw in DMA cycles.
+        let mirroring = self.mapper.borrow().mirroring();
+        if mirroring != self.mirroring {
+            self.set_mirroring(mirroring);
+        }
+
+        // The frame ends and vblank starts once the picture has been output,
+        // raising an NMI if they're enabled. The vblank and sprite flags then
+        // stay set until the end of vblank.
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            self.ppu_status |= PPUSTATUS_VBLANK;
+            self.frame_complete = true;
+            if self.ppu_ctrl_nmi_enabled() {
+                self.nmi_occurred = true;
+            }
+        } else if self.scanline == PRE_RENDER_SCANLINE && self.dot == 1 {
+            self.ppu_status &=
+                !(PPUSTATUS_VBLANK | PPUSTATUS_SPRITE_OVERFLOW | PPUSTATUS_SPRITE_0_HIT);
+        }
+        if self.rendering_enabled() {
+            self.render_dot();
+        }
+        memory.ppu_ctrl_registers[PPUSTATUS] = self.ppu_status;
+
+        let stolen_cycles = self.check_misc_registers(memory);
+        self.advance_dot();
+
+        stolen_cycles
+    }
+}
+
+impl fmt::Display for PPU {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "").unwrap();
+        writeln!(f, "===== PPU State =====").unwrap();
+        writeln!(f, "").unwrap();
+        writeln!(f, "Scanline:  {}", self.scanline).unwrap();
+        writeln!(f, "Dot:       {}", self.dot).unwrap();
+        writeln!(f, "PPUCTRL:   {:#04X}", self.ppu_ctrl).unwrap();
+        writeln!(f, "PPUMASK:   {:#04X}", self.ppu_mask).unwrap();
+        writeln!(f, "PPUSTATUS: {:#04X}", self.ppu_status).unwrap();
+        writeln!(f, "OAMADDR:   {:#04X}", self.oam_address).unwrap();
+        writeln!(f, "V:         {:#06X}", self.v).unwrap();
+        writeln!(f, "T:         {:#06X}", self.t).unwrap();
+        writeln!(f, "Fine X:    {}", self.x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::cpu::CPU;
+    use nes::instruction::Instruction;
+    use nes::testing;
+
+    // Colors the test scene is drawn with.
+    const BACKDROP:           u8 = 0x0F;
+    const BACKGROUND_COLOR:   u8 = 0x21;
+    const FRONT_SPRITE_COLOR: u8 = 0x16;
+    const BACK_SPRITE_COLOR:  u8 = 0x2A;
+
+    /// Draws 2 frames of a scene where every background tile is solid except
+    /// for the 7th column, which is transparent. There's a front priority
+    /// sprite at X 16 and back priority ones at X 32 and X 48, all from
+    /// scanline 10, and a front priority sprite at X 0 for left clipping.
+    fn render(mask: u8) -> PPU {
+        let mapper = testing::mapper(&testing::nrom(&[]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+
+        // Tile 1 is solid color 1 and tile 2 is solid color 3.
+        for row in 0..8 {
+            ppu.write_u8(0x0010 + row, 0xFF);
+            ppu.write_u8(0x0020 + row, 0xFF);
+            ppu.write_u8(0x0028 + row, 0xFF);
+        }
+        for offset in 0..0x3C0 {
+            let tile = if offset % 32 == 6 { 0 } else { 1 };
+            ppu.write_u8(0x2000 + offset, tile);
+        }
+        ppu.write_u8(0x3F00, BACKDROP);
+        ppu.write_u8(0x3F01, BACKGROUND_COLOR);
+        ppu.write_u8(0x3F17, FRONT_SPRITE_COLOR);
+        ppu.write_u8(0x3F1B, BACK_SPRITE_COLOR);
+
+        let sprites = [[9, 2, 0x01, 16], [9, 2, 0x22, 32], [9, 2, 0x22, 48], [9, 2, 0x01, 0]];
+        for (index, sprite) in sprites.iter().enumerate() {
+            ppu.spr_ram[index * 4..index * 4 + 4].copy_from_slice(sprite);
+        }
+
+        ppu.ppu_mask = mask;
+        for _ in 0..2 * DOTS_PER_SCANLINE as usize * SCANLINES_PER_FRAME as usize {
+            ppu.step(&mut memory);
+        }
+        ppu
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u8 {
+        ppu.framebuffer()[y * VISIBLE_DOTS as usize + x]
+    }
+
+    #[test]
+    fn sprite_priority_decides_which_layer_is_drawn() {
+        let ppu = render(PPUMASK_SHOW_BACKGROUND | PPUMASK_SHOW_SPRITES);
+
+        assert_eq!(pixel(&ppu, 100, 12), BACKGROUND_COLOR);
+        assert_eq!(pixel(&ppu, 20, 12), FRONT_SPRITE_COLOR);
+        assert_eq!(pixel(&ppu, 36, 12), BACKGROUND_COLOR);
+        assert_eq!(pixel(&ppu, 52, 12), BACK_SPRITE_COLOR);
+
+        // The sprites start on the scanline after their Y position and are 8
+        // pixels wide and tall.
+        assert_eq!(pixel(&ppu, 20, 9), BACKGROUND_COLOR);
+        assert_eq!(pixel(&ppu, 20, 10), FRONT_SPRITE_COLOR);
+        assert_eq!(pixel(&ppu, 20, 17), FRONT_SPRITE_COLOR);
+        assert_eq!(pixel(&ppu, 20, 18), BACKGROUND_COLOR);
+        assert_eq!(pixel(&ppu, 15, 12), BACKGROUND_COLOR);
+        assert_eq!(pixel(&ppu, 24, 12), BACKGROUND_COLOR);
+    }
+
+    #[test]
+    fn left_column_is_clipped() {
+        let ppu = render(PPUMASK_SHOW_BACKGROUND | PPUMASK_SHOW_SPRITES);
+        assert_eq!(pixel(&ppu, 0, 12), BACKDROP);
+        assert_eq!(pixel(&ppu, 7, 12), BACKDROP);
+        assert_eq!(pixel(&ppu, 8, 12), BACKGROUND_COLOR);
+
+        let ppu = render(
+            PPUMASK_SHOW_BACKGROUND | PPUMASK_SHOW_SPRITES | PPUMASK_SHOW_BACKGROUND_LEFT,
+        );
+        assert_eq!(pixel(&ppu, 0, 12), BACKGROUND_COLOR);
+        assert_eq!(pixel(&ppu, 7, 12), BACKGROUND_COLOR);
+
+        let ppu = render(
+            PPUMASK_SHOW_BACKGROUND
+                | PPUMASK_SHOW_SPRITES
+                | PPUMASK_SHOW_BACKGROUND_LEFT
+                | PPUMASK_SHOW_SPRITES_LEFT,
+        );
+        assert_eq!(pixel(&ppu, 0, 12), FRONT_SPRITE_COLOR);
+        assert_eq!(pixel(&ppu, 7, 12), FRONT_SPRITE_COLOR);
+        assert_eq!(pixel(&ppu, 0, 8), BACKGROUND_COLOR);
+    }
+
+    #[test]
+    fn disabled_layers_are_transparent() {
+        let ppu = render(PPUMASK_SHOW_SPRITES);
+        assert_eq!(pixel(&ppu, 100, 12), BACKDROP);
+        assert_eq!(pixel(&ppu, 20, 12), FRONT_SPRITE_COLOR);
+        assert_eq!(pixel(&ppu, 36, 12), BACK_SPRITE_COLOR);
+
+        let ppu = render(PPUMASK_SHOW_BACKGROUND);
+        assert_eq!(pixel(&ppu, 20, 12), BACKGROUND_COLOR);
+        assert_eq!(pixel(&ppu, 52, 12), BACKDROP);
+    }
+
+    /// Steps the PPU until it's about to output the given dot.
+    fn run_to(ppu: &mut PPU, memory: &mut Memory, scanline: u16, dot: u16) {
+        while ppu.scanline() != scanline || ppu.dot() != dot {
+            ppu.step(memory);
+        }
+    }
+
+    #[test]
+    fn vblank_ends_the_frame_and_raises_nmi() {
+        let mapper = testing::mapper(&testing::nrom(&[]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        ppu.ppu_ctrl = PPUCTRL_NMI_ENABLE;
+        ppu.ppu_status = 0;
+
+        run_to(&mut ppu, &mut memory, VBLANK_SCANLINE, 1);
+        assert!(!ppu.ppu_status_vblank());
+        assert!(!ppu.poll_nmi());
+        assert!(!ppu.poll_frame());
+
+        ppu.step(&mut memory);
+        assert!(ppu.ppu_status_vblank());
+        assert!(ppu.poll_nmi());
+        assert!(ppu.poll_frame());
+        assert!(!ppu.poll_frame());
+
+        // Reading PPUSTATUS returns the flag and then clears it.
+        assert_eq!(memory.read_u8(0x2002) & PPUSTATUS_VBLANK, PPUSTATUS_VBLANK);
+        ppu.step(&mut memory);
+        assert!(!ppu.ppu_status_vblank());
+        assert_eq!(memory.read_u8(0x2002) & PPUSTATUS_VBLANK, 0);
+
+        // Without NMIs enabled the frame still ends, and the flag is cleared
+        // on the pre-render scanline if it isn't read.
+        ppu.ppu_ctrl = 0;
+        run_to(&mut ppu, &mut memory, VBLANK_SCANLINE, 2);
+        assert!(ppu.ppu_status_vblank());
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.poll_frame());
+        run_to(&mut ppu, &mut memory, PRE_RENDER_SCANLINE, 1);
+        assert!(ppu.ppu_status_vblank());
+        ppu.step(&mut memory);
+        assert!(!ppu.ppu_status_vblank());
+    }
+
+    #[test]
+    fn oam_data_writes_land_at_oam_addr() {
+        let mapper = testing::mapper(&testing::nrom(&[]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        let mut write = |ppu: &mut PPU, addr: usize, value: u8| {
+            memory.write_u8(addr, value);
+            ppu.step(&mut memory);
+        };
+
+        // Each write bumps OAMADDR, wrapping around the end of OAM.
+        write(&mut ppu, 0x2003, 0x10);
+        write(&mut ppu, 0x2004, 0xAA);
+        write(&mut ppu, 0x2004, 0xBB);
+        write(&mut ppu, 0x2003, 0xFF);
+        write(&mut ppu, 0x2004, 0xCC);
+        write(&mut ppu, 0x2004, 0xDD);
+        assert_eq!(&ppu.spr_ram[0x10..0x12], &[0xAA, 0xBB]);
+        assert_eq!(ppu.spr_ram[0xFF], 0xCC);
+        assert_eq!(ppu.spr_ram[0x00], 0xDD);
+        assert_eq!(ppu.oam_address, 0x01);
+
+        // Reads come from OAMADDR without moving it.
+        write(&mut ppu, 0x2003, 0x11);
+        assert_eq!(memory.read_u8(0x2004), 0xBB);
+        ppu.step(&mut memory);
+        assert_eq!(memory.read_u8(0x2004), 0xBB);
+    }
+
+    #[test]
+    fn four_screen_carts_keep_every_name_table_separate() {
+        let mut rom = testing::nrom(&[]);
+        rom[6] |= 0x08;
+        let mut ppu = PPU::new(testing::runtime_options(), testing::mapper(&rom));
+        assert_eq!(ppu.mirroring, MirrorType::Both);
+
+        let tables = [0x2000, 0x2400, 0x2800, 0x2C00];
+        for (index, &table) in tables.iter().enumerate() {
+            ppu.write_u8(table + 0x123, 0x10 + index as u8);
+        }
+        for (index, &table) in tables.iter().enumerate() {
+            assert_eq!(ppu.read_u8(table + 0x123), 0x10 + index as u8);
+            assert_eq!(ppu.read_u8(table + 0x1123), 0x10 + index as u8);
+        }
+    }
+
+    #[test]
+    fn sprite_backdrop_entries_mirror_the_background_ones() {
+        let mut ppu = PPU::new(testing::runtime_options(), testing::mapper(&testing::nrom(&[])));
+
+        let mirrors = [(0x3F10, 0x3F00), (0x3F14, 0x3F04), (0x3F18, 0x3F08), (0x3F1C, 0x3F0C)];
+        for &(sprite, background) in mirrors.iter() {
+            ppu.write_u8(sprite, 0x21);
+            assert_eq!(ppu.read_u8(background), 0x21);
+            ppu.write_u8(background, 0x12);
+            assert_eq!(ppu.read_u8(sprite), 0x12);
+            assert_eq!(ppu.read_u8(sprite + 0x20), 0x12);
+        }
+
+        // The other sprite palette entries have memory of their own.
+        ppu.write_u8(0x3F01, 0x01);
+        ppu.write_u8(0x3F11, 0x11);
+        assert_eq!(ppu.read_u8(0x3F01), 0x01);
+        assert_eq!(ppu.palettes()[0x11], 0x11);
+    }
+
+    #[test]
+    fn ppudata_writes_step_by_the_configured_increment() {
+        let mapper = testing::mapper(&testing::nrom(&[]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        let mut write = |ppu: &mut PPU, addr: usize, value: u8| {
+            memory.write_u8(addr, value);
+            ppu.step(&mut memory);
+        };
+
+        // With the increment bit set, writes go down a column of tiles.
+        write(&mut ppu, 0x2000, PPUCTRL_VRAM_ADDRESS_INCREMENT);
+        write(&mut ppu, 0x2006, 0x20);
+        write(&mut ppu, 0x2006, 0x00);
+        for value in 1..4 {
+            write(&mut ppu, 0x2007, value);
+        }
+        assert_eq!(ppu.read_u8(0x2000), 1);
+        assert_eq!(ppu.read_u8(0x2020), 2);
+        assert_eq!(ppu.read_u8(0x2040), 3);
+        assert_eq!(ppu.read_u8(0x2001), 0);
+
+        // Without it they go across a row.
+        write(&mut ppu, 0x2000, 0);
+        write(&mut ppu, 0x2006, 0x21);
+        write(&mut ppu, 0x2006, 0x00);
+        for value in 4..7 {
+            write(&mut ppu, 0x2007, value);
+        }
+        assert_eq!(&[ppu.read_u8(0x2100), ppu.read_u8(0x2101), ppu.read_u8(0x2102)], &[4, 5, 6]);
+    }
+
+    #[test]
+    fn mapper_mirroring_changes_apply_while_running() {
+        // An MMC1 cart, which sets mirroring through its control register.
+        let mut rom = testing::nrom(&[]);
+        rom[6] |= 0x10;
+        let mapper = testing::mapper(&rom);
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper.clone());
+        let set_control = |ppu: &mut PPU, memory: &mut Memory, value: u8| {
+            for bit in 0..5 {
+                mapper.borrow_mut().write_prg(0x8000, value >> bit);
+            }
+            ppu.step(memory);
+        };
+
+        set_control(&mut ppu, &mut memory, 0x0F);
+        assert_eq!(ppu.mirroring, MirrorType::Horizontal);
+        ppu.write_u8(0x2400, 0xAA);
+        assert_eq!(ppu.read_u8(0x2000), 0xAA);
+        assert_eq!(ppu.read_u8(0x2800), 0x00);
+
+        // $2400 now folds to the second physical table rather than the first.
+        set_control(&mut ppu, &mut memory, 0x0E);
+        assert_eq!(ppu.mirroring, MirrorType::Vertical);
+        assert_eq!(ppu.read_u8(0x2400), 0x00);
+        assert_eq!(ppu.read_u8(0x2800), 0xAA);
+    }
+
+    #[test]
+    fn disassembling_a_ppustatus_read_leaves_vblank_set() {
+        // LDA $2002
+        let mapper = testing::mapper(&testing::nrom(&[0xAD, 0x02, 0x20]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        let cpu = CPU::new(testing::runtime_options());
+        run_to(&mut ppu, &mut memory, VBLANK_SCANLINE, 2);
+        assert!(ppu.ppu_status_vblank());
+
+        let instr = Instruction::parse_unrestricted(0x8000, &mut memory).unwrap();
+        let disassembled = instr.disassemble(&cpu, &mut memory);
+        assert!(disassembled.starts_with("LDA $2002 = "), "{}", disassembled);
+        ppu.step(&mut memory);
+        assert!(ppu.ppu_status_vblank());
+
+        // Actually running the read clears the flag.
+        memory.read_u8(0x2002);
+        ppu.step(&mut memory);
+        assert!(!ppu.ppu_status_vblank());
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank_raises_it_straight_away() {
+        let mapper = testing::mapper(&testing::nrom(&[]));
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper);
+        ppu.ppu_ctrl = 0;
+        run_to(&mut ppu, &mut memory, VBLANK_SCANLINE, 10);
+        assert!(ppu.ppu_status_vblank());
+        assert!(!ppu.poll_nmi());
+
+        memory.write_u8(0x2000, PPUCTRL_NMI_ENABLE);
+        ppu.step(&mut memory);
+        assert!(ppu.poll_nmi());
+
+        // Writing it again while it's already enabled doesn't raise another.
+        memory.write_u8(0x2000, PPUCTRL_NMI_ENABLE);
+        ppu.step(&mut memory);
+        assert!(!ppu.poll_nmi());
+
+        // Outside of vblank nothing happens until the next one starts.
+        memory.write_u8(0x2000, 0);
+        run_to(&mut ppu, &mut memory, 10, 0);
+        memory.write_u8(0x2000, PPUCTRL_NMI_ENABLE);
+        ppu.step(&mut memory);
+        assert!(!ppu.poll_nmi());
+    }
+
+    #[test]
+    fn four_screen_mmc1_carts_ignore_the_mirroring_register() {
+        let mut rom = testing::nrom(&[]);
+        rom[6] |= 0x18;
+        let mapper = testing::mapper(&rom);
+        let mut memory = Memory::new(testing::runtime_options(), mapper.clone());
+        let mut ppu = PPU::new(testing::runtime_options(), mapper.clone());
+        assert_eq!(ppu.mirroring, MirrorType::Both);
+
+        // Ask for single-screen mirroring, which the board's VRAM overrides.
+        for _ in 0..5 {
+            mapper.borrow_mut().write_prg(0x8000, 0x00);
+        }
+        ppu.step(&mut memory);
+        assert_eq!(ppu.mirroring, MirrorType::Both);
+
+        let tables = [0x2000, 0x2400, 0x2800, 0x2C00];
+        for (index, &table) in tables.iter().enumerate() {
+            ppu.write_u8(table, 0x10 + index as u8);
+        }
+        for (index, &table) in tables.iter().enumerate() {
+            assert_eq!(ppu.read_u8(table), 0x10 + index as u8);
+        }
+    }
+
+    #[test]
+    fn horizontally_flipped_sprites_reverse_their_pattern_rows() {
+        let mut ppu = PPU::new(testing::runtime_options(), testing::mapper(&testing::nrom(&[])));
+        ppu.write_u8(0x0000, 0b1100_0001);
+        ppu.write_u8(0x0008, 0b0000_0011);
+        let sprite = Sprite {
+            y: 0,
+            tile: 0,
+            attributes: 0,
+            x: 0,
+        };
+
+        assert_eq!(ppu.fetch_sprite_row(sprite, 0), (0b1100_0001, 0b0000_0011));
+        let flipped = Sprite {
+            attributes: SPRITE_ATTRIBUTE_FLIP_HORIZONTAL,
+            ..sprite
+        };
+        assert_eq!(ppu.fetch_sprite_row(flipped, 0), (0b1000_0011, 0b1100_0000));
     }
 }
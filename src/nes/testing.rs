@@ -0,0 +1,77 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use io::binutils::{INESHeader, HEADER_SIZE, PRG_ROM_BANK_SIZE};
+use io::log::LogLevel;
+use nes::cpu::CPU;
+use nes::mapper::{self, SharedMapper};
+use nes::memory::Memory;
+use nes::nes::NESRuntimeOptions;
+use std::cell::RefCell;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+
+/// Returns the options the emulator would run with if it was started with
+/// --quiet and nothing else, so tests only print errors.
+pub fn runtime_options() -> NESRuntimeOptions {
+    NESRuntimeOptions {
+        log_level: LogLevel::Error,
+        no_audio: true,
+        headless: true,
+        ..Default::default()
+    }
+}
+
+/// Builds an iNES image of an NROM cart with 32KB of PRG-ROM and 8KB of
+/// CHR-RAM. The program is placed at $8000, which every interrupt vector
+/// points to.
+pub fn nrom(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; HEADER_SIZE + 2 * PRG_ROM_BANK_SIZE];
+    rom[0..6].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A, 2, 0]);
+    rom[HEADER_SIZE..HEADER_SIZE + program.len()].copy_from_slice(program);
+    for vector in [0xFFFA, 0xFFFC, 0xFFFE].iter() {
+        set_vector(&mut rom, *vector, 0x8000);
+    }
+    rom
+}
+
+/// Points one of the interrupt vectors of an image built by nrom at the given
+/// address.
+pub fn set_vector(rom: &mut [u8], vector: usize, addr: u16) {
+    let offset = HEADER_SIZE + vector - 0x8000;
+    rom[offset] = addr as u8;
+    rom[offset + 1] = (addr >> 8) as u8;
+}
+
+/// Loads the cartridge in an iNES image, panicking if it can't be.
+pub fn mapper(rom: &[u8]) -> SharedMapper {
+    let header = INESHeader::new(rom).unwrap();
+    Rc::new(RefCell::new(mapper::load_cartridge(rom, &header).unwrap()))
+}
+
+/// Returns a powered on CPU along with memory holding the given program,
+/// ready to execute the first instruction at $8000.
+pub fn machine(program: &[u8]) -> (CPU, Memory) {
+    machine_with_options(runtime_options(), program)
+}
+
+/// Like machine, but with the given runtime options.
+pub fn machine_with_options(runtime_options: NESRuntimeOptions, program: &[u8]) -> (CPU, Memory) {
+    let mut memory = Memory::new(runtime_options.clone(), mapper(&nrom(program)));
+    let mut cpu = CPU::new(runtime_options);
+    cpu.power_on(&mut memory);
+    (cpu, memory)
+}
+
+/// Returns a path in the temporary directory that's unique to this test run,
+/// so tests running in parallel don't trip over each other's files.
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("nes-rs-test-{}-{}", process::id(), name))
+}
@@ -0,0 +1,173 @@
+// Copyright 2016 Walter Kuppens.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use nes::palette;
+
+// Bits of the controller port the Zapper drives. The light bit is low while
+// light is seen, and the trigger bit is high while it's pulled.
+const ZAPPER_NO_LIGHT: u8 = 0b00001000;
+const ZAPPER_TRIGGER:  u8 = 0b00010000;
+
+// Size of the picture the Zapper can be aimed at.
+const SCREEN_WIDTH:  i32 = 256;
+const SCREEN_HEIGHT: i32 = 240;
+
+// The photodiode keeps seeing light for about this many scanlines after the
+// beam passes over a bright spot, which is when games check for a hit.
+const LIGHT_SCANLINES: i32 = 20;
+
+// How many pixels around the aim the photodiode can see, and how bright one
+// of them has to be, as the sum of its red, green and blue components.
+const SENSOR_RADIUS:        i32 = 2;
+const BRIGHTNESS_THRESHOLD: u32 = 0x240;
+
+/// The Zapper light gun, which can be plugged into the second port in place
+/// of a controller. It's aimed and fired with the mouse.
+pub struct Zapper {
+    pub trigger: bool,
+
+    // Where on the screen the Zapper is pointed, or None when it's pointed
+    // off screen. Games reload when the trigger is pulled pointing at nothing.
+    pub aim: Option<(u8, u8)>,
+
+    // Whether the photodiode currently sees light, as of the scanline it was
+    // last updated on.
+    light: bool,
+    scanline: u16,
+}
+
+impl Zapper {
+    /// Returns a Zapper pointed off screen with the trigger released.
+    pub fn new() -> Self {
+        Zapper {
+            trigger: false,
+            aim: None,
+            light: false,
+            scanline: 0,
+        }
+    }
+
+    /// Points the Zapper at a position in the window, which is off screen if
+    /// it's outside of the picture.
+    pub fn aim_at(&mut self, x: i32, y: i32) {
+        self.aim = if x >= 0 && x < SCREEN_WIDTH && y >= 0 && y < SCREEN_HEIGHT {
+            Some((x as u8, y as u8))
+        } else {
+            None
+        };
+    }
+
+    /// Updates the photodiode as the PPU draws the given scanline. Light is
+    /// seen if there's a bright pixel around the aim and the beam passed over
+    /// it within the last few scanlines, so a target only registers while
+    /// it's being drawn and shortly after.
+    pub fn sense(&mut self, framebuffer: &[u8], scanline: u16) {
+        if scanline == self.scanline {
+            return;
+        }
+        self.scanline = scanline;
+        self.light = match self.aim {
+            Some((x, y)) => {
+                let since_drawn = scanline as i32 - y as i32;
+                since_drawn > 0
+                    && since_drawn <= LIGHT_SCANLINES
+                    && bright_spot(framebuffer, x as i32, y as i32)
+            }
+            None => false,
+        };
+    }
+
+    /// Returns true if the Zapper's photodiode sees a bright pixel where it's
+    /// aimed.
+    pub fn sees_light(&self) -> bool {
+        self.light
+    }
+
+    /// Reads the state of the light sensor and trigger. Reading doesn't shift
+    /// anything, so this has no side effects.
+    pub fn read(&self) -> u8 {
+        let mut value = 0;
+        if !self.sees_light() {
+            value |= ZAPPER_NO_LIGHT;
+        }
+        if self.trigger {
+            value |= ZAPPER_TRIGGER;
+        }
+        value
+    }
+}
+
+/// Returns true if any pixel of the framebuffer within the sensor's radius of
+/// the given position is bright enough to register as light.
+fn bright_spot(framebuffer: &[u8], x: i32, y: i32) -> bool {
+    for pixel_y in y - SENSOR_RADIUS..y + SENSOR_RADIUS + 1 {
+        for pixel_x in x - SENSOR_RADIUS..x + SENSOR_RADIUS + 1 {
+            if pixel_x < 0 || pixel_x >= SCREEN_WIDTH || pixel_y < 0 || pixel_y >= SCREEN_HEIGHT {
+                continue;
+            }
+            let color = framebuffer[(pixel_y * SCREEN_WIDTH + pixel_x) as usize];
+            let (r, g, b) = palette::rgb(color);
+            if r as u32 + g as u32 + b as u32 >= BRIGHTNESS_THRESHOLD {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Black and white in the NES palette.
+    const BLACK: u8 = 0x0F;
+    const WHITE: u8 = 0x30;
+
+    /// Returns a black frame with a white square from (100, 50) to (109, 59).
+    fn target_frame() -> Vec<u8> {
+        let mut framebuffer = vec![BLACK; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize];
+        for y in 50..60 {
+            for x in 100..110 {
+                framebuffer[y * SCREEN_WIDTH as usize + x] = WHITE;
+            }
+        }
+        framebuffer
+    }
+
+    #[test]
+    fn light_is_seen_after_the_target_is_drawn() {
+        let framebuffer = target_frame();
+        let mut zapper = Zapper::new();
+        zapper.aim_at(105, 55);
+
+        zapper.sense(&framebuffer, 40);
+        assert!(!zapper.sees_light());
+        assert_eq!(zapper.read() & ZAPPER_NO_LIGHT, ZAPPER_NO_LIGHT);
+
+        zapper.sense(&framebuffer, 56);
+        assert!(zapper.sees_light());
+        assert_eq!(zapper.read() & ZAPPER_NO_LIGHT, 0);
+
+        // The light fades once the beam has moved far enough down.
+        zapper.sense(&framebuffer, 100);
+        assert!(!zapper.sees_light());
+    }
+
+    #[test]
+    fn dark_pixels_and_pointing_off_screen_see_nothing() {
+        let framebuffer = target_frame();
+        let mut zapper = Zapper::new();
+        zapper.aim_at(20, 20);
+        zapper.sense(&framebuffer, 25);
+        assert!(!zapper.sees_light());
+
+        zapper.aim_at(-1, 55);
+        zapper.sense(&framebuffer, 56);
+        assert!(!zapper.sees_light());
+    }
+}
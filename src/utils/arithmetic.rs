@@ -26,10 +26,19 @@ pub fn add_relative(base_addr: u16, displacement: i8) -> u16 {
     }
 }
 
-/// Converts a hexadecimal string to a u16 with or without leading 0x.
+/// Reverses the order of the bits in a byte, which is how sprites are flipped
+/// horizontally.
+#[inline(always)]
+pub fn reverse_bits(arg: u8) -> u8 {
+    (0..8).fold(0, |reversed, bit| reversed | ((arg >> bit) & 1) << (7 - bit))
+}
+
+/// Converts a hexadecimal string to a u16 with or without leading 0x or $.
 pub fn hex_to_u16(hex: &String) -> Option<u16> {
     let stripped = if hex.len() >= 2 && &hex[0..2] == "0x" {
         &hex[2..]
+    } else if hex.len() >= 1 && &hex[0..1] == "$" {
+        &hex[1..]
     } else {
         hex.as_str()
     };
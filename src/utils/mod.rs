@@ -7,4 +7,5 @@
 // except according to those terms.
 
 pub mod arithmetic;
+pub mod hash;
 pub mod paging;